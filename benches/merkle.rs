@@ -28,10 +28,10 @@ pub fn map(crit: &mut Criterion) {
         }
     }));
 
-    let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+    let (alice, snap) = block::genesis();
     let builder = block::Builder::new(&alice, 1, &snap);
     let bob = account::Keypair::gen();
     crit.bench_function("state payment", |b| b.iter(|| {
-        assert!(builder.clone().add(alice.send(bob.kp.public, 1, state::JENNY_SLOTS)).is_ok());
+        assert!(builder.clone().add(alice.send(bob.kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS, None)).is_ok());
     }));
 }
\ No newline at end of file
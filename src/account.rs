@@ -1,36 +1,103 @@
 use ed25519_dalek::{self, Verifier, Signer};
-use rand::Rng;
 use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
-use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fmt::Debug;
+use std::ops::Range;
 use rand::rngs::OsRng;
 
-use crate::state::{State, VALIDATOR_SLOTS, VALIDATOR_STAKE};
-use crate::{txn, rollup, merkle, validator};
+use crate::state::VALIDATOR_SLOTS;
+use crate::{txn, rollup, merkle, validator, senator, attest};
 
 pub type Id = [u8; 32];
 pub type PublicKey = ed25519_dalek::PublicKey;
 pub type SecretKey = ed25519_dalek::SecretKey;
 pub type Signature = ed25519_dalek::Signature;
 
-pub const JENNY_PK_BYTES: [u8; 32] = [
-    78, 236, 79, 93, 128, 157, 88, 31, 
-    180, 214, 106, 188, 148, 28, 247, 180, 
-    192, 230, 246, 236, 44, 60, 26, 166, 
-    80, 178, 25, 196, 255, 66, 189, 177
-];
-pub const JENNY_SK_BYTES: [u8; 32] = [
-    191, 138, 2, 115, 144, 114, 100, 247, 
-    67, 205, 70, 44, 129, 0, 4, 97, 
-    247, 20, 168, 62, 111, 208, 138, 117, 
-    205, 14, 172, 198, 231, 24, 204, 42
-];
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct Data {
     pub bal: u32,
-    pub nonce: u32
+    pub nonce: u32,
+    // Frozen accounts can't send payments.
+    pub frozen: bool,
+    // Senators' votes on `frozen`, true to freeze, one each. Cleared once a majority agrees to flip it.
+    pub votes: BTreeSet<(senator::Id, bool)>,
+    // Parts of bal that can't be spent yet, each unlocking on its own schedule.
+    pub vesting: Vec<Vesting>
+}
+
+pub const MAX_TRANCHES: usize = 8; // locks an account holds at once, not counting ones already free
+
+pub type ReceiptId = [u8; 32];
+
+// Credit owed to an account in another shard. Delivered at the start of the next block.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Receipt {
+    pub id: ReceiptId,
+    pub to: Id,
+    pub amount: u32,
+    pub unlock_round: u32 // vests like a VestedPayment if it's in the future
+}
+
+// Locks `amount` at start_round, unlocking linearly until it's all free at unlock_round.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Vesting {
+    pub amount: u32,
+    pub start_round: u32,
+    pub unlock_round: u32
+}
+
+impl Vesting {
+    pub fn locked(&self, round: u32) -> u32 {
+        if round >= self.unlock_round {
+            0
+        } else if round <= self.start_round {
+            self.amount
+        } else {
+            let left = (self.unlock_round - round) as u64;
+            let total = (self.unlock_round - self.start_round) as u64;
+            (self.amount as u64 * left / total) as u32
+        }
+    }
+}
+
+impl Data {
+    pub fn locked(&self, round: u32) -> u32 {
+        self.vesting.iter().fold(0, |locked, v| locked.saturating_add(v.locked(round)))
+    }
+
+    pub fn spendable(&self, round: u32) -> u32 {
+        self.bal - self.locked(round).min(self.bal)
+    }
+
+    // Whether a lock until unlock_round would fit: there's a tranche free, or one it can join.
+    // `pending` counts locks already on their way here in receipts, each taking a tranche.
+    pub fn can_vest(&self, round: u32, unlock_round: u32, pending: usize) -> bool {
+        unlock_round <= round
+            || self.vesting.iter().filter(|v| v.locked(round) > 0).count() + pending < MAX_TRANCHES
+            || self.vesting.iter().any(|v| v.unlock_round >= unlock_round)
+    }
+
+    // Add a lock as a tranche of its own, so nobody else's lock can hold back what's already
+    // here. Once they're all taken it joins the one unlocking soonest after it: that tranche's
+    // own amount unlocks just as before, and the new one no sooner than asked. Callers check
+    // can_vest first, counting receipts on their way, so a delivered receipt always fits.
+    pub fn vest(&mut self, amount: u32, round: u32, unlock_round: u32) {
+        if unlock_round <= round {
+            return;
+        }
+        self.vesting.retain(|v| v.locked(round) > 0);
+        let full = self.vesting.len() >= MAX_TRANCHES;
+        let join = self.vesting.iter_mut()
+            .filter(|v| v.unlock_round >= unlock_round)
+            .min_by_key(|v| v.unlock_round);
+        match join {
+            Some(v) if full => {
+                *v = Vesting { amount: v.locked(round) + amount, start_round: round, unlock_round: v.unlock_round };
+            },
+            _ => self.vesting.push(Vesting { amount, start_round: round, unlock_round })
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,8 +111,18 @@ impl Keypair {
         Self { kp: ed25519_dalek::Keypair::generate(&mut csprng) }
     }
 
-    pub fn sign<T: Serialize>(&self, msg: &T) -> Signature {
-        self.kp.sign(&serde_json::to_string(&msg).expect("").as_bytes())
+    pub fn sign<T: Signable + ?Sized>(&self, msg: &T) -> Signature {
+        self.kp.sign(&msg.signing_bytes())
+    }
+
+    // Sign a txn built by hand, e.g. a resend with a higher fee.
+    pub fn sign_txn(&self, msg: txn::Txn) -> Signed<txn::Txn> {
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }
     }
 
     pub fn send(&self, to: PublicKey, amount: u32, nonce: u32, opt_rollup: Option<rollup::Id>) -> Signed<txn::Txn> {
@@ -56,70 +133,180 @@ impl Keypair {
         let msg = txn::Txn {
             payload: txn::Payload::Payment(to, amount),
             opt_rollup,
-            nonce
+            nonce,
+            fee: 0
         };
         let sig = self.sign(&msg);
         Signed::<txn::Txn> {
             msg,
-            from: self.kp.public.clone(),
+            from: self.kp.public,
             sig
         }
     }
 
-    pub fn stake(&self, validators: &merkle::Map<validator::Data>, nonce: u32) -> Signed<txn::Txn> {
-        let mut rng = rand::thread_rng();
-        let idx = loop {
-            let rand = rng.gen::<u32>() % VALIDATOR_SLOTS;
-            if validators.get(&rand.to_be_bytes()).unwrap().is_none() {
-                break rand;
-            }
-        };
+    // First slot in range that's free in `slots` and a txn claiming it. None if they're all taken.
+    pub fn stake(&self, slots: &merkle::Map<validator::SlotData>, range: Range<u32>, nonce: u32) -> Option<(u32, Signed<txn::Txn>)> {
+        let idx = validator::first_free(slots, range)?;
+        Some((idx, self.stake_slot(idx, nonce)))
+    }
+
+    pub fn stake_slot(&self, idx: u32, nonce: u32) -> Signed<txn::Txn> {
         let msg = txn::Txn {
             payload: txn::Payload::Stake(idx.to_be_bytes()),
             opt_rollup: None,
-            nonce
+            nonce,
+            fee: 0
         };
         let sig = self.sign(&msg);
         Signed::<txn::Txn> {
             msg,
-            from: self.kp.public.clone(),
+            from: self.kp.public,
             sig
         }
     }
 
-    pub fn unstake(&self, validators: &merkle::Map<validator::Data>, nonce: u32) -> Signed<txn::Txn> {
-        let mut rng = rand::thread_rng();
-        let idx = loop {
-            let rand = rng.gen::<u32>() % VALIDATOR_SLOTS;
-            if let Some(stake_data) = validators.get(&rand.to_be_bytes()).unwrap() {
-                if stake_data.pk == self.kp.public {
-                    break rand;
-                }
-            }
+    // Let the state pick the first free slot in range when the txn is applied,
+    // so racing stakers don't collide on the slot we saw free. Once it's in,
+    // `validator::claimed` with our id and this nonce says which slot it took.
+    pub fn stake_any(&self, range: Range<u32>, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::StakeIn(range.start, range.end),
+            opt_rollup: None,
+            nonce,
+            fee: 0
         };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }
+    }
+
+    pub fn vest(&self, to: PublicKey, amount: u32, unlock_round: u32, nonce: u32) -> Signed<txn::Txn> {
         let msg = txn::Txn {
-            payload: txn::Payload::Unstake(idx.to_be_bytes()),
+            payload: txn::Payload::VestedPayment(Sha256::digest(to).into(), amount, unlock_round),
             opt_rollup: None,
-            nonce
+            nonce,
+            fee: 0
         };
         let sig = self.sign(&msg);
         Signed::<txn::Txn> {
             msg,
-            from: self.kp.public.clone(),
+            from: self.kp.public,
+            sig
+        }
+    }
+
+    pub fn freeze(&self, acc: Id, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::Freeze(acc),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }
+    }
+
+    pub fn attester(&self, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::Attester(attest::public(self), attest::pop(self)),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }
+    }
+
+    pub fn unfreeze(&self, acc: Id, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::Unfreeze(acc),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }
+    }
+
+    pub fn weighting(&self, on: bool, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::Weighting(on),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }
+    }
+
+    pub fn oppose(&self, senator: senator::Id, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::Oppose(senator),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
             sig
         }
     }
-}
 
-impl Default for Keypair {
-    fn default() -> Self {
-        Keypair { 
-            kp: ed25519_dalek::Keypair {
-                public: PublicKey::from_bytes(&JENNY_PK_BYTES).unwrap(),
-                secret: SecretKey::from_bytes(&JENNY_SK_BYTES).unwrap()
-            } 
+    pub fn support(&self, senator: senator::Id, nonce: u32) -> Signed<txn::Txn> {
+        let msg = txn::Txn {
+            payload: txn::Payload::Support(senator),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
         }
     }
+
+    // Give up the first slot we own in `slots`. None if we don't own any.
+    pub fn unstake(&self, slots: &merkle::Map<validator::SlotData>, nonce: u32) -> Option<(u32, Signed<txn::Txn>)> {
+        let id: validator::Id = Sha256::digest(self.kp.public.to_bytes()).into();
+        let idx = (0..VALIDATOR_SLOTS).find(|i| {
+            matches!(slots.get(&i.to_be_bytes()), Ok(Some(slot)) if slot.owner == id)
+        })?;
+        let msg = txn::Txn {
+            payload: txn::Payload::Unstake(idx.to_be_bytes()),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        };
+        let sig = self.sign(&msg);
+        Some((idx, Signed::<txn::Txn> {
+            msg,
+            from: self.kp.public,
+            sig
+        }))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -143,12 +330,36 @@ impl<T: PartialOrd> PartialOrd for Signed<T> {
 
 impl<T: PartialOrd + Eq> Ord for Signed<T> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(&other).unwrap()
+        self.partial_cmp(other).unwrap()
     }
 }
 
-impl<T: Serialize> Signed<T> {
+impl<T: Signable> Signed<T> {
     pub fn verify(&self) -> bool {
-        self.from.verify(serde_json::to_string(&self.msg).expect("").as_bytes(), &self.sig).is_ok()
+        self.from.verify(&self.msg.signing_bytes(), &self.sig).is_ok()
+    }
+}
+
+// The exact bytes a message is signed as. Types with a canonical binary encoding
+// (headers) sign that, so what's signed and what's hashed can't drift apart.
+pub trait Signable {
+    fn signing_bytes(&self) -> Vec<u8>;
+}
+
+impl<T: Signable + ?Sized> Signable for &T {
+    fn signing_bytes(&self) -> Vec<u8> {
+        (**self).signing_bytes()
+    }
+}
+
+impl Signable for [u8] {
+    fn signing_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl<const N: usize> Signable for [u8; N] {
+    fn signing_bytes(&self) -> Vec<u8> {
+        self.to_vec()
     }
 }
\ No newline at end of file
@@ -1,19 +1,31 @@
-use std::{fs, sync::Arc};
-use ethnum::serde::bytes::ne;
-use tokio::sync::Mutex;
-use crate::{node, account, block, msg, state};
-use axum::{Router, routing, extract::FromRef};
-use serde::{Serialize, Deserialize};
-use tokio::time;
+use std::{fs, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, collections::BTreeMap};
+use tokio::sync::{Mutex, Notify};
+use crate::{node, account, block, msg, state, txn, store, peers, ratelimit, metrics, gossip, transport, framed, validator, faucet, rpc, config, compress};
+use crate::deadline::Deadline;
+use axum::{Router, http, routing, extract::{self, FromRef}};
+use tower_http::{cors::{AllowOrigin, CorsLayer}, set_header::SetResponseHeaderLayer, compression::CompressionLayer};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use tokio::{signal, time};
 use std::fmt::Debug;
 
 mod handlers {
     use super::*;
 
-    use std::{sync::Arc, collections::HashMap, vec};
+    use std::{sync::Arc, collections::HashMap, convert::Infallible};
     use sha2::{Sha256, Digest};
-    use axum::{http, extract, response};
+    use axum::{http, extract, response::{self, IntoResponse}};
     use ethnum::U256;
+    use futures::{stream, Stream, StreamExt};
+    use tokio::sync::broadcast;
+
+    // How far back the explorer looks for rollup activity, and the most blocks it lists.
+    const RECENT_BLOCKS: usize = 64;
+    const BLOCKS_SHOWN: usize = 20; // unless asked for more
+    // Account search results per page, unless asked for more, and the most we'll give.
+    const SEARCH_SHOWN: usize = 10;
+    const MAX_SEARCH_SHOWN: usize = 100;
+    // Heavy explorer queries give up after this so they can't pin the node.
+    const QUERY_TIMEOUT: time::Duration = time::Duration::from_millis(2_000);
 
     pub async fn index(
         extract::State(appstate): extract::State<AppState>
@@ -23,24 +35,146 @@ mod handlers {
             .get_template("index")
             .unwrap()
             .render(minijinja::context!{ 
-                node_id => appstate.client.node.kp.kp.public.as_bytes()[0],
-                peers => *appstate.client.neighbors.lock().await,
+                node_id => appstate.client.node.kp().kp.public.as_bytes()[0],
+                peers => appstate.client.peers.lock().await.ranked(state::timestamp()),
                 round => head.block.sheader.msg.data.round,
                 last_leader => head.block.sheader.from.as_bytes()[0],
-                account_data => head.state.accounts.get(&Sha256::digest(appstate.client.node.kp.kp.public.as_bytes())).unwrap(),
-                num_slots => head.state.validators.iter().filter(|s| s.owner == appstate.client.node.kp.kp.public).count()
+                account_data => head.state.accounts.get(&Sha256::digest(appstate.client.node.kp().kp.public.as_bytes())).unwrap(),
+                num_slots => head.state.validators.iter().filter(|s| s.pk == appstate.client.node.kp().kp.public).map(|s| s.slots).sum::<u32>()
             })
             .unwrap();
         response::Html(page)
     }
 
+    pub async fn metrics(
+        extract::State(client): extract::State<Arc<Client>>
+    ) -> String {
+        client.node.metrics.render()
+    }
+
     pub async fn p2p(
         extract::State(client): extract::State<Arc<Client>>,
-        extract::Json(msg): extract::Json<msg::Message>
-    ) -> String {
-        let (resp, bcasts) = client.node.receive(msg).await;
-        client.broadcast(bcasts).await;
-        resp
+        extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+        headers: http::HeaderMap,
+        body: axum::body::Bytes
+    ) -> Result<([(http::header::HeaderName, &'static str); 1], Vec<u8>), http::StatusCode> {
+        let header = |name| headers.get(name).and_then(|value: &http::HeaderValue| value.to_str().ok());
+        let codec = msg::Codec::of(header(http::header::CONTENT_TYPE));
+        // Unpacked no bigger than we'd take it plain
+        let body = match header(http::header::CONTENT_ENCODING) {
+            None => body.to_vec(),
+            Some(name) => compress::Encoding::of(Some(name))
+                .and_then(|encoding| encoding.decode(&body, msg::MAX_CHAIN_BYTES))
+                .ok_or(http::StatusCode::BAD_REQUEST)?
+        };
+        // Peers are told apart by ip, the port they connect from changes.
+        let (codec, resp) = client.answer_p2p(&addr.ip().to_string(), &body, codec).await;
+        Ok(([(http::header::CONTENT_TYPE, codec.content_type())], resp))
+    }
+
+    // External block builders and admins authenticate with `Authorization: Bearer <token>`.
+    fn authed(token: &Option<String>, headers: &http::HeaderMap) -> bool {
+        match (token, headers.get(http::header::AUTHORIZATION)) {
+            (Some(token), Some(value)) => value.to_str().is_ok_and(|v| v == format!("Bearer {}", token)),
+            _ => false
+        }
+    }
+
+    fn builder_authed(client: &Client, headers: &http::HeaderMap) -> bool {
+        authed(&client.builder_token, headers)
+    }
+
+    fn admin_authed(client: &Client, headers: &http::HeaderMap) -> bool {
+        authed(&client.admin_token, headers)
+    }
+
+    // Every neighbor with how it's been doing, best first, then any that are banned.
+    pub async fn api_admin_peers(
+        extract::State(client): extract::State<Arc<Client>>,
+        headers: http::HeaderMap
+    ) -> Result<String, http::StatusCode> {
+        if !admin_authed(&client, &headers) {
+            return Err(http::StatusCode::UNAUTHORIZED);
+        }
+        let peers = client.peers.lock().await;
+        let ranked = peers.ranked(state::timestamp());
+        let banned = peers.iter().map(|(addr, _)| addr).filter(|addr| !ranked.contains(addr));
+        let health: Vec<_> = ranked.iter()
+            .chain(banned)
+            .filter_map(|addr| Some((addr.clone(), peers.get(addr)?.clone())))
+            .collect();
+        Ok(msg::ser(&health))
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct PeerForm {
+        pub addr: String
+    }
+
+    // Shaken hands with like any other, so one on another chain is turned away.
+    pub async fn api_admin_add_peer(
+        extract::State(client): extract::State<Arc<Client>>,
+        headers: http::HeaderMap,
+        extract::Json(params): extract::Json<PeerForm>
+    ) -> Result<String, http::StatusCode> {
+        if !admin_authed(&client, &headers) {
+            return Err(http::StatusCode::UNAUTHORIZED);
+        }
+        Ok(msg::ser(&client.add_peer(params.addr).await))
+    }
+
+    pub async fn api_admin_remove_peer(
+        extract::State(client): extract::State<Arc<Client>>,
+        headers: http::HeaderMap,
+        extract::Json(params): extract::Json<PeerForm>
+    ) -> Result<String, http::StatusCode> {
+        if !admin_authed(&client, &headers) {
+            return Err(http::StatusCode::UNAUTHORIZED);
+        }
+        match client.remove_peer(&params.addr).await {
+            Some(peer) => Ok(msg::ser(&peer)),
+            None => Err(http::StatusCode::NOT_FOUND)
+        }
+    }
+
+    // Server sent events, one serialized txn each: the whole pool, then txns as they arrive.
+    // Ends if the subscriber lags so it can reconnect and pick up a fresh snapshot.
+    pub async fn api_builder_subscribe_pool(
+        extract::State(client): extract::State<Arc<Client>>,
+        headers: http::HeaderMap
+    ) -> Result<response::Sse<impl Stream<Item = Result<response::sse::Event, Infallible>>>, http::StatusCode> {
+        if !builder_authed(&client, &headers) {
+            return Err(http::StatusCode::UNAUTHORIZED);
+        }
+        let (pending, feed) = client.node.subscribe_pool().await;
+        let live = stream::unfold(feed, |mut feed| async move {
+            match feed.recv().await {
+                Ok(txn) => Some((txn, feed)),
+                Err(broadcast::error::RecvError::Lagged(_)) | Err(broadcast::error::RecvError::Closed) => None
+            }
+        });
+        let events = stream::iter(pending)
+            .chain(live)
+            .map(|txn| Ok(response::sse::Event::default().data(msg::ser(&txn))));
+        Ok(response::Sse::new(events).keep_alive(response::sse::KeepAlive::default()))
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SubmitForm {
+        txns: Vec<account::Signed<txn::Txn>>,
+        state_commit: Option<[u8; 32]> // checked against ours if given
+    }
+
+    pub async fn api_builder_submit_block(
+        extract::State(client): extract::State<Arc<Client>>,
+        headers: http::HeaderMap,
+        extract::Json(params): extract::Json<SubmitForm>
+    ) -> Result<String, http::StatusCode> {
+        if !builder_authed(&client, &headers) {
+            return Err(http::StatusCode::UNAUTHORIZED);
+        }
+        let result = client.node.submit_block(params.txns, params.state_commit).await;
+        Ok(msg::ser(&result))
     }
 
     pub async fn explorer(
@@ -51,7 +185,30 @@ mod handlers {
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct AccountForm {
-        address: String
+        pub address: String
+    }
+
+    // Json for wallets and the command line, see rpc.rs.
+    pub async fn api_rpc_account(
+        extract::State(client): extract::State<Arc<Client>>,
+        extract::Query(params): extract::Query<AccountForm>
+    ) -> Result<String, http::StatusCode> {
+        let id = u256_parser(&params.address).map_err(|_| http::StatusCode::BAD_REQUEST)?.to_be_bytes();
+        let head = client.node.get_head().await;
+        Ok(msg::ser(&rpc::Account {
+            round: head.block.sheader.msg.data.round,
+            data: head.state.accounts.get(&id).ok().flatten().cloned()
+        }))
+    }
+
+    // Answers as a peer sending it would be answered.
+    pub async fn api_rpc_txn(
+        extract::State(client): extract::State<Arc<Client>>,
+        extract::Json(stxn): extract::Json<account::Signed<txn::Txn>>
+    ) -> ([(http::header::HeaderName, &'static str); 1], String) {
+        let (resp, bcasts) = client.node.receive(msg::Message::Txn(Vec::from([stxn]))).await;
+        client.broadcast(bcasts).await;
+        ([(http::header::CONTENT_TYPE, msg::Codec::Json.content_type())], resp)
     }
 
     pub async fn api_account(
@@ -74,47 +231,96 @@ mod handlers {
         )
     }
 
-    pub async fn api_account_search(
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct TxnForm {
+        pub hash: String
+    }
+
+    // Where a txn has got to, for someone checking their payment landed.
+    pub async fn api_txn(
         extract::State(appstate): extract::State<AppState>,
-        extract::Query(params): extract::Query<AccountForm>
+        extract::Query(params): extract::Query<TxnForm>
     ) -> response::Html<String> {
-        println!("hi");
-        let mut chars = params.address.chars();
-        let vec = if chars.next() != Some('0') || chars.next() != Some('x') {
-            Vec::default()
-        } else {
-            let mut vec = Vec::<u8>::default();
-            for hex in chars.map(|c| c.to_digit(16)) {
-                match hex {
-                    None => {
-                        vec = Vec::default();
-                        break;
-                    },
-                    Some(x) => vec.push(x as u8)
-                }
-            }
-            if vec.is_empty() {
-                Vec::default()
-            } else {
-                match appstate.client.node.get_head().await
-                    .state.accounts.get_subtrie(&vec).unwrap() {
-                        None => Vec::default(),
-                        Some((sub, path)) => {
-                            sub.entry_iter()
-                                .map(|(p, _)| {
-                                    let mut full = path.clone();
-                                    full.extend(&p);
-                                    nibble_array_to_hex(&full)
-                                })
-                                .take(10).collect::<Vec<_>>()
+        let hash = match u256_parser(&params.hash) {
+            Err(e) => return render_response(&appstate, e, "txn_response"),
+            Ok(x) => x.to_be_bytes()
+        };
+        let Some(found) = appstate.client.node.lookup_txn(&hash).await else {
+            return render_response(&appstate, "Txn not found in recent blocks or the pool".to_owned(), "txn_response");
+        };
+        response::Html(
+            appstate.templates.get_template("txn").unwrap()
+                .render(minijinja::context!{
+                    id => "txn_response",
+                    hash => params.hash,
+                    status => format!("{:?}", found.status),
+                    round => found.at.map(|(round, _, _)| round),
+                    block => found.at.map(|(_, block_hash, _)| bytes_to_hex(&block_hash)),
+                    position => found.at.map(|(_, _, pos)| pos),
+                    from => bytes_to_hex(found.stxn.from.as_bytes()),
+                    nonce => found.stxn.msg.nonce,
+                    fee => found.stxn.msg.fee,
+                    payload => format!("{:?}", found.stxn.msg.payload)
+                }).unwrap()
+        )
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SearchForm {
+        pub address: String, // a prefix of one, in hex
+        pub offset: Option<usize>,
+        pub limit: Option<usize>
+    }
+
+    // Account ids under a prefix, a page at a time. Json if the client accepts it, for
+    // wallets and other explorers, otherwise a datalist for the search box.
+    pub async fn api_account_search(
+        extract::State(appstate): extract::State<AppState>,
+        headers: http::HeaderMap,
+        extract::Query(params): extract::Query<SearchForm>
+    ) -> response::Response {
+        let json = headers.get(http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("application/json"));
+        let offset = params.offset.unwrap_or(0);
+        let limit = params.limit.unwrap_or(SEARCH_SHOWN).min(MAX_SEARCH_SHOWN);
+        let mut page = rpc::SearchPage { total: 0, offset, matches: Vec::default() };
+        let prefix = params.address.strip_prefix("0x")
+            .and_then(|hex| hex.chars().map(|c| c.to_digit(16).map(|x| x as u8)).collect::<Option<Vec<u8>>>())
+            .filter(|prefix| !prefix.is_empty());
+        if let Some(prefix) = prefix {
+            if let Some((sub, path)) = appstate.client.node.get_head().await.state.accounts.get_subtrie(&prefix).unwrap() {
+                // Counted to the end for the total, only the page is turned into hex
+                let found = bounded(move |deadline| {
+                    let mut total = 0;
+                    let mut matches = Vec::default();
+                    for (p, _) in sub.entry_iter().until(deadline) {
+                        if total >= offset && matches.len() < limit {
+                            let mut full = path.clone();
+                            full.extend(&p);
+                            matches.push(nibble_array_to_hex(&full));
                         }
+                        total += 1;
                     }
+                    (total, matches)
+                }).await;
+                match found {
+                    Some((total, matches)) => {
+                        page.total = total;
+                        page.matches = matches;
+                    },
+                    None if json => return http::StatusCode::GATEWAY_TIMEOUT.into_response(),
+                    None => return timed_out(&appstate, "search_response").into_response()
+                }
             }
-        };
+        }
+        if json {
+            return ([(http::header::CONTENT_TYPE, msg::Codec::Json.content_type())], msg::ser(&page)).into_response();
+        }
         response::Html(
             appstate.templates.get_template("search-response").unwrap()
-                .render(minijinja::context!{ response => vec, id => "search_response" }).unwrap()
-        )
+                .render(minijinja::context!{ response => page.matches, id => "search_response" }).unwrap()
+        ).into_response()
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -126,7 +332,7 @@ mod handlers {
         extract::State(appstate): extract::State<AppState>,
         extract::Query(params): extract::Query<ValidatorForm>
     ) -> response::Html<String> {
-        let resp = match u32::from_str_radix(&params.slot, 10) {
+        let resp = match params.slot.parse::<u32>() {
             Err(e) => e.to_string(),
             Ok(x) => {
                 match appstate.client.node.get_head().await
@@ -142,6 +348,342 @@ mod handlers {
         )
     }
 
+    pub async fn rollups(
+        extract::State(appstate): extract::State<AppState>
+    ) -> (http::StatusCode, response::Html<String>) {
+        let head = appstate.client.node.get_head().await;
+        let rollups = bounded(move |deadline| {
+            head.state.rollups.entry_iter()
+                .until(deadline)
+                .map(|(path, data)| minijinja::context!{
+                    id => nibble_array_to_hex(&path),
+                    state_hash => bytes_to_hex(&data.state_hash),
+                    sequencer => bytes_to_hex(&data.sequencer.id),
+                    num_senators => data.senators.len(),
+                    bal => data.bal
+                })
+                .collect::<Vec<_>>()
+        }).await;
+        let rollups = match rollups {
+            Some(rollups) => rollups,
+            None => return timed_out(&appstate, "response")
+        };
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates.get_template("rollups").unwrap()
+                    .render(minijinja::context!{ rollups => rollups }).unwrap()
+            )
+        )
+    }
+
+    // Every senator with who owns it, the votes against it and the rollups it works on. The
+    // validators opposing each are counted off their opposed sets, which means a full scan.
+    pub async fn senators(
+        extract::State(appstate): extract::State<AppState>
+    ) -> (http::StatusCode, response::Html<String>) {
+        let head = appstate.client.node.get_head().await;
+        let reputations = appstate.client.node.reputations().await;
+        let senators = bounded(move |deadline| {
+            let state = &head.state;
+            let mut opposing: HashMap<String, u32> = HashMap::default();
+            for (_, val) in state.validators.entry_iter().until(deadline) {
+                for (path, _) in val.opposed.entry_iter().until(deadline) {
+                    *opposing.entry(nibble_array_to_hex(&path)).or_default() += 1;
+                }
+            }
+            let mut serving: HashMap<String, Vec<String>> = HashMap::default();
+            for (path, rollup) in state.rollups.entry_iter().until(deadline) {
+                let rollup_id = nibble_array_to_hex(&path);
+                for verifier in rollup.senators.iter().chain([&rollup.sequencer]) {
+                    serving.entry(bytes_to_hex(&verifier.id)).or_default().push(rollup_id.clone());
+                }
+            }
+            state.senators.entry_iter()
+                .until(deadline)
+                .map(|(path, data)| {
+                    let id = nibble_array_to_hex(&path);
+                    let reputation = reputations.iter()
+                        .find(|(senator, _)| bytes_to_hex(*senator) == id)
+                        .map(|(_, rep)| rep.to_string());
+                    minijinja::context!{
+                        owner => state.validators.get(&data.owner).ok().flatten().map(|val| bytes_to_hex(val.pk.as_bytes())),
+                        votes_against => data.votes_against,
+                        opposing => opposing.get(&id).copied().unwrap_or(0),
+                        rollups => serving.remove(&id).unwrap_or_default(),
+                        reputation => reputation,
+                        id => id
+                    }
+                })
+                .collect::<Vec<_>>()
+        }).await;
+        let senators = match senators {
+            Some(senators) => senators,
+            None => return timed_out(&appstate, "response")
+        };
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates.get_template("senators").unwrap()
+                    .render(minijinja::context!{ senators => senators }).unwrap()
+            )
+        )
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RollupForm {
+        id: String
+    }
+
+    pub async fn api_rollup(
+        extract::State(appstate): extract::State<AppState>,
+        extract::Query(params): extract::Query<RollupForm>
+    ) -> (http::StatusCode, response::Html<String>) {
+        let id = match u256_parser(&params.id) {
+            Err(e) => return (http::StatusCode::OK, render_response(&appstate, e, "rollup_response")),
+            Ok(x) => x.to_be_bytes()
+        };
+        let data = match appstate.client.node.get_head().await
+            .state.rollups.get(&id).unwrap() {
+                Some(data) => data.clone(),
+                None => return (
+                    http::StatusCode::OK, 
+                    render_response(&appstate, "Rollup not found".to_owned(), "rollup_response")
+                )
+            };
+        let senators = {
+            let reputations = appstate.client.node.reputations().await;
+            data.senators.iter()
+                .map(|s| minijinja::context!{
+                    id => bytes_to_hex(&s.id),
+                    at_round => s.at_round,
+                    reputation => reputations.get(&s.id).map(|rep| rep.to_string())
+                })
+                .collect::<Vec<_>>()
+        };
+        // Header payloads posted for this rollup in recent blocks.
+        let deadline = Deadline::after(QUERY_TIMEOUT);
+        let blocks = match time::timeout(
+            QUERY_TIMEOUT, 
+            appstate.client.node.recent_blocks(RECENT_BLOCKS, &deadline)
+        ).await {
+            Ok(blocks) if !deadline.expired() => blocks,
+            _ => return timed_out(&appstate, "rollup_response")
+        };
+        let mut headers = Vec::default();
+        for block in blocks {
+            for stxn in block.txnseq.iter() {
+                if let txn::Payload::Header(rollup_id, ref txns, _) = stxn.msg.payload {
+                    if rollup_id == id {
+                        headers.push(minijinja::context!{
+                            round => block.sheader.msg.data.round,
+                            from => bytes_to_hex(stxn.from.as_bytes()),
+                            num_txns => txns.len()
+                        });
+                    }
+                }
+            }
+        }
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates.get_template("rollup").unwrap()
+                    .render(minijinja::context!{
+                        id => "rollup_response",
+                        rollup => params.id,
+                        state_hash => bytes_to_hex(&data.state_hash),
+                        sequencer => bytes_to_hex(&data.sequencer.id),
+                        sequencer_round => data.sequencer.at_round,
+                        bal => data.bal,
+                        senators => senators,
+                        headers => headers
+                    }).unwrap()
+            )
+        )
+    }
+
+    // Every occupied slot and who owns it, and each validator with how many slots it holds
+    // and which of the recent blocks it proposed.
+    pub async fn validators(
+        extract::State(appstate): extract::State<AppState>
+    ) -> (http::StatusCode, response::Html<String>) {
+        let head = appstate.client.node.get_head().await;
+        let head_round = head.block.sheader.msg.data.round;
+        let state = head.state.clone();
+        let occupied = bounded(move |deadline| {
+            state.slots.entry_iter()
+                .until(deadline)
+                .map(|(path, data)| (path.iter().fold(0u32, |slot, nib| slot << 4 | *nib as u32), data.clone()))
+                .collect::<Vec<_>>()
+        }).await;
+        let occupied = match occupied {
+            Some(occupied) => occupied,
+            None => return timed_out(&appstate, "response")
+        };
+        let deadline = Deadline::after(QUERY_TIMEOUT);
+        let blocks = match time::timeout(
+            QUERY_TIMEOUT,
+            appstate.client.node.recent_blocks(RECENT_BLOCKS, &deadline)
+        ).await {
+            Ok(blocks) if !deadline.expired() => blocks,
+            _ => return timed_out(&appstate, "response")
+        };
+        let mut proposed: HashMap<[u8; 32], Vec<u32>> = HashMap::default();
+        for block in &blocks {
+            proposed.entry(block.sheader.from.to_bytes()).or_default().push(block.sheader.msg.data.round);
+        }
+        let mut owners: Vec<validator::Id> = occupied.iter().map(|(_, data)| data.owner).collect();
+        owners.sort();
+        owners.dedup();
+        let validators: Vec<_> = owners.iter()
+            .filter_map(|owner| head.state.validators.get(owner).ok().flatten())
+            .map(|data| minijinja::context!{
+                pk => bytes_to_hex(data.pk.as_bytes()),
+                slots => data.slots,
+                missed => data.missed,
+                proposed => proposed.get(&data.pk.to_bytes()).cloned().unwrap_or_default()
+            })
+            .collect();
+        let slots: Vec<_> = occupied.iter()
+            .map(|(slot, data)| minijinja::context!{
+                slot => slot,
+                owner => head.state.validators.get(&data.owner).ok().flatten().map(|data| bytes_to_hex(data.pk.as_bytes())),
+                round => data.round,
+                age => head_round.saturating_sub(data.round)
+            })
+            .collect();
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates.get_template("validators").unwrap()
+                    .render(minijinja::context!{
+                        round => head_round,
+                        num_blocks => blocks.len(),
+                        validators => validators,
+                        slots => slots
+                    }).unwrap()
+            )
+        )
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct BlocksForm {
+        pub count: Option<usize>
+    }
+
+    // The latest blocks on our chain, newest first.
+    pub async fn blocks(
+        extract::State(appstate): extract::State<AppState>,
+        extract::Query(params): extract::Query<BlocksForm>
+    ) -> (http::StatusCode, response::Html<String>) {
+        let count = params.count.unwrap_or(BLOCKS_SHOWN).min(RECENT_BLOCKS);
+        let deadline = Deadline::after(QUERY_TIMEOUT);
+        let blocks = match time::timeout(
+            QUERY_TIMEOUT,
+            appstate.client.node.recent_blocks(count, &deadline)
+        ).await {
+            Ok(blocks) if !deadline.expired() => blocks,
+            _ => return timed_out(&appstate, "response")
+        };
+        let blocks: Vec<_> = blocks.iter()
+            .map(|block| minijinja::context!{
+                round => block.sheader.msg.data.round,
+                proposer => bytes_to_hex(block.sheader.from.as_bytes()),
+                num_txns => block.txnseq.len(),
+                hash => bytes_to_hex(&block.sheader.msg.hash())
+            })
+            .collect();
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates.get_template("blocks").unwrap()
+                    .render(minijinja::context!{ blocks => blocks }).unwrap()
+            )
+        )
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct BlockForm {
+        pub hash: String
+    }
+
+    // Any block we still have, on our chain or not, with its txns in order.
+    pub async fn block(
+        extract::State(appstate): extract::State<AppState>,
+        extract::Query(params): extract::Query<BlockForm>
+    ) -> (http::StatusCode, response::Html<String>) {
+        let hash = match u256_parser(&params.hash) {
+            Err(e) => return (http::StatusCode::OK, render_response(&appstate, e, "block_response")),
+            Ok(x) => x.to_be_bytes()
+        };
+        let block = match time::timeout(QUERY_TIMEOUT, appstate.client.node.find_block(&hash)).await {
+            Ok(Some(block)) => block,
+            Ok(None) => return (
+                http::StatusCode::OK,
+                render_response(&appstate, "Block not found".to_owned(), "block_response")
+            ),
+            Err(_) => return timed_out(&appstate, "block_response")
+        };
+        let data = &block.sheader.msg.data;
+        let txns: Vec<_> = block.txnseq.iter()
+            .map(|stxn| minijinja::context!{
+                hash => bytes_to_hex(&txn::hash(stxn)),
+                from => bytes_to_hex(stxn.from.as_bytes()),
+                nonce => stxn.msg.nonce,
+                fee => stxn.msg.fee,
+                payload => format!("{:?}", stxn.msg.payload)
+            })
+            .collect();
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates.get_template("block").unwrap()
+                    .render(minijinja::context!{
+                        id => "block_response",
+                        hash => bytes_to_hex(&hash),
+                        round => data.round,
+                        timestamp => data.timestamp,
+                        prev_hash => bytes_to_hex(&data.prev_hash),
+                        proposer => bytes_to_hex(block.sheader.from.as_bytes()),
+                        txns => txns
+                    }).unwrap()
+            )
+        )
+    }
+
+    // Run CPU heavy work off the async threads, giving up after QUERY_TIMEOUT.
+    // Work should poll the deadline so the thread is actually freed once we stop waiting.
+    // None if it didn't finish in time.
+    async fn bounded<T: Send + 'static>(work: impl FnOnce(&Deadline) -> T + Send + 'static) -> Option<T> {
+        let deadline = Deadline::after(QUERY_TIMEOUT);
+        let worker = deadline.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let out = work(&worker);
+            (!worker.expired()).then_some(out)
+        });
+        match time::timeout(QUERY_TIMEOUT, handle).await {
+            Ok(Ok(out)) => out,
+            _ => {
+                deadline.cancel();
+                None
+            }
+        }
+    }
+
+    fn timed_out(appstate: &AppState, id: &str) -> (http::StatusCode, response::Html<String>) {
+        (
+            http::StatusCode::GATEWAY_TIMEOUT,
+            render_response(appstate, format!("Query timed out after {}ms", QUERY_TIMEOUT.as_millis()), id)
+        )
+    }
+
+    fn render_response(appstate: &AppState, resp: String, id: &str) -> response::Html<String> {
+        response::Html(
+            appstate.templates.get_template("response").unwrap()
+                .render(minijinja::context!{ response => resp, id => id }).unwrap()
+        )
+    }
+
     pub async fn faucet(
         extract::State(appstate): extract::State<AppState>
     ) -> response::Html<String> {
@@ -150,7 +692,7 @@ mod handlers {
     }
 
     pub fn u256_parser(s: &str) -> Result<U256, String> {
-        if s.chars().nth(0) != Some('0') || s.chars().nth(1) != Some('x') {
+        if !s.starts_with('0') || s.chars().nth(1) != Some('x') {
             Err("Address should be prefixed with 0x".to_owned())
         } else {
             if s[2..].len() != 64 {
@@ -172,52 +714,190 @@ mod handlers {
         hex
     }
 
+    pub fn bytes_to_hex(arr: &[u8]) -> String {
+        let mut hex = "0x".to_string();
+        for byte in arr {
+            hex.push_str(&format!("{:02x}", byte));
+        };
+        hex
+    }
+
     #[derive(Serialize, Deserialize, Debug)]
     pub struct FaucetForm {
         address: String,
         amount: String
     }
 
+    // Limited by the client's faucet policy, by address and by the ip asking.
     pub async fn api_faucet(
         extract::State(appstate): extract::State<AppState>,
+        extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
         extract::Json(params): extract::Json<FaucetForm>
-    ) -> response::Html<String> {
+    ) -> (http::StatusCode, response::Html<String>) {
         let resp = {
-            match u32::from_str_radix(&params.amount, 10) {
+            match params.amount.parse::<u32>() {
                 Err(e) => e.to_string(),
                 Ok(amount) => {
                     match u256_parser(&params.address) {
                         Err(e) => e,
                         Ok(hex) => {
-                            let mut nonce = appstate.client.node.nonce.lock().await;
-                            let txn = appstate.client.node.kp.send_acc(
+                            let (ip, now) = (addr.ip().to_string(), state::timestamp());
+                            let allowed = appstate.client.faucet.lock().await
+                                .take(hex.to_be_bytes(), &ip, amount, now);
+                            if let Err(e) = allowed {
+                                let status = match e {
+                                    faucet::Error::TooMuch { .. } => http::StatusCode::BAD_REQUEST,
+                                    _ => http::StatusCode::TOO_MANY_REQUESTS
+                                };
+                                return (status, render_response(&appstate, e.to_string(), "response"));
+                            }
+                            let txn = appstate.client.node.sign_own(|kp, nonce| kp.send_acc(
                                 hex.to_be_bytes(),
                                 amount, 
-                                *nonce
-                            );
-                            *nonce += 1;
-                            appstate.client.node.receive(
+                                nonce,
+                                None
+                            )).await;
+                            let (sent, _) = appstate.client.node.receive(
                                 msg::Message::Txn(Vec::from([txn]))
                             ).await;
+                            // Only a drip on its way counts against the asker.
+                            let failure = match serde_json::from_str::<Result<msg::ok::Txn, msg::error::Txn>>(&sent) {
+                                Ok(Ok(ok)) => match ok.outcomes.into_iter().next() {
+                                    Some((_, msg::ok::Outcome::Included | msg::ok::Outcome::Pooled)) => None,
+                                    Some((_, outcome)) => Some(format!("{:?}", outcome)),
+                                    None => Some("No outcome".to_owned())
+                                },
+                                Ok(Err(msg::error::Txn::Rejected(rejected))) => Some(rejected.into_iter()
+                                    .next()
+                                    .map_or("Rejected".to_owned(), |(_, err)| format!("Rejected({:?})", err))),
+                                Ok(Err(e)) => Some(format!("{:?}", e)),
+                                Err(_) => Some("Node unavailable".to_owned())
+                            };
+                            if let Some(failure) = failure {
+                                appstate.client.faucet.lock().await.refund(hex.to_be_bytes(), &ip, amount, now);
+                                let resp = format!("The faucet couldn't send: {}", failure);
+                                return (http::StatusCode::SERVICE_UNAVAILABLE, render_response(&appstate, resp, "response"));
+                            }
                             "Request was successful. Account will be credited in a few seconds.".to_owned()
                         }
                     }
                 }
             }
         };
-        response::Html(
-            appstate.templates
-                .get_template("response")
-                .unwrap()
-                .render(minijinja::context!{ response => resp, id => "response" })
-                .unwrap()
+        (
+            http::StatusCode::OK,
+            response::Html(
+                appstate.templates
+                    .get_template("response")
+                    .unwrap()
+                    .render(minijinja::context!{ response => resp, id => "response" })
+                    .unwrap()
+            )
         )
     }
 }
 
+const CLOCK_SAMPLE_TICKS: u64 = 64; // how often we check our clock against our peers'
+const DISCOVER_TICKS: u64 = 16; // how often we look for more neighbors, if we're short
+pub(crate) const TARGET_PEERS: usize = 8; // neighbors we look for
+const LISTEN_P2P: &str = "/ip4/0.0.0.0/tcp/0"; // gossip, unless with_transport says otherwise
+const ASK_TIMEOUT: time::Duration = time::Duration::from_millis(5_000); // for a neighbor's whole answer
+
+// Shared by every ask so connections are kept, and a neighbor that stalls mid answer times out.
+fn http_client(roots: &[reqwest::Certificate]) -> reqwest::Client {
+    roots.iter()
+        .fold(reqwest::Client::builder().timeout(ASK_TIMEOUT), |builder, root| builder.add_root_certificate(root.clone()))
+        .build()
+        .expect("tls backend starts")
+}
+
+// A response body, read a chunk at a time so a peer can't send us more than `limit`.
+async fn read_capped(mut resp: reqwest::Response, limit: usize) -> Option<Vec<u8>> {
+    let mut body = Vec::default();
+    while let Some(chunk) = resp.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() > limit {
+            return None;
+        }
+    }
+    Some(body)
+}
+
+// Where the certificate chain and private key to serve https with are, both pem.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsError {
+    BadCert
+}
+
+// Which sites' pages may call the public api from the browser, e.g. a wallet or someone
+// else's explorer. "*" lets any. Methods default to GET and POST.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cors {
+    pub origins: Vec<String>,
+    #[serde(default)]
+    pub methods: Vec<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsError {
+    BadOrigin(String),
+    BadMethod(String)
+}
+
+// Sent with every page and answer: don't guess content types, don't frame us, and don't
+// tell other sites which page sent someone there.
+const SECURITY_HEADERS: [(&str, &str); 3] = [
+    ("x-content-type-options", "nosniff"),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "no-referrer")
+];
+
+// A neighbor's address is host:port, asked over http, or a url with the scheme to ask it over.
+pub(crate) fn url(neighbor: &str) -> String {
+    match neighbor.contains("://") {
+        true => neighbor.to_string(),
+        false => format!("http://{}", neighbor)
+    }
+}
+
+// Just the host, which is what messages to us are told apart by.
+fn host(neighbor: &str) -> Option<&str> {
+    let addr = neighbor.split_once("://").map_or(neighbor, |(_, addr)| addr);
+    addr.rsplit_once(':').map(|(host, _)| host)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HandshakeError {
+    Unreachable, // or answered with something other than a handshake
+    Rejected(msg::error::Handshake) // by them, or by us going by what they said
+}
+
 pub struct Client {
     pub node: node::Node,
-    pub neighbors: Mutex<Vec<String>>,
+    pub peers: Mutex<peers::Peers>,
+    pub builder_token: Option<String>, // enables the external builder api
+    pub admin_token: Option<String>, // enables the peer admin api
+    bootstrap: Vec<String>, // asked for who else is out there
+    target_peers: usize,
+    advertise: Option<String>, // where others can reach us, gossiped so they hear of it
+    tls: Option<Tls>, // serve https rather than http
+    cors: Option<CorsLayer>, // on the public api, if browsers elsewhere may call it
+    roots: Vec<reqwest::Certificate>, // trusted for https neighbors, besides the system's
+    http: reqwest::Client, // kept, so connections to neighbors are too
+    codecs: Mutex<BTreeMap<String, msg::Codec>>, // what each neighbor and we agreed to talk in
+    encodings: Mutex<BTreeMap<String, compress::Encoding>>, // how neighbors that compress last answered us
+    framed_addrs: Mutex<BTreeMap<String, String>>, // where to ask each neighbor that takes framed asks
+    pool: framed::Pool, // connections to them
+    transport: transport::Config, // broadcasts go out over this once running
+    gossip: Mutex<gossip::Queues>, // broadcasts waiting to go out
+    gossip_ready: Notify,
+    faucet: Mutex<faucet::Faucet> // who's had what from the faucet
 }
 
 #[derive(Clone)]
@@ -234,75 +914,727 @@ impl FromRef<AppState> for Arc<Client> {
 
 impl Client {
     pub fn new(kp: account::Keypair, gen: &block::Snap, nonce: u32) -> Self {
+        Self::around(node::Node::new(kp, gen.clone(), nonce))
+    }
+
+    pub fn open(dir: &Path, kp: account::Keypair) -> Result<Self, node::OpenError> {
+        Ok(Self::around(node::Node::open(dir, kp)?))
+    }
+
+    // Everything as a config file says, see config.rs. Run it on config.listen.
+    pub fn from_config(config: &config::Config) -> Result<Self, config::Error> {
+        let bytes = fs::read(&config.key).map_err(|_| config::Error::BadKey)?;
+        let kp = serde_json::from_slice(&bytes).map_err(|_| config::Error::BadKey)?;
+        let node = node::Node::open_with(&config.data_dir, kp, config.node).map_err(config::Error::Open)?;
+        let mut client = Self::around(node)
+            .with_bootstrap(config.bootstrap.clone())
+            .with_target_peers(config.target_peers)
+            .with_faucet_policy(config.faucet);
+        client.advertise = config.advertise.clone();
+        client.tls = config.tls.clone();
+        if let Some(cors) = &config.cors {
+            client = client.with_cors(cors).map_err(config::Error::BadCors)?;
+        }
+        client.admin_token = config.admin_token.clone();
+        client.builder_token = config.builder_token.clone();
+        if let Some(port) = config.framed {
+            client = client.with_framed(port);
+        }
+        for path in &config.root_certs {
+            let pem = fs::read(path).map_err(|_| config::Error::BadCert)?;
+            client = client.with_root_cert(&pem).map_err(|_| config::Error::BadCert)?;
+        }
+        Ok(client)
+    }
+
+    fn around(node: node::Node) -> Self {
         Self {
-            node: node::Node::new(kp, gen.clone(), nonce),
-            neighbors: Mutex::new(Vec::default())
+            node,
+            peers: Mutex::new(peers::Peers::default()),
+            builder_token: None,
+            admin_token: None,
+            bootstrap: Vec::default(),
+            target_peers: TARGET_PEERS,
+            advertise: None,
+            tls: None,
+            cors: None,
+            roots: Vec::default(),
+            http: http_client(&[]),
+            codecs: Mutex::new(BTreeMap::default()),
+            encodings: Mutex::new(BTreeMap::default()),
+            framed_addrs: Mutex::new(BTreeMap::default()),
+            pool: framed::Pool::default(),
+            transport: transport::Config::new(LISTEN_P2P.parse().unwrap()),
+            gossip: Mutex::new(gossip::Queues::default()),
+            gossip_ready: Notify::new(),
+            faucet: Mutex::new(faucet::Faucet::default())
         }
     }
 
-    pub async fn run(self, addr: &str) {
-        // Load templates
+    pub fn with_store(mut self, store: store::Store) -> Result<Self, store::Error> {
+        self.node = self.node.with_store(store)?;
+        Ok(self)
+    }
+
+    pub fn with_role(mut self, role: node::Role) -> Self {
+        self.node = self.node.with_role(role);
+        self
+    }
+
+    pub fn with_builder_token(mut self, token: String) -> Self {
+        self.builder_token = Some(token);
+        self
+    }
+
+    pub fn with_faucet_policy(mut self, policy: faucet::Policy) -> Self {
+        self.faucet = Mutex::new(faucet::Faucet::new(policy));
+        self
+    }
+
+    pub fn with_admin_token(mut self, token: String) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    pub fn with_transport(mut self, config: transport::Config) -> Self {
+        self.transport = config;
+        self
+    }
+
+    pub fn with_bootstrap(mut self, bootstrap: Vec<String>) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    pub fn with_target_peers(mut self, target_peers: usize) -> Self {
+        self.target_peers = target_peers;
+        self
+    }
+
+    pub fn with_advertise(mut self, addr: String) -> Self {
+        self.advertise = Some(addr);
+        self
+    }
+
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_cors(mut self, cors: &Cors) -> Result<Self, CorsError> {
+        let origins = match cors.origins.iter().any(|origin| origin == "*") {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(cors.origins.iter()
+                .map(|origin| origin.parse().map_err(|_| CorsError::BadOrigin(origin.clone())))
+                .collect::<Result<Vec<http::HeaderValue>, _>>()?)
+        };
+        let methods = match cors.methods.is_empty() {
+            true => Vec::from([http::Method::GET, http::Method::POST]),
+            false => cors.methods.iter()
+                .map(|method| method.parse().map_err(|_| CorsError::BadMethod(method.clone())))
+                .collect::<Result<_, _>>()?
+        };
+        self.cors = Some(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers([http::header::CONTENT_TYPE]));
+        Ok(self)
+    }
+
+    // Trust `pem` for neighbors we ask over https, for nets that run their own ca.
+    pub fn with_root_cert(mut self, pem: &[u8]) -> Result<Self, TlsError> {
+        self.roots.push(reqwest::Certificate::from_pem(pem).map_err(|_| TlsError::BadCert)?);
+        self.http = http_client(&self.roots);
+        Ok(self)
+    }
+
+    // Take asks over kept connections on `port` too, on the same ip as http.
+    pub fn with_framed(mut self, port: u16) -> Self {
+        self.node = self.node.with_framed(port);
+        self
+    }
+
+    // Out of templates/, by file name without the .html.
+    fn templates() -> minijinja::Environment<'static> {
         let mut templates = minijinja::Environment::new();
-        templates.add_template_owned("index", fs::read_to_string("templates/index.html").unwrap()).unwrap();
-        templates.add_template_owned("faucet", fs::read_to_string("templates/faucet.html").unwrap()).unwrap();
-        templates.add_template_owned("explorer", fs::read_to_string("templates/explorer.html").unwrap()).unwrap();
-        templates.add_template_owned("response", fs::read_to_string("templates/response.html").unwrap()).unwrap();
-        templates.add_template_owned("search-response", fs::read_to_string("templates/search-response.html").unwrap()).unwrap();
+        for name in ["index", "faucet", "explorer", "response", "search-response", "rollups", "rollup", "blocks", "block", "txn", "validators", "senators"] {
+            templates.add_template_owned(name, fs::read_to_string(format!("templates/{}.html", name)).unwrap()).unwrap();
+        }
+        templates
+    }
+
+    pub async fn run(self, addr: &str) {
+        let templates = Self::templates();
         // Block time sync!
         let gen = self.node.get_head().await;
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_millis() as u64;
-        let wait_for = block::BLOCK_TIME - ((now - gen.block.sheader.msg.data.timestamp) % block::BLOCK_TIME);
+        let block_time = gen.state.clock.block_time;
+        let genesis = gen.block.sheader.msg.data.timestamp;
+        // A genesis still to come is waited out first, so the first tick is round one's slot.
+        let wait_for = genesis.saturating_sub(now) + block_time - now.saturating_sub(genesis) % block_time;
         time::sleep(time::Duration::from_millis(wait_for)).await;
         // Now synced!
         let now = time::Instant::now();
-        let mut interval = time::interval_at(now, time::Duration::from_millis(block::BLOCK_TIME));
+        let mut interval = time::interval_at(now, time::Duration::from_millis(block_time));
+        // After a stall, carry on from the next slot rather than firing every missed tick at once.
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
         interval.tick().await;
         // Spin up server
+        let (transport, inbox) = transport::Transport::spawn(self.transport.clone()).expect("couldn't start gossip");
+        println!("gossiping as {}", transport.peer_id);
         let client = Arc::new(self);
-        let app = Router::new()
-            .route("/", routing::get(handlers::index))
-            .route("/faucet.html", routing::get(handlers::faucet))
-            .route("/explorer.html", routing::get(handlers::explorer))
-            .route("/p2p", routing::post(handlers::p2p))
+        tokio::spawn({
+            let client = client.clone();
+            async move { client.send_gossip(transport).await }
+        });
+        tokio::spawn({
+            let client = client.clone();
+            async move { client.receive_gossip(inbox).await }
+        });
+        let addr: SocketAddr = addr.parse().unwrap();
+        client.serve(Self::router(client.clone(), templates), addr).await;
+        if let Some(port) = client.node.framed {
+            let listener = tokio::net::TcpListener::bind(SocketAddr::new(addr.ip(), port)).await.expect("couldn't listen for framed asks");
+            let client = client.clone();
+            tokio::spawn(framed::serve(listener, msg::MAX_CHAIN_BYTES, move |ip, codec, body| {
+                let client = client.clone();
+                async move { client.answer_p2p(&ip.to_string(), &body, codec).await }
+            }));
+        }
+        // Ctrl-c is only picked up between ticks, so the one in progress always finishes.
+        let mut ctrl_c = std::pin::pin!(signal::ctrl_c());
+        for ticks in 0.. {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = &mut ctrl_c => break
+            }
+            if ticks % DISCOVER_TICKS == 0 {
+                client.discover().await;
+            }
+            if ticks % CLOCK_SAMPLE_TICKS == 0 {
+                client.sample_clocks().await;
+            }
+            let bcasts = client.node.tick().await;
+            client.broadcast(bcasts).await;
+            let upkeep = client.node.upkeep().await;
+            if !upkeep.is_empty() {
+                let (_, bcasts) = client.node.receive_txns(upkeep).await;
+                client.broadcast(bcasts).await;
+            }
+            if client.node.stalled().await && !client.sync().await && !client.checkpoint_sync().await {
+                client.resync().await;
+            }
+        }
+        println!("shutting down");
+        client.node.shutdown().await;
+    }
+
+    // Cors only covers the public api. The builder and admin apis take a token, which
+    // shouldn't be sitting in some web page.
+    fn router(client: Arc<Client>, templates: minijinja::Environment<'static>) -> Router {
+        let mut api = Router::new()
             .route("/api/faucet", routing::post(handlers::api_faucet))
             .route("/api/account", routing::get(handlers::api_account))
             .route("/api/account_search", routing::get(handlers::api_account_search))
+            .route("/api/rpc/account", routing::get(handlers::api_rpc_account))
+            .route("/api/rpc/txn", routing::post(handlers::api_rpc_txn))
             .route("/api/validator", routing::get(handlers::api_validator))
-            .with_state(AppState { client: client.clone(), templates });
-        let _ = tokio::spawn(
-            axum::Server::bind(&addr.parse().unwrap())
-                .serve(app.into_make_service())
-        );
+            .route("/api/txn", routing::get(handlers::api_txn))
+            .route("/api/rollup", routing::get(handlers::api_rollup));
+        if let Some(cors) = &client.cors {
+            api = api.layer(cors.clone());
+        }
+        let mut app = Router::new()
+            .route("/", routing::get(handlers::index))
+            .route("/faucet.html", routing::get(handlers::faucet))
+            .route("/explorer.html", routing::get(handlers::explorer))
+            .route("/rollups.html", routing::get(handlers::rollups))
+            .route("/senators.html", routing::get(handlers::senators))
+            .route("/blocks.html", routing::get(handlers::blocks))
+            .route("/validators.html", routing::get(handlers::validators))
+            .route("/block.html", routing::get(handlers::block))
+            .route("/p2p", routing::post(handlers::p2p).layer(extract::DefaultBodyLimit::max(msg::MAX_CHAIN_BYTES)))
+            .route("/metrics", routing::get(handlers::metrics))
+            .merge(api)
+            .route("/api/builder_subscribePool", routing::get(handlers::api_builder_subscribe_pool))
+            .route("/api/builder_submitBlock", routing::post(handlers::api_builder_submit_block))
+            .route("/api/admin/peers", routing::get(handlers::api_admin_peers)
+                .post(handlers::api_admin_add_peer)
+                .delete(handlers::api_admin_remove_peer));
+        for (name, value) in SECURITY_HEADERS {
+            app = app.layer(SetResponseHeaderLayer::if_not_present(
+                http::HeaderName::from_static(name),
+                http::HeaderValue::from_static(value)
+            ));
+        }
+        // Gzip or zstd answers, whichever the asker takes. Peers' compressed asks are
+        // unpacked by handlers::p2p.
+        app.layer(CompressionLayer::new()).with_state(AppState { client, templates })
+    }
+
+    // Over https if we've a certificate, http if not, in the background.
+    async fn serve(&self, app: Router, addr: SocketAddr) {
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        match &self.tls {
+            Some(tls) => {
+                let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(&tls.cert, &tls.key).await.expect("couldn't load tls certificate");
+                tokio::spawn(axum_server::bind_rustls(addr, config).serve(service));
+            },
+            None => {
+                tokio::spawn(axum::Server::bind(&addr).serve(service));
+            }
+        }
+    }
+
+    // Once it's told us it speaks our protocol on our chain. False if we already had it.
+    pub async fn add_peer(&self, addr: String) -> Result<bool, HandshakeError> {
+        let ours = self.node.hello().await;
+        let theirs = self.ask::<Result<msg::ok::Handshake, msg::error::Handshake>>(&addr, &msg::Message::Handshake(ours.clone())).await
+            .ok_or(HandshakeError::Unreachable)?
+            .map_err(HandshakeError::Rejected)?
+            .hello;
+        ours.check(&theirs).map_err(HandshakeError::Rejected)?;
+        let codec = ours.codec(&theirs);
+        println!("{} is {:?}, at round {}, talking {:?}", addr, theirs.node_id, theirs.head_round, codec);
+        self.codecs.lock().await.insert(addr.clone(), codec);
+        if let (Some(port), Some(host)) = (theirs.framed, host(&addr)) {
+            self.framed_addrs.lock().await.insert(addr.clone(), format!("{}:{}", host, port));
+        }
+        Ok(self.peers.lock().await.add(addr))
+    }
+
+    // Tops our neighbors up to target_peers. Bootstrap peers and neighbors are asked who they
+    // know of, then we shake hands with bootstrap peers and whoever they told us about until
+    // we've enough. Addresses that don't take us are forgotten. Whoever we took on is gossiped
+    // so others hear of them too, along with us if we know where we can be reached.
+    pub async fn discover(&self) {
+        let mut announce: Vec<_> = self.advertise.iter().cloned().collect();
+        let neighbors = self.neighbors().await;
+        if neighbors.len() < self.target_peers {
+            let message = msg::Message::Peers();
+            let bootstrap = self.bootstrap.iter().filter(|addr| !neighbors.contains(addr));
+            for asked in bootstrap.chain(&neighbors) {
+                let Some(Ok(ok)) = self.ask::<Result<msg::ok::Peers, msg::error::Peers>>(asked, &message).await else { continue };
+                for addr in ok.addrs.into_iter().take(peers::MAX_SHARED) {
+                    self.node.learn(addr).await;
+                }
+            }
+            let mut count = neighbors.len();
+            for addr in self.bootstrap.iter().cloned().chain(self.node.known_addrs().await) {
+                if count >= self.target_peers {
+                    break;
+                }
+                if self.advertise.as_ref() == Some(&addr) || self.peers.lock().await.get(&addr).is_some() {
+                    continue;
+                }
+                match self.add_peer(addr.clone()).await {
+                    Ok(_) => {
+                        count += 1;
+                        announce.push(addr);
+                    },
+                    Err(e) => {
+                        println!("not taking on {}: {:?}", addr, e);
+                        self.node.forget(&addr).await;
+                    }
+                }
+            }
+        }
+        if !announce.is_empty() {
+            self.broadcast(Vec::from([msg::ser(&msg::Message::Addrs(announce))])).await;
+        }
+    }
+
+    // Along with what we'd agreed with it. What we knew of it, if it was a neighbor.
+    pub async fn remove_peer(&self, addr: &str) -> Option<peers::Peer> {
+        self.codecs.lock().await.remove(addr);
+        self.encodings.lock().await.remove(addr);
+        self.framed_addrs.lock().await.remove(addr);
+        self.peers.lock().await.remove(addr)
+    }
+
+    // Peers to ask, best first.
+    async fn neighbors(&self) -> Vec<String> {
+        self.peers.lock().await.ranked(state::timestamp())
+    }
+
+    async fn record(&self, neighbor: &str, event: peers::Event) {
+        self.peers.lock().await.record(neighbor, event, state::timestamp());
+    }
+
+    // Bandwidth, in the totals by message variant and against the neighbor it went to.
+    async fn sent(&self, neighbor: &str, variant: &str, bytes: usize) {
+        self.node.metrics.add_with(metrics::BYTES_OUT, variant.to_string(), bytes as u64);
+        self.record(neighbor, peers::Event::Sent(bytes as u64)).await;
+    }
+
+    // As for `sent`. `from` can be just an ip, for messages sent to us, and then it counts
+    // against every neighbor there.
+    async fn received(&self, from: &str, variant: &str, bytes: usize) {
+        self.node.metrics.add_with(metrics::BYTES_IN, variant.to_string(), bytes as u64);
+        let mut peers = self.peers.lock().await;
+        let senders: Vec<_> = peers.iter()
+            .map(|(neighbor, _)| neighbor.clone())
+            .filter(|neighbor| neighbor == from || host(neighbor) == Some(from))
+            .collect();
+        for neighbor in senders {
+            peers.record(&neighbor, peers::Event::Received(bytes as u64), state::timestamp());
+        }
+    }
+
+    // Timed, so the answer counts towards the peer's latency, or against it if there's none
+    // within ASK_TIMEOUT.
+    // Asked in whatever codec we agreed on in the handshake, json before then, and over a kept
+    // connection if they said they take them. An answer over MAX_RESPONSE_BYTES counts as
+    // none, one that isn't a T as no answer at all.
+    async fn ask<T: DeserializeOwned>(&self, neighbor: &str, message: &msg::Message) -> Option<T> {
+        let codec = self.codecs.lock().await.get(neighbor).copied().unwrap_or(msg::Codec::Json);
+        let body = codec.encode(message);
+        let variant = codec.variant(&body).unwrap_or_default();
+        let start = time::Instant::now();
+        self.sent(neighbor, variant, body.len()).await;
+        let framed_addr = self.framed_addrs.lock().await.get(neighbor).cloned();
+        let answer = match framed_addr {
+            Some(addr) => time::timeout(ASK_TIMEOUT, self.pool.ask(&addr, codec, &body, msg::MAX_RESPONSE_BYTES)).await
+                .ok()
+                .and_then(Result::ok),
+            None => self.post(neighbor, codec, body).await
+        };
+        let event = match answer {
+            Some((_, ref bytes)) => {
+                self.received(neighbor, variant, bytes.len()).await;
+                peers::Event::Answered(start.elapsed().as_millis() as u64)
+            },
+            None => peers::Event::Unreachable
+        };
+        self.record(neighbor, event).await;
+        let (codec, bytes) = answer?;
+        codec.decode(&bytes, msg::MAX_RESPONSE_BYTES)
+    }
+
+    // Compressed both ways with neighbors that have shown they can, see compress.rs.
+    async fn post(&self, neighbor: &str, codec: msg::Codec, body: Vec<u8>) -> Option<(msg::Codec, Vec<u8>)> {
+        let mut req = self.http
+            .post(format!("{}/p2p", url(neighbor)))
+            .header(reqwest::header::CONTENT_TYPE, codec.content_type())
+            .header(reqwest::header::ACCEPT_ENCODING, compress::ACCEPT);
+        let encoding = self.encodings.lock().await.get(neighbor).copied();
+        req = match encoding {
+            Some(encoding) if body.len() >= compress::MIN_BYTES => req
+                .header(reqwest::header::CONTENT_ENCODING, encoding.name())
+                .body(encoding.encode(&body)),
+            _ => req.body(body)
+        };
+        let resp = req.send().await.ok()?;
+        let header = |name| resp.headers().get(name).and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok());
+        let codec = msg::Codec::of(header(reqwest::header::CONTENT_TYPE));
+        let encoding = match header(reqwest::header::CONTENT_ENCODING) {
+            None => None,
+            Some(name) => Some(compress::Encoding::of(Some(name))?)
+        };
+        let bytes = read_capped(resp, msg::MAX_RESPONSE_BYTES).await?;
+        let Some(encoding) = encoding else { return Some((codec, bytes)) };
+        self.encodings.lock().await.insert(neighbor.to_string(), encoding);
+        encoding.decode(&bytes, msg::MAX_RESPONSE_BYTES).map(|bytes| (codec, bytes))
+    }
+
+    // Header first catch up. Take the longest checked header chain any neighbor offers past
+    // our head, then pull its blocks from that neighbor a few at a time. Repeats until no one
+    // has more, and says whether we got anywhere.
+    pub async fn sync(&self) -> bool {
+        let mut progressed = false;
         loop {
-            interval.tick().await;
-            let bcasts = client.node.tick().await;
-            client.broadcast(bcasts).await;
+            let head = self.node.get_head().await;
+            let message = msg::Message::Headers(head.block_hash);
+            let mut best: Option<(String, Vec<_>)> = None;
+            for neighbor in self.neighbors().await {
+                let Some(Ok(ok)) = self.ask::<Result<msg::ok::Headers, msg::error::Headers>>(&neighbor, &message).await else { continue };
+                if let Err(e) = block::verify_headers(&head.block.sheader.msg, &head.epoch, &ok.headers) {
+                    println!("bad sync headers from {}: {:?}", neighbor, e);
+                    self.record(&neighbor, peers::Event::Invalid).await;
+                    continue;
+                }
+                let round = |headers: &Vec<(account::Signed<block::Header>, _)>| headers.last().map_or(0, |(sheader, _)| sheader.msg.data.round);
+                if !ok.headers.is_empty() && best.as_ref().is_none_or(|(_, headers)| round(&ok.headers) > round(headers)) {
+                    best = Some((neighbor, ok.headers));
+                }
+            }
+            let Some((neighbor, headers)) = best else {
+                return progressed;
+            };
+            println!("syncing headers to round {} from {}", headers.last().unwrap().0.msg.data.round, neighbor);
+            for chunk in headers.chunks(node::MAX_SYNC_BODIES) {
+                let hashes: Vec<_> = chunk.iter().map(|(sheader, _)| sheader.msg.hash()).collect();
+                let message = msg::Message::Bodies(hashes.clone());
+                let blocks = match self.ask::<Result<msg::ok::Bodies, msg::error::Bodies>>(&neighbor, &message).await
+                {
+                    Some(Ok(ok)) => ok.blocks,
+                    _ => return progressed
+                };
+                // Bodies have to be for exactly the headers we checked.
+                if blocks.len() != hashes.len() || blocks.iter().zip(&hashes).any(|(block, hash)| block.sheader.msg.hash() != *hash) {
+                    println!("sync bodies from {} don't match their headers", neighbor);
+                    self.record(&neighbor, peers::Event::Invalid).await;
+                    return progressed;
+                }
+                if let Err(e) = self.node.sync_chain(blocks).await {
+                    println!("sync failed: {:?}", e);
+                    self.record(&neighbor, peers::Event::Invalid).await;
+                    return progressed;
+                }
+                self.record(&neighbor, peers::Event::Useful).await;
+                progressed = true;
+            }
+        }
+    }
+
+    // State sync for a node too far behind to replay. Take the latest checked checkpoint any
+    // neighbor offers past our head, pull its state from that neighbor a chunk at a time,
+    // checking each trie against the header, and carry on from there.
+    pub async fn checkpoint_sync(&self) -> bool {
+        let head = self.node.get_head().await;
+        let message = msg::Message::Checkpoint();
+        let round = |checkpoint: &block::Checkpoint| checkpoint.block.sheader.msg.data.round;
+        let mut best: Option<(String, block::Checkpoint)> = None;
+        for neighbor in self.neighbors().await {
+            let Some(Ok(ok)) = self.ask::<Result<msg::ok::Checkpoint, msg::error::Checkpoint>>(&neighbor, &message).await else { continue };
+            if let Err(e) = ok.checkpoint.verify(&head.epoch) {
+                println!("bad checkpoint from {}: {:?}", neighbor, e);
+                self.record(&neighbor, peers::Event::Invalid).await;
+                continue;
+            }
+            if round(&ok.checkpoint) > head.block.sheader.msg.data.round 
+                && best.as_ref().is_none_or(|(_, checkpoint)| round(&ok.checkpoint) > round(checkpoint)) 
+            {
+                best = Some((neighbor, ok.checkpoint));
+            }
+        }
+        let Some((neighbor, checkpoint)) = best else {
+            return false;
+        };
+        let header = &checkpoint.block.sheader.msg;
+        let block_hash = header.hash();
+        let roots = header.checkpoint.expect("checked");
+        let Ok(mut import) = state::Import::new(roots, header.commits.shards.clone(), head.state.clock) else {
+            println!("bad shard commits in checkpoint from {}", neighbor);
+            self.record(&neighbor, peers::Event::Invalid).await;
+            return false;
+        };
+        println!("syncing state at round {} from {}", header.data.round, neighbor);
+        while let Some((part, after)) = import.next() {
+            let message = msg::Message::State(block_hash, part, after);
+            let updates = match self.ask::<Result<msg::ok::State, msg::error::State>>(&neighbor, &message).await
+            {
+                Some(Ok(ok)) => ok.updates,
+                _ => return false
+            };
+            if let Err(part) = import.add(updates, node::STATE_CHUNK_SIZE) {
+                println!("bad state chunk for {:?} from {}", part, neighbor);
+                self.record(&neighbor, peers::Event::Invalid).await;
+                return false;
+            }
+        }
+        match checkpoint.into_snap(import.finish().expect("every part in")) {
+            Ok(snap) => {
+                self.node.accept_checkpoint(snap).await;
+                self.record(&neighbor, peers::Event::Useful).await;
+                true
+            },
+            Err(e) => {
+                println!("synced state doesn't match checkpoint from {}: {:?}", neighbor, e);
+                self.record(&neighbor, peers::Event::Invalid).await;
+                false
+            }
+        }
+    }
+
+    // Ask every neighbor the time and correct our clock by the median offset. A peer's answer
+    // is taken as its time halfway through the round trip.
+    pub async fn sample_clocks(&self) {
+        let message = msg::Message::Time();
+        for neighbor in self.neighbors().await {
+            let sent = state::timestamp();
+            let Some(Ok(ok)) = self.ask::<Result<msg::ok::Time, msg::error::Time>>(&neighbor, &message).await else { continue };
+            let received = state::timestamp();
+            let offset = ok.timestamp as i64 - ((sent + received) / 2) as i64;
+            self.record(&neighbor, peers::Event::Clock(offset)).await;
+        }
+        let offset = self.peers.lock().await.clock_offset(state::timestamp());
+        self.node.set_clock_offset(offset).await;
+    }
+
+    // Ask every neighbor for a recent block and jump to the highest one our trusted committee
+    // attested to. Offers that don't check out are held against whoever made them.
+    pub async fn resync(&self) {
+        let message = msg::Message::Resync();
+        let mut best: Option<(String, block::Snap, account::Signed<block::Header>)> = None;
+        for neighbor in self.neighbors().await {
+            if let Some(Ok(ok)) = self.ask::<Result<msg::ok::Resync, msg::error::Resync>>(&neighbor, &message).await {
+                if let Err(e) = self.node.check_resync(ok.snap.clone(), ok.next.clone()).await {
+                    println!("bad resync snap from {}: {:?}", neighbor, e);
+                    self.record(&neighbor, peers::Event::Invalid).await;
+                    continue;
+                }
+                let round = ok.snap.block.sheader.msg.data.round;
+                if best.as_ref().is_none_or(|(_, b, _)| round > b.block.sheader.msg.data.round) {
+                    best = Some((neighbor, ok.snap, ok.next));
+                }
+            }
+        }
+        let head_round = self.node.get_head().await.block.sheader.msg.data.round;
+        match best {
+            Some((neighbor, snap, next)) if snap.block.sheader.msg.data.round > head_round => {
+                let round = snap.block.sheader.msg.data.round;
+                match self.node.accept_resync(snap, next).await {
+                    Ok(()) => {
+                        println!("resyncing to round {}", round);
+                        self.record(&neighbor, peers::Event::Useful).await;
+                    },
+                    Err(e) => {
+                        println!("resync snap from {} rejected: {:?}", neighbor, e);
+                        self.record(&neighbor, peers::Event::Invalid).await;
+                    }
+                }
+            },
+            _ => println!("resync found no better head")
+        }
+    }
+
+    // A compact block from the peer at `from`, who's held to account for it like any other message.
+    pub async fn receive_compact(&self, from: &str, compact: block::Compact) -> (msg::Response, msg::Bcasts) {
+        if let Some(resp) = self.node.refuse(from, Some((ratelimit::Kind::Blocks, 1))).await {
+            return (resp, Vec::default());
+        }
+        let (resp, bcasts) = self.fill_compact(compact).await;
+        self.node.judge(from, &resp).await;
+        (resp, bcasts)
+    }
+
+    // Fill in what our pool is missing from a compact block. Any neighbor holding the block can
+    // serve it, over the shared client. Past ASK_TIMEOUT in all the block's too stale to bother.
+    async fn fill_compact(&self, compact: block::Compact) -> (msg::Response, msg::Bcasts) {
+        let (resp, bcasts) = self.node.receive_compact(compact.clone(), Vec::default()).await;
+        let missing = match serde_json::from_str::<Result<msg::ok::Chain, msg::error::Chain>>(&resp) {
+            Ok(Err(msg::error::Chain::Missing(missing))) => missing,
+            _ => return (resp, bcasts)
+        };
+        let message = msg::Message::GetTxns(compact.sheader.msg.hash(), missing);
+        let fetch = async {
+            for neighbor in self.neighbors().await {
+                if let Some(Ok(ok)) = self.ask::<Result<msg::ok::GetTxns, msg::error::GetTxns>>(&neighbor, &message).await {
+                    self.record(&neighbor, peers::Event::Useful).await;
+                    return Some(ok.txns);
+                }
+            }
+            None
+        };
+        match time::timeout(ASK_TIMEOUT, fetch).await {
+            Ok(Some(txns)) => self.node.receive_compact(compact, txns).await,
+            _ => (resp, bcasts)
         }
     }
 
+    // Fetch the blocks between our chain and one from the peer at `from` that didn't connect,
+    // from that peer, and run them in with what it sent.
+    async fn backfill(&self, from: &str, prev_round: u32, prev_hash: [u8; 32]) -> Option<(msg::Response, msg::Bcasts)> {
+        let head_round = self.node.get_head().await.block.sheader.msg.data.round;
+        let count = prev_round.saturating_sub(head_round).clamp(1, node::MAX_GET_BLOCKS);
+        let message = msg::Message::GetBlocks(prev_hash, count);
+        // Peers are listed by where they listen, which shares only the ip with where they sent from.
+        let senders: Vec<_> = self.neighbors().await
+            .into_iter()
+            .filter(|neighbor| host(neighbor) == Some(from))
+            .collect();
+        for neighbor in senders {
+            if let Some(Ok(ok)) = self.ask::<Result<msg::ok::GetBlocks, msg::error::GetBlocks>>(&neighbor, &message).await {
+                println!("backfilling {} blocks from {}", ok.blocks.len(), neighbor);
+                return Some(self.node.backfill(ok.blocks).await);
+            }
+        }
+        None
+    }
+
+    // A message from a peer, over http or gossip, in `codec`. Whatever it sets off goes out as
+    // gossip and the answer, in json, goes back to the peer if it's waiting on one.
+    pub async fn receive_p2p(&self, from: &str, body: &[u8], codec: msg::Codec) -> msg::Response {
+        self.received(from, codec.variant(body).unwrap_or_default(), body.len()).await;
+        let msg = match msg::parse_with(codec, body) {
+            Ok(msg) => msg,
+            Err(refused) => return msg::ser(&Err::<(), _>(refused))
+        };
+        // Where the chain hangs off, in case we don't have it.
+        let prev = match msg {
+            msg::Message::Chain(ref chain) => chain.first().map(|block| &block.sheader.msg.data),
+            msg::Message::Compact(ref compact) => Some(&compact.sheader.msg.data),
+            _ => None
+        }.map(|data| (data.prev_round(), data.prev_hash));
+        let (mut resp, mut bcasts) = match msg {
+            msg::Message::Compact(compact) if !self.node.is_closing() => self.receive_compact(from, compact).await,
+            msg => self.node.receive_from(from, msg).await
+        };
+        let bad_prev = matches!(
+            serde_json::from_str::<Result<msg::ok::Chain, msg::error::Chain>>(&resp),
+            Ok(Err(msg::error::Chain::BadPrev))
+        );
+        if let (true, Some((prev_round, prev_hash))) = (bad_prev, prev) {
+            if let Some(backfilled) = self.backfill(from, prev_round, prev_hash).await {
+                (resp, bcasts) = backfilled;
+            }
+        }
+        self.broadcast(bcasts).await;
+        resp
+    }
+
+    // As receive_p2p, with the answer in the codec we were asked in if it recodes, json if not.
+    pub async fn answer_p2p(&self, from: &str, body: &[u8], codec: msg::Codec) -> (msg::Codec, Vec<u8>) {
+        let resp = self.receive_p2p(from, body, codec).await;
+        let recoded = match codec {
+            msg::Codec::Bincode => codec.variant(body).and_then(|variant| msg::recode(variant, &resp)),
+            msg::Codec::Json => None
+        };
+        match recoded {
+            Some(bytes) => (msg::Codec::Bincode, bytes),
+            None => (msg::Codec::Json, resp.into_bytes())
+        }
+    }
+
+    // Queued, to go out by lane. See gossip.rs.
     pub async fn broadcast(&self, bcasts: msg::Bcasts) {
+        if bcasts.is_empty() {
+            return;
+        }
+        let mut gossip = self.gossip.lock().await;
         for message in bcasts {
-            println!("I just bcasted {}", message);
-            let neighbs = &*self.neighbors.lock().await;
-            let mut handles = Vec::with_capacity(neighbs.len());
-            for neighbor in neighbs {
-                let client = reqwest::Client::new();
-                println!("sending to {:?}", neighbor);
-                let fut = client
-                    .post(format!("http://{}/p2p", neighbor))
-                    .header("Content-type", "application/json")
-                    .body(message.clone())
-                    .send();
-                handles.push(tokio::spawn(fut));
-            }
-            let mut results = Vec::with_capacity(handles.len());
-            for handle in handles {
-                results.push(handle.await.unwrap());
-            }
-            println!("bcast results {:?}", results);
+            if let Some(dropped) = gossip.push(message) {
+                self.node.metrics.inc_with(metrics::GOSSIP_DROPPED, format!("{:?}", gossip::Lane::of(&dropped)));
+            }
+        }
+        self.gossip_ready.notify_one();
+    }
+
+    // Publishes queued broadcasts one at a time, best lane first. Runs as long as the transport.
+    async fn send_gossip(&self, transport: transport::Transport) {
+        loop {
+            let next = self.gossip.lock().await.pop();
+            match next {
+                Some(message) => {
+                    let variant = msg::variant(message.as_bytes()).unwrap_or_default().to_string();
+                    self.node.metrics.add_with(metrics::BYTES_OUT, variant, message.len() as u64);
+                    if !transport.publish(message).await {
+                        return;
+                    }
+                },
+                None => self.gossip_ready.notified().await
+            }
+        }
+    }
+
+    // Messages off the gossip topic, taken like any other. There's no one waiting on an answer.
+    async fn receive_gossip(&self, mut inbox: tokio::sync::mpsc::Receiver<transport::Received>) {
+        while let Some(received) = inbox.recv().await {
+            self.receive_p2p(&received.from, &received.data, msg::Codec::Json).await;
         }
     }
 }
@@ -311,27 +1643,282 @@ impl Client {
 mod tests {
     use super::*;
     use crate::{account, block};
+    use axum::http;
 
     #[tokio::test]
     async fn app() {
-        let genesis = block::Snap::default();
-
-        let kp = account::Keypair::default();
-        let alice = Client::new(kp, &genesis, state::JENNY_SLOTS);
-        alice.neighbors.lock().await.push(String::from("127.0.0.1:3001"));
+        use sha2::{Sha256, Digest};
+        let (kp, genesis) = block::genesis();
+        let alice_id: account::Id = Sha256::digest(kp.kp.public.to_bytes()).into();
+        let alice = Client::new(kp, &genesis, state::GENESIS_SLOTS).with_advertise(String::from("127.0.0.1:3000"));
         let fut = alice.run("127.0.0.1:3000");
         let alice_fut = tokio::spawn(fut);
 
         let kp = account::Keypair::gen();
-        let bob = Client::new(kp, &genesis, 0);
-        bob.neighbors.lock().await.push(String::from("127.0.0.1:3000"));
+        let bob = Client::new(kp, &genesis, 0)
+            .with_bootstrap(Vec::from([String::from("127.0.0.1:3000")]))
+            .with_advertise(String::from("127.0.0.1:3001"));
         let fut = bob.run("127.0.0.1:3001");
         let bob_fut = tokio::spawn(fut);
 
-        // time::sleep(time::Duration::from_millis(10_000)).await; panic!();
+        // Bob follows the chain alice leads, as far as his api can tell
+        let http = reqwest::Client::new();
+        let followed = time::timeout(time::Duration::from_secs(30), async {
+            loop {
+                time::sleep(time::Duration::from_millis(500)).await;
+                if let Ok(found) = rpc::account(&http, "127.0.0.1:3001", &alice_id).await {
+                    if found.round >= 2 {
+                        break;
+                    }
+                }
+            }
+        }).await;
+        alice_fut.abort();
+        bob_fut.abort();
+        assert!(followed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let (kp, genesis) = block::genesis();
+        let cert = rcgen::generate_simple_self_signed(Vec::from([String::from("localhost")])).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        let dir = std::env::temp_dir().join(format!("tam-tls-{:x}", u64::from_be_bytes(genesis.block_hash[..8].try_into().unwrap())));
+        fs::create_dir_all(&dir).unwrap();
+        let tls = Tls { cert: dir.join("cert.pem"), key: dir.join("key.pem") };
+        fs::write(&tls.cert, &pem).unwrap();
+        fs::write(&tls.key, cert.serialize_private_key_pem()).unwrap();
+        let alice = Arc::new(Client::new(kp, &genesis, state::GENESIS_SLOTS).with_tls(tls));
+        let app = Router::new().route("/p2p", routing::post(handlers::p2p)).with_state(alice.clone());
+        alice.serve(app, "127.0.0.1:3106".parse().unwrap()).await;
+        time::sleep(time::Duration::from_millis(100)).await;
+        // Not until bob trusts alice's certificate
+        let bob = Client::new(account::Keypair::gen(), &genesis, 0);
+        assert_eq!(bob.add_peer(String::from("https://localhost:3106")).await, Err(HandshakeError::Unreachable));
+        let bob = bob.with_root_cert(pem.as_bytes()).unwrap();
+        assert_eq!(bob.add_peer(String::from("https://localhost:3106")).await, Ok(true));
+        assert_eq!(host("https://localhost:3106"), Some("localhost"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn admin() {
+        let (kp, genesis) = block::genesis();
+        let client = Arc::new(Client::new(kp, &genesis, 0).with_admin_token(String::from("secret")));
+        let mut headers = http::HeaderMap::new();
+        let peers = |headers: http::HeaderMap| handlers::api_admin_peers(extract::State(client.clone()), headers);
+        assert_eq!(peers(headers.clone()).await, Err(http::StatusCode::UNAUTHORIZED));
+        headers.insert(http::header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        // No one's listening, so it isn't taken on
+        let form = |addr: &str| extract::Json(handlers::PeerForm { addr: String::from(addr) });
+        let added = handlers::api_admin_add_peer(extract::State(client.clone()), headers.clone(), form("127.0.0.1:3110")).await.unwrap();
+        assert_eq!(serde_json::from_str::<Result<bool, HandshakeError>>(&added).unwrap(), Err(HandshakeError::Unreachable));
+        client.peers.lock().await.add(String::from("127.0.0.1:3110"));
+        client.record("127.0.0.1:3110", peers::Event::Useful).await;
+        let health: Vec<(String, peers::Peer)> = serde_json::from_str(&peers(headers.clone()).await.unwrap()).unwrap();
+        assert_eq!(health.len(), 1);
+        assert_eq!(health[0].1.useful, 1);
+        let remove = |addr| handlers::api_admin_remove_peer(extract::State(client.clone()), headers.clone(), form(addr));
+        assert!(remove("127.0.0.1:3110").await.is_ok());
+        assert_eq!(remove("127.0.0.1:3110").await, Err(http::StatusCode::NOT_FOUND));
+        assert!(client.neighbors().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn explorer_blocks() {
+        let (kp, genesis) = block::genesis();
+        let client = Arc::new(Client::new(kp, &genesis, 0));
+        let appstate = AppState { client, templates: Client::templates() };
+        let hash = handlers::bytes_to_hex(&genesis.block_hash);
+        let (status, page) = handlers::blocks(extract::State(appstate.clone()), extract::Query(handlers::BlocksForm { count: None })).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(page.0.contains(&format!("/block.html?hash={}", hash)));
+        let block = |hash: String| handlers::block(extract::State(appstate.clone()), extract::Query(handlers::BlockForm { hash }));
+        let (_, page) = block(hash).await;
+        assert!(page.0.contains("Round: 0"));
+        let (_, page) = block(handlers::bytes_to_hex(&[1; 32])).await;
+        assert!(page.0.contains("Block not found"));
+        let page = handlers::api_txn(extract::State(appstate.clone()), extract::Query(handlers::TxnForm { hash: handlers::bytes_to_hex(&[1; 32]) })).await;
+        assert!(page.0.contains("Txn not found"));
+    }
+
+    #[tokio::test]
+    async fn explorer_validators() {
+        let (kp, genesis) = block::genesis();
+        let pk = handlers::bytes_to_hex(kp.kp.public.as_bytes());
+        let client = Arc::new(Client::new(kp, &genesis, 0));
+        let appstate = AppState { client, templates: Client::templates() };
+        let (status, page) = handlers::validators(extract::State(appstate)).await;
+        assert_eq!(status, http::StatusCode::OK);
+        // The authority holds every genesis slot and proposed genesis
+        assert!(page.0.contains(&format!("<td>{}</td>\n        <td>{}</td>\n        <td>0</td>\n        <td>0</td>", pk, state::GENESIS_SLOTS)));
+        assert!(page.0.contains(&format!("<td>{}</td>", state::GENESIS_SLOTS - 1)));
+    }
 
-        let _ = alice_fut.await;
-        let _ = bob_fut.await;
-        panic!();
+    #[tokio::test]
+    async fn explorer_senators() {
+        let (kp, mut genesis) = block::genesis();
+        // Straight into state, nothing checks it
+        let senator = [7; 32];
+        let sequencer = crate::senator::Verifier { id: senator, at_round: 0 };
+        assert!(genesis.state.senators.insert(&senator, crate::senator::Data { votes_against: 3, owner: [0; 32], weighting: false }).is_ok());
+        let rollup = crate::rollup::Data { state_hash: [0; 32], senators: Vec::default(), sequencer, bal: 0 };
+        assert!(genesis.state.rollups.insert(&[9; 32], rollup).is_ok());
+        let client = Arc::new(Client::new(kp, &genesis, 0));
+        let appstate = AppState { client, templates: Client::templates() };
+        let (status, page) = handlers::senators(extract::State(appstate.clone())).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(page.0.contains(&format!("<td>{}</td>", handlers::bytes_to_hex(&senator))));
+        assert!(page.0.contains("<td>3</td>"));
+        assert!(page.0.contains(&format!("<td>{}</td>", handlers::bytes_to_hex(&[9; 32]))));
+        let (_, page) = handlers::rollups(extract::State(appstate)).await;
+        assert!(page.0.contains("/senators.html"));
+    }
+
+    #[tokio::test]
+    async fn discover() {
+        let (kp, genesis) = block::genesis();
+        let mut servers = Vec::default();
+        for (kp, addr) in [(kp, "127.0.0.1:3107"), (account::Keypair::gen(), "127.0.0.1:3108")] {
+            let client = Arc::new(Client::new(kp, &genesis, 0));
+            let app = Router::new().route("/p2p", routing::post(handlers::p2p)).with_state(client.clone());
+            client.serve(app, addr.parse().unwrap()).await;
+            servers.push(client);
+        }
+        time::sleep(time::Duration::from_millis(100)).await;
+        // Alice has heard of carol, bob only knows alice and of somewhere no one's listening
+        servers[0].node.learn(String::from("127.0.0.1:3108")).await;
+        servers[0].node.learn(String::from("127.0.0.1:3109")).await;
+        let bob = Client::new(account::Keypair::gen(), &genesis, 0)
+            .with_bootstrap(Vec::from([String::from("127.0.0.1:3107")]))
+            .with_target_peers(3);
+        bob.discover().await;
+        assert_eq!(bob.neighbors().await.len(), 2);
+        assert!(bob.peers.lock().await.get("127.0.0.1:3108").is_some());
+        // Taken on and gossiped
+        assert_eq!(bob.gossip.lock().await.pop(), Some(msg::ser(&msg::Message::Addrs(Vec::from([
+            String::from("127.0.0.1:3107"), String::from("127.0.0.1:3108")
+        ])))));
+        assert!(bob.node.known_addrs().await.iter().all(|addr| addr != "127.0.0.1:3109"));
+    }
+
+    #[tokio::test]
+    async fn rpc() {
+        use sha2::{Sha256, Digest};
+        let (kp, genesis) = block::genesis();
+        let client = Arc::new(Client::new(kp, &genesis, 0));
+        let app = Router::new()
+            .route("/api/rpc/account", routing::get(handlers::api_rpc_account))
+            .route("/api/rpc/txn", routing::post(handlers::api_rpc_txn))
+            .with_state(client.clone());
+        client.serve(app, "127.0.0.1:3111".parse().unwrap()).await;
+        let http = reqwest::Client::new();
+        let kp = client.node.kp();
+        let id: account::Id = Sha256::digest(kp.kp.public.to_bytes()).into();
+        let found = rpc::account(&http, "127.0.0.1:3111", &id).await.unwrap();
+        let data = found.data.unwrap();
+        assert_eq!(found.round, 0);
+        assert_eq!(rpc::account(&http, "127.0.0.1:3111", &[1; 32]).await.unwrap().data, None);
+        let pay = kp.send_acc([1; 32], state::DUST_BALANCE, data.nonce, None);
+        let taken = rpc::submit(&http, "127.0.0.1:3111", &pay).await.unwrap().unwrap();
+        assert_eq!(taken.outcomes, Vec::from([(txn::hash(&pay), msg::ok::Outcome::Pooled)]));
+        // Not something the node takes
+        let forged = account::Keypair::gen().send_acc([1; 32], 5, 0, None);
+        assert!(matches!(rpc::submit(&http, "127.0.0.1:3111", &forged).await.unwrap(), Err(msg::error::Txn::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn cors() {
+        let (kp, genesis) = block::genesis();
+        let cors = Cors { origins: Vec::from([String::from("https://wallet.example")]), methods: Vec::default() };
+        let bad = Cors { origins: Vec::from([String::from("bad\n")]), methods: Vec::default() };
+        assert_eq!(Client::new(account::Keypair::gen(), &genesis, 0).with_cors(&bad).err(), Some(CorsError::BadOrigin(String::from("bad\n"))));
+        let client = Arc::new(Client::new(kp, &genesis, 0).with_cors(&cors).unwrap());
+        client.serve(Client::router(client.clone(), Client::templates()), "127.0.0.1:3112".parse().unwrap()).await;
+        let http = reqwest::Client::new();
+        let preflight = |path: &str, origin: &str| http.request(reqwest::Method::OPTIONS, format!("http://127.0.0.1:3112{}", path))
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .send();
+        let allowed = |resp: &reqwest::Response| resp.headers().get("access-control-allow-origin").map(|value| value.to_str().unwrap().to_string());
+        let resp = preflight("/api/rpc/txn", "https://wallet.example").await.unwrap();
+        assert_eq!(allowed(&resp).as_deref(), Some("https://wallet.example"));
+        assert!(resp.headers().get("access-control-allow-methods").unwrap().to_str().unwrap().contains("POST"));
+        // Not some other site, and not the apis behind a token
+        assert_eq!(allowed(&preflight("/api/rpc/txn", "https://evil.example").await.unwrap()), None);
+        assert_eq!(allowed(&preflight("/api/admin/peers", "https://wallet.example").await.unwrap()), None);
+        // Security headers on everything
+        let resp = http.get("http://127.0.0.1:3112/metrics").send().await.unwrap();
+        for (name, value) in SECURITY_HEADERS {
+            assert_eq!(resp.headers().get(name).unwrap(), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn account_search() {
+        let kp = account::Keypair::gen();
+        let mut config = crate::genesis::Config::new(state::timestamp());
+        config.balances = (0..48).map(|_| (account::Keypair::gen().kp.public, 1)).collect();
+        let genesis = crate::genesis::build(&kp, &config);
+        let client = Arc::new(Client::new(kp, &genesis, 0));
+        let appstate = AppState { client, templates: Client::templates() };
+        let mut json = http::HeaderMap::new();
+        json.insert(http::header::ACCEPT, "application/json".parse().unwrap());
+        let search = |headers: http::HeaderMap, address: &str, offset, limit| handlers::api_account_search(
+            extract::State(appstate.clone()),
+            headers,
+            extract::Query(handlers::SearchForm { address: String::from(address), offset, limit })
+        );
+        let body = |resp: axum::response::Response| async move {
+            use axum::body::HttpBody;
+            let mut body = resp.into_body();
+            let mut bytes = Vec::default();
+            while let Some(chunk) = body.data().await {
+                bytes.extend_from_slice(&chunk.unwrap());
+            }
+            bytes
+        };
+        let page = |resp| async move { serde_json::from_slice::<rpc::SearchPage>(&body(resp).await).unwrap() };
+        // Every id starts with one of these
+        let mut all = Vec::default();
+        for nibble in "0123456789abcdef".chars() {
+            all.extend(page(search(json.clone(), &format!("0x{}", nibble), None, Some(1_000)).await).await.matches);
+        }
+        assert!(all.len() > 1);
+        let prefix = &all[0][..3];
+        let under: Vec<_> = all.iter().filter(|id| id.starts_with(prefix)).cloned().collect();
+        let first = page(search(json.clone(), prefix, None, Some(1)).await).await;
+        assert_eq!(first, rpc::SearchPage { total: under.len(), offset: 0, matches: under[..1].to_vec() });
+        let rest = page(search(json.clone(), prefix, Some(1), None).await).await;
+        assert_eq!(rest.matches, under[1..].to_vec());
+        // Not hex, so nothing
+        assert_eq!(page(search(json, "0xzz", None, None).await).await.total, 0);
+        // The search box still gets its datalist
+        let resp = search(http::HeaderMap::new(), prefix, None, None).await;
+        assert!(String::from_utf8(body(resp).await).unwrap().contains(&format!("<option value=\"{}\">", under[0])));
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        let (kp, genesis) = block::genesis();
+        let alice = Arc::new(Client::new(kp, &genesis, state::GENESIS_SLOTS));
+        alice.serve(Client::router(alice.clone(), Client::templates()), "127.0.0.1:3113".parse().unwrap()).await;
+        time::sleep(time::Duration::from_millis(100)).await;
+        // Alice answers the handshake compressed, so bob compresses his asks from then on
+        let bob = Client::new(account::Keypair::gen(), &genesis, 0);
+        assert_eq!(bob.add_peer(String::from("127.0.0.1:3113")).await, Ok(true));
+        assert!(bob.encodings.lock().await.contains_key("127.0.0.1:3113"));
+        let kp = alice.node.kp();
+        let txns: Vec<_> = (0..16).map(|i| kp.send_acc([1; 32], state::DUST_BALANCE, state::GENESIS_SLOTS + i, None)).collect();
+        let message = msg::Message::Txn(txns);
+        assert!(msg::ser(&message).len() >= compress::MIN_BYTES);
+        let answer = bob.ask::<Result<msg::ok::Txn, msg::error::Txn>>("127.0.0.1:3113", &message).await;
+        assert!(matches!(answer, Some(Ok(_))));
+        // Says it's gzip but isn't
+        let resp = reqwest::Client::new().post("http://127.0.0.1:3113/p2p")
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .body(msg::ser(&message))
+            .send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
     }
 }
\ No newline at end of file
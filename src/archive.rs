@@ -0,0 +1,203 @@
+use sha2::{Sha256, Digest};
+use serde::{Serialize, Deserialize};
+use std::fmt::Debug;
+
+use crate::{block, msg};
+
+// Cold storage for snaps and blocks that have fallen out of the fork window.
+// Talks to any S3-compatible object store (path style urls, SigV4 auth).
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    BadEndpoint,
+    Request,
+    Status(u16),
+    BadBody,
+    Corrupt(block::LoadError)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Archive {
+    pub endpoint: String, // e.g. http://127.0.0.1:9000
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String
+}
+
+impl Debug for Archive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Archive")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl Archive {
+    pub async fn put_snap(&self, snap: &block::Snap) -> Result<(), Error> {
+        self.put(&format!("blocks/{}", hex(&snap.block_hash)), msg::ser(&snap.block).into_bytes()).await?;
+        self.put(&format!("snaps/{}", hex(&snap.block_hash)), snap.store()).await
+    }
+
+    // The bucket isn't trusted: whatever comes back has to be the block asked for.
+    pub async fn get_snap(&self, block_hash: &[u8; 32]) -> Result<Option<block::Snap>, Error> {
+        let Some(body) = self.get(&format!("snaps/{}", hex(block_hash))).await? else {
+            return Ok(None);
+        };
+        let snap = block::Snap::load(&body).map_err(Error::Corrupt)?;
+        if snap.block_hash != *block_hash {
+            return Err(Error::Corrupt(block::LoadError::BadHash));
+        }
+        Ok(Some(snap))
+    }
+
+    // Cheaper than `get_snap` when the state isn't needed, e.g. walking back a block range.
+    pub async fn get_block(&self, block_hash: &[u8; 32]) -> Result<Option<block::Block>, Error> {
+        let Some(block) = self.get_json::<block::Block>(&format!("blocks/{}", hex(block_hash))).await? else {
+            return Ok(None);
+        };
+        check_block(&block, block_hash)?;
+        Ok(Some(block))
+    }
+
+    async fn get_json<T: for<'a> Deserialize<'a>>(&self, key: &str) -> Result<Option<T>, Error> {
+        match self.get(key).await? {
+            None => Ok(None),
+            Some(body) => serde_json::from_slice(&body).map(Some).map_err(|_| Error::BadBody)
+        }
+    }
+
+    pub async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        let req = self.request(reqwest::Method::PUT, key, &body)?.body(body);
+        let resp = req.send().await.map_err(|_| Error::Request)?;
+        if !resp.status().is_success() {
+            return Err(Error::Status(resp.status().as_u16()));
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let resp = self.request(reqwest::Method::GET, key, &[])?
+            .send().await.map_err(|_| Error::Request)?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(Error::Status(resp.status().as_u16()));
+        }
+        Ok(Some(resp.bytes().await.map_err(|_| Error::Request)?.to_vec()))
+    }
+
+    fn request(&self, method: reqwest::Method, key: &str, body: &[u8]) -> Result<reqwest::RequestBuilder, Error> {
+        let url = reqwest::Url::parse(&format!("{}/{}/{}", self.endpoint, self.bucket, key))
+            .map_err(|_| Error::BadEndpoint)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(Error::BadEndpoint)
+        };
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(body));
+        let canonical = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, url.path(), host, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let key = signing_key(&self.secret_key, &date, &self.region, "s3");
+        let auth = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, SIGNED_HEADERS, hex(&hmac(&key, to_sign.as_bytes()))
+        );
+        Ok(reqwest::Client::new()
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("Authorization", auth))
+    }
+}
+
+// A block is what its hash says only if the header hashes to it and the body matches the header.
+fn check_block(block: &block::Block, block_hash: &[u8; 32]) -> Result<(), Error> {
+    let header = &block.sheader.msg;
+    if header.hash() != *block_hash {
+        return Err(Error::Corrupt(block::LoadError::BadHash));
+    }
+    if header.commits.txnseq != block.txnseq.commit() || header.commits.evidence != block.evidence.commit() {
+        return Err(Error::Corrupt(block::LoadError::BadCommits));
+    }
+    Ok(())
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(msg);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let k_date = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let k_region = hmac(&k_date, region.as_bytes());
+    let k_service = hmac(&k_region, service.as_bytes());
+    hmac(&k_service, b"aws4_request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_block() {
+        let (alice, head) = block::genesis();
+        let block = block::Builder::new(&alice, 1, &head).finalize(&alice).block;
+        let block_hash = block.sheader.msg.hash();
+        assert_eq!(check_block(&block, &block_hash), Ok(()));
+        // Some other block under the key asked for.
+        assert_eq!(check_block(&head.block, &block_hash), Err(Error::Corrupt(block::LoadError::BadHash)));
+        // The right header over a body it doesn't commit to.
+        let mut swapped = block.clone();
+        assert!(swapped.txnseq.push(alice.send(alice.kp.public, 1, 0, None)).is_ok());
+        assert_eq!(check_block(&swapped, &block_hash), Err(Error::Corrupt(block::LoadError::BadCommits)));
+    }
+
+    #[test]
+    fn hmac_vectors() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hex(&hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn sigv4_key() {
+        // Example from the AWS SigV4 docs
+        assert_eq!(
+            hex(&signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam")),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use blst::BLST_ERROR;
+use blst::min_pk::{SecretKey, PublicKey, Signature, AggregateSignature};
+use serde::{Serialize, Deserialize};
+
+use crate::{account, validator};
+
+// Committee attestations: validators BLS sign the head's block hash and the next
+// leader packs the votes into one aggregate signature plus a bitmap of who signed.
+
+const VOTE_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+const POP_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+// Attesting key, derived from the account key so there's nothing extra to store.
+fn secret(kp: &account::Keypair) -> SecretKey {
+    SecretKey::key_gen(kp.kp.secret.as_bytes(), b"tam attester").expect("ed25519 secrets are 32 bytes")
+}
+
+pub fn public(kp: &account::Keypair) -> Vec<u8> {
+    secret(kp).sk_to_pk().to_bytes().to_vec()
+}
+
+// Proof of possession, registered with the key so nobody can claim a rogue aggregate key.
+pub fn pop(kp: &account::Keypair) -> Vec<u8> {
+    secret(kp).sign(&public(kp), POP_DST, &[]).to_bytes().to_vec()
+}
+
+pub fn check_pop(pk: &[u8], pop: &[u8]) -> bool {
+    match (PublicKey::key_validate(pk), Signature::from_bytes(pop)) {
+        (Ok(key), Ok(sig)) => sig.verify(true, pk, POP_DST, &[], &key, false) == BLST_ERROR::BLST_SUCCESS,
+        _ => false
+    }
+}
+
+pub fn vote(kp: &account::Keypair, block_hash: &[u8; 32]) -> Vec<u8> {
+    secret(kp).sign(block_hash, VOTE_DST, &[]).to_bytes().to_vec()
+}
+
+pub fn check_vote(pk: &[u8], block_hash: &[u8; 32], vote: &[u8]) -> bool {
+    match (PublicKey::from_bytes(pk), Signature::from_bytes(vote)) {
+        (Ok(key), Ok(sig)) => sig.verify(true, block_hash, VOTE_DST, &[], &key, false) == BLST_ERROR::BLST_SUCCESS,
+        _ => false
+    }
+}
+
+// More than two thirds of the committee's slots.
+pub fn quorum(weight: u32, committee: &[&validator::Data]) -> bool {
+    let total: u32 = committee.iter().map(|val| val.slots).sum();
+    validator::supermajority(weight, total)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Attestation {
+    pub signers: Vec<u8>, // bitmap over committee indices
+    pub sig: Vec<u8>, // aggregate of their votes, empty if nobody signed
+}
+
+impl Attestation {
+    // Votes keyed by committee index. They should already be checked.
+    pub fn aggregate(votes: &BTreeMap<usize, Vec<u8>>) -> Result<Self, ()> {
+        let sigs = votes.values()
+            .map(|vote| Signature::from_bytes(vote).map_err(|_| ()))
+            .collect::<Result<Vec<_>, ()>>()?;
+        if sigs.is_empty() {
+            return Ok(Self::default());
+        }
+        let sig = AggregateSignature::aggregate(&sigs.iter().collect::<Vec<_>>(), true)
+            .map_err(|_| ())?
+            .to_signature();
+        let mut signers = Vec::default();
+        for &i in votes.keys() {
+            if signers.len() <= i / 8 {
+                signers.resize(i / 8 + 1, 0);
+            }
+            signers[i / 8] |= 1 << (i % 8);
+        }
+        Ok(Self { signers, sig: sig.to_bytes().to_vec() })
+    }
+
+    pub fn signers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.signers.len() * 8).filter(|i| self.signers[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    // Slots behind the attestation, if it's a valid aggregate over block_hash by the committee.
+    pub fn verify(&self, committee: &[&validator::Data], block_hash: &[u8; 32]) -> Result<u32, ()> {
+        if self.signers.iter().all(|byte| *byte == 0) {
+            // Canonical empty attestation only.
+            return if self.signers.is_empty() && self.sig.is_empty() { Ok(0) } else { Err(()) };
+        }
+        let mut keys = Vec::default();
+        let mut weight = 0;
+        for i in self.signers() {
+            let val = committee.get(i).ok_or(())?;
+            let bls = val.bls.as_ref().ok_or(())?;
+            keys.push(PublicKey::from_bytes(bls).map_err(|_| ())?);
+            weight += val.slots;
+        }
+        let sig = Signature::from_bytes(&self.sig).map_err(|_| ())?;
+        let keys: Vec<_> = keys.iter().collect();
+        if sig.fast_aggregate_verify(true, block_hash, VOTE_DST, &keys) != BLST_ERROR::BLST_SUCCESS {
+            return Err(());
+        }
+        Ok(weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate() {
+        let kps: Vec<_> = (0..3).map(|_| account::Keypair::gen()).collect();
+        let committee: Vec<_> = kps.iter()
+            .map(|kp| validator::Data {
+                opposed: Default::default(),
+                slots: 2,
+                pk: kp.kp.public,
+                missed: 0,
+                bls: Some(public(kp))
+            })
+            .collect();
+        let committee: Vec<_> = committee.iter().collect();
+        assert!(check_pop(&public(&kps[0]), &pop(&kps[0])));
+        assert!(!check_pop(&public(&kps[0]), &pop(&kps[1])));
+        let hash = [7u8; 32];
+        let votes: BTreeMap<_, _> = [0, 2].into_iter().map(|i| (i, vote(&kps[i], &hash))).collect();
+        let att = Attestation::aggregate(&votes).unwrap();
+        assert_eq!(att.signers().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(att.verify(&committee, &hash), Ok(4));
+        assert!(!quorum(4, &committee));
+        assert!(att.verify(&committee, &[0u8; 32]).is_err());
+        // Claiming a signer who didn't sign breaks the aggregate.
+        let forged = Attestation { signers: vec![0b111], ..att };
+        assert!(forged.verify(&committee, &hash).is_err());
+        assert_eq!(Attestation::default().verify(&committee, &hash), Ok(0));
+    }
+}
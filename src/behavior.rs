@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use sha2::{Sha256, Digest};
+
+use crate::{account, block, msg, state};
+
+// Ways to make a node misbehave on purpose, so tests can check honest nodes turn it away
+// and carry on. A node without one is honest; see Node::with_behavior.
+
+pub trait Behavior: Debug + Send {
+    // What to send out for a block we just signed on top of `prev`. Honest nodes send it compact.
+    fn propose(&mut self, kp: &account::Keypair, prev: &block::Snap, snap: &block::Snap) -> Vec<msg::Message> {
+        let _ = (kp, prev);
+        Vec::from([msg::Message::Compact(block::Compact::new(&snap.block))])
+    }
+
+    // Everything a tick sends, before it goes.
+    fn outgoing(&mut self, bcasts: msg::Bcasts) -> msg::Bcasts {
+        bcasts
+    }
+}
+
+// Holds each tick's broadcasts back for a number of ticks.
+#[derive(Debug, Clone, Default)]
+pub struct Delay {
+    pub ticks: usize,
+    held: VecDeque<msg::Bcasts>
+}
+
+impl Delay {
+    pub fn new(ticks: usize) -> Self {
+        Self { ticks, held: VecDeque::default() }
+    }
+}
+
+impl Behavior for Delay {
+    fn outgoing(&mut self, bcasts: msg::Bcasts) -> msg::Bcasts {
+        self.held.push_back(bcasts);
+        if self.held.len() > self.ticks {
+            self.held.pop_front().unwrap_or_default()
+        } else {
+            Vec::default()
+        }
+    }
+}
+
+// Signs a second, different block for every proposal and sends both.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Equivocate;
+
+impl Behavior for Equivocate {
+    fn propose(&mut self, kp: &account::Keypair, prev: &block::Snap, snap: &block::Snap) -> Vec<msg::Message> {
+        let mut builder = block::Builder::new(kp, snap.block.sheader.msg.data.proposal, prev);
+        // An empty twin differs from a full block, so only an empty one needs something in it.
+        if snap.block.txnseq.is_empty() {
+            let id: account::Id = Sha256::digest(kp.kp.public.to_bytes()).into();
+            let nonce = prev.state.accounts.get(&id).ok().flatten().map_or(0, |data| data.nonce);
+            let _ = builder.add(kp.send_acc([0; 32], state::DUST_BALANCE, nonce, None));
+        }
+        let twin = builder.finalize(kp);
+        Vec::from([
+            msg::Message::Compact(block::Compact::new(&snap.block)),
+            msg::Message::Chain(Vec::from([twin.block]))
+        ])
+    }
+}
+
+// Keeps its blocks to itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Withhold;
+
+impl Behavior for Withhold {
+    fn propose(&mut self, _: &account::Keypair, _: &block::Snap, _: &block::Snap) -> Vec<msg::Message> {
+        Vec::default()
+    }
+}
+
+// Sends its blocks with a txn slipped in that the header doesn't commit to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Corrupt;
+
+impl Behavior for Corrupt {
+    fn propose(&mut self, kp: &account::Keypair, _: &block::Snap, snap: &block::Snap) -> Vec<msg::Message> {
+        let mut block = snap.block.clone();
+        let _ = block.txnseq.push(kp.send_acc([0; 32], 1, 0, None));
+        Vec::from([msg::Message::Chain(Vec::from([block]))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay() {
+        let mut delay = Delay::new(2);
+        let tick = |i: usize| Vec::from([i.to_string()]);
+        assert!(delay.outgoing(tick(0)).is_empty());
+        assert!(delay.outgoing(tick(1)).is_empty());
+        assert_eq!(delay.outgoing(tick(2)), tick(0));
+        assert_eq!(delay.outgoing(Vec::default()), tick(1));
+    }
+}
@@ -0,0 +1,27 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use tammany::{account, genesis};
+
+// Genesis ceremony: genesis <config.json> <out dir>
+// Writes genesis.json for every node and authority.json for the first validator only,
+// then prints the genesis hash to publish alongside the file.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <config.json> <out dir>", args[0]);
+        std::process::exit(1);
+    }
+    let config: genesis::Config = serde_json::from_slice(
+        &fs::read(&args[1]).expect("couldn't read config")
+    ).expect("bad config");
+    let out = Path::new(&args[2]);
+    fs::create_dir_all(out).expect("couldn't make out dir");
+    let authority = account::Keypair::gen();
+    let snap = genesis::build(&authority, &config);
+    genesis::write(&out.join("genesis.json"), &snap).expect("couldn't write genesis");
+    fs::write(out.join("authority.json"), serde_json::to_vec(&authority).unwrap()).expect("couldn't write authority");
+    let hash: String = snap.block_hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+    println!("0x{}", hash);
+}
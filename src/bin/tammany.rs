@@ -0,0 +1,157 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+use ethnum::U256;
+use sha2::{Sha256, Digest};
+
+use tammany::{account, app, config, genesis, msg, rpc, state, txn};
+
+const USAGE: &str = "usage:
+  tammany keygen <key.json>
+  tammany init <dir> <key.json> [genesis config.json]
+  tammany run <node.toml>
+  tammany send <node> <key.json> <to 0x...> <amount>
+  tammany stake <node> <key.json>
+  tammany balance <node> <0x... | key.json>";
+
+fn fail(msg: impl std::fmt::Display) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("0x{}", hex)
+}
+
+fn read_key(path: &str) -> account::Keypair {
+    let bytes = fs::read(path).unwrap_or_else(|_| fail(format!("couldn't read {}", path)));
+    serde_json::from_slice(&bytes).unwrap_or_else(|_| fail(format!("{} isn't a key", path)))
+}
+
+fn id(kp: &account::Keypair) -> account::Id {
+    Sha256::digest(kp.kp.public.to_bytes()).into()
+}
+
+// An account is named by 0x and its 64 hex digit id, or by the key file that owns it.
+fn parse_id(arg: &str) -> account::Id {
+    match arg.starts_with("0x") && arg.len() == 66 {
+        true => U256::from_str_hex(arg).unwrap_or_else(|_| fail("bad address")).to_be_bytes(),
+        false => id(&read_key(arg))
+    }
+}
+
+async fn account(http: &reqwest::Client, node: &str, id: &account::Id) -> rpc::Account {
+    rpc::account(http, node, id).await.unwrap_or_else(|e| fail(format!("couldn't ask {}: {:?}", node, e)))
+}
+
+async fn submit(http: &reqwest::Client, node: &str, stxn: account::Signed<txn::Txn>) {
+    let hash = txn::hash(&stxn);
+    let outcome = match rpc::submit(http, node, &stxn).await {
+        Err(e) => fail(format!("couldn't send to {}: {:?}", node, e)),
+        Ok(Err(msg::error::Txn::Rejected(rejected))) => rejected.into_iter().next().map(|(_, err)| msg::ok::Outcome::Rejected(err)),
+        Ok(Err(e)) => fail(format!("not taken: {:?}", e)),
+        Ok(Ok(ok)) => ok.outcomes.into_iter().find(|(of, _)| *of == hash).map(|(_, outcome)| outcome)
+    };
+    match outcome {
+        Some(msg::ok::Outcome::Rejected(err)) => fail(format!("rejected: {:?}", err)),
+        Some(msg::ok::Outcome::Dropped(err)) => fail(format!("valid, but the node's pool wouldn't take it: {:?}", err)),
+        Some(outcome) => println!("sent {}: {:?}", hex(&hash), outcome),
+        None => fail("no word on it from the node")
+    }
+}
+
+// Key files hold the secret key as is, so keep them somewhere only you can read.
+fn keygen(path: &str) {
+    let mut options = fs::OpenOptions::new();
+    // Made readable by us alone, and never over a key already there.
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).unwrap_or_else(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => fail(format!("{} already exists", path)),
+        _ => fail(format!("couldn't write {}", path))
+    });
+    let kp = account::Keypair::gen();
+    file.write_all(&serde_json::to_vec(&kp).unwrap()).unwrap_or_else(|_| fail(format!("couldn't write {}", path)));
+    println!("{}", hex(&id(&kp)));
+}
+
+// A genesis.json put in `dir` beforehand, from someone else's ceremony, is kept. Otherwise
+// we're the authority of a fresh chain, funded as the genesis config says. Either way a
+// node.toml to run it with goes alongside, unless there's one already.
+fn init(dir: &str, key: &str, config: Option<&str>) {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir).unwrap_or_else(|_| fail("couldn't make dir"));
+    let genesis_path = dir.join("genesis.json");
+    let snap = match genesis_path.exists() {
+        true => genesis::read(&genesis_path).unwrap_or_else(|e| fail(format!("bad genesis: {:?}", e))),
+        false => {
+            let config = match config {
+                Some(path) => serde_json::from_slice(&fs::read(path).unwrap_or_else(|_| fail("couldn't read genesis config")))
+                    .unwrap_or_else(|_| fail("bad genesis config")),
+                None => genesis::Config::new(state::timestamp())
+            };
+            let snap = genesis::build(&read_key(key), &config);
+            genesis::write(&genesis_path, &snap).unwrap_or_else(|e| fail(format!("couldn't write genesis: {:?}", e)));
+            snap
+        }
+    };
+    let config_path = dir.join("node.toml");
+    if !config_path.exists() {
+        let config = config::Config { data_dir: dir.to_path_buf(), key: key.into(), ..config::Config::default() };
+        config.write(&config_path).unwrap_or_else(|e| fail(format!("couldn't write config: {:?}", e)));
+    }
+    println!("{}", hex(&snap.block_hash));
+}
+
+// With TAMMANY_ environment variables over the file, see config.rs.
+async fn run(path: &str) {
+    let config = config::Config::load(Path::new(path)).unwrap_or_else(|e| fail(format!("bad config {}: {:?}", path, e)));
+    let client = app::Client::from_config(&config).unwrap_or_else(|e| fail(format!("couldn't start: {:?}", e)));
+    client.run(&config.listen).await;
+}
+
+async fn send(node: &str, key: &str, to: &str, amount: &str) {
+    let kp = read_key(key);
+    let to = parse_id(to);
+    let amount: u32 = amount.parse().unwrap_or_else(|_| fail("bad amount"));
+    let http = reqwest::Client::new();
+    let nonce = account(&http, node, &id(&kp)).await.data.map_or(0, |data| data.nonce);
+    submit(&http, node, kp.send_acc(to, amount, nonce, None)).await;
+}
+
+// Into whichever slot is free when the txn lands.
+async fn stake(node: &str, key: &str) {
+    let kp = read_key(key);
+    let http = reqwest::Client::new();
+    let nonce = account(&http, node, &id(&kp)).await.data.map_or(0, |data| data.nonce);
+    submit(&http, node, kp.stake_any(0..state::VALIDATOR_SLOTS, nonce)).await;
+}
+
+async fn balance(node: &str, of: &str) {
+    let http = reqwest::Client::new();
+    let found = account(&http, node, &parse_id(of)).await;
+    let data = found.data.unwrap_or_default();
+    println!("{} ({} spendable) as of round {}", data.bal, data.spendable(found.round), found.round);
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["keygen", key] => keygen(key),
+        ["init", dir, key] => init(dir, key, None),
+        ["init", dir, key, config] => init(dir, key, Some(config)),
+        ["run", config] => run(config).await,
+        ["send", node, key, to, amount] => send(node, key, to, amount).await,
+        ["stake", node, key] => stake(node, key).await,
+        ["balance", node, of] => balance(node, of).await,
+        _ => fail(USAGE)
+    }
+}
@@ -1,29 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use rand::rngs::OsRng;
+use rayon::prelude::*;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_big_array::BigArray;
 use sha2::Sha256;
 use digest::Digest;
 
 use crate::account;
+use crate::attest;
+use crate::evidence;
+use crate::genesis;
 use crate::merkle;
+use crate::senator;
 use crate::state;
 use crate::txn;
 use crate::validator;
 
 pub const TXN_BATCH_SIZE: usize = 128;
 pub const MAX_BLOCK_SIZE: usize = 1024;
+// Txns at the front of every block only protocol txns can take, so a fee market can't crowd them out.
+pub const PROTOCOL_LANE: usize = MAX_BLOCK_SIZE / 8;
+// Txns a verifier trusting a peer's state audits per block.
+pub const SPOT_CHECKS: usize = 16;
 
-pub const BLOCK_TIME: u64 = 2_000; // ms
 
-impl Default for Metadata {
-    fn default() -> Self {
-        let beacon = account::Keypair::default()
-            .sign(&[0u8; 32]);
+// Round each protocol version takes over from. A block must carry the newest version
+// active in its round, so new rules switch on everywhere at once.
+pub const ACTIVATIONS: [(u16, u32); 1] = [
+    (1, 0)
+];
+
+pub fn version_at(round: u32) -> u16 {
+    ACTIVATIONS.iter()
+        .filter(|(_, from)| *from <= round)
+        .map(|(version, _)| *version)
+        .max()
+        .expect("some version is active from genesis")
+}
+
+// Leaders for a whole epoch are drawn from the validator set as it stood when the epoch
+// began, so stake moving mid epoch doesn't reshuffle the proposer schedule.
+pub const EPOCH_ROUNDS: u32 = 32;
+
+pub fn epoch_of(round: u32) -> u32 {
+    round / EPOCH_ROUNDS
+}
+
+// Checkpoint headers list every trie root behind the state commit, so light clients and
+// resyncing nodes can fetch and check each trie on its own.
+pub const CHECKPOINT_ROUNDS: u32 = 16;
+
+pub fn is_checkpoint(round: u32) -> bool {
+    round.is_multiple_of(CHECKPOINT_ROUNDS)
+}
+
+// A round gets this many proposals. Past that it's skipped and the next round's schedule
+// takes over, so a run of offline leaders still moves the round along.
+pub const MAX_PROPOSALS: u32 = 16;
+
+// Blocks whose timestamps a new block has to beat the median of, so a leader with a bad
+// clock can't drag chain time around on its own.
+pub const MEDIAN_BLOCKS: usize = 11;
+
+// Recent timestamps after one more block, oldest first.
+fn push_timestamp(recent: &[u64], timestamp: u64) -> Vec<u64> {
+    let skip = (recent.len() + 1).saturating_sub(MEDIAN_BLOCKS);
+    recent.iter().skip(skip).copied().chain([timestamp]).collect()
+}
+
+pub fn median(timestamps: &[u64]) -> u64 {
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied().unwrap_or_default()
+}
+
+// Where the proposal-th slot after a block lands: rounds skipped on the way, then proposal within its round.
+pub fn place(proposal: u32) -> (u32, u32) {
+    ((proposal - 1) / MAX_PROPOSALS, (proposal - 1) % MAX_PROPOSALS + 1)
+}
+
+// Leader of the proposal-th slot after a block with this seed. A skipped round's
+// successor draws from the seed hashed with how many rounds were skipped.
+pub fn leader_at<'a>(seed: &[u8; 32], epoch: &'a ValidatorSet, proposal: u32) -> Result<&'a account::PublicKey, txn::Error> {
+    let (skipped, proposal) = place(proposal);
+    let seed: [u8; 32] = match skipped {
+        0 => *seed,
+        _ => Sha256::new().chain_update(seed).chain_update(skipped.to_be_bytes()).finalize().into()
+    };
+    validator::leader(&seed, &epoch.slots, &epoch.validators, proposal, epoch.weighted())
+}
+
+pub const BLOOM_BYTES: usize = 256;
+const BLOOM_HASHES: usize = 3;
+
+impl Metadata {
+    pub fn genesis(authority: &account::Keypair, timestamp: u64) -> Self {
+        let beacon = authority.sign(&[0u8; 32]);
         Self { 
             prev_hash: [0u8; 32],
             round: 0, 
             proposal: 1,
-            timestamp: state::timestamp(), 
-            seed: Sha256::digest(&beacon).into(),
+            timestamp, 
+            seed: Sha256::digest(beacon).into(),
             beacon
         }
     }
@@ -33,37 +113,118 @@ impl Default for Metadata {
 pub struct Commits {
     pub state: [u8; 32],
     pub txnseq: [u8; 32],
+    pub evidence: [u8; 32],
+    pub shards: Vec<[u8; 32]>, // per account shard, for validators only checking some
 }
 
-impl Default for Commits {
-    fn default() -> Self {
-        let state = state::State::default();
-        let txnseq = txn::Seq::default();
+impl Commits {
+    pub fn new(state: &state::State, txnseq: &txn::Seq, evidence: &evidence::Seq) -> Self {
         Self { 
             state: state.commit(),
-            txnseq: txnseq.commit()
+            txnseq: txnseq.commit(),
+            evidence: evidence.commit(),
+            shards: state.accounts.commits()
+        }
+    }
+}
+
+// Accounts a block's txns touched. Ids are already hashes so their bytes index the bits directly.
+// No false negatives, so a wallet can skip any block whose bloom doesn't have its account.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Bloom(#[serde(with = "BigArray")] pub [u8; BLOOM_BYTES]);
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Self([0u8; BLOOM_BYTES])
+    }
+}
+
+impl Bloom {
+    // Senders of every txn plus every account the block wrote.
+    pub fn touched(txnseq: &txn::Seq, updates: &[state::Update]) -> Self {
+        let mut bloom = Self::default();
+        for txn in txnseq.iter() {
+            bloom.insert(&Sha256::digest(txn.from.to_bytes()).into());
         }
+        for up in updates {
+            if let state::Update::Account(k, _) = up {
+                bloom.insert(k);
+            }
+        }
+        bloom
+    }
+
+    fn bits(k: &account::Id) -> impl Iterator<Item = usize> + '_ {
+        (0..BLOOM_HASHES).map(move |i| {
+            u16::from_be_bytes([k[2 * i], k[2 * i + 1]]) as usize % (BLOOM_BYTES * 8)
+        })
+    }
+
+    pub fn insert(&mut self, k: &account::Id) {
+        for bit in Self::bits(k) {
+            self.0[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn may_contain(&self, k: &account::Id) -> bool {
+        Self::bits(k).all(|bit| self.0[bit / 8] & (1 << (bit % 8)) != 0)
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Header {
+    pub version: u16,
     pub data: Metadata,
     pub commits: Commits,
+    pub bloom: Bloom,
+    pub attestation: attest::Attestation, // committee votes for the previous block
+    pub checkpoint: Option<state::Roots>, // trie roots, on checkpoint rounds only
 }
 
 impl Header {
+    // Canonical encoding: fields in declaration order, integers big endian,
+    // shards length prefixed. Hashed for the block hash and signed by the leader.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::default();
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.data.prev_hash);
+        bytes.extend_from_slice(&self.data.round.to_be_bytes());
+        bytes.extend_from_slice(&self.data.proposal.to_be_bytes());
+        bytes.extend_from_slice(&self.data.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.data.seed);
+        bytes.extend_from_slice(&self.data.beacon.to_bytes());
+        bytes.extend_from_slice(&self.commits.state);
+        bytes.extend_from_slice(&self.commits.txnseq);
+        bytes.extend_from_slice(&self.commits.evidence);
+        bytes.extend_from_slice(&(self.commits.shards.len() as u32).to_be_bytes());
+        for shard in self.commits.shards.iter() {
+            bytes.extend_from_slice(shard);
+        }
+        bytes.extend_from_slice(&self.bloom.0);
+        bytes.extend_from_slice(&(self.attestation.signers.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.attestation.signers);
+        bytes.extend_from_slice(&(self.attestation.sig.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.attestation.sig);
+        match self.checkpoint {
+            Some(roots) => {
+                bytes.push(1);
+                for root in roots.iter() {
+                    bytes.extend_from_slice(root);
+                }
+            },
+            None => bytes.push(0)
+        }
+        bytes
+    }
+
     pub fn hash(&self) -> [u8; 32] {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.data.prev_hash);
-        hasher.update(&self.data.round.to_be_bytes());
-        hasher.update(&self.data.proposal.to_be_bytes());
-        hasher.update(&self.data.timestamp.to_be_bytes());
-        hasher.update(&self.data.seed);
-        hasher.update(&self.data.beacon);
-        hasher.update(&self.commits.state);
-        hasher.update(&self.commits.txnseq);
-        hasher.finalize().into()
+        Sha256::digest(self.encode()).into()
+    }
+}
+
+impl account::Signable for Header {
+    fn signing_bytes(&self) -> Vec<u8> {
+        self.encode()
     }
 }
 
@@ -78,13 +239,24 @@ pub struct Metadata {
 }
 
 impl Metadata {
+    // Round of the block this one builds on, allowing for skipped rounds.
+    pub fn prev_round(&self) -> u32 {
+        self.round.saturating_sub(1 + place(self.proposal.max(1)).0)
+    }
+
+    // Epoch whose set elects this block: the one of the round after its parent, so a block
+    // skipping into a new epoch is still led by the set its parent handed on.
+    pub fn epoch(&self) -> u32 {
+        epoch_of(self.prev_round() + 1)
+    }
+
     pub fn new(kp: &account::Keypair, proposal: u32, head: &Snap) -> Self {
-        let timestamp = head.block.sheader.msg.data.timestamp + BLOCK_TIME * (proposal as u64);
+        let timestamp = head.block.sheader.msg.data.timestamp + head.state.clock.block_time * (proposal as u64);
         let beacon = kp.sign(&head.block.sheader.msg.data.seed);
         let seed = Sha256::digest(beacon).into();
         Metadata {
             prev_hash: head.block_hash,
-            round: head.block.sheader.msg.data.round + 1,
+            round: head.block.sheader.msg.data.round + 1 + place(proposal).0,
             proposal,
             timestamp,
             seed,
@@ -96,19 +268,80 @@ impl Metadata {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Block {
     pub sheader: account::Signed<Header>,
-    pub txnseq: txn::Seq
+    pub txnseq: txn::Seq,
+    pub evidence: evidence::Seq
 }
 
-impl Default for Block {
-    fn default() -> Self {
-        let msg = Header::default();
-        let kp = account::Keypair::default();
-        let sig = kp.sign(&msg);
-        let from = kp.kp.public;
+// A block as relayed: txns by short id, for the receiver to fill in from its pool.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Compact {
+    pub sheader: account::Signed<Header>,
+    pub ids: Vec<txn::ShortId>, // in txnseq order
+    pub evidence: evidence::Seq
+}
+
+impl Compact {
+    pub fn new(block: &Block) -> Self {
+        Self {
+            sheader: block.sheader.clone(),
+            ids: block.txnseq.iter().map(txn::short_id).collect(),
+            evidence: block.evidence.clone()
+        }
+    }
+
+    // The full block, or the positions of txns we don't have.
+    pub fn fill(&self, pool: &HashMap<txn::ShortId, account::Signed<txn::Txn>>) -> Result<Block, Vec<u32>> {
+        let missing: Vec<u32> = (0..self.ids.len() as u32)
+            .filter(|pos| !pool.contains_key(&self.ids[*pos as usize]))
+            .collect();
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+        let mut txnseq = txn::Seq::default();
+        for id in self.ids.iter() {
+            assert!(txnseq.push(pool[id].clone()).is_ok());
+        }
+        Ok(Block { sheader: self.sheader.clone(), txnseq, evidence: self.evidence.clone() })
+    }
+}
+
+impl Block {
+    pub fn genesis(authority: &account::Keypair, state: &state::State, timestamp: u64) -> Self {
+        let txnseq = txn::Seq::default();
+        let evidence = evidence::Seq::default();
+        let msg = Header { 
+            version: version_at(0),
+            data: Metadata::genesis(authority, timestamp), 
+            commits: Commits::new(state, &txnseq, &evidence),
+            bloom: Bloom::default(),
+            attestation: attest::Attestation::default(),
+            checkpoint: Some(state.roots())
+        };
+        let sig = authority.sign(&msg);
+        let from = authority.kp.public;
         Self {
             sheader: account::Signed::<Header> { msg, from, sig },
-            txnseq: txn::Seq::default()
+            txnseq,
+            evidence
+        }
+    }
+
+    // Fork choice between heads: higher round, then lower proposal, then lower hash.
+    // Every honest node holding both blocks picks the same one whatever order they came in.
+    pub fn beats(&self, other: &Block) -> bool {
+        let (ours, theirs) = (&self.sheader.msg, &other.sheader.msg);
+        (ours.data.round, Reverse(ours.data.proposal), Reverse(ours.hash()))
+            > (theirs.data.round, Reverse(theirs.data.proposal), Reverse(theirs.hash()))
+    }
+
+    // Txns at positions batch_no * TXN_BATCH_SIZE onward, so big blocks can be fetched piecewise.
+    pub fn batch(&self, batch_no: u32) -> Option<Vec<account::Signed<txn::Txn>>> {
+        let start = (batch_no as usize).checked_mul(TXN_BATCH_SIZE)?;
+        if start >= self.txnseq.len() as usize {
+            return None;
         }
+        let end = (start + TXN_BATCH_SIZE).min(self.txnseq.len() as usize);
+        Some((start..end).map(|i| self.txnseq.get(i as u32).unwrap().unwrap().clone()).collect())
     }
 }
 
@@ -121,8 +354,19 @@ pub enum Error {
     BadSeed,
     BadTxnseq,
     BadTxn(account::Signed<txn::Txn>, txn::Error),
+    BadTxns(Vec<(u32, txn::Error)>), // every failing position in txnseq, from a reporting verifier
     BadState,
     NotLeader,
+    BadPrev,
+    BadValidators,
+    BadBloom,
+    BadVersion,
+    BadAttestation,
+    BadEvidenceSeq,
+    BadCheckpoint,
+    BadLane, // a protocol txn behind others, or other txns eating into the protocol lane
+    BadSize,
+    BadEvidence(evidence::Evidence, evidence::Error),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -130,58 +374,318 @@ pub struct Snap {
     pub block: Block,
     pub block_hash: [u8; 32],
     pub state: state::State,
+    pub updates: Vec<state::Update>, // net state diff from the previous snap
+    pub epoch: ValidatorSet, // elects the next block's leader
+    pub finalized: bool, // over 2/3 of the epoch's slots voted for it. Never reorged away
+    pub txn_index: merkle::Map<u32>, // txn hash to position in txnseq
+    pub timestamps: Vec<u64>, // of the last MEDIAN_BLOCKS blocks up to this one, oldest first
 }
 
-impl Default for Snap {
-    fn default() -> Self {
-        let block = Block::default();
-        let block_hash = block.sheader.msg.hash();
-        Self { block, block_hash, state: state::State::default() }
+// What can be wrong with a snap read back from storage or handed over by a peer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LoadError {
+    Truncated,
+    BadChecksum,
+    BadFormat,
+    BadHash,
+    BadState,
+    BadSig,
+    BadCommits, // txnseq or evidence don't match the header
+    NotLeader, // proposer holds no slots in the set we trust
+    NotAttested // the committee we trust didn't vouch for it
+}
+
+// A slot owner's finality vote for a block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vote {
+    pub round: u32,
+    pub block_hash: [u8; 32]
+}
+
+impl account::Signable for Vote {
+    fn signing_bytes(&self) -> Vec<u8> {
+        [b"tam vote".as_slice(), &self.round.to_be_bytes(), &self.block_hash].concat()
     }
 }
 
+// Fresh authority and the genesis it controls, for tests and local nets.
+pub fn genesis() -> (account::Keypair, Snap) {
+    let authority = account::Keypair::gen();
+    let snap = Snap::genesis(&authority);
+    (authority, snap)
+}
+
 impl Snap {
+    // Genesis from the default config, starting now. Real chains load theirs with genesis::read.
+    pub fn genesis(authority: &account::Keypair) -> Self {
+        genesis::build(authority, &genesis::Config::new(state::timestamp()))
+    }
+
+    // Bytes for storage: checksum, block hash and state commit ahead of the json,
+    // with the checksum over everything after it.
+    pub fn store(&self) -> Vec<u8> {
+        let mut body = Vec::default();
+        body.extend_from_slice(&self.block_hash);
+        body.extend_from_slice(&self.state.commit());
+        body.extend_from_slice(&serde_json::to_vec(self).expect("snaps serialize"));
+        let checksum: [u8; 32] = Sha256::digest(&body).into();
+        [checksum.as_slice(), &body].concat()
+    }
+
+    // Reads back what `store` wrote, checking it's intact and still hangs together.
+    pub fn load(bytes: &[u8]) -> Result<Self, LoadError> {
+        if bytes.len() < 96 {
+            return Err(LoadError::Truncated);
+        }
+        let (checksum, body) = bytes.split_at(32);
+        if Sha256::digest(body).as_slice() != checksum {
+            return Err(LoadError::BadChecksum);
+        }
+        let snap: Self = serde_json::from_slice(&body[64..]).map_err(|_| LoadError::BadFormat)?;
+        if body[..32] != snap.block_hash {
+            return Err(LoadError::BadHash);
+        }
+        if body[32..64] != snap.state.commit() {
+            return Err(LoadError::BadState);
+        }
+        snap.check()?;
+        Ok(snap)
+    }
+
+    // The block hash, signature and every state commit in the header agree with what's in here.
+    // Says nothing about whether the block belongs on any chain.
+    pub fn check(&self) -> Result<(), LoadError> {
+        let header = &self.block.sheader.msg;
+        if header.hash() != self.block_hash {
+            return Err(LoadError::BadHash);
+        }
+        if !self.block.sheader.verify() {
+            return Err(LoadError::BadSig);
+        }
+        if header.commits.state != self.state.commit() || header.commits.shards != self.state.accounts.commits() {
+            return Err(LoadError::BadState);
+        }
+        if header.checkpoint.is_some_and(|roots| roots != self.state.roots()) {
+            return Err(LoadError::BadState);
+        }
+        Ok(())
+    }
+
+    // A block a peer offers to jump to, with the header after it. There's no chain back to it to
+    // pick out its exact leader, so whoever proposed it has to hold slots in `trusted`, a set we
+    // already stand by, and more than two thirds of its committee has to have attested to it.
+    pub fn verify_resync(&self, trusted: &ValidatorSet, next: &account::Signed<Header>) -> Result<(), LoadError> {
+        self.check()?;
+        self.state.valid_commits().map_err(|_| LoadError::BadState)?;
+        let commits = &self.block.sheader.msg.commits;
+        if commits.txnseq != self.block.txnseq.commit() || self.block.txnseq.valid_commits().is_err() {
+            return Err(LoadError::BadCommits);
+        }
+        if commits.evidence != self.block.evidence.commit() || self.block.evidence.valid_commits().is_err() {
+            return Err(LoadError::BadCommits);
+        }
+        if trusted.weight(&self.block.sheader.from) == 0 {
+            return Err(LoadError::NotLeader);
+        }
+        if next.msg.data.prev_hash != self.block_hash || !trusted.vouches(&self.epoch, &next.msg.attestation, &self.block_hash) {
+            return Err(LoadError::NotAttested);
+        }
+        Ok(())
+    }
+
+    // Where in this block a txn went, if it's here.
+    pub fn position(&self, hash: &txn::Hash) -> Option<u32> {
+        self.txn_index.get(hash).ok().flatten().copied()
+    }
+
+    // Proof against the header's txnseq commit that a txn sat at its position.
+    pub fn prove_txn(&self, hash: &txn::Hash) -> Option<(u32, merkle::ListProof)> {
+        let pos = self.position(hash)?;
+        Some((pos, self.block.txnseq.prove(pos).ok()?))
+    }
+
     pub fn leader(&self, proposal: u32) -> Result<&account::PublicKey, txn::Error> {
-        validator::leader(
-            &self.block.sheader.msg.data.seed, 
-            &self.state.slots, 
-            &self.state.validators, 
-            proposal
-        )
+        leader_at(&self.block.sheader.msg.data.seed, &self.epoch, proposal)
+    }
+
+    // Everything but the state, which the updates rebuild from the parent's.
+    pub fn thin(self) -> Delta {
+        Delta {
+            block: self.block,
+            block_hash: self.block_hash,
+            updates: self.updates,
+            epoch: self.epoch,
+            finalized: self.finalized,
+            txn_index: self.txn_index,
+            timestamps: self.timestamps
+        }
     }
 }
 
+// A snap without its own copy of the state, for keeping blocks further back cheaply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    pub block: Block,
+    pub block_hash: [u8; 32],
+    pub updates: Vec<state::Update>,
+    pub epoch: ValidatorSet,
+    pub finalized: bool,
+    pub txn_index: merkle::Map<u32>,
+    pub timestamps: Vec<u64>
+}
+
+impl Delta {
+    // The whole snap back, given the state its parent left. Checked against the header.
+    pub fn fill(&self, mut state: state::State) -> Result<Snap, LoadError> {
+        state.replay(&self.updates).map_err(|_| LoadError::BadState)?;
+        let commits = &self.block.sheader.msg.commits;
+        if commits.state != state.commit() || commits.shards != state.accounts.commits() {
+            return Err(LoadError::BadState);
+        }
+        Ok(Snap {
+            block: self.block.clone(),
+            block_hash: self.block_hash,
+            state,
+            updates: self.updates.clone(),
+            epoch: self.epoch.clone(),
+            finalized: self.finalized,
+            txn_index: self.txn_index.clone(),
+            timestamps: self.timestamps.clone()
+        })
+    }
+
+    pub fn position(&self, hash: &txn::Hash) -> Option<u32> {
+        self.txn_index.get(hash).ok().flatten().copied()
+    }
+}
+
+// A builder as written to disk, so a leader that restarts mid-slot can carry on.
+// The overlay isn't kept: replaying the txns over the head state rebuilds it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Draft {
+    pub metadata: Metadata,
+    pub txnseq: txn::Seq,
+    pub evidence: evidence::Seq,
+    pub attestation: attest::Attestation
+}
+
 #[derive(Debug, Clone)]
 pub struct Builder {
-    pub txnseq: merkle::Map::<account::Signed::<txn::Txn>>,
-    pub batch: u32,
-    pub count: u32,
-    pub state: state::State,
-    pub metadata: Metadata
+    pub txnseq: txn::Seq,
+    pub lane: u32, // protocol txns at the front of txnseq
+    pub txn_index: merkle::Map<u32>,
+    pub evidence: evidence::Seq,
+    pub state: state::State, // head state, untouched until finalize
+    pub overlay: state::Overlay, // writes from txns added so far
+    base: state::Overlay, // writes before any txns, to replay from on replacement
+    lane_overlay: state::Overlay, // writes up to the end of the lane, once anything's behind it
+    touched: HashSet<(u8, Vec<u8>)>, // keys written by txns behind the lane
+    pub displaced: Vec<account::Signed<txn::Txn>>, // pushed out by txns put in ahead, for the caller to pool again
+    pub metadata: Metadata,
+    pub epoch: ValidatorSet, // head's
+    pub timestamps: Vec<u64>, // head's recent ones
+    pub attestation: attest::Attestation // votes for head gathered so far
 }
 
 impl Builder {
     pub fn new(kp: &account::Keypair, proposal: u32, head: &Snap) -> Self {
+        let mut overlay = state::Overlay::default();
+        overlay.record_misses(
+            &head.state, 
+            &head.epoch,
+            &head.block.sheader.msg.data.seed, 
+            proposal, 
+            &kp.kp.public
+        ).expect("head state has a leader for every proposal");
+        let metadata = Metadata::new(kp, proposal, head);
+        overlay.deliver_receipts(&head.state, metadata.round)
+            .expect("head state holds its own receipts");
         Self {
             txnseq: txn::Seq::default(),
-            count: 0,
-            batch: 0,
+            lane: 0,
+            txn_index: merkle::Map::default(),
+            evidence: evidence::Seq::default(),
             state: head.state.clone(),
-            metadata: Metadata::new(kp, proposal, head)
+            base: overlay.clone(),
+            lane_overlay: overlay.clone(),
+            touched: HashSet::default(),
+            displaced: Vec::default(),
+            overlay,
+            metadata,
+            epoch: head.epoch.clone(),
+            timestamps: head.timestamps.clone(),
+            attestation: attest::Attestation::default()
         }
     }
 
+    pub fn save(&self) -> Draft {
+        Draft {
+            metadata: self.metadata.clone(),
+            txnseq: self.txnseq.clone(),
+            evidence: self.evidence.clone(),
+            attestation: self.attestation.clone()
+        }
+    }
+
+    // Picks a saved draft back up on head. Fails if it was built on anything else.
+    pub fn resume(kp: &account::Keypair, head: &Snap, draft: Draft) -> Result<Self, Error> {
+        let mut builder = Self::new(kp, draft.metadata.proposal, head);
+        if builder.metadata != draft.metadata {
+            return Err(Error::BadPrev);
+        }
+        for stxn in draft.txnseq.iter() {
+            builder.push(stxn.clone()).map_err(|(stxn, e)| Error::BadTxn(stxn, e))?;
+        }
+        for ev in draft.evidence.iter() {
+            builder.add_evidence(ev.clone()).map_err(|(ev, e)| Error::BadEvidence(ev, e))?;
+        }
+        builder.attestation = draft.attestation;
+        Ok(builder)
+    }
+
+    // Head state with everything added so far applied.
+    // Next nonce for an account with the txns added so far.
+    pub fn nonce(&self, id: &account::Id) -> Option<u32> {
+        self.overlay.account(&self.state, id).ok().flatten().map(|data| data.nonce)
+    }
+
+    pub fn current_state(&self) -> state::State {
+        let mut state = self.state.clone();
+        assert!(self.overlay.clone().flush(&mut state).is_ok());
+        state
+    }
+
     pub fn add(&mut self, stxn: account::Signed<txn::Txn>) -> Result<(), (account::Signed<txn::Txn>, txn::Error)> {
-        match self.state.apply(&stxn, &self.metadata) {
-            Ok(()) => {
-                let idx = (self.batch as u64) << 32 | (self.count as u64);
-                assert!(
-                    self.txnseq.insert(&idx.to_be_bytes(), stxn).is_ok()
-                );
-                self.count += 1;
-                if self.count == TXN_BATCH_SIZE as u32 {
-                    self.count = 0;
-                    self.batch += 1;
+        let res = if stxn.msg.payload.is_protocol() && self.lane < self.txnseq.len() {
+            self.promote(stxn)
+        } else {
+            self.push(stxn)
+        };
+        match res {
+            Err((stxn, err @ txn::Error::SmallNonce { .. })) => self.replace(stxn, err),
+            res => res
+        }
+    }
+
+    // Appends. Callers keep protocol txns ahead of the rest.
+    fn push(&mut self, stxn: account::Signed<txn::Txn>) -> Result<(), (account::Signed<txn::Txn>, txn::Error)> {
+        let protocol = stxn.msg.payload.is_protocol();
+        let others = (self.txnseq.len() - self.lane) as usize;
+        if self.txnseq.len() as usize == MAX_BLOCK_SIZE || (!protocol && others == MAX_BLOCK_SIZE - PROTOCOL_LANE) {
+            return Err((stxn, txn::Error::FullBlock));
+        }
+        if !protocol && self.txnseq.len() == self.lane {
+            self.lane_overlay = self.overlay.clone();
+        }
+        match self.overlay.apply(&self.state, &stxn, &self.metadata) {
+            Ok(ups) => {
+                if !protocol {
+                    self.touched.extend(ups.iter().map(state::Update::key));
+                }
+                assert!(self.txn_index.insert(&txn::hash(&stxn), self.txnseq.len()).is_ok());
+                assert!(self.txnseq.push(stxn).is_ok());
+                if protocol {
+                    self.lane += 1;
                 }
                 Ok(())
             },
@@ -191,13 +695,144 @@ impl Builder {
         }
     }
 
+    pub fn add_evidence(&mut self, ev: evidence::Evidence) -> Result<(), (evidence::Evidence, evidence::Error)> {
+        let count = self.evidence.iter().count() as u32;
+        if count == evidence::MAX_BLOCK_EVIDENCE {
+            return Err((ev, evidence::Error::TooMuch));
+        }
+        if let Err(e) = ev.check() {
+            return Err((ev, e));
+        }
+        if self.evidence.iter().any(|old| old.same_offence(&ev)) {
+            return Err((ev, evidence::Error::Duplicate));
+        }
+        assert!(self.evidence.insert(&count.to_be_bytes(), ev).is_ok());
+        Ok(())
+    }
+
+    // Same builder with no txns in it yet.
+    fn emptied(&self) -> Self {
+        Self {
+            txnseq: txn::Seq::default(),
+            lane: 0,
+            txn_index: merkle::Map::default(),
+            evidence: self.evidence.clone(),
+            state: self.state.clone(),
+            overlay: self.base.clone(),
+            base: self.base.clone(),
+            lane_overlay: self.base.clone(),
+            touched: HashSet::default(),
+            displaced: self.displaced.clone(),
+            metadata: self.metadata.clone(),
+            epoch: self.epoch.clone(),
+            timestamps: self.timestamps.clone(),
+            attestation: self.attestation.clone()
+        }
+    }
+
+    // Put a protocol txn in at the back of the lane. If it touches nothing the txns behind the
+    // lane wrote it runs the same there as here, so it's applied in place. Otherwise those txns
+    // are replayed behind it and any that no longer apply are displaced.
+    fn promote(&mut self, stxn: account::Signed<txn::Txn>) -> Result<(), (account::Signed<txn::Txn>, txn::Error)> {
+        if self.txnseq.len() as usize == MAX_BLOCK_SIZE {
+            return Err((stxn, txn::Error::FullBlock));
+        }
+        match self.overlay.verify(&self.state, &stxn, &self.metadata) {
+            Ok(ups) if ups.iter().all(|up| !self.touched.contains(&up.key())) => {
+                self.overlay.write_all(&ups);
+                self.lane_overlay.write_all(&ups);
+            },
+            _ => {
+                let behind: Vec<_> = self.txnseq.iter().skip(self.lane as usize).cloned().collect();
+                let mut overlay = self.lane_overlay.clone();
+                if let Err(err) = overlay.apply(&self.state, &stxn, &self.metadata) {
+                    return Err((stxn, err));
+                }
+                self.lane_overlay = overlay.clone();
+                // Signatures were checked when they first went in.
+                let checked = self.overlay.sigs_checked();
+                overlay.set_sigs_checked(true);
+                let mut kept = Vec::default();
+                self.touched.clear();
+                for txn in behind {
+                    match overlay.apply(&self.state, &txn, &self.metadata) {
+                        Ok(ups) => {
+                            self.touched.extend(ups.iter().map(state::Update::key));
+                            kept.push(txn);
+                        },
+                        Err(_) => {
+                            assert!(self.txn_index.remove(&txn::hash(&txn)).is_ok());
+                            self.displaced.push(txn);
+                        }
+                    }
+                }
+                overlay.set_sigs_checked(checked);
+                self.overlay = overlay;
+                let lane: Vec<_> = self.txnseq.iter().take(self.lane as usize).cloned().collect();
+                self.txnseq = txn::Seq::default();
+                for txn in lane.into_iter().chain(kept) {
+                    assert!(self.txnseq.push(txn).is_ok());
+                }
+            }
+        }
+        // Shift everything behind the lane back one to make room.
+        let mut moved = stxn;
+        for i in self.lane..self.txnseq.len() {
+            moved = self.txnseq.set(i, moved).expect("in range");
+            let at = self.txnseq.get(i).expect("in range").expect("just set");
+            assert!(self.txn_index.insert(&txn::hash(at), i).is_ok());
+        }
+        assert!(self.txn_index.insert(&txn::hash(&moved), self.txnseq.len()).is_ok());
+        assert!(self.txnseq.push(moved).is_ok());
+        self.lane += 1;
+        Ok(())
+    }
+
+    // Swap out an included txn for one with the same sender and nonce paying a higher fee.
+    // Replays everything from the base overlay, dropping later txns that no longer apply.
+    fn replace(&mut self, stxn: account::Signed<txn::Txn>, err: txn::Error) -> Result<(), (account::Signed<txn::Txn>, txn::Error)> {
+        let old: Vec<_> = self.txnseq.iter().cloned().collect();
+        let pos = old.iter().position(|old| old.from == stxn.from && old.msg.nonce == stxn.msg.nonce);
+        // Swapping lanes would leave the replacement out of order.
+        let pos = match pos {
+            Some(pos) if old[pos].msg.fee < stxn.msg.fee 
+                && old[pos].msg.payload.is_protocol() == stxn.msg.payload.is_protocol() => pos,
+            _ => return Err((stxn, err))
+        };
+        let mut rebuilt = self.emptied();
+        let mut old = old.into_iter();
+        for txn in old.by_ref().take(pos) {
+            if let Err((txn, _)) = rebuilt.push(txn) {
+                rebuilt.displaced.push(txn);
+            }
+        }
+        old.next();
+        rebuilt.push(stxn)?;
+        for txn in old {
+            if let Err((txn, _)) = rebuilt.push(txn) {
+                rebuilt.displaced.push(txn);
+            }
+        }
+        *self = rebuilt;
+        Ok(())
+    }
+
     pub fn finalize(self, kp: &account::Keypair) -> Snap {
+        let mut state = self.state;
+        let updates = self.overlay.flush(&mut state).expect("builder state is complete");
+        let checkpoint = is_checkpoint(self.metadata.round).then(|| state.roots());
         let header = Header {
+            version: version_at(self.metadata.round),
             data: self.metadata,
             commits: Commits {
-                state: self.state.commit(),
-                txnseq: self.txnseq.commit()
-            }
+                state: state.commit(),
+                txnseq: self.txnseq.commit(),
+                evidence: self.evidence.commit(),
+                shards: state.accounts.commits()
+            },
+            bloom: Bloom::touched(&self.txnseq, &updates),
+            attestation: self.attestation,
+            checkpoint
         };
         let block_hash = header.hash();
         let sig = kp.sign(&header);
@@ -207,9 +842,12 @@ impl Builder {
                 from: kp.kp.public,
                 sig
             },
-            txnseq: self.txnseq.clone()
+            txnseq: self.txnseq.clone(),
+            evidence: self.evidence
         };
-        Snap { block, block_hash, state: self.state }
+        let epoch = self.epoch.next(block.sheader.msg.data.round, &state);
+        let timestamps = push_timestamp(&self.timestamps, block.sheader.msg.data.timestamp);
+        Snap { block, block_hash, state, updates, epoch, finalized: false, txn_index: self.txn_index, timestamps }
     }
 }
 
@@ -218,12 +856,26 @@ impl Builder {
 pub struct Verifier<'a> {
     pub head: &'a Snap,
     pub block: Block,
-    pub batch: u32
+    pub batch: u32,
+    pub report: bool, // carry on past bad txns and list them all
+    pub trusted: Option<state::State> // claimed state after the block, taken on spot checks
 }
 
 impl<'a> Verifier<'a> {
     pub fn new(head: &'a Snap, block: Block) -> Self {
-        Self { head, block, batch: 0 }
+        Self { head, block, batch: 0, report: false, trusted: None }
+    }
+
+    // Optimistic mode for fast sync from a trusted peer: finalize takes this state if it matches
+    // the header's commits and a random sample of txns shows up in it. Replay fully later.
+    pub fn with_trusted_state(mut self, state: state::State) -> Self {
+        self.trusted = Some(state);
+        self
+    }
+
+    pub fn with_report(mut self) -> Self {
+        self.report = true;
+        self
     }
 
     // possible alternative later: streaming build
@@ -258,53 +910,498 @@ impl<'a> Verifier<'a> {
     }
     */
 
-    pub fn finalize(self) -> Result<Snap, (Block, Error)> {
-        let sheader = &self.block.sheader;
-        let header = &sheader.msg;
-        assert_eq!(header.data.prev_hash, self.head.block_hash);
-        if !sheader.verify() { return Err((self.block, Error::BadSig)); }
-        if header.data.round != self.head.block.sheader.msg.data.round + 1 {
-            return Err((self.block, Error::BadRound));
-        }
-        if header.data.timestamp != self.head.block.sheader.msg.data.timestamp + (header.data.proposal as u64) * BLOCK_TIME  {
-            return Err((self.block, Error::BadBlockTime));
+    fn in_shards(&self, txn: &account::Signed<txn::Txn>, shards: Option<&[usize]>) -> bool {
+        match shards {
+            Some(shards) => {
+                let from_addy: account::Id = Sha256::digest(txn.from.to_bytes()).into();
+                shards.contains(&self.head.state.accounts.shard_of(&from_addy))
+            },
+            None => true
         }
+    }
+
+    // Signature checks dominate verification and don't depend on state,
+    // so do them all in parallel before the sequential pass over txns.
+    fn check_sigs(&self, shards: Option<&[usize]>) -> Result<(), Error> {
+        let sheader = &self.block.sheader;
         let sbeacon = account::Signed::<[u8; 32]> {
             msg: self.head.block.sheader.msg.data.seed,
-            from: sheader.from.clone(),
-            sig: header.data.beacon
+            from: sheader.from,
+            sig: sheader.msg.data.beacon
         };
-        if !sbeacon.verify() {
-            return Err((self.block, Error::BadBeacon));
+        // When reporting the overlay checks each txn's sig instead, so a bad one doesn't stop the run.
+        let txns: Vec<_> = self.block.txnseq.iter()
+            .filter(|txn| !self.report && self.in_shards(txn, shards))
+            .collect();
+        let ((header_ok, beacon_ok), bad_txn) = rayon::join(
+            || rayon::join(|| sheader.verify(), || sbeacon.verify()),
+            || txns.par_iter().find_first(|txn| !txn.verify())
+        );
+        if !header_ok {
+            return Err(Error::BadSig);
+        }
+        if !beacon_ok {
+            return Err(Error::BadBeacon);
         }
-        let seed: [u8; 32] = Sha256::digest(&header.data.beacon).into();
-        if header.data.seed != seed {
-            return Err((self.block, Error::BadSeed));
+        if let Some(txn) = bad_txn {
+            return Err(Error::BadTxn((*txn).clone(), txn::Error::BadSig));
+        }
+        Ok(())
+    }
+
+    fn check_header(&self) -> Result<(), Error> {
+        let sheader = &self.block.sheader;
+        let header = &sheader.msg;
+        check_link(&self.head.block.sheader.msg, header, &self.head.state.clock)?;
+        check_epoch(&self.head.epoch, header)?;
+        if header.data.timestamp <= median(&self.head.timestamps) {
+            return Err(Error::BadBlockTime);
+        }
+        if header.attestation.verify(&self.head.epoch.committee(), &self.head.block_hash).is_err() {
+            return Err(Error::BadAttestation);
         }
         if header.commits.txnseq != self.block.txnseq.commit() {
-            return Err((self.block, Error::BadTxnseq));
+            return Err(Error::BadTxnseq);
         }
         if self.block.txnseq.valid_commits().is_err() {
-            return Err((self.block, Error::BadTxnseq));
+            return Err(Error::BadTxnseq);
         }
+        self.check_lane()?;
+        self.check_evidence()?;
         let leader = self.head.leader(
             header.data.proposal
         ).unwrap();
         if leader != &sheader.from {
-            return Err((self.block, Error::NotLeader));
+            return Err(Error::NotLeader);
         }
-        let mut state = self.head.state.clone();
-        for txn in self.block.txnseq.iter() {
-            if let Err(e) = state.apply(txn, &header.data) {
-                let txn_clone = txn.clone();
-                return Err((self.block, Error::BadTxn(txn_clone, e)));
+        Ok(())
+    }
+
+    fn check_lane(&self) -> Result<(), Error> {
+        if self.block.txnseq.len() as usize > MAX_BLOCK_SIZE {
+            return Err(Error::BadSize);
+        }
+        let lane = self.block.txnseq.iter().take_while(|txn| txn.msg.payload.is_protocol()).count();
+        let others = self.block.txnseq.len() as usize - lane;
+        if others > MAX_BLOCK_SIZE - PROTOCOL_LANE || self.block.txnseq.iter().skip(lane).any(|txn| txn.msg.payload.is_protocol()) {
+            return Err(Error::BadLane);
+        }
+        Ok(())
+    }
+
+    fn check_evidence(&self) -> Result<(), Error> {
+        let header = &self.block.sheader.msg;
+        if header.commits.evidence != self.block.evidence.commit() || self.block.evidence.valid_commits().is_err() {
+            return Err(Error::BadEvidenceSeq);
+        }
+        let mut seen: Vec<&evidence::Evidence> = Vec::default();
+        for ev in self.block.evidence.iter() {
+            if seen.len() as u32 == evidence::MAX_BLOCK_EVIDENCE {
+                return Err(Error::BadEvidence(ev.clone(), evidence::Error::TooMuch));
+            }
+            ev.check().map_err(|e| Error::BadEvidence(ev.clone(), e))?;
+            if seen.iter().any(|old| old.same_offence(ev)) {
+                return Err(Error::BadEvidence(ev.clone(), evidence::Error::Duplicate));
+            }
+            seen.push(ev);
+        }
+        Ok(())
+    }
+
+    // Run the block over the head state. With Some(shards) only txns sent from those shards are applied.
+    fn overlay(&self, shards: Option<&[usize]>) -> Result<state::Overlay, Error> {
+        let sheader = &self.block.sheader;
+        let header = &sheader.msg;
+        let mut overlay = state::Overlay::default();
+        if !self.report {
+            overlay = overlay.with_sigs_checked();
+        }
+        if overlay.record_misses(
+            &self.head.state, 
+            &self.head.epoch,
+            &self.head.block.sheader.msg.data.seed, 
+            header.data.proposal, 
+            &sheader.from
+        ).is_err() {
+            return Err(Error::BadState);
+        }
+        if overlay.deliver_receipts(&self.head.state, header.data.round).is_err() {
+            return Err(Error::BadState);
+        }
+        let mut failures = Vec::default();
+        for (pos, txn) in self.block.txnseq.iter().enumerate().filter(|(_, txn)| self.in_shards(txn, shards)) {
+            if let Err(e) = overlay.apply(&self.head.state, txn, &header.data) {
+                if !self.report {
+                    return Err(Error::BadTxn(txn.clone(), e));
+                }
+                failures.push((pos as u32, e));
+            }
+        }
+        if !failures.is_empty() {
+            return Err(Error::BadTxns(failures));
+        }
+        Ok(overlay)
+    }
+
+    // Each sampled txn has to be signed, and its sender's nonce in state past it.
+    fn spot_check(&self, state: &state::State) -> Result<(), Error> {
+        let len = self.block.txnseq.len() as usize;
+        for pos in rand::seq::index::sample(&mut OsRng, len, SPOT_CHECKS.min(len)).iter() {
+            let stxn = self.block.txnseq.get(pos as u32).ok().flatten().ok_or(Error::BadTxnseq)?;
+            if !stxn.verify() {
+                return Err(Error::BadTxn(stxn.clone(), txn::Error::BadSig));
             }
+            match state.accounts.get(&Sha256::digest(stxn.from.to_bytes())) {
+                Ok(Some(acc)) if acc.nonce > stxn.msg.nonce => {},
+                _ => return Err(Error::BadState)
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize_trusted(self, state: state::State) -> Result<Snap, (Block, Error)> {
+        // No shards picked means no txn sigs, just the header's.
+        if let Err(e) = self.check_sigs(Some(&[])).and_then(|_| self.check_header()) {
+            return Err((self.block, e));
         }
-        if header.commits.state != state.commit() {
+        let commits = &self.block.sheader.msg.commits;
+        if commits.state != state.commit() || commits.shards != state.accounts.commits() {
             return Err((self.block, Error::BadState));
         }
+        if let Err(e) = self.spot_check(&state) {
+            return Err((self.block, e));
+        }
         let block_hash = self.block.sheader.msg.hash();
-        Ok( Snap { block: self.block, block_hash, state } )
+        let epoch = self.head.epoch.next(self.block.sheader.msg.data.round, &state);
+        let mut txn_index = merkle::Map::default();
+        for (pos, txn) in self.block.txnseq.iter().enumerate() {
+            assert!(txn_index.insert(&txn::hash(txn), pos as u32).is_ok());
+        }
+        let timestamps = push_timestamp(&self.head.timestamps, self.block.sheader.msg.data.timestamp);
+        // The diff is only known from a replay.
+        Ok( Snap { block: self.block, block_hash, state, updates: Vec::default(), epoch, finalized: false, txn_index, timestamps } )
+    }
+
+    pub fn finalize(mut self) -> Result<Snap, (Block, Error)> {
+        if let Some(state) = self.trusted.take() {
+            return self.finalize_trusted(state);
+        }
+        if let Err(e) = self.check_sigs(None).and_then(|_| self.check_header()) {
+            return Err((self.block, e));
+        }
+        let overlay = match self.overlay(None) {
+            Ok(overlay) => overlay,
+            Err(e) => return Err((self.block, e))
+        };
+        let mut state = self.head.state.clone();
+        let updates = match overlay.flush(&mut state) {
+            Ok(updates) => updates,
+            Err(_) => return Err((self.block, Error::BadState))
+        };
+        let commits = &self.block.sheader.msg.commits;
+        if commits.state != state.commit() || commits.shards != state.accounts.commits() {
+            return Err((self.block, Error::BadState));
+        }
+        if self.block.sheader.msg.bloom != Bloom::touched(&self.block.txnseq, &updates) {
+            return Err((self.block, Error::BadBloom));
+        }
+        let block_hash = self.block.sheader.msg.hash();
+        let epoch = self.head.epoch.next(self.block.sheader.msg.data.round, &state);
+        let mut txn_index = merkle::Map::default();
+        for (pos, txn) in self.block.txnseq.iter().enumerate() {
+            assert!(txn_index.insert(&txn::hash(txn), pos as u32).is_ok());
+        }
+        let timestamps = push_timestamp(&self.head.timestamps, self.block.sheader.msg.data.timestamp);
+        Ok( Snap { block: self.block, block_hash, state, updates, epoch, finalized: false, txn_index, timestamps } )
+    }
+
+    // Cheaper check for a validator assigned just some account shards: only txns sent
+    // from them are run and only their commits are checked. Doesn't produce a snap.
+    pub fn verify_shards(&self, shards: &[usize]) -> Result<(), Error> {
+        self.check_sigs(Some(shards))?;
+        self.check_header()?;
+        let commits = &self.block.sheader.msg.commits;
+        if commits.shards.len() != self.head.state.accounts.len() {
+            return Err(Error::BadState);
+        }
+        let mut state = self.head.state.clone();
+        self.overlay(Some(shards))?
+            .flush(&mut state)
+            .map_err(|_| Error::BadState)?;
+        for &i in shards {
+            if commits.shards.get(i) != Some(&state.accounts.shard(i).commit()) {
+                return Err(Error::BadState);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Checks the set is the one electing header's leader.
+fn check_epoch(validators: &ValidatorSet, header: &Header) -> Result<(), Error> {
+    if validators.epoch != header.data.epoch() {
+        return Err(Error::BadValidators);
+    }
+    Ok(())
+}
+
+// Checks a header makes for a valid next header after prev, leaving out signatures and leader.
+fn check_link(prev: &Header, header: &Header, clock: &state::Clock) -> Result<(), Error> {
+    if header.data.prev_hash != prev.hash() {
+        return Err(Error::BadPrev);
+    }
+    if header.data.proposal == 0 || header.data.round != prev.data.round + 1 + place(header.data.proposal).0 {
+        return Err(Error::BadRound);
+    }
+    if header.version != version_at(header.data.round) {
+        return Err(Error::BadVersion);
+    }
+    if header.data.timestamp != prev.data.timestamp + (header.data.proposal as u64) * clock.block_time {
+        return Err(Error::BadBlockTime);
+    }
+    let seed: [u8; 32] = Sha256::digest(header.data.beacon).into();
+    if header.data.seed != seed {
+        return Err(Error::BadSeed);
+    }
+    // Roots listed have to be the ones the state commit is over.
+    match header.checkpoint {
+        Some(ref roots) if is_checkpoint(header.data.round) && state::commit_roots(roots) == header.commits.state => Ok(()),
+        None if !is_checkpoint(header.data.round) => Ok(()),
+        _ => Err(Error::BadCheckpoint)
+    }
+}
+
+// Slots and validators frozen at an epoch boundary, which elect every leader in the epoch.
+// Also what a light client keeps in place of the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorSet {
+    pub epoch: u32,
+    pub roots: state::Roots, // every state root, hashing to the state commit of the epoch's last block
+    pub slots: merkle::Map<validator::SlotData>,
+    pub validators: merkle::Map<validator::Data>,
+    pub senators: merkle::Map<senator::Data>, // for their vote on performance weighting
+    pub clock: state::Clock
+}
+
+impl ValidatorSet {
+    pub fn new(epoch: u32, state: &state::State) -> Self {
+        Self { 
+            epoch,
+            roots: state.roots(), 
+            slots: state.slots.clone(), 
+            validators: state.validators.clone(),
+            senators: state.senators.clone(),
+            clock: state.clock
+        }
+    }
+
+    // Set for the block after one at `round` which left `state`. Snapshots afresh when that's a new epoch.
+    pub fn next(&self, round: u32, state: &state::State) -> Self {
+        if epoch_of(round + 1) == self.epoch {
+            self.clone()
+        } else {
+            Self::new(epoch_of(round + 1), state)
+        }
+    }
+
+    // Validators who registered an attesting key, in pk order. Bitmaps index into this.
+    pub fn committee(&self) -> Vec<&validator::Data> {
+        let mut committee: Vec<_> = self.validators.iter()
+            .filter(|val| val.bls.is_some())
+            .collect();
+        committee.sort_by_key(|val| val.pk.to_bytes());
+        committee
+    }
+
+    // Slots held by pk, which is its finality vote's weight.
+    pub fn weight(&self, pk: &account::PublicKey) -> u32 {
+        match self.validators.get(&Sha256::digest(pk.to_bytes())) {
+            Ok(Some(val)) => val.slots,
+            _ => 0
+        }
+    }
+
+    pub fn total_weight(&self) -> u32 {
+        self.validators.iter().map(|val| val.slots).sum()
+    }
+
+    // Whether leaders this epoch are drawn weighted by performance.
+    pub fn weighted(&self) -> bool {
+        validator::weighting(&self.senators)
+    }
+
+    pub fn verify(&self, state_commit: [u8; 32]) -> bool {
+        state::commit_roots(&self.roots) == state_commit
+            && self.roots[1] == self.slots.commit()
+            && self.roots[2] == self.validators.commit()
+            && self.roots[3] == self.senators.commit()
+    }
+
+    // Whether an attestation over block_hash by `set`'s committee has more than two thirds of
+    // our committee behind it. A signer only counts if it attests with the same key in both, at
+    // the slots it holds with us, so a set made up by a peer vouches for nothing.
+    pub fn vouches(&self, set: &ValidatorSet, attestation: &attest::Attestation, block_hash: &[u8; 32]) -> bool {
+        let committee = set.committee();
+        if attestation.verify(&committee, block_hash).is_err() {
+            return false;
+        }
+        let weight = attestation.signers()
+            .filter_map(|i| {
+                let signer = committee[i];
+                match self.validators.get(&Sha256::digest(signer.pk.to_bytes())) {
+                    Ok(Some(val)) if val.bls.is_some() && val.bls == signer.bls => Some(val.slots),
+                    _ => None
+                }
+            })
+            .sum();
+        attest::quorum(weight, &self.committee())
+    }
+}
+
+// Checks a header follows prev without the state or txnseq, for light clients and header first sync.
+// The validator set must be the header's epoch's, checked with `ValidatorSet::verify` against
+// the last header of the epoch before.
+#[derive(Debug, Clone)]
+pub struct HeaderVerifier<'a> {
+    pub prev: &'a Header,
+    pub validators: &'a ValidatorSet,
+    pub sheader: account::Signed<Header>
+}
+
+impl<'a> HeaderVerifier<'a> {
+    pub fn new(prev: &'a Header, validators: &'a ValidatorSet, sheader: account::Signed<Header>) -> Self {
+        Self { prev, validators, sheader }
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        let header = &self.sheader.msg;
+        check_epoch(self.validators, header)?;
+        check_link(self.prev, header, &self.validators.clock)?;
+        if !self.sheader.verify() {
+            return Err(Error::BadSig);
+        }
+        let sbeacon = account::Signed::<[u8; 32]> {
+            msg: self.prev.data.seed,
+            from: self.sheader.from,
+            sig: header.data.beacon
+        };
+        if !sbeacon.verify() {
+            return Err(Error::BadBeacon);
+        }
+        if header.attestation.verify(&self.validators.committee(), &self.prev.hash()).is_err() {
+            return Err(Error::BadAttestation);
+        }
+        let leader = leader_at(&self.prev.data.seed, self.validators, header.data.proposal)
+            .map_err(|_| Error::BadValidators)?;
+        if leader != &self.sheader.from {
+            return Err(Error::NotLeader);
+        }
+        Ok(())
+    }
+}
+
+// Checks a run of headers from a peer follows on from prev, each under its epoch's validator set.
+// A set handed over with the first header of an epoch has to add up to the state commit before it.
+pub fn verify_headers(
+    prev: &Header, 
+    validators: &ValidatorSet, 
+    headers: &[(account::Signed<Header>, Option<ValidatorSet>)]
+) -> Result<(), (usize, Error)> {
+    let (mut prev, mut validators) = (prev, validators);
+    for (i, (sheader, opt_set)) in headers.iter().enumerate() {
+        if let Some(set) = opt_set {
+            if set.epoch != sheader.msg.data.epoch() || !set.verify(prev.commits.state) {
+                return Err((i, Error::BadValidators));
+            }
+            validators = set;
+        }
+        HeaderVerifier::new(prev, validators, sheader.clone()).verify().map_err(|e| (i, e))?;
+        prev = &sheader.msg;
+    }
+    Ok(())
+}
+
+// A recent checkpoint block offered to a node that syncs state instead of replaying every
+// block. Comes with the set electing the next leader and the headers before it, oldest first
+// and ending with its parent, going back far enough to check that set and the median timestamps.
+// The header after it carries the committee's attestation for it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub block: Block,
+    pub epoch: ValidatorSet,
+    pub link: Vec<account::Signed<Header>>,
+    pub next: account::Signed<Header>
+}
+
+impl Checkpoint {
+    // Everything but the state hangs together, and more than two thirds of the committee in
+    // `trusted`, a set we already stand by, attested to the block.
+    pub fn verify(&self, trusted: &ValidatorSet) -> Result<(), Error> {
+        let clock = &trusted.clock;
+        let header = &self.block.sheader.msg;
+        let round = header.data.round;
+        match header.checkpoint {
+            Some(ref roots) if is_checkpoint(round) && state::commit_roots(roots) == header.commits.state => {},
+            _ => return Err(Error::BadCheckpoint)
+        }
+        if header.commits.txnseq != self.block.txnseq.commit() {
+            return Err(Error::BadTxnseq);
+        }
+        if header.commits.evidence != self.block.evidence.commit() {
+            return Err(Error::BadEvidenceSeq);
+        }
+        if self.epoch.clock != *clock {
+            return Err(Error::BadValidators);
+        }
+        let sheaders: Vec<&account::Signed<Header>> = self.link.iter().chain([&self.block.sheader]).collect();
+        let headers: Vec<&Header> = sheaders.iter().map(|sheader| &sheader.msg).collect();
+        if headers.len() < MEDIAN_BLOCKS && headers[0].data.round != 0 {
+            return Err(Error::BadPrev);
+        }
+        // The set is snapshotted off the last block before its epoch, or genesis for the first.
+        let epoch = epoch_of(round + 1);
+        match headers.iter().rev().find(|h| h.data.round == 0 || h.data.epoch() < epoch) {
+            Some(h) if self.epoch.epoch == epoch && self.epoch.verify(h.commits.state) => {},
+            Some(_) => return Err(Error::BadValidators),
+            None => return Err(Error::BadPrev)
+        }
+        // Every header is signed, and led by the set's pick once in its epoch. Those before
+        // are bound to the block by their hashes.
+        if !sheaders.iter().all(|sheader| sheader.verify()) {
+            return Err(Error::BadSig);
+        }
+        for pair in sheaders.windows(2) {
+            match pair[1].msg.data.epoch() == self.epoch.epoch {
+                true => HeaderVerifier::new(&pair[0].msg, &self.epoch, pair[1].clone()).verify()?,
+                false => check_link(&pair[0].msg, &pair[1].msg, clock)?
+            }
+        }
+        let next = &self.next.msg;
+        if next.data.prev_hash != header.hash() || next.data.epoch() != self.epoch.epoch {
+            return Err(Error::BadPrev);
+        }
+        if !trusted.vouches(&self.epoch, &next.attestation, &next.data.prev_hash) {
+            return Err(Error::BadAttestation);
+        }
+        Ok(())
+    }
+
+    // The snap for the block, given its state put together with `state::Import`.
+    pub fn into_snap(self, state: state::State) -> Result<Snap, LoadError> {
+        let block_hash = self.block.sheader.msg.hash();
+        let mut txn_index = merkle::Map::default();
+        for (pos, txn) in self.block.txnseq.iter().enumerate() {
+            assert!(txn_index.insert(&txn::hash(txn), pos as u32).is_ok());
+        }
+        let skip = (self.link.len() + 1).saturating_sub(MEDIAN_BLOCKS);
+        let timestamps = self.link.iter()
+            .chain([&self.block.sheader])
+            .skip(skip)
+            .map(|sheader| sheader.msg.data.timestamp)
+            .collect();
+        let snap = Snap { block: self.block, block_hash, state, updates: Vec::default(), epoch: self.epoch, finalized: false, txn_index, timestamps };
+        snap.check()?;
+        Ok(snap)
     }
 }
 
@@ -328,27 +1425,26 @@ pub mod tests {
     NotLeader
      */
 
-    fn setup() -> (account::Keypair, account::Keypair, Vec<account::Signed<txn::Txn>>) {
-        let alice = account::Keypair::default();
+    fn setup() -> (Snap, account::Keypair, account::Keypair, Vec<account::Signed<txn::Txn>>) {
+        let (alice, head) = genesis();
         let bob = account::Keypair::gen();
         let mut vec = Vec::default();
         for i in 0..128 {
             vec.push(
                 alice.send(
                     bob.kp.public,
-                    1,
-                    i + state::JENNY_SLOTS,
+                    state::DUST_BALANCE,
+                    i + state::GENESIS_SLOTS,
                     None
                 )
             );
         }
-        (alice, bob, vec)
+        (head, alice, bob, vec)
     }
 
     #[test]
     fn ok() {
-        let (alice, _, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, _, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
             assert_eq!(builder.add(txn), Ok(()));
@@ -360,8 +1456,7 @@ pub mod tests {
 
     #[test]
     fn badsig() {
-        let (alice, _, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, _, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
             assert_eq!(builder.add(txn), Ok(()));
@@ -374,8 +1469,7 @@ pub mod tests {
 
     #[test]
     fn badround() {
-        let (alice, _, txns) = setup();
-        let mut head = Snap::default();
+        let (mut head, alice, _, txns) = setup();
         head.block.sheader.msg.data.round += 1;
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
@@ -389,8 +1483,7 @@ pub mod tests {
 
     #[test]
     fn badblocktime() {
-        let (alice, _, txns) = setup();
-        let mut head = Snap::default();
+        let (mut head, alice, _, txns) = setup();
         head.block.sheader.msg.data.timestamp += 1_000;
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
@@ -404,8 +1497,7 @@ pub mod tests {
 
     #[test]
     fn badbeacon() {
-        let (alice, _, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, _, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         builder.metadata.beacon = alice.sign(b"other data");
         for txn in txns {
@@ -418,8 +1510,7 @@ pub mod tests {
 
     #[test]
     fn badseed() {
-        let (alice, _, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, _, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         builder.metadata.seed = [0u8; 32];
         for txn in txns {
@@ -432,8 +1523,7 @@ pub mod tests {
 
     #[test]
     fn badtxnseq() {
-        let (alice, _, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, _, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
             assert_eq!(builder.add(txn), Ok(()));
@@ -447,8 +1537,7 @@ pub mod tests {
 
     #[test]
     fn badtxn() {
-        let (alice, bob, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, bob, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
             assert_eq!(builder.add(txn), Ok(()));
@@ -456,19 +1545,91 @@ pub mod tests {
         let bad = alice.send(
             bob.kp.public, 
             state::VALIDATOR_STAKE * state::VALIDATOR_SLOTS, 
-            state::JENNY_SLOTS + 128,
+            state::GENESIS_SLOTS + 128,
             None
         );
-        assert_eq!(builder.txnseq.insert(&[0u8], bad.clone()), Ok(None));
+        assert!(builder.txnseq.push(bad.clone()).is_ok());
         let block = builder.finalize(&alice).block;
         let verifier = Verifier::new(&head, block);
-        assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::BadTxn(bad, txn::Error::InsuffBal)));
+        match verifier.finalize().map_err(|(_, e)| e) {
+            Err(Error::BadTxn(txn, txn::Error::InsuffBal { required, .. })) => {
+                assert_eq!(txn, bad);
+                assert_eq!(required, state::VALIDATOR_STAKE * state::VALIDATOR_SLOTS);
+            },
+            other => panic!("expected InsuffBal, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn resume() {
+        let (head, alice, _, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().take(5) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        let json = serde_json::to_string(&builder.save()).unwrap();
+        let draft: Draft = serde_json::from_str(&json).unwrap();
+        let resumed = Builder::resume(&alice, &head, draft.clone()).unwrap();
+        assert_eq!(resumed.current_state(), builder.current_state());
+        assert_eq!(resumed.finalize(&alice).block, builder.finalize(&alice).block);
+        // Only resumes on the head it was built on
+        let next = Builder::new(&alice, 1, &head).finalize(&alice);
+        assert_eq!(Builder::resume(&alice, &next, draft).map(|_| ()), Err(Error::BadPrev));
+    }
+
+    #[test]
+    fn report() {
+        let (head, alice, bob, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns {
+            assert_eq!(builder.add(txn), Ok(()));
+        }
+        let n = builder.txnseq.len();
+        let mut badsig = alice.send(bob.kp.public, 1, state::GENESIS_SLOTS + 128, None);
+        badsig.sig = alice.sign(b"other data");
+        let broke = alice.send(
+            bob.kp.public, 
+            state::VALIDATOR_STAKE * state::VALIDATOR_SLOTS, 
+            state::GENESIS_SLOTS + 128,
+            None
+        );
+        assert!(builder.txnseq.push(badsig.clone()).is_ok());
+        assert!(builder.txnseq.push(broke).is_ok());
+        let block = builder.finalize(&alice).block;
+        // Without a report we stop at the first
+        assert_eq!(
+            Verifier::new(&head, block.clone()).finalize().map_err(|(_, e)| e), 
+            Err(Error::BadTxn(badsig, txn::Error::BadSig))
+        );
+        match Verifier::new(&head, block).with_report().finalize().map_err(|(_, e)| e) {
+            Err(Error::BadTxns(failures)) => {
+                assert_eq!(failures.len(), 2);
+                assert_eq!(failures[0], (n, txn::Error::BadSig));
+                assert_eq!(failures[1].0, n + 1);
+                assert!(matches!(failures[1].1, txn::Error::InsuffBal { .. }));
+            },
+            other => panic!("expected BadTxns, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn badtxnsig() {
+        let (head, alice, bob, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns {
+            assert_eq!(builder.add(txn), Ok(()));
+        }
+        let mut bad = alice.send(bob.kp.public, 1, state::GENESIS_SLOTS + 128, None);
+        bad.sig = alice.sign(b"other data");
+        assert!(builder.txnseq.push(bad.clone()).is_ok());
+        let block = builder.finalize(&alice).block;
+        let verifier = Verifier::new(&head, block);
+        assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::BadTxn(bad, txn::Error::BadSig)));
     }
 
     #[test]
     fn badstate() {
-        let (alice, _, txns) = setup();
-        let head = Snap::default();
+        let (head, alice, _, txns) = setup();
         let mut builder = Builder::new(&alice, 1, &head);
         for txn in txns {
             assert_eq!(builder.add(txn), Ok(()));
@@ -480,10 +1641,436 @@ pub mod tests {
         assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::BadState));
     }
 
+    #[test]
+    fn replacebyfee() {
+        let (head, alice, bob, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().take(3) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        let mut bump = txns[1].msg.clone();
+        bump.fee = 5;
+        assert_eq!(builder.add(alice.sign_txn(bump.clone())), Ok(()));
+        assert_eq!(builder.txnseq.iter().count(), 3);
+        assert!(builder.txnseq.iter().any(|txn| txn.msg == bump));
+        // Matching the current fee doesn't outbid it.
+        let same = alice.sign_txn(bump.clone());
+        assert!(matches!(builder.add(same), Err((_, txn::Error::SmallNonce { .. }))));
+        let before = head.state.accounts.get(&Sha256::digest(alice.kp.public.to_bytes())).unwrap().unwrap().bal;
+        let snap = builder.finalize(&alice);
+        let after = snap.state.accounts.get(&Sha256::digest(alice.kp.public.to_bytes())).unwrap().unwrap().bal;
+        assert_eq!(before - after, 3 * state::DUST_BALANCE + 5);
+        assert_eq!(snap.state.accounts.get(&Sha256::digest(bob.kp.public.to_bytes())).unwrap().unwrap().bal, 3 * state::DUST_BALANCE);
+        let verifier = Verifier::new(&head, snap.block);
+        assert!(verifier.finalize().is_ok());
+    }
+
+    #[test]
+    fn lane() {
+        let (head, alice, bob, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        assert_eq!(builder.add(alice.send(bob.kp.public, state::VALIDATOR_STAKE, state::GENESIS_SLOTS, None)), Ok(()));
+        let head = builder.finalize(&alice);
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().skip(1).take(3) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        // Jumps the payments already in.
+        let stake = bob.stake_slot(state::GENESIS_SLOTS, 0);
+        assert_eq!(builder.add(stake.clone()), Ok(()));
+        assert_eq!(builder.lane, 1);
+        assert_eq!(builder.txnseq.len(), 4);
+        assert_eq!(builder.txnseq.get(0), Ok(Some(&stake)));
+        let mut block = builder.finalize(&alice).block;
+        assert!(Verifier::new(&head, block.clone()).finalize().is_ok());
+        // Behind a payment it's out of its lane.
+        block.txnseq = txn::Seq::default();
+        assert!(block.txnseq.push(txns[1].clone()).is_ok());
+        assert!(block.txnseq.push(stake).is_ok());
+        block.sheader.msg.commits.txnseq = block.txnseq.commit();
+        block.sheader.sig = alice.sign(&block.sheader.msg);
+        assert_eq!(Verifier::new(&head, block).finalize().map_err(|(_, e)| e), Err(Error::BadLane));
+    }
+
+    #[test]
+    fn displaced() {
+        let (mut head, alice, bob, txns) = setup();
+        let senator = account::Keypair::gen();
+        let carol = account::Keypair::gen();
+        let id = |kp: &account::Keypair| -> account::Id { Sha256::digest(kp.kp.public.to_bytes()).into() };
+        let data = crate::senator::Data { votes_against: 0, owner: id(&senator), weighting: false };
+        assert!(head.state.senators.insert(&id(&senator), data).is_ok());
+        for kp in [&senator, &bob, &carol] {
+            assert!(head.state.accounts.insert(&id(kp), account::Data { bal: 100, ..Default::default() }).is_ok());
+        }
+        let mut builder = Builder::new(&alice, 1, &head);
+        let spend = bob.send(alice.kp.public, 1, 0, None);
+        assert_eq!(builder.add(txns[0].clone()), Ok(()));
+        assert_eq!(builder.add(spend.clone()), Ok(()));
+        // Nothing behind the lane touches carol, so this goes straight in.
+        let freeze_carol = senator.freeze(id(&carol), 0);
+        assert_eq!(builder.add(freeze_carol.clone()), Ok(()));
+        assert_eq!(builder.txnseq.get(0), Ok(Some(&freeze_carol)));
+        assert_eq!(builder.txn_index.get(&txn::hash(&spend)), Ok(Some(&2)));
+        // Bob's spend can't run once he's frozen ahead of it.
+        let freeze_bob = senator.freeze(id(&bob), 1);
+        assert_eq!(builder.add(freeze_bob.clone()), Ok(()));
+        assert_eq!(builder.displaced, Vec::from([spend.clone()]));
+        assert_eq!((builder.lane, builder.txnseq.len()), (2, 3));
+        assert_eq!(builder.txnseq.get(1), Ok(Some(&freeze_bob)));
+        assert_eq!(builder.txn_index.get(&txn::hash(&spend)), Ok(None));
+        assert_eq!(builder.txn_index.get(&txn::hash(&txns[0])), Ok(Some(&2)));
+        let block = builder.finalize(&alice).block;
+        assert!(Verifier::new(&head, block).finalize().is_ok());
+    }
+
+    #[test]
+    fn evidence() {
+        let (head, alice, bob, txns) = setup();
+        let first = Builder::new(&alice, 1, &head).finalize(&alice).block.sheader;
+        let mut builder = Builder::new(&alice, 1, &head);
+        assert_eq!(builder.add(txns[0].clone()), Ok(()));
+        let second = builder.finalize(&alice).block.sheader;
+        let equivocation = evidence::Evidence::Equivocation(first.clone(), second.clone());
+        let mut builder = Builder::new(&alice, 1, &head);
+        assert_eq!(builder.add_evidence(equivocation.clone()), Ok(()));
+        let flipped = evidence::Evidence::Equivocation(second, first.clone());
+        assert_eq!(builder.add_evidence(flipped.clone()), Err((flipped, evidence::Error::Duplicate)));
+        let same = evidence::Evidence::Equivocation(first.clone(), first.clone());
+        assert_eq!(builder.add_evidence(same.clone()), Err((same, evidence::Error::NotEquivocation)));
+        let payment = evidence::Evidence::RollupHeader(txns[1].clone());
+        assert_eq!(builder.add_evidence(payment.clone()), Err((payment, evidence::Error::NotRollupHeader)));
+        let block = builder.finalize(&alice).block;
+        assert_eq!(block.txnseq.iter().count(), 0);
+        assert!(Verifier::new(&head, block.clone()).finalize().is_ok());
+        // Forged evidence is caught on the other side too.
+        let mut forged = first;
+        forged.sig = bob.sign(&forged.msg);
+        let mut bad = block;
+        let forged = evidence::Evidence::Equivocation(forged.clone(), forged);
+        assert_eq!(bad.evidence.insert(&[1u8], forged), Ok(None));
+        bad.sheader.msg.commits.evidence = bad.evidence.commit();
+        bad.sheader.sig = alice.sign(&bad.sheader.msg);
+        assert!(matches!(Verifier::new(&head, bad).finalize(), Err((_, Error::BadEvidence(..)))));
+    }
+
+    #[test]
+    fn clock() {
+        let (alice, head) = genesis();
+        let config = genesis::Config::new(head.block.sheader.msg.data.timestamp)
+            .with_clock(state::Clock { block_time: 500, ..Default::default() });
+        let fast = genesis::build(&alice, &config);
+        let block = Builder::new(&alice, 2, &fast).finalize(&alice).block;
+        assert_eq!(block.sheader.msg.data.timestamp, head.block.sheader.msg.data.timestamp + 1_000);
+        assert!(Verifier::new(&fast, block.clone()).finalize().is_ok());
+        // The clock's in the state commit, so another clock is another genesis.
+        assert_ne!(fast.block.sheader.msg.commits.state, head.block.sheader.msg.commits.state);
+        assert_eq!(Verifier::new(&head, block).finalize().map_err(|(_, e)| e), Err(Error::BadPrev));
+    }
+
+    #[test]
+    fn checkpoint() {
+        let (alice, mut snap) = genesis();
+        assert_eq!(snap.block.sheader.msg.checkpoint, Some(snap.state.roots()));
+        while !is_checkpoint(snap.block.sheader.msg.data.round + 1) {
+            snap = Builder::new(&alice, 1, &snap).finalize(&alice);
+            assert_eq!(snap.block.sheader.msg.checkpoint, None);
+        }
+        let prev = snap.clone();
+        let snap = Builder::new(&alice, 1, &prev).finalize(&alice);
+        let roots = snap.block.sheader.msg.checkpoint.expect("checkpoint round");
+        assert_eq!(roots[2], snap.state.validators.commit());
+        let verifier = HeaderVerifier::new(&prev.block.sheader.msg, &prev.epoch, snap.block.sheader.clone());
+        assert_eq!(verifier.verify(), Ok(()));
+        // Roots that don't add up to the state commit.
+        let mut sheader = snap.block.sheader.clone();
+        sheader.msg.checkpoint = Some([[0u8; 32]; 7]);
+        sheader.sig = alice.sign(&sheader.msg);
+        let verifier = HeaderVerifier::new(&prev.block.sheader.msg, &prev.epoch, sheader.clone());
+        assert_eq!(verifier.verify(), Err(Error::BadCheckpoint));
+        let block = Block { sheader, ..snap.block };
+        assert_eq!(Verifier::new(&prev, block).finalize().map_err(|(_, e)| e), Err(Error::BadCheckpoint));
+    }
+
+    #[test]
+    fn txnindex() {
+        let (head, alice, _, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().take(3) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        let built = builder.finalize(&alice);
+        assert_eq!(built.position(&txn::hash(&txns[2])), Some(2));
+        assert_eq!(built.position(&txn::hash(&txns[3])), None);
+        let verified = Verifier::new(&head, built.block.clone()).finalize().unwrap();
+        assert_eq!(verified.txn_index, built.txn_index);
+    }
+
+    #[test]
+    fn mediantime() {
+        let (mut head, alice, _, _) = setup();
+        assert_eq!(head.timestamps, Vec::from([head.block.sheader.msg.data.timestamp]));
+        let mut snap = head.clone();
+        for _ in 0..MEDIAN_BLOCKS + 2 {
+            snap = Builder::new(&alice, 1, &snap).finalize(&alice);
+        }
+        assert_eq!(snap.timestamps.len(), MEDIAN_BLOCKS);
+        assert_eq!(snap.timestamps.last(), Some(&snap.block.sheader.msg.data.timestamp));
+        // Recent blocks ran ahead of this one's clock.
+        let time = head.block.sheader.msg.data.timestamp;
+        head.timestamps = Vec::from([time + (1 << 20); 3]);
+        let block = Builder::new(&alice, 1, &head).finalize(&alice).block;
+        assert_eq!(Verifier::new(&head, block).finalize().map_err(|(_, e)| e), Err(Error::BadBlockTime));
+    }
+
+    #[test]
+    fn skip() {
+        let (head, alice, _, _) = setup();
+        assert_eq!(place(MAX_PROPOSALS), (0, MAX_PROPOSALS));
+        assert_eq!(place(MAX_PROPOSALS + 1), (1, 1));
+        // Nobody proposed for a whole round, so this one lands in the next.
+        let proposal = MAX_PROPOSALS + 2;
+        let snap = Builder::new(&alice, proposal, &head).finalize(&alice);
+        let data = &snap.block.sheader.msg.data;
+        assert_eq!(data.round, 2);
+        assert_eq!(data.prev_round(), 0);
+        assert_eq!(data.timestamp, head.block.sheader.msg.data.timestamp + proposal as u64 * head.state.clock.block_time);
+        assert_eq!(Verifier::new(&head, snap.block.clone()).finalize().map(|s| s.block_hash), Ok(snap.block_hash));
+        // Claiming the skipped round instead doesn't fly.
+        let mut block = snap.block;
+        block.sheader.msg.data.round = 1;
+        block.sheader.sig = alice.sign(&block.sheader.msg);
+        assert_eq!(Verifier::new(&head, block).finalize().map_err(|(_, e)| e), Err(Error::BadRound));
+        // Skipping past the epoch's end is still led by the set head hands on, for headers too.
+        let proposal = EPOCH_ROUNDS * MAX_PROPOSALS + 1;
+        let snap = Builder::new(&alice, proposal, &head).finalize(&alice);
+        let data = &snap.block.sheader.msg.data;
+        assert_eq!((epoch_of(data.round), data.epoch()), (1, 0));
+        assert!(Verifier::new(&head, snap.block.clone()).finalize().is_ok());
+        assert_eq!(HeaderVerifier::new(&head.block.sheader.msg, &head.epoch, snap.block.sheader.clone()).verify(), Ok(()));
+        assert_eq!(snap.epoch.epoch, 1);
+    }
+
+    #[test]
+    fn optimistic() {
+        let (head, alice, _, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns {
+            assert_eq!(builder.add(txn), Ok(()));
+        }
+        let snap = builder.finalize(&alice);
+        let trusted = Verifier::new(&head, snap.block.clone()).with_trusted_state(snap.state.clone()).finalize().unwrap();
+        assert_eq!((trusted.block_hash, &trusted.state, &trusted.txn_index), (snap.block_hash, &snap.state, &snap.txn_index));
+        let lying = Verifier::new(&head, snap.block.clone()).with_trusted_state(head.state.clone());
+        assert_eq!(lying.finalize().map_err(|(_, e)| e), Err(Error::BadState));
+        // Matching commits but the txns never made it into the state.
+        let mut block = snap.block;
+        block.sheader.msg.commits = Commits::new(&head.state, &block.txnseq, &block.evidence);
+        block.sheader.sig = alice.sign(&block.sheader.msg);
+        let lying = Verifier::new(&head, block).with_trusted_state(head.state.clone());
+        assert_eq!(lying.finalize().map_err(|(_, e)| e), Err(Error::BadState));
+    }
+
+    #[test]
+    fn store() {
+        let (head, alice, _, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().take(3) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        let snap = builder.finalize(&alice);
+        let mut bytes = snap.store();
+        assert_eq!(Snap::load(&bytes), Ok(snap.clone()));
+        assert_eq!(Snap::load(&bytes[..64]), Err(LoadError::Truncated));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(Snap::load(&bytes), Err(LoadError::BadChecksum));
+        // Intact bytes of a snap whose state no longer matches its header.
+        let mut bad = snap.clone();
+        bad.state = head.state.clone();
+        assert_eq!(Snap::load(&bad.store()), Err(LoadError::BadState));
+        let mut bad = snap;
+        bad.block.sheader.msg.data.timestamp += 1;
+        assert_eq!(Snap::load(&bad.store()), Err(LoadError::BadHash));
+    }
+
+    #[test]
+    fn delta() {
+        let (head, alice, _, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().take(3) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        let snap = builder.finalize(&alice);
+        let delta = snap.clone().thin();
+        assert_eq!(delta.fill(head.state.clone()), Ok(snap.clone()));
+        // Missing an update, the state doesn't match the header.
+        let mut bad = delta;
+        bad.updates.pop();
+        assert_eq!(bad.fill(head.state), Err(LoadError::BadState));
+    }
+
+    #[test]
+    fn txnproof() {
+        let (head, alice, _, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns.iter().take(3) {
+            assert_eq!(builder.add(txn.clone()), Ok(()));
+        }
+        let snap = builder.finalize(&alice);
+        let commit = snap.block.sheader.msg.commits.txnseq;
+        let (pos, proof) = snap.prove_txn(&txn::hash(&txns[1])).unwrap();
+        assert_eq!(pos, 1);
+        assert!(proof.verify(commit, 1, &txns[1]));
+        assert!(!proof.verify(commit, 0, &txns[1]));
+        assert!(!proof.verify(commit, 1, &txns[0]));
+        assert!(snap.prove_txn(&txn::hash(&txns[3])).is_none());
+    }
+
+    #[test]
+    fn bloom() {
+        let (head, alice, bob, txns) = setup();
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns {
+            assert_eq!(builder.add(txn), Ok(()));
+        }
+        let mut block = builder.finalize(&alice).block;
+        let bloom = &block.sheader.msg.bloom;
+        assert!(bloom.may_contain(&Sha256::digest(alice.kp.public.to_bytes()).into()));
+        assert!(bloom.may_contain(&Sha256::digest(bob.kp.public.to_bytes()).into()));
+        let nobody = account::Keypair::gen();
+        assert!(!bloom.may_contain(&Sha256::digest(nobody.kp.public.to_bytes()).into()));
+        block.sheader.msg.bloom = Bloom::default();
+        block.sheader.sig = alice.sign(&block.sheader.msg);
+        let verifier = Verifier::new(&head, block);
+        assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::BadBloom));
+    }
+
+    #[test]
+    fn encoding() {
+        let (alice, head) = genesis();
+        let sheader = Builder::new(&alice, 1, &head).finalize(&alice).block.sheader;
+        let bytes = sheader.msg.encode();
+        // One encoding for both the hash and the signature.
+        assert_eq!(sheader.msg.hash(), <[u8; 32]>::from(Sha256::digest(&bytes)));
+        assert!(alice.kp.verify(&bytes, &sheader.sig).is_ok());
+        let mut other = sheader.msg.clone();
+        other.commits.shards.push([0u8; 32]);
+        assert_ne!(other.encode(), bytes);
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(version_at(0), 1);
+        assert_eq!(version_at(u32::MAX), ACTIVATIONS.iter().map(|(v, _)| *v).max().unwrap());
+        let (head, alice, _, _) = setup();
+        let mut block = Builder::new(&alice, 1, &head).finalize(&alice).block;
+        assert_eq!(block.sheader.msg.version, version_at(1));
+        block.sheader.msg.version += 1;
+        block.sheader.sig = alice.sign(&block.sheader.msg);
+        let verifier = Verifier::new(&head, block);
+        assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::BadVersion));
+    }
+
+    #[test]
+    fn epoch() {
+        let (alice, mut snap) = genesis();
+        let genesis_slots = snap.state.slots.commit();
+        let mut builder = Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.stake_slot(state::GENESIS_SLOTS, state::GENESIS_SLOTS)).is_ok());
+        snap = builder.finalize(&alice);
+        // Staked, but still electing from the genesis set.
+        assert_ne!(snap.state.slots.commit(), genesis_slots);
+        while epoch_of(snap.block.sheader.msg.data.round + 1) == 0 {
+            assert_eq!(snap.epoch.slots.commit(), genesis_slots);
+            snap = Builder::new(&alice, 1, &snap).finalize(&alice);
+        }
+        assert_eq!(snap.epoch.epoch, 1);
+        assert_eq!(snap.epoch.slots.commit(), snap.state.slots.commit());
+        assert!(snap.epoch.verify(snap.block.sheader.msg.commits.state));
+    }
+
+    #[test]
+    fn attestation() {
+        let (alice, head) = genesis();
+        let committee = head.epoch.committee();
+        assert_eq!(committee.len(), 1);
+        let votes = BTreeMap::from([(0, attest::vote(&alice, &head.block_hash))]);
+        let mut builder = Builder::new(&alice, 1, &head);
+        builder.attestation = attest::Attestation::aggregate(&votes).unwrap();
+        let snap = builder.finalize(&alice);
+        let weight = snap.block.sheader.msg.attestation.verify(&committee, &head.block_hash);
+        assert!(attest::quorum(weight.unwrap(), &committee));
+        let verifier = Verifier::new(&head, snap.block.clone());
+        assert!(verifier.finalize().is_ok());
+        // Votes for some other block don't count.
+        let votes = BTreeMap::from([(0, attest::vote(&alice, &[0u8; 32]))]);
+        let mut builder = Builder::new(&alice, 1, &head);
+        builder.attestation = attest::Attestation::aggregate(&votes).unwrap();
+        let verifier = Verifier::new(&head, builder.finalize(&alice).block);
+        assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::BadAttestation));
+    }
+
+    #[test]
+    fn missed() {
+        let (alice, head) = genesis();
+        let alice_id: validator::Id = Sha256::digest(alice.kp.public.to_bytes()).into();
+        // Genesis slots are all alice's, so she let proposals 1 and 2 lapse herself.
+        let snap = Builder::new(&alice, 3, &head).finalize(&alice);
+        assert_eq!(snap.state.validators.get(&alice_id).unwrap().unwrap().missed, 1);
+        let verifier = Verifier::new(&head, snap.block.clone());
+        assert_eq!(verifier.finalize().map(|snap| snap.state.commit()), Ok(snap.state.commit()));
+        // Producing on time decays the record.
+        let next = Builder::new(&alice, 1, &snap).finalize(&alice);
+        assert_eq!(next.state.validators.get(&alice_id).unwrap().unwrap().missed, 0);
+    }
+
+    #[test]
+    fn weighting() {
+        let (alice, mut snap) = genesis();
+        let bob = account::Keypair::gen();
+        let id = |kp: &account::Keypair| -> account::Id { Sha256::digest(kp.kp.public.to_bytes()).into() };
+        let senators: Vec<account::Keypair> = (0..3).map(|_| account::Keypair::gen()).collect();
+        for senator in senators.iter() {
+            let data = crate::senator::Data { votes_against: 0, owner: id(senator), weighting: false };
+            assert!(snap.state.senators.insert(&id(senator), data).is_ok());
+            assert!(snap.state.accounts.insert(&id(senator), account::Data { bal: 100, ..Default::default() }).is_ok());
+        }
+        // Bob holds the other half of the slots but has been missing his proposals.
+        for i in state::GENESIS_SLOTS..state::VALIDATOR_SLOTS {
+            assert!(snap.state.slots.insert(&i.to_be_bytes(), validator::SlotData { round: 0, owner: id(&bob), nonce: 0 }).is_ok());
+        }
+        let val = validator::Data {
+            opposed: merkle::Map::default(),
+            slots: state::VALIDATOR_SLOTS - state::GENESIS_SLOTS,
+            pk: bob.kp.public,
+            missed: validator::MAX_MISSED,
+            bls: None
+        };
+        assert!(snap.state.validators.insert(&id(&bob), val).is_ok());
+        let before = ValidatorSet::new(1, &snap.state);
+        let mut builder = Builder::new(&alice, 1, &snap);
+        assert_eq!(builder.add(alice.weighting(true, state::GENESIS_SLOTS)).map_err(|(_, e)| e), Err(txn::Error::NotSenator(id(&alice))));
+        // One of three isn't a majority.
+        assert!(builder.add(senators[0].weighting(true, 0)).is_ok());
+        assert!(!ValidatorSet::new(1, &builder.current_state()).weighted());
+        assert!(builder.add(senators[1].weighting(true, 0)).is_ok());
+        let snap = builder.finalize(&alice);
+        // Switched on from the next epoch's snapshot.
+        let after = snap.epoch.next(EPOCH_ROUNDS - 1, &snap.state);
+        assert!(!before.weighted() && after.weighted());
+        assert!(after.verify(snap.block.sheader.msg.commits.state));
+        let led = |set: &ValidatorSet| (0u32..200)
+            .filter(|i| leader_at(&Sha256::digest(i.to_be_bytes()).into(), set, 1).unwrap() == &bob.kp.public)
+            .count();
+        // Half the slots lead about half the time, then about a tenth once bob's passed over.
+        assert!((70..130).contains(&led(&before)));
+        assert!(led(&after) < 50);
+    }
+
     #[test]
     fn notleader() {
-        let (_, bob, txns) = setup();
-        let head = Snap::default();
+        let (head, _, bob, txns) = setup();
         let mut builder = Builder::new(&bob, 1, &head);
         for txn in txns {
             assert_eq!(builder.add(txn), Ok(()));
@@ -492,4 +2079,27 @@ pub mod tests {
         let verifier = Verifier::new(&head, block);
         assert_eq!(verifier.finalize().map_err(|(_, e)| e), Err(Error::NotLeader));
     }
+
+    #[test]
+    fn header() {
+        let (head, alice, bob, txns) = setup();
+        let prev = &head.block.sheader.msg;
+        let validators = head.epoch.clone();
+        assert!(validators.verify(prev.commits.state));
+        let mut builder = Builder::new(&alice, 1, &head);
+        for txn in txns {
+            assert_eq!(builder.add(txn), Ok(()));
+        }
+        let snap = builder.finalize(&alice);
+        let sheader = snap.block.sheader.clone();
+        assert_eq!(HeaderVerifier::new(prev, &validators, sheader.clone()).verify(), Ok(()));
+        // Chains on with the next validator set.
+        let next = Builder::new(&alice, 1, &snap).finalize(&alice);
+        assert_eq!(HeaderVerifier::new(&sheader.msg, &snap.epoch, next.block.sheader.clone()).verify(), Ok(()));
+        assert_eq!(HeaderVerifier::new(prev, &validators, next.block.sheader).verify(), Err(Error::BadPrev));
+        let stale = ValidatorSet { epoch: 1, ..validators.clone() };
+        assert_eq!(HeaderVerifier::new(prev, &stale, sheader.clone()).verify(), Err(Error::BadValidators));
+        let bobs = Builder::new(&bob, 1, &head).finalize(&bob).block.sheader;
+        assert_eq!(HeaderVerifier::new(prev, &validators, bobs).verify(), Err(Error::NotLeader));
+    }
 }
\ No newline at end of file
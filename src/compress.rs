@@ -0,0 +1,76 @@
+use std::io::{Read, Write};
+use serde::{Serialize, Deserialize};
+
+// Content-encodings for http bodies. Chains of json blocks shrink about tenfold, and they're
+// most of what goes between neighbors. The server side is tower-http's, this is for our own
+// asks: we say what we take, and once a neighbor has answered compressed we know it takes
+// compressed asks too, so send it them.
+
+pub const ACCEPT: &str = "zstd, gzip"; // best first
+pub const MIN_BYTES: usize = 1 << 10; // smaller isn't worth compressing
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    Zstd,
+    Gzip
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip"
+        }
+    }
+
+    // None for identity, or for one we don't know.
+    pub fn of(header: Option<&str>) -> Option<Self> {
+        match header?.trim() {
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None
+        }
+    }
+
+    pub fn encode(self, body: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Zstd => zstd::encode_all(body, 0).expect("writes to a vec"),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::default(), flate2::Compression::default());
+                encoder.write_all(body).expect("writes to a vec");
+                encoder.finish().expect("writes to a vec")
+            }
+        }
+    }
+
+    // Stops at `limit` bytes out, so a small body can't unpack into something huge.
+    pub fn decode(self, body: &[u8], limit: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::default();
+        let read = match self {
+            Encoding::Zstd => zstd::Decoder::new(body).ok()?.take(limit as u64 + 1).read_to_end(&mut out),
+            Encoding::Gzip => flate2::read::GzDecoder::new(body).take(limit as u64 + 1).read_to_end(&mut out)
+        };
+        (read.is_ok() && out.len() <= limit).then_some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let body = "{\"Chain\":[]}".repeat(1_000).into_bytes();
+        for encoding in [Encoding::Zstd, Encoding::Gzip] {
+            assert_eq!(Encoding::of(Some(encoding.name())), Some(encoding));
+            let encoded = encoding.encode(&body);
+            assert!(encoded.len() * 10 < body.len());
+            assert_eq!(encoding.decode(&encoded, body.len()), Some(body.clone()));
+            // Unpacks past the limit, or isn't what it says it is
+            assert_eq!(encoding.decode(&encoded, body.len() - 1), None);
+            assert_eq!(encoding.decode(&body, body.len()), None);
+        }
+        assert_eq!(Encoding::of(Some("br")), None);
+        assert_eq!(Encoding::of(None), None);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{env, fs, path::{Path, PathBuf}};
+use serde::{Serialize, Deserialize};
+
+use crate::{app, faucet, node};
+
+// What a node runs with, from a toml file, so none of it has to be written into code.
+// Anything left out takes its default. Environment variables named TAMMANY_ and a field in
+// capitals win over the file, their value read as toml and as a plain string failing that,
+// e.g. TAMMANY_LISTEN=0.0.0.0:3000 or TAMMANY_BOOTSTRAP='["10.0.0.1:3000"]'.
+
+const ENV_PREFIX: &str = "TAMMANY_";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen: String, // http, and the ip framed asks are taken on
+    pub data_dir: PathBuf,
+    pub key: PathBuf, // json, as tammany keygen writes it
+    pub bootstrap: Vec<String>,
+    pub advertise: Option<String>, // where others can reach us, if not where we listen
+    pub target_peers: usize,
+    pub framed: Option<u16>,
+    pub admin_token: Option<String>,
+    pub builder_token: Option<String>,
+    pub root_certs: Vec<PathBuf>, // pem, trusted on top of the system's
+    pub tls: Option<app::Tls>,
+    pub cors: Option<app::Cors>, // for browsers calling the public api
+    pub faucet: faucet::Policy,
+    #[serde(flatten)]
+    pub node: node::Config // role and chain parameters
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: String::from("127.0.0.1:3000"),
+            data_dir: PathBuf::from("data"),
+            key: PathBuf::from("key.json"),
+            bootstrap: Vec::default(),
+            advertise: None,
+            target_peers: app::TARGET_PEERS,
+            framed: None,
+            admin_token: None,
+            builder_token: None,
+            root_certs: Vec::default(),
+            tls: None,
+            cors: None,
+            faucet: faucet::Policy::default(),
+            node: node::Config::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Io,
+    BadFormat(String), // what toml made of it
+    BadEnv(String), // the variable, if it's no field of ours
+    BadKey,
+    BadCert,
+    BadCors(app::CorsError),
+    Open(node::OpenError)
+}
+
+impl Config {
+    // The file at `path` under this process's environment.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|_| Error::Io)?;
+        Self::parse(&text, env::vars())
+    }
+
+    pub fn parse(text: &str, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, Error> {
+        let mut table: toml::Table = toml::from_str(text).map_err(|e| Error::BadFormat(e.to_string()))?;
+        let fields = toml::Table::try_from(Self::default()).expect("config serializes");
+        for (name, value) in vars {
+            let Some(field) = name.strip_prefix(ENV_PREFIX) else { continue };
+            let field = field.to_lowercase();
+            // Optional fields are left out of the defaults
+            let optional = ["advertise", "framed", "admin_token", "builder_token", "tls", "cors"];
+            if !fields.contains_key(&field) && !optional.contains(&field.as_str()) {
+                return Err(Error::BadEnv(name));
+            }
+            let value = toml::from_str::<toml::Table>(&format!("value = {}", value))
+                .ok()
+                .and_then(|mut parsed| parsed.remove("value"))
+                .unwrap_or(toml::Value::String(value));
+            table.insert(field, value);
+        }
+        table.try_into().map_err(|e: toml::de::Error| Error::BadFormat(e.to_string()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let text = toml::to_string(self).map_err(|e| Error::BadFormat(e.to_string()))?;
+        fs::write(path, text).map_err(|_| Error::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let text = r#"
+            listen = "0.0.0.0:4000"
+            data_dir = "/var/lib/tammany"
+            bootstrap = ["10.0.0.1:3000"]
+            role = "Full"
+            fork_window = 32
+
+            [txn_rate]
+            rate = 10
+            burst = 20
+        "#;
+        let config = Config::parse(text, Vec::default()).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:4000");
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/tammany"));
+        assert_eq!(config.node.role, node::Role::Full);
+        assert_eq!(config.node.fork_window, 32);
+        assert_eq!(config.node.txn_rate.burst, 20);
+        // Left out, so the default
+        assert_eq!(config.key, PathBuf::from("key.json"));
+        assert_eq!(config.node.finality_depth, node::Config::default().finality_depth);
+        // The environment wins, and isn't ours unless it's prefixed
+        let vars = [
+            ("TAMMANY_LISTEN", "127.0.0.1:5000"),
+            ("TAMMANY_BOOTSTRAP", r#"["a:1", "b:2"]"#),
+            ("TAMMANY_FRAMED", "5001"),
+            ("TAMMANY_ROLE", "Observer"),
+            ("HOME", "/root")
+        ].map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = Config::parse(text, vars).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:5000");
+        assert_eq!(config.bootstrap, vec!["a:1", "b:2"]);
+        assert_eq!(config.framed, Some(5001));
+        assert_eq!(config.node.role, node::Role::Observer);
+        let typo = [(String::from("TAMMANY_LISTN"), String::from("x"))];
+        assert_eq!(Config::parse(text, typo), Err(Error::BadEnv(String::from("TAMMANY_LISTN"))));
+        assert!(matches!(Config::parse("fork_window = \"lots\"", Vec::default()), Err(Error::BadFormat(_))));
+        // What we write reads back the same
+        let path = env::temp_dir().join("tam-config-roundtrip.toml");
+        assert_eq!(config.write(&path), Ok(()));
+        assert_eq!(Config::load(&path).map(|loaded| loaded.listen), Ok(String::from("127.0.0.1:5000")));
+        let _ = fs::remove_file(path);
+    }
+}
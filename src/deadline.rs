@@ -0,0 +1,42 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Cooperative cancellation for long running queries.
+// Work checks `expired` between steps and bails out early, clones share the same cancel flag.
+
+#[derive(Debug, Clone, Default)]
+pub struct Deadline {
+    at: Option<Instant>, // None never times out
+    cancelled: Arc<AtomicBool>
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self { at: Some(Instant::now() + timeout), cancelled: Arc::default() }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn expired(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        assert!(!Deadline::default().expired());
+        assert!(Deadline::after(Duration::ZERO).expired());
+        let deadline = Deadline::after(Duration::from_secs(60));
+        let worker = deadline.clone();
+        assert!(!worker.expired());
+        deadline.cancel();
+        assert!(worker.expired());
+    }
+}
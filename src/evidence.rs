@@ -0,0 +1,64 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{account, block, merkle, txn};
+
+// Misbehaviour a leader can put on chain, kept apart from user txns.
+// Only checked to be well formed here. Slashing and senator removal act on it.
+
+pub const MAX_BLOCK_EVIDENCE: u32 = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Evidence {
+    // Two different headers signed by the same validator for the same round and proposal.
+    Equivocation(account::Signed<block::Header>, account::Signed<block::Header>),
+    // A rollup header a sequencer signed, for the senators to judge.
+    RollupHeader(account::Signed<txn::Txn>)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Error {
+    BadSig,
+    NotEquivocation,
+    NotRollupHeader,
+    Duplicate,
+    TooMuch
+}
+
+pub type Seq = merkle::Map<Evidence>;
+
+impl Evidence {
+    // Both name the same misbehaviour, whichever way round.
+    pub fn same_offence(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Evidence::Equivocation(a, _), Evidence::Equivocation(b, _)) => 
+                a.from == b.from && a.msg.data.round == b.msg.data.round && a.msg.data.proposal == b.msg.data.proposal,
+            _ => self == other
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        match self {
+            Evidence::Equivocation(first, second) => {
+                let (a, b) = (&first.msg.data, &second.msg.data);
+                if first.from != second.from
+                    || a.round != b.round
+                    || a.proposal != b.proposal
+                    || first.msg.hash() == second.msg.hash() {
+                    return Err(Error::NotEquivocation);
+                }
+                if !first.verify() || !second.verify() {
+                    return Err(Error::BadSig);
+                }
+            },
+            Evidence::RollupHeader(stxn) => {
+                if !matches!(stxn.msg.payload, txn::Payload::Header(..)) {
+                    return Err(Error::NotRollupHeader);
+                }
+                if !stxn.verify() {
+                    return Err(Error::BadSig);
+                }
+            }
+        }
+        Ok(())
+    }
+}
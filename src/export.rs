@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::block;
+
+// Blocks written out to a file and read back, for archiving a testnet or reproducing a bug
+// report offline. MAGIC, then a frame per block, oldest first: body length, checksum of the
+// body, then the block as json.
+
+pub const MAGIC: &[u8; 8] = b"tamchain";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Error {
+    Io,
+    BadMagic,
+    Truncated,
+    BadChecksum,
+    BadFormat
+}
+
+pub fn encode(blocks: &[block::Block]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    for block in blocks {
+        let body = serde_json::to_vec(block).expect("blocks serialize");
+        let checksum: [u8; 32] = Sha256::digest(&body).into();
+        bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&checksum);
+        bytes.extend_from_slice(&body);
+    }
+    bytes
+}
+
+pub fn decode(mut bytes: &[u8]) -> Result<Vec<block::Block>, Error> {
+    bytes = bytes.strip_prefix(MAGIC.as_slice()).ok_or(Error::BadMagic)?;
+    let mut blocks = Vec::default();
+    while !bytes.is_empty() {
+        if bytes.len() < 36 {
+            return Err(Error::Truncated);
+        }
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let (checksum, rest) = bytes[4..].split_at(32);
+        if rest.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (body, rest) = rest.split_at(len);
+        if Sha256::digest(body).as_slice() != checksum {
+            return Err(Error::BadChecksum);
+        }
+        blocks.push(serde_json::from_slice(body).map_err(|_| Error::BadFormat)?);
+        bytes = rest;
+    }
+    Ok(blocks)
+}
+
+pub fn write(path: &Path, blocks: &[block::Block]) -> Result<(), Error> {
+    fs::write(path, encode(blocks)).map_err(|_| Error::Io)
+}
+
+// Only checks the file is intact. Whether the blocks are any good is for the node replaying them.
+pub fn read(path: &Path) -> Result<Vec<block::Block>, Error> {
+    decode(&fs::read(path).map_err(|_| Error::Io)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let (authority, gen) = block::genesis();
+        let next = block::Builder::new(&authority, 1, &gen).finalize(&authority);
+        let blocks = Vec::from([gen.block, next.block]);
+        let bytes = encode(&blocks);
+        assert_eq!(decode(&bytes), Ok(blocks.clone()));
+        assert_eq!(decode(&bytes[..bytes.len() - 1]), Err(Error::Truncated));
+        assert_eq!(decode(&bytes[1..]), Err(Error::BadMagic));
+        let mut bad = bytes.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert_eq!(decode(&bad), Err(Error::BadChecksum));
+        assert_eq!(decode(MAGIC), Ok(Vec::default()));
+    }
+}
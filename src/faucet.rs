@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, fmt};
+use serde::{Serialize, Deserialize};
+
+// What the faucet will hand out. Each address and each ip gets one drip per cooldown, none
+// bigger than max_drip, and everyone together no more than the daily budget, which starts
+// over at midnight utc. Otherwise a loop of requests would drain the node's own balance.
+
+pub const DAY: u64 = 24 * 60 * 60 * 1_000; // ms
+const MAX_TRACKED: usize = 1 << 14; // past this, addresses and ips whose cooldown is over are forgotten
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub max_drip: u32,
+    pub cooldown: u64, // ms
+    pub daily_budget: u64
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self { max_drip: 1_000, cooldown: 60 * 60 * 1_000, daily_budget: 100_000 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Error {
+    TooMuch { max: u32 },
+    AddressTooSoon { retry_in: u64 }, // ms
+    IpTooSoon { retry_in: u64 },
+    BudgetSpent { retry_in: u64 }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooMuch { max } => write!(f, "At most {} per request", max),
+            Error::AddressTooSoon { retry_in } => write!(f, "This address was funded recently, try again in {}s", retry_in / 1_000 + 1),
+            Error::IpTooSoon { retry_in } => write!(f, "Too many requests, try again in {}s", retry_in / 1_000 + 1),
+            Error::BudgetSpent { retry_in } => write!(f, "The faucet is empty for today, try again in {}s", retry_in / 1_000 + 1)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Faucet {
+    policy: Policy,
+    by_address: BTreeMap<[u8; 32], u64>, // last drip, timestamp ms
+    by_ip: BTreeMap<String, u64>,
+    day: u64, // since the epoch
+    spent: u64 // today
+}
+
+impl Faucet {
+    pub fn new(policy: Policy) -> Self {
+        Self { policy, ..Self::default() }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    // Counts a drip of `amount` to `address` asked for from `ip`, or nothing if any limit says no.
+    pub fn take(&mut self, address: [u8; 32], ip: &str, amount: u32, now: u64) -> Result<(), Error> {
+        if amount > self.policy.max_drip {
+            return Err(Error::TooMuch { max: self.policy.max_drip });
+        }
+        if now / DAY != self.day {
+            self.day = now / DAY;
+            self.spent = 0;
+        }
+        if self.spent + amount as u64 > self.policy.daily_budget {
+            return Err(Error::BudgetSpent { retry_in: DAY - now % DAY });
+        }
+        let cooldown = self.policy.cooldown;
+        let retry_in = |last: Option<&u64>| last.map_or(0, |last| (last + cooldown).saturating_sub(now));
+        match retry_in(self.by_address.get(&address)) {
+            0 => (),
+            retry_in => return Err(Error::AddressTooSoon { retry_in })
+        }
+        match retry_in(self.by_ip.get(ip)) {
+            0 => (),
+            retry_in => return Err(Error::IpTooSoon { retry_in })
+        }
+        if self.by_address.len() + self.by_ip.len() >= MAX_TRACKED {
+            self.by_address.retain(|_, last| *last + cooldown > now);
+            self.by_ip.retain(|_, last| *last + cooldown > now);
+        }
+        self.by_address.insert(address, now);
+        self.by_ip.insert(ip.to_string(), now);
+        self.spent += amount as u64;
+        Ok(())
+    }
+
+    // Gives back a take made at `now` whose txn never went out.
+    pub fn refund(&mut self, address: [u8; 32], ip: &str, amount: u32, now: u64) {
+        if self.by_address.get(&address) == Some(&now) {
+            self.by_address.remove(&address);
+        }
+        if self.by_ip.get(ip) == Some(&now) {
+            self.by_ip.remove(ip);
+        }
+        if now / DAY == self.day {
+            self.spent = self.spent.saturating_sub(amount as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        let mut faucet = Faucet::new(Policy { max_drip: 10, cooldown: 1_000, daily_budget: 25 });
+        let now = 5 * DAY;
+        assert_eq!(faucet.take([1; 32], "a", 11, now), Err(Error::TooMuch { max: 10 }));
+        assert_eq!(faucet.take([1; 32], "a", 10, now), Ok(()));
+        // Same address from elsewhere, or somewhere else from the same ip
+        assert_eq!(faucet.take([1; 32], "b", 10, now + 400), Err(Error::AddressTooSoon { retry_in: 600 }));
+        assert_eq!(faucet.take([2; 32], "a", 10, now + 400), Err(Error::IpTooSoon { retry_in: 600 }));
+        assert_eq!(faucet.take([2; 32], "b", 10, now + 400), Ok(()));
+        // Budget runs out for the day, then starts over
+        assert_eq!(faucet.take([3; 32], "c", 10, now + 500), Err(Error::BudgetSpent { retry_in: DAY - 500 }));
+        assert_eq!(faucet.take([3; 32], "c", 5, now + 500), Ok(()));
+        assert_eq!(faucet.take([3; 32], "c", 10, now + DAY), Ok(()));
+    }
+
+    #[test]
+    fn refund() {
+        let mut faucet = Faucet::new(Policy { max_drip: 10, cooldown: 1_000, daily_budget: 10 });
+        let now = 5 * DAY;
+        assert_eq!(faucet.take([1; 32], "a", 10, now), Ok(()));
+        assert_eq!(faucet.take([2; 32], "b", 10, now + 100), Err(Error::BudgetSpent { retry_in: DAY - 100 }));
+        // The drip never went out, so the same asker can try again straight away
+        faucet.refund([1; 32], "a", 10, now);
+        assert_eq!(faucet.take([1; 32], "a", 10, now + 100), Ok(()));
+    }
+}
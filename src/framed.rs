@@ -0,0 +1,139 @@
+use std::{collections::BTreeMap, future::Future, net::IpAddr};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::Mutex, time};
+
+use crate::msg;
+
+// Asking one peer something over a connection we keep open, rather than a fresh http post each
+// time. Setting up a connection per ask costs a round trip or two that comes out of
+// MAX_PROP_TIME. A frame is the body's length as a big endian u32, a byte for its codec, then
+// the body. Each ask is one frame out and one back, so a connection carries one ask at a time.
+
+const IDLE: time::Duration = time::Duration::from_secs(60); // before we hang up on a quiet peer
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Connect,
+    Closed, // before a whole frame came through
+    TooBig,
+    BadCodec
+}
+
+fn tag(codec: msg::Codec) -> u8 {
+    match codec {
+        msg::Codec::Json => 0,
+        msg::Codec::Bincode => 1
+    }
+}
+
+pub async fn write(stream: &mut TcpStream, codec: msg::Codec, body: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(body.len()).map_err(|_| Error::TooBig)?;
+    let mut frame = Vec::with_capacity(5 + body.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.push(tag(codec));
+    frame.extend_from_slice(body);
+    stream.write_all(&frame).await.map_err(|_| Error::Closed)
+}
+
+// Bodies over `limit` are turned away before we read them.
+pub async fn read(stream: &mut TcpStream, limit: usize) -> Result<(msg::Codec, Vec<u8>), Error> {
+    let mut head = [0u8; 5];
+    stream.read_exact(&mut head).await.map_err(|_| Error::Closed)?;
+    let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+    if len > limit {
+        return Err(Error::TooBig);
+    }
+    let codec = match head[4] {
+        0 => msg::Codec::Json,
+        1 => msg::Codec::Bincode,
+        _ => return Err(Error::BadCodec)
+    };
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await.map_err(|_| Error::Closed)?;
+    Ok((codec, body))
+}
+
+// Connections to peers, kept between asks. One idle connection per address; asks that
+// overlap open more and all but one are dropped after.
+#[derive(Debug, Default)]
+pub struct Pool {
+    idle: Mutex<BTreeMap<String, TcpStream>>
+}
+
+impl Pool {
+    // A connection that sat idle may have been hung up on since, so if it fails we try once
+    // more on a fresh one.
+    pub async fn ask(&self, addr: &str, codec: msg::Codec, body: &[u8], limit: usize) -> Result<(msg::Codec, Vec<u8>), Error> {
+        let idle = self.idle.lock().await.remove(addr);
+        if let Some(mut stream) = idle {
+            if let Ok(answer) = Self::exchange(&mut stream, codec, body, limit).await {
+                self.idle.lock().await.entry(addr.to_string()).or_insert(stream);
+                return Ok(answer);
+            }
+        }
+        let mut stream = TcpStream::connect(addr).await.map_err(|_| Error::Connect)?;
+        let _ = stream.set_nodelay(true);
+        let answer = Self::exchange(&mut stream, codec, body, limit).await?;
+        self.idle.lock().await.entry(addr.to_string()).or_insert(stream);
+        Ok(answer)
+    }
+
+    async fn exchange(stream: &mut TcpStream, codec: msg::Codec, body: &[u8], limit: usize) -> Result<(msg::Codec, Vec<u8>), Error> {
+        write(stream, codec, body).await?;
+        read(stream, limit).await
+    }
+
+    // Connections held open for next time.
+    pub async fn kept(&self) -> usize {
+        self.idle.lock().await.len()
+    }
+}
+
+// Answers asks on `listener` until it fails, each connection on its own task. `answer` gets
+// the asker's ip, as with http, and frames in by their codec and comes back with the frame
+// to send back. Requests over `limit` close the connection.
+pub async fn serve<F, Fut>(listener: TcpListener, limit: usize, answer: F)
+where
+    F: Fn(IpAddr, msg::Codec, Vec<u8>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = (msg::Codec, Vec<u8>)> + Send
+{
+    while let Ok((mut stream, addr)) = listener.accept().await {
+        let answer = answer.clone();
+        let _ = stream.set_nodelay(true);
+        tokio::spawn(async move {
+            while let Ok(Ok((codec, body))) = time::timeout(IDLE, read(&mut stream, limit)).await {
+                let (codec, resp) = answer(addr.ip(), codec, body).await;
+                if write(&mut stream, codec, &resp).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn framed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // Echoes back in json, with the length of what it got
+        tokio::spawn(serve(listener, 16, |_, _, body: Vec<u8>| async move {
+            (msg::Codec::Json, body.len().to_string().into_bytes())
+        }));
+        let pool = Pool::default();
+        let answer = pool.ask(&addr, msg::Codec::Bincode, b"hello", 64).await;
+        assert_eq!(answer, Ok((msg::Codec::Json, b"5".to_vec())));
+        // Second ask goes over the same connection
+        assert_eq!(pool.kept().await, 1);
+        let answer = pool.ask(&addr, msg::Codec::Json, b"", 64).await;
+        assert_eq!(answer, Ok((msg::Codec::Json, b"0".to_vec())));
+        // Too big, so they hang up, and the retry fails the same way
+        let answer = pool.ask(&addr, msg::Codec::Json, &[0u8; 17], 64).await;
+        assert_eq!(answer, Err(Error::Closed));
+        // Still answers on a fresh connection
+        let answer = pool.ask(&addr, msg::Codec::Json, b"hi", 64).await;
+        assert_eq!(answer, Ok((msg::Codec::Json, b"2".to_vec())));
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::{account, block, state};
+
+// Round 0 is built once from a config and handed out as a file.
+// Nodes load that file instead of each rolling their own genesis and forking at round 0.
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Config {
+    pub timestamp: u64,
+    pub clock: state::Clock,
+    pub balances: Vec<(account::PublicKey, u32)>, // funded on top of the authority's stake
+    #[serde(default = "num_shards")]
+    pub num_shards: u8
+}
+
+fn num_shards() -> u8 {
+    state::NUM_SHARDS
+}
+
+impl Config {
+    pub fn new(timestamp: u64) -> Self {
+        Self { timestamp, clock: state::Clock::default(), balances: Vec::default(), num_shards: num_shards() }
+    }
+
+    // For chains that don't run on the default clock.
+    pub fn with_clock(mut self, clock: state::Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Power of two up to 16, see state::Shards.
+    pub fn with_shards(mut self, num_shards: u8) -> Self {
+        self.num_shards = num_shards;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Error {
+    Io,
+    BadFormat,
+    BadHash,
+    BadSig,
+    BadState
+}
+
+pub fn build(authority: &account::Keypair, config: &Config) -> block::Snap {
+    let mut state = state::State::genesis_sharded(authority, config.num_shards);
+    state.clock = config.clock;
+    for (pk, bal) in &config.balances {
+        let id: account::Id = Sha256::digest(pk.to_bytes()).into();
+        let mut data = state.accounts.get(&id).unwrap().cloned().unwrap_or_default();
+        data.bal += bal;
+        assert!(state.accounts.insert(&id, data).is_ok());
+    }
+    let block = block::Block::genesis(authority, &state, config.timestamp);
+    let block_hash = block.sheader.msg.hash();
+    let epoch = block::ValidatorSet::new(0, &state);
+    block::Snap {
+        block,
+        block_hash,
+        state,
+        updates: Vec::default(),
+        epoch,
+        finalized: true,
+        txn_index: Default::default(),
+        timestamps: Vec::from([config.timestamp])
+    }
+}
+
+pub fn write(path: &Path, snap: &block::Snap) -> Result<(), Error> {
+    fs::write(path, snap.store()).map_err(|_| Error::Io)
+}
+
+// Loads a genesis file, checking it holds together. Whether it's the right one is up to
+// the caller: compare the hash to the published one.
+pub fn read(path: &Path) -> Result<block::Snap, Error> {
+    let bytes = fs::read(path).map_err(|_| Error::Io)?;
+    let snap = block::Snap::load(&bytes).map_err(|e| match e {
+        block::LoadError::BadHash => Error::BadHash,
+        block::LoadError::BadState => Error::BadState,
+        block::LoadError::BadSig => Error::BadSig,
+        _ => Error::BadFormat
+    })?;
+    if snap.block.sheader.msg.data.round != 0 {
+        return Err(Error::BadState);
+    }
+    // The state's clock is in the signed commit, the epoch's copy has to match it.
+    if snap.epoch.clock != snap.state.clock {
+        return Err(Error::BadState);
+    }
+    Ok(snap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let authority = account::Keypair::gen();
+        let bob = account::Keypair::gen();
+        let mut config = Config::new(state::timestamp());
+        config.balances.push((bob.kp.public, 100));
+        let snap = build(&authority, &config);
+        let bob_id: account::Id = Sha256::digest(bob.kp.public.to_bytes()).into();
+        assert_eq!(snap.state.accounts.get(&bob_id).unwrap().unwrap().bal, 100);
+        // Same config, same genesis
+        assert_eq!(build(&authority, &config), snap);
+        let path = std::env::temp_dir().join(format!("tam-genesis-{:x}.json", u64::from_be_bytes(snap.block_hash[..8].try_into().unwrap())));
+        assert_eq!(write(&path, &snap), Ok(()));
+        assert_eq!(read(&path), Ok(snap.clone()));
+        // A tampered balance doesn't match the signed commit
+        let mut bad = snap.clone();
+        assert!(bad.state.accounts.insert(&bob_id, account::Data { bal: 1 << 20, ..Default::default() }).is_ok());
+        assert_eq!(write(&path, &bad), Ok(()));
+        assert_eq!(read(&path), Err(Error::BadState));
+        // So does a different clock, on either copy
+        let fast = state::Clock { block_time: 500, ..Default::default() };
+        let mut bad = snap.clone();
+        bad.state.clock = fast;
+        assert_eq!(write(&path, &bad), Ok(()));
+        assert_eq!(read(&path), Err(Error::BadState));
+        let mut bad = snap.clone();
+        bad.epoch.clock = fast;
+        assert_eq!(write(&path, &bad), Ok(()));
+        assert_eq!(read(&path), Err(Error::BadState));
+        let _ = fs::remove_file(path);
+    }
+}
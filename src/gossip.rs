@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+use crate::msg;
+
+// Broadcasts wait here on their way out, in lanes. The highest lane with anything in it always
+// goes next, so a new block isn't stuck behind a flood of txns and turning up too late to
+// take. Lanes are bounded: a full one drops its oldest message, it's only gossip.
+
+pub const LANE_SIZES: [usize; 3] = [256, 256, 1024]; // by lane
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lane {
+    Blocks, // blocks and the votes on them
+    Other,
+    Txns
+}
+
+impl Lane {
+    pub fn of(message: &str) -> Self {
+        match msg::variant(message.as_bytes()) {
+            Some("Compact" | "Chain" | "Attest" | "Vote") => Lane::Blocks,
+            Some("Txn") => Lane::Txns,
+            _ => Lane::Other
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Queues {
+    lanes: [VecDeque<String>; 3]
+}
+
+impl Queues {
+    // The message dropped to make room, if the lane was full.
+    pub fn push(&mut self, message: String) -> Option<String> {
+        let lane = Lane::of(&message) as usize;
+        let queue = &mut self.lanes[lane];
+        let dropped = if queue.len() >= LANE_SIZES[lane] { queue.pop_front() } else { None };
+        queue.push_back(message);
+        dropped
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.lanes.iter_mut().find_map(VecDeque::pop_front)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lanes() {
+        let mut queues = Queues::default();
+        let txn = msg::ser(&msg::Message::Txn(Vec::default()));
+        let time = msg::ser(&msg::Message::Time());
+        let headers = msg::ser(&msg::Message::Headers([0; 32]));
+        let block = msg::ser(&msg::Message::Chain(Vec::default()));
+        for message in [&txn, &time, &headers, &block] {
+            assert_eq!(queues.push(message.clone()), None);
+        }
+        // Blocks first whenever they came in, txns last
+        assert_eq!(queues.pop(), Some(block));
+        assert_eq!(queues.pop(), Some(time));
+        assert_eq!(queues.pop(), Some(headers));
+        assert_eq!(queues.pop(), Some(txn.clone()));
+        assert!(queues.is_empty());
+        // A full lane loses its oldest
+        let txns: Vec<_> = (0..=LANE_SIZES[Lane::Txns as usize])
+            .map(|i| format!("{{\"Txn\": {}}}", i)) // only the variant is read
+            .collect();
+        for message in &txns[..LANE_SIZES[Lane::Txns as usize]] {
+            assert_eq!(queues.push(message.clone()), None);
+        }
+        assert_eq!(queues.push(txns.last().unwrap().clone()), Some(txns[0].clone()));
+        assert_eq!(queues.len(), LANE_SIZES[Lane::Txns as usize]);
+    }
+}
@@ -1,3 +1,6 @@
+// Tries fail with () throughout, and errors hand the offending block or txn back by value.
+#![allow(clippy::result_unit_err, clippy::result_large_err, clippy::large_enum_variant)]
+
 pub mod merkle;
 pub mod state;
 pub mod account;
@@ -8,4 +11,24 @@ pub mod node;
 pub mod app;
 pub mod msg;
 pub mod rollup;
-pub mod senator;
\ No newline at end of file
+pub mod senator;
+pub mod archive;
+pub mod deadline;
+pub mod attest;
+pub mod evidence;
+pub mod genesis;
+pub mod txpool;
+pub mod store;
+pub mod peers;
+pub mod metrics;
+pub mod ratelimit;
+pub mod behavior;
+pub mod gossip;
+pub mod liveness;
+pub mod export;
+pub mod transport;
+pub mod framed;
+pub mod faucet;
+pub mod rpc;
+pub mod config;
+pub mod compress;
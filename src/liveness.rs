@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, VecDeque};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::{block, validator};
+
+// Who was due to propose on our chain and whether their block turned up, over the last
+// WINDOW proposals. For uptime pages now and for evicting slots that never show up later.
+
+pub const WINDOW: usize = 1024; // proposals remembered
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proposal {
+    pub round: u32,
+    pub proposal: u32,
+    pub leader: validator::Id,
+    pub produced: bool
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Uptime {
+    pub produced: u32,
+    pub missed: u32
+}
+
+impl Uptime {
+    // Blocks produced per thousand proposals due, within the window.
+    pub fn score(&self) -> u32 {
+        let due = self.produced + self.missed;
+        (self.produced as u64 * 1_000 / due.max(1) as u64) as u32
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Liveness {
+    recent: VecDeque<Proposal>, // oldest first
+    uptime: BTreeMap<validator::Id, Uptime>
+}
+
+impl Liveness {
+    // A new head on top of `prev`: its leader produced, every proposal before it lapsed.
+    // Anything we had past `prev` was on a chain we've since left, so it goes first.
+    pub fn record(&mut self, prev: &block::Snap, block: &block::Block) {
+        let prev_round = prev.block.sheader.msg.data.round;
+        while self.recent.back().is_some_and(|last| last.round > prev_round) {
+            let dropped = self.recent.pop_back().expect("just checked");
+            self.forget(&dropped);
+        }
+        let proposal = block.sheader.msg.data.proposal;
+        for lapsed in 1..proposal {
+            let Ok(pk) = prev.leader(lapsed) else { continue };
+            self.push(Proposal {
+                round: prev_round + 1 + block::place(lapsed).0,
+                proposal: lapsed,
+                leader: Sha256::digest(pk.to_bytes()).into(),
+                produced: false
+            });
+        }
+        self.push(Proposal {
+            round: block.sheader.msg.data.round,
+            proposal,
+            leader: Sha256::digest(block.sheader.from.to_bytes()).into(),
+            produced: true
+        });
+    }
+
+    fn push(&mut self, entry: Proposal) {
+        let uptime = self.uptime.entry(entry.leader).or_default();
+        if entry.produced {
+            uptime.produced += 1;
+        } else {
+            uptime.missed += 1;
+        }
+        self.recent.push_back(entry);
+        if self.recent.len() > WINDOW {
+            let oldest = self.recent.pop_front().expect("over the window");
+            self.forget(&oldest);
+        }
+    }
+
+    fn forget(&mut self, entry: &Proposal) {
+        let Some(uptime) = self.uptime.get_mut(&entry.leader) else { return };
+        if entry.produced {
+            uptime.produced -= 1;
+        } else {
+            uptime.missed -= 1;
+        }
+        if *uptime == Uptime::default() {
+            self.uptime.remove(&entry.leader);
+        }
+    }
+
+    pub fn uptime(&self, id: &validator::Id) -> Option<Uptime> {
+        self.uptime.get(id).copied()
+    }
+
+    // Newest first.
+    pub fn recent(&self, count: usize) -> Vec<Proposal> {
+        self.recent.iter().rev().take(count).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime() {
+        let (authority, gen) = block::genesis();
+        let id: validator::Id = Sha256::digest(authority.kp.public.to_bytes()).into();
+        let mut liveness = Liveness::default();
+        // Third time lucky
+        let late = block::Builder::new(&authority, 3, &gen).finalize(&authority);
+        liveness.record(&gen, &late.block);
+        assert_eq!(liveness.uptime(&id), Some(Uptime { produced: 1, missed: 2 }));
+        assert_eq!(liveness.uptime(&id).unwrap().score(), 333);
+        assert_eq!(
+            liveness.recent(2).iter().map(|entry| (entry.round, entry.proposal, entry.produced)).collect::<Vec<_>>(),
+            Vec::from([(1, 3, true), (1, 2, false)])
+        );
+        // A sibling that was on time replaces it
+        let sibling = block::Builder::new(&authority, 1, &gen).finalize(&authority);
+        liveness.record(&gen, &sibling.block);
+        assert_eq!(liveness.uptime(&id), Some(Uptime { produced: 1, missed: 0 }));
+        assert_eq!(liveness.recent(WINDOW).len(), 1);
+    }
+}
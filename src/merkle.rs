@@ -4,6 +4,8 @@ use serde::{Serialize, Deserialize};
 use std::fmt::Debug;
 use std::sync::Arc;
 
+use crate::deadline::Deadline;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 struct TrieNode<T> {
     substr: Vec<u8>,
@@ -56,7 +58,7 @@ impl<T: Serialize + Clone> Node<T> {
     // Can always unwrap children after split call
     fn split(&self, cut_at: usize) -> Result<Self, ()> {
         let mut clone = self.clone();
-        let mut node = clone.node.as_mut().ok_or(())?;
+        let node = clone.node.as_mut().ok_or(())?;
         if cut_at < node.substr.len() {
             let suffix = node.substr.split_off(cut_at + 1);
             let mut children = Self::empty_children_array();
@@ -78,7 +80,7 @@ impl<T: Serialize + Clone> Node<T> {
     // If I only have one child and no value absorb it into me.
     // Otherwise do nothing.
     fn unsplit(&mut self) -> Result<(), ()> {
-        let mut node = self.node.as_mut().ok_or(())?;
+        let node = self.node.as_mut().ok_or(())?;
         if node.value.is_none() {
             if let Some(mut children) = node.children.take() {
                 let mut some_iter = children.iter_mut().enumerate().filter_map(|(i, opt_g)| opt_g.as_mut().map(|g| (i, g)));
@@ -100,7 +102,7 @@ impl<T: Serialize + Clone> Node<T> {
 
     pub fn insert(&self, k: &[u8], v: T) -> Result<(Self, Option<T>), ()> {
         let node = self.node.as_ref().ok_or(())?;
-        let cut_at = Self::prefix_len(&k, &node.substr);
+        let cut_at = Self::prefix_len(k, &node.substr);
         let mut clone = self.split(cut_at)?;
         let clone_node = clone.node.as_mut().unwrap();
         if k.len() > cut_at {
@@ -137,42 +139,33 @@ impl<T: Serialize + Clone> Node<T> {
     }
 
     fn commit(&self) -> [u8; 32] {
-        let mut hasher = Sha256::new();
         let node = self.node.as_ref().unwrap();
-        hasher.update(&node.substr);
-        if let Some(ref v) = node.value {
-            hasher.update(serde_json::to_string(v).expect("can't serialize value"));
-        }
-        let mut count: u8 = 0;
-        if let Some(ref children) = &node.children {
-            for i in 0u8..16 {
-                if let Some(ref child) = children[i as usize] {
-                    count += 1;
-                    hasher.update(&[i]);
-                    hasher.update(child.commit);
-                }
-            }
-        }
-        hasher.update((node.substr.len() as u32).to_be_bytes());
-        hasher.update(&[count]);
-        hasher.finalize().into()
+        let value = node.value.as_ref()
+            .map(|v| serde_json::to_string(v).expect("can't serialize value"));
+        hash_node(&node.substr, value.as_deref(), Self::child_commits(node))
+    }
+
+    fn child_commits(node: &TrieNode<T>) -> impl Iterator<Item = (u8, &[u8; 32])> {
+        node.children.iter()
+            .flat_map(|children| children.iter().enumerate())
+            .filter_map(|(i, opt_child)| opt_child.as_ref().map(|child| (i as u8, &child.commit)))
     }
 
     fn remove(&self, k: &[u8]) -> Result<(Self, Option<T>), ()> {
         let node = self.node.as_ref().ok_or(())?;
-        let cut_at = Self::prefix_len(&k, &node.substr);
+        let cut_at = Self::prefix_len(k, &node.substr);
         if k.len() > cut_at { 
             if node.substr.len() > cut_at {
                 // Key forks from `substr`
                 Ok((self.clone(), None))
             } else {
                 // Key continues after `substr`
-                if let Some(ref children) = node.children.as_ref() {
+                if let Some(children) = node.children.as_ref() {
                     let suffix = &k[cut_at + 1..];
                     let nibble = k[cut_at] as usize;
                     if let Some(ref child) = children[nibble] {
                         let mut clone = self.clone();
-                        let mut clone_node = clone.node.as_mut().ok_or(())?;
+                        let clone_node = clone.node.as_mut().ok_or(())?;
                         let (child_clone, ret) = child.remove(suffix)?;
                         let child_clone_node = child_clone.node.as_ref().ok_or(())?;
                         if let (None, None) = (&child_clone_node.children, &child_clone_node.value) {
@@ -181,7 +174,7 @@ impl<T: Serialize + Clone> Node<T> {
                         } else {
                             clone_node.children.as_mut().unwrap()[nibble] = Some(Arc::new(child_clone));
                         }
-                        if clone_node.children.as_ref().unwrap().iter().filter(|g| g.is_some()).next().is_none() {
+                        if clone_node.children.as_ref().unwrap().iter().find(|g| g.is_some()).is_none() {
                             // children is empty, make it none.
                             clone_node.children = None;
                         }
@@ -264,11 +257,32 @@ impl<T: Serialize + Clone> Node<T> {
     */
 
     fn iter<'a>(&'a self) -> MerkleIterator<'a, T> {
-        MerkleIterator { stack: Vec::from([(self, false)]) }
+        MerkleIterator { stack: Vec::from([(self, false)]), deadline: Deadline::default() }
     }
 
     fn entry_iter<'a>(&'a self) -> MerkleEntryIterator<'a, T> {
-        MerkleEntryIterator { stack: Vec::from([(self, false, self.node.as_ref().unwrap().substr.clone())]) }
+        MerkleEntryIterator { 
+            stack: Vec::from([(self, false, self.node.as_ref().unwrap().substr.clone())]), 
+            deadline: Deadline::default() 
+        }
+    }
+
+    // Nodes from here down to where k ends or falls off the trie.
+    fn prove(&self, k: &[u8], mut path: Vec<ProofNode>) -> Result<Vec<ProofNode>, ()> {
+        let node = self.node.as_ref().ok_or(())?;
+        path.push(ProofNode {
+            substr: node.substr.clone(),
+            value: node.value.as_ref().map(|v| serde_json::to_string(v).expect("can't serialize value")),
+            children: Self::child_commits(node).map(|(i, c)| (i, *c)).collect()
+        });
+        let cut_at = Self::prefix_len(k, &node.substr);
+        if node.substr.len() > cut_at || k.len() == cut_at {
+            return Ok(path);
+        }
+        match node.children.as_ref().and_then(|children| children[k[cut_at] as usize].as_ref()) {
+            Some(child) => child.prove(&k[cut_at + 1..], path),
+            None => Ok(path)
+        }
     }
 
     // Get subtrie matching k. Plus the path from root to it.
@@ -281,7 +295,7 @@ impl<T: Serialize + Clone> Node<T> {
                 Ok(None)
             } else {
                 // Key contained in `substr`
-                Ok(Some((&self, Vec::default())))
+                Ok(Some((self, Vec::default())))
             }
         } else {
             if k.len() > cut_at {
@@ -298,7 +312,7 @@ impl<T: Serialize + Clone> Node<T> {
                 }
             } else {
                 // Key is `substr`
-                Ok(Some((&self, Vec::default())))
+                Ok(Some((self, Vec::default())))
             }
         }
     }
@@ -312,7 +326,7 @@ impl<T: Serialize + Clone> Node<T> {
                 Ok(None)
             } else {
                 // Key contained in `substr`
-                Ok(Some((&self, path)))
+                Ok(Some((self, path)))
             }
         } else {
             if k.len() > cut_at {
@@ -329,7 +343,7 @@ impl<T: Serialize + Clone> Node<T> {
                 }
             } else {
                 // Key is `substr`
-                Ok(Some((&self, path)))
+                Ok(Some((self, path)))
             }
         }
     }
@@ -405,6 +419,37 @@ impl<T: Serialize + Clone> Node<T> {
         }
     }
 
+    // Entries keyed past `after`, in key order, until `out` holds `count`. `path` is the
+    // full key down to and including this node's substr.
+    fn chunk(&self, path: Vec<u8>, after: Option<&[u8]>, count: usize, out: &mut Vec<(Vec<u8>, T)>) {
+        let Some(node) = self.node.as_ref() else {
+            return;
+        };
+        if let Some(ref v) = node.value {
+            if out.len() < count && after.is_none_or(|a| path.as_slice() > a) {
+                out.push((path.clone(), v.clone()));
+            }
+        }
+        if let Some(ref children) = node.children {
+            for (i, opt_child) in children.iter().enumerate() {
+                let Some(inner) = opt_child.as_ref().and_then(|child| child.node.as_ref()) else {
+                    continue;
+                };
+                if out.len() == count {
+                    return;
+                }
+                let mut child_path = path.clone();
+                child_path.push(i as u8);
+                child_path.extend(&inner.substr);
+                // Everything under a path that sorts before `after` without leading to it is done
+                if after.is_some_and(|a| child_path.as_slice() < a && !a.starts_with(&child_path)) {
+                    continue;
+                }
+                opt_child.as_ref().unwrap().chunk(child_path, after, count, out);
+            }
+        }
+    }
+
     // verify hash integrity fn
     pub fn valid_commits(&self) -> Result<(), ()> {
         if self.commit != self.commit() {
@@ -413,10 +458,8 @@ impl<T: Serialize + Clone> Node<T> {
         } else {
             if let Some(node) = self.node.as_ref() {
                 if let Some(ref children) = node.children {
-                    for opt_child in children {
-                        if let Some(child) = opt_child {
-                            child.valid_commits()?;
-                        }
+                    for child in children.iter().flatten() {
+                        child.valid_commits()?;
                     }
                 }
             }
@@ -426,15 +469,91 @@ impl<T: Serialize + Clone> Node<T> {
     
 }
 
+fn hash_node<'a>(substr: &[u8], value: Option<&str>, children: impl Iterator<Item = (u8, &'a [u8; 32])>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(substr);
+    if let Some(v) = value {
+        hasher.update(v);
+    }
+    let mut count: u8 = 0;
+    for (i, commit) in children {
+        count += 1;
+        hasher.update([i]);
+        hasher.update(commit);
+    }
+    hasher.update((substr.len() as u32).to_be_bytes());
+    hasher.update([count]);
+    hasher.finalize().into()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProofNode {
+    substr: Vec<u8>,
+    value: Option<String>, // serialized, as it was hashed
+    children: Vec<(u8, [u8; 32])>
+}
+
+impl ProofNode {
+    fn commit(&self) -> [u8; 32] {
+        hash_node(&self.substr, self.value.as_deref(), self.children.iter().map(|(i, c)| (*i, c)))
+    }
+}
+
+// Inclusion (or exclusion) proof for one key: root first.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Proof(Vec<ProofNode>);
+
+impl Proof {
+    // Check that k maps to v (None for absent) in the map committed to by root.
+    pub fn verify<V: Serialize + Clone>(&self, root: [u8; 32], k: &[u8], v: Option<&V>) -> bool {
+        let digest = to_digest(k);
+        let mut k = &digest[..];
+        let mut expected = root;
+        let v = v.map(|v| serde_json::to_string(v).expect("can't serialize value"));
+        for (i, pnode) in self.0.iter().enumerate() {
+            let last = i + 1 == self.0.len();
+            if pnode.commit() != expected {
+                return false;
+            }
+            let cut_at = Node::<V>::prefix_len(k, &pnode.substr);
+            if pnode.substr.len() > cut_at {
+                // Key forks from `substr` or is contained in `substr`
+                return last && v.is_none();
+            }
+            if k.len() == cut_at {
+                // Key is `substr`
+                return last && pnode.value == v;
+            }
+            // Key continues after `substr`
+            match pnode.children.iter().find(|(nibble, _)| *nibble == k[cut_at]) {
+                Some((_, commit)) if !last => {
+                    expected = *commit;
+                    k = &k[cut_at + 1..];
+                },
+                Some(_) => return false,
+                None => return last && v.is_none()
+            }
+        }
+        false
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MerkleIterator<'a, T> {
-    stack: Vec<(&'a Node<T>, bool)>
+    stack: Vec<(&'a Node<T>, bool)>,
+    deadline: Deadline
 }
 
 impl<'a, T> MerkleIterator<'a, T> {
+    // Stop early once deadline expires. Check it after iterating to tell a cut short scan from a full one.
+    pub fn until(mut self, deadline: &Deadline) -> Self {
+        self.deadline = deadline.clone();
+        self
+    }
+
     // Push stuff until last vec entry has no children.
     fn advance(&mut self) {
-        while let Some((ref merk, ref explored)) = self.stack.pop() {
+        while let Some((merk, ref explored)) = self.stack.pop() {
             self.stack.push((merk, true));
             if *explored { return; }
             if let Some(ref children) = merk.node.as_ref().unwrap().children {
@@ -452,9 +571,10 @@ impl<'a, T> Iterator for MerkleIterator<'a, T> {
     fn next(&mut self) -> Option<Self::Item> {
         let mut val = None;
         while val.is_none() {
+            if self.deadline.expired() { return None; }
             self.advance();
             val = match &self.stack.pop() {
-                Some((ref merk, _)) => {
+                Some((merk, _)) => {
                     match merk.node {
                         Some(ref node) => node.value.as_ref(),
                         None => continue,
@@ -469,10 +589,16 @@ impl<'a, T> Iterator for MerkleIterator<'a, T> {
 
 #[derive(Debug, Clone)]
 pub struct MerkleEntryIterator<'a, T> {
-    stack: Vec<(&'a Node<T>, bool, Vec<u8>)> // lazy impl -> log cost but whatever
+    stack: Vec<(&'a Node<T>, bool, Vec<u8>)>, // lazy impl -> log cost but whatever
+    deadline: Deadline
 }
 
 impl<'a, T> MerkleEntryIterator<'a, T> {
+    pub fn until(mut self, deadline: &Deadline) -> Self {
+        self.deadline = deadline.clone();
+        self
+    }
+
     // Push stuff until last vec entry has no children.
     fn advance(&mut self) {
         while let Some((merk, ref explored, path)) = self.stack.pop() {
@@ -485,7 +611,7 @@ impl<'a, T> MerkleEntryIterator<'a, T> {
                         child_path.push(i as u8);
                         child_path.extend(&child.node.as_ref().unwrap().substr);
                         println!("i pushed {:?}", child_path);
-                        self.stack.push((&child, false, child_path));
+                        self.stack.push((child, false, child_path));
                     }
                 }
             }
@@ -499,9 +625,10 @@ impl<'a, T> Iterator for MerkleEntryIterator<'a, T> {
     fn next(&mut self) -> Option<Self::Item> {
         let mut val = None;
         while val.is_none() {
+            if self.deadline.expired() { return None; }
             self.advance();
             val = match self.stack.pop() {
-                Some((ref merk, _, path)) => {
+                Some((merk, _, path)) => {
                     match merk.node {
                         Some(ref node) => node.value.as_ref().map(|v| (path, v)),
                         None => continue,
@@ -527,46 +654,51 @@ impl<V: Serialize + Clone> Default for Map<V> {
     }
 }
 
-impl<V: Serialize + Clone> Map<V> {
-    fn to_digest(k: &[u8]) -> Vec<u8> {
-        let mut extended = Vec::with_capacity(2 * k.len());
-        for byte in k {
-            extended.push(byte >> 4);
-            extended.push(byte & 0x0f);
-        }
-        extended
+fn to_digest(k: &[u8]) -> Vec<u8> {
+    let mut extended = Vec::with_capacity(2 * k.len());
+    for byte in k {
+        extended.push(byte >> 4);
+        extended.push(byte & 0x0f);
     }
+    extended
+}
+
+fn from_digest(k: &[u8]) -> Vec<u8> {
+    k.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0)).collect()
+}
+
+impl<V: Serialize + Clone> Map<V> {
 
     pub fn insert(&mut self, k: &[u8], v: V) -> Result<Option<V>, ()> {
-        let (root, opt_val) = self.root.insert(&Self::to_digest(k), v)?;
+        let (root, opt_val) = self.root.insert(&to_digest(k), v)?;
         self.root = root;
         Ok(opt_val)
     }
 
     pub fn remove(&mut self, k: &[u8]) -> Result<Option<V>, ()> {
-        let (root, opt_val) = self.root.remove(&Self::to_digest(k))?;
+        let (root, opt_val) = self.root.remove(&to_digest(k))?;
         self.root = root;
         Ok(opt_val)
     }
 
     pub fn get(&self, k: &[u8]) -> Result<Option<&V>, ()> {
-        self.root.get(&Self::to_digest(k))
+        self.root.get(&to_digest(k))
     }
 
     // for only this one the input is already digested.
     // TODO: all inputs to all fns should be pre digested!
     pub fn get_subtrie(&self, k: &[u8]) -> Result<Option<(Self, Vec<u8>)>, ()> {
-        match self.root.get_subtrie(&k)? {
+        match self.root.get_subtrie(k)? {
             None => Ok(None),
             Some((r, p)) => Ok(Some((Self { root: r.clone() }, p)))
         }
     }
 
-    pub fn iter(&self) -> MerkleIterator<V> {
+    pub fn iter(&self) -> MerkleIterator<'_, V> {
         self.root.iter()
     }
 
-    pub fn entry_iter(&self) -> MerkleEntryIterator<V> {
+    pub fn entry_iter(&self) -> MerkleEntryIterator<'_, V> {
         self.root.entry_iter()
     }
 
@@ -574,12 +706,116 @@ impl<V: Serialize + Clone> Map<V> {
         self.root.commit
     }
 
+    pub fn prove(&self, k: &[u8]) -> Result<Proof, ()> {
+        Ok(Proof(self.root.prove(&to_digest(k), Vec::default())?))
+    }
+
     pub fn valid_commits(&self) -> Result<(), ()> {
         self.root.valid_commits()
     }
 
+    // Up to `count` entries keyed after `after`, in key order. Exports the trie a piece at a
+    // time: each chunk picks up after the last key of the one before.
+    pub fn chunk(&self, after: Option<&[u8]>, count: usize) -> Vec<(Vec<u8>, V)> {
+        let after = after.map(to_digest);
+        let path = self.root.node.as_ref().map_or(Vec::default(), |node| node.substr.clone());
+        let mut out = Vec::default();
+        self.root.chunk(path, after.as_deref(), count, &mut out);
+        out.into_iter().map(|(k, v)| (from_digest(&k), v)).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.iter().next().is_none()
+    }
+}
+
+// A map keyed by big endian position, so proofs pin a value to its index
+// and iteration runs in insertion order. The length is part of the commit.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct List<V> {
+    map: Map<V>,
+    len: u32
+}
+
+impl<V: Serialize + Clone> Default for List<V> {
+    fn default() -> Self {
+        List {
+            map: Map::default(),
+            len: 0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ListProof {
+    pub len: u32,
+    pub root: [u8; 32],
+    pub proof: Proof
+}
+
+impl ListProof {
+    // Checks v sat at index i of the list committed to.
+    pub fn verify<V: Serialize + Clone>(&self, commit: [u8; 32], i: u32, v: &V) -> bool {
+        list_commit(self.len, self.root) == commit
+            && i < self.len
+            && self.proof.verify(self.root, &i.to_be_bytes(), Some(v))
+    }
+}
+
+fn list_commit(len: u32, root: [u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(len.to_be_bytes());
+    hasher.update(root);
+    hasher.finalize().into()
+}
+
+impl<V: Serialize + Clone> List<V> {
+
+    pub fn push(&mut self, v: V) -> Result<(), ()> {
+        self.map.insert(&self.len.to_be_bytes(), v)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn set(&mut self, i: u32, v: V) -> Result<V, ()> {
+        if i >= self.len { return Err(()); }
+        self.map.insert(&i.to_be_bytes(), v)?.ok_or(())
+    }
+
+    pub fn get(&self, i: u32) -> Result<Option<&V>, ()> {
+        if i >= self.len { return Ok(None); }
+        self.map.get(&i.to_be_bytes())
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.root.iter().next().is_some()
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> MerkleIterator<'_, V> {
+        self.map.iter()
+    }
+
+    pub fn commit(&self) -> [u8; 32] {
+        list_commit(self.len, self.map.commit())
+    }
+
+    pub fn prove(&self, i: u32) -> Result<ListProof, ()> {
+        if i >= self.len { return Err(()); }
+        Ok(ListProof { len: self.len, root: self.map.commit(), proof: self.map.prove(&i.to_be_bytes())? })
+    }
+
+    // Commits hold and exactly the indices 0..len are filled.
+    pub fn valid_commits(&self) -> Result<(), ()> {
+        self.map.valid_commits()?;
+        for i in 0..self.len {
+            self.map.get(&i.to_be_bytes())?.ok_or(())?;
+        }
+        if self.map.iter().count() != self.len as usize { return Err(()); }
+        Ok(())
     }
 }
 
@@ -753,6 +989,43 @@ mod tests {
         assert_eq!(vals, Vec::from([&2, &1, &3, &4, &5, &0]));
     }
 
+    #[test]
+    fn proof() {
+        let mut map: Map<u8> = Map::default();
+        assert!(map.prove(&[1, 2]).unwrap().verify::<u8>(map.commit(), &[1, 2], None));
+        for k in [[0u8, 1], [0, 2], [1, 2], [1, 0x23]] {
+            assert!(map.insert(&k, k[1]).is_ok());
+        }
+        for k in [[0u8, 1], [0, 2], [1, 2], [1, 0x23]] {
+            let proof = map.prove(&k).unwrap();
+            assert!(proof.verify(map.commit(), &k, Some(&k[1])));
+            assert!(!proof.verify(map.commit(), &k, Some(&(k[1] + 1))));
+            assert!(!proof.verify::<u8>(map.commit(), &k, None));
+            assert!(!proof.verify(map.commit(), &[2, 2], Some(&k[1])));
+            assert!(!proof.verify([0u8; 32], &k, Some(&k[1])));
+        }
+        // Absent keys
+        for k in [[0u8, 3], [1, 0x24], [2, 0]] {
+            let proof = map.prove(&k).unwrap();
+            assert!(proof.verify::<u8>(map.commit(), &k, None));
+            assert!(!proof.verify(map.commit(), &k, Some(&0)));
+        }
+    }
+
+    #[test]
+    fn deadline() {
+        let mut map: Map<u8> = Map::default();
+        for i in 0..16u8 {
+            assert!(map.insert(&[i], i).is_ok());
+        }
+        assert_eq!(map.iter().until(&Deadline::default()).count(), 16);
+        let deadline = Deadline::default();
+        let mut iter = map.entry_iter().until(&deadline);
+        assert!(iter.next().is_some());
+        deadline.cancel();
+        assert_eq!(iter.next(), None);
+    }
+
     #[test]
     fn validcommits() {
         // Don't really test for errors but the code is pretty obviously correct for error catching?
@@ -771,5 +1044,66 @@ mod tests {
         node = node.insert(&[2], 5).unwrap().0;
         assert_eq!(node.valid_commits(), Ok(()));
     }
+
+    #[test]
+    fn list() {
+        let mut list: List<u8> = List::default();
+        assert!(list.prove(0).is_err());
+        for v in [5u8, 7, 9] {
+            assert!(list.push(v).is_ok());
+        }
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.iter().collect::<Vec<&u8>>(), vec![&5, &7, &9]);
+        assert_eq!(list.get(1), Ok(Some(&7)));
+        assert_eq!(list.get(3), Ok(None));
+        assert_eq!(list.valid_commits(), Ok(()));
+        let proof = list.prove(1).unwrap();
+        assert!(proof.verify(list.commit(), 1, &7u8));
+        assert!(!proof.verify(list.commit(), 0, &7u8));
+        assert!(!proof.verify(list.commit(), 1, &5u8));
+        assert!(!proof.verify([0u8; 32], 1, &7u8));
+        // The length is committed, so a shorter claimed list doesn't verify
+        let mut short = proof.clone();
+        short.len = 1;
+        assert!(!short.verify(list.commit(), 0, &5u8));
+        let old = list.commit();
+        assert_eq!(list.set(1, 8), Ok(7));
+        assert_ne!(list.commit(), old);
+        assert!(list.set(3, 0).is_err());
+        // A gap in the indices is caught
+        list.len = 4;
+        assert_eq!(list.valid_commits(), Err(()));
+    }
+
+    #[test]
+    fn chunk() {
+        let mut map: Map<u32> = Map::default();
+        let mut keys: Vec<[u8; 4]> = (0..100u32).map(|i| i.wrapping_mul(2654435761).to_be_bytes()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(map.insert(k, i as u32), Ok(None));
+        }
+        keys.sort();
+        // Chunks line up end to end, in key order
+        let mut after: Option<Vec<u8>> = None;
+        let mut exported = Vec::default();
+        loop {
+            let chunk = map.chunk(after.as_deref(), 7);
+            assert!(chunk.len() <= 7);
+            let Some((last, _)) = chunk.last() else {
+                break;
+            };
+            after = Some(last.clone());
+            exported.extend(chunk);
+        }
+        assert_eq!(exported.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(), keys.iter().map(|k| k.to_vec()).collect::<Vec<_>>());
+        // Putting them back gives the same trie
+        let mut copy: Map<u32> = Map::default();
+        for (k, v) in exported {
+            assert_eq!(copy.get(&k), Ok(None));
+            assert_eq!(copy.insert(&k, v), Ok(None));
+        }
+        assert_eq!(copy.commit(), map.commit());
+        assert_eq!(Map::<u32>::default().chunk(None, 7), Vec::default());
+    }
     
 }
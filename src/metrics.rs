@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+use std::sync::Mutex;
+
+// Counters and gauges the node keeps for its operator, served over http in the
+// prometheus text format. Counters can carry one label, like the reason a block was rejected.
+
+pub const BLOCKS_PROPOSED: &str = "tam_blocks_proposed";
+pub const BLOCKS_ACCEPTED: &str = "tam_blocks_accepted";
+pub const BLOCKS_REJECTED: &str = "tam_blocks_rejected"; // by reason
+pub const REORGS: &str = "tam_reorgs";
+pub const HEAD_ROUND: &str = "tam_head_round";
+pub const TXPOOL_TXNS: &str = "tam_txpool_txns";
+pub const TXPOOL_BYTES: &str = "tam_txpool_bytes";
+pub const VERIFY_MS: &str = "tam_verify_ms"; // per block
+pub const BYTES_IN: &str = "tam_bytes_in"; // p2p messages and answers, by variant
+pub const BYTES_OUT: &str = "tam_bytes_out"; // by variant
+pub const GOSSIP_DROPPED: &str = "tam_gossip_dropped"; // broadcasts pushed out of a full queue, by lane
+pub const SLOTS_MISSED: &str = "tam_slots_missed"; // our proposals whose slot was over before we ticked
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    counters: BTreeMap<(&'static str, Option<String>), u64>,
+    gauges: BTreeMap<&'static str, u64>,
+    summaries: BTreeMap<&'static str, Summary>
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    inner: Mutex<Inner>
+}
+
+// Variant name of an error, for a label: `BadBlock(..)` gives "BadBlock".
+pub fn reason(e: &impl Debug) -> String {
+    format!("{:?}", e).split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default().to_string()
+}
+
+impl Registry {
+    pub fn inc(&self, name: &'static str) {
+        *self.inner.lock().unwrap().counters.entry((name, None)).or_default() += 1;
+    }
+
+    pub fn inc_with(&self, name: &'static str, label: String) {
+        *self.inner.lock().unwrap().counters.entry((name, Some(label))).or_default() += 1;
+    }
+
+    pub fn add_with(&self, name: &'static str, label: String, amount: u64) {
+        *self.inner.lock().unwrap().counters.entry((name, Some(label))).or_default() += amount;
+    }
+
+    pub fn set(&self, name: &'static str, value: u64) {
+        self.inner.lock().unwrap().gauges.insert(name, value);
+    }
+
+    pub fn observe(&self, name: &'static str, value: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let summary = inner.summaries.entry(name).or_default();
+        summary.count += 1;
+        summary.sum += value;
+        summary.max = summary.max.max(value);
+    }
+
+    pub fn counter(&self, name: &'static str, label: Option<&str>) -> u64 {
+        let key = (name, label.map(str::to_string));
+        self.inner.lock().unwrap().counters.get(&key).copied().unwrap_or_default()
+    }
+
+    pub fn gauge(&self, name: &'static str) -> Option<u64> {
+        self.inner.lock().unwrap().gauges.get(name).copied()
+    }
+
+    pub fn summary(&self, name: &'static str) -> Summary {
+        self.inner.lock().unwrap().summaries.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::default();
+        for ((name, label), value) in inner.counters.iter() {
+            match label {
+                Some(label) => writeln!(out, "{}{{reason=\"{}\"}} {}", name, label, value),
+                None => writeln!(out, "{} {}", name, value)
+            }.unwrap();
+        }
+        for (name, value) in inner.gauges.iter() {
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        for (name, summary) in inner.summaries.iter() {
+            writeln!(out, "{}_count {}\n{}_sum {}\n{}_max {}", name, summary.count, name, summary.sum, name, summary.max).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block;
+
+    #[test]
+    fn render() {
+        let metrics = Registry::default();
+        metrics.inc(BLOCKS_ACCEPTED);
+        metrics.inc(BLOCKS_ACCEPTED);
+        metrics.inc_with(BLOCKS_REJECTED, reason(&block::Error::BadSig));
+        metrics.inc_with(BLOCKS_REJECTED, reason(&block::Error::BadTxns(Vec::default())));
+        metrics.set(HEAD_ROUND, 7);
+        metrics.observe(VERIFY_MS, 3);
+        metrics.observe(VERIFY_MS, 5);
+        assert_eq!(metrics.counter(BLOCKS_ACCEPTED, None), 2);
+        assert_eq!(metrics.counter(BLOCKS_REJECTED, Some("BadTxns")), 1);
+        assert_eq!(metrics.counter(REORGS, None), 0);
+        assert_eq!(metrics.gauge(HEAD_ROUND), Some(7));
+        assert_eq!(metrics.summary(VERIFY_MS), Summary { count: 2, sum: 8, max: 5 });
+        assert_eq!(
+            metrics.render(),
+            "tam_blocks_accepted 2\n\
+             tam_blocks_rejected{reason=\"BadSig\"} 1\n\
+             tam_blocks_rejected{reason=\"BadTxns\"} 1\n\
+             tam_head_round 7\n\
+             tam_verify_ms_count 2\n\
+             tam_verify_ms_sum 8\n\
+             tam_verify_ms_max 5\n"
+        );
+    }
+}
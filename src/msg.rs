@@ -1,5 +1,6 @@
-use serde::{Serialize, Deserialize};
-use crate::{block, state, txn, account, app, merkle};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use bincode::Options;
+use crate::{block, state, txn, txpool, account};
 
 // Clients send a Message::X and recieve Result<ok::X, error::X>
 
@@ -8,7 +9,115 @@ pub enum Message {
     Txn(Vec<account::Signed<txn::Txn>>),
     Chain(Vec<block::Block>),
     Resync(),
-    Batch([u8; 32], u32)
+    Batch([u8; 32], u32),
+    Diff([u8; 32]),
+    Simulate(account::Signed<txn::Txn>),
+    Attest([u8; 32], account::PublicKey, Vec<u8>), // block hash, attester, BLS vote
+    Vote(account::Signed<block::Vote>),
+    Compact(block::Compact),
+    GetTxns([u8; 32], Vec<u32>), // block hash, txnseq positions
+    Headers([u8; 32]), // headers on the peer's chain after this block
+    Bodies(Vec<[u8; 32]>), // full blocks by hash
+    Checkpoint(), // the peer's latest checkpoint block, to sync state from
+    State([u8; 32], state::Part, Option<Vec<u8>>), // checkpoint block hash, trie, last key already had
+    Time(), // the peer's clock, to estimate how far off ours is
+    GetBlocks([u8; 32], u32), // up to this many blocks on the peer's chain, ending at this one
+    Handshake(Hello), // before either side takes the other as a neighbor
+    Peers(), // addresses the peer knows of, to find more neighbors
+    Addrs(Vec<String>) // addresses to pass on, gossiped as nodes turn up
+}
+
+pub const PROTOCOL_VERSION: u32 = 1; // bumped whenever peers on the last one couldn't follow us
+
+// Who a node is and what it's following, swapped in a handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub genesis: [u8; 32],
+    pub head_round: u32,
+    pub node_id: account::PublicKey,
+    #[serde(default)]
+    pub codecs: Vec<Codec>, // besides json, which everyone reads
+    #[serde(default)]
+    pub framed: Option<u16> // port to ask us over a kept connection on, see framed.rs
+}
+
+impl Hello {
+    // Whether a peer saying `theirs` can be a neighbor of ours. Where their head is doesn't matter.
+    pub fn check(&self, theirs: &Hello) -> Result<(), error::Handshake> {
+        if theirs.version != self.version {
+            return Err(error::Handshake::WrongVersion(self.version));
+        }
+        if theirs.genesis != self.genesis {
+            return Err(error::Handshake::WrongGenesis(self.genesis));
+        }
+        Ok(())
+    }
+
+    // What to talk to them in: the best we both read.
+    pub fn codec(&self, theirs: &Hello) -> Codec {
+        self.codecs.iter().filter(|codec| theirs.codecs.contains(codec)).max().copied().unwrap_or(Codec::Json)
+    }
+}
+
+// How messages and answers to them go over the wire to a peer. Json is what every peer reads,
+// and what gossip goes out in since the mesh passes it on to peers we've never shaken hands
+// with. Bincode is smaller and quicker, for peers that said they read it too. Answers are
+// made in json and recoded on their way out, see `recode`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Codec {
+    Json,
+    Bincode
+}
+
+pub const CODECS: [Codec; 1] = [Codec::Bincode]; // we read, for our hello
+
+// Message variants in declaration order, which is how bincode tells them apart.
+const VARIANTS: [&str; 19] = [
+    "Txn", "Chain", "Resync", "Batch", "Diff", "Simulate", "Attest", "Vote", "Compact",
+    "GetTxns", "Headers", "Bodies", "Checkpoint", "State", "Time", "GetBlocks", "Handshake",
+    "Peers", "Addrs"
+];
+
+impl Codec {
+    // Goes by the content type on an http body. Anything we don't know is json.
+    pub fn of(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some("application/octet-stream") => Codec::Bincode,
+            _ => Codec::Json
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::Bincode => "application/octet-stream"
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, x: &T) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(x).unwrap(),
+            Codec::Bincode => bincode::DefaultOptions::new().serialize(x).unwrap()
+        }
+    }
+
+    // None if it doesn't decode as a T, or for bincode would take more than `limit` bytes.
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8], limit: usize) -> Option<T> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).ok(),
+            Codec::Bincode => bincode::DefaultOptions::new().with_limit(limit as u64).deserialize(bytes).ok()
+        }
+    }
+
+    // Variant name of an encoded message, without decoding the rest.
+    pub fn variant(self, body: &[u8]) -> Option<&'static str> {
+        match self {
+            Codec::Json => variant(body).and_then(|name| VARIANTS.iter().find(|v| **v == name).copied()),
+            // Varint index, a single byte for anything under 251.
+            Codec::Bincode => VARIANTS.get(*body.first()? as usize).copied()
+        }
+    }
 }
 
 impl Message {
@@ -43,29 +152,241 @@ impl Message {
             None
         }
     }
+
+    pub fn diff(self) -> Option<[u8; 32]> {
+        if let Message::Diff(block_hash) = self {
+            Some(block_hash)
+        } else {
+            None
+        }
+    }
+
+    pub fn simulate(self) -> Option<account::Signed<txn::Txn>> {
+        if let Message::Simulate(stxn) = self {
+            Some(stxn)
+        } else {
+            None
+        }
+    }
+
+    pub fn attest(self) -> Option<([u8; 32], account::PublicKey, Vec<u8>)> {
+        if let Message::Attest(block_hash, from, vote) = self {
+            Some((block_hash, from, vote))
+        } else {
+            None
+        }
+    }
+
+    pub fn vote(self) -> Option<account::Signed<block::Vote>> {
+        if let Message::Vote(svote) = self {
+            Some(svote)
+        } else {
+            None
+        }
+    }
+
+    pub fn compact(self) -> Option<block::Compact> {
+        if let Message::Compact(compact) = self {
+            Some(compact)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_txns(self) -> Option<([u8; 32], Vec<u32>)> {
+        if let Message::GetTxns(block_hash, positions) = self {
+            Some((block_hash, positions))
+        } else {
+            None
+        }
+    }
+
+    pub fn headers(self) -> Option<[u8; 32]> {
+        if let Message::Headers(block_hash) = self {
+            Some(block_hash)
+        } else {
+            None
+        }
+    }
+
+    pub fn bodies(self) -> Option<Vec<[u8; 32]>> {
+        if let Message::Bodies(block_hashes) = self {
+            Some(block_hashes)
+        } else {
+            None
+        }
+    }
+
+    pub fn checkpoint(self) -> Option<()> {
+        if let Message::Checkpoint() = self {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    pub fn state(self) -> Option<([u8; 32], state::Part, Option<Vec<u8>>)> {
+        if let Message::State(block_hash, part, after) = self {
+            Some((block_hash, part, after))
+        } else {
+            None
+        }
+    }
+
+    pub fn time(self) -> Option<()> {
+        if let Message::Time() = self {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    pub fn get_blocks(self) -> Option<([u8; 32], u32)> {
+        if let Message::GetBlocks(block_hash, count) = self {
+            Some((block_hash, count))
+        } else {
+            None
+        }
+    }
+
+    pub fn handshake(self) -> Option<Hello> {
+        if let Message::Handshake(hello) = self {
+            Some(hello)
+        } else {
+            None
+        }
+    }
+
+    pub fn peers(self) -> Option<()> {
+        if let Message::Peers() = self {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    pub fn addrs(self) -> Option<Vec<String>> {
+        if let Message::Addrs(addrs) = self {
+            Some(addrs)
+        } else {
+            None
+        }
+    }
 }
 
 pub mod ok {
     use super::*;
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct Txn {}
+    // Some were taken. What became of each, by hash, rejected ones included.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub struct Txn {
+        #[serde(default)]
+        pub outcomes: Vec<(txn::Hash, Outcome)>
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub enum Outcome {
+        Included, // in the block we're building
+        Pooled, // waiting in our pool, or the rollup's, e.g. on an earlier nonce
+        Known, // we had it already
+        Dropped(txpool::Error), // valid, but the pool wouldn't take it
+        Rejected(txn::Error)
+    }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Chain {}
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct Resync { snap: block::Snap }
+    pub struct Resync { 
+        pub snap: block::Snap,
+        pub next: account::Signed<block::Header> // attests to the snap
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Batch {
+        pub len: u32, // txns in the whole block
+        pub txns: Vec<account::Signed<txn::Txn>>
+    }
+
+    // Every update is proven against state_commit, so a light client only needs the header.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Diff {
+        pub state_commit: [u8; 32],
+        pub updates: Vec<(state::Update, state::Proof)>
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Simulate {
+        pub round: u32, // run as if included in this round
+        pub simulation: state::Simulation
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Attest {}
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Vote {
+        pub finalized: bool // head is final now
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct GetTxns {
+        pub txns: Vec<account::Signed<txn::Txn>> // in the order asked for
+    }
+
+    // Oldest first. The first header of each new epoch comes with that epoch's validator set.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Headers {
+        pub headers: Vec<(account::Signed<block::Header>, Option<block::ValidatorSet>)>
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Bodies {
+        pub blocks: Vec<block::Block> // in the order asked for
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Checkpoint { pub checkpoint: block::Checkpoint }
+
+    // Fewer than node::STATE_CHUNK_SIZE means that's the end of the part.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct State {
+        pub updates: Vec<state::Update>
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Time {
+        pub timestamp: u64 // ms, by the peer's own clock
+    }
+
+    // Oldest first, ending at the block asked for. Fewer if the peer ran out of chain.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct GetBlocks {
+        pub blocks: Vec<block::Block>
+    }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct Batch { batch: merkle::Map<account::Signed<txn::Txn>> }
+    pub struct Handshake { pub hello: Hello }
+
+    // Up to peers::MAX_SHARED of them.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Peers { pub addrs: Vec<String> }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Addrs {
+        pub new: usize // we hadn't heard of, and pass on
+    }
 }
 
 pub mod error {
     use super::*;
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub enum Txn {}
+    pub enum Txn {
+        // Every one of them, so none were taken.
+        Rejected(Vec<(account::Signed<txn::Txn>, txn::Error)>),
+        Observer // keeps no pool
+    }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum Chain {
@@ -74,7 +395,10 @@ pub mod error {
         SmallTimestamp,
         BadPrev,
         TooShort,
-        AlreadyHave
+        TooManyUncles, // we already hold as many competing blocks for the round as we keep
+        AlreadyHave,
+        PastFinality, // forks before our last finalized block
+        Missing(Vec<u32>) // compact block positions we couldn't fill, ask with GetTxns
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -86,6 +410,167 @@ pub mod error {
     pub enum Batch {
         DoesntExist
     }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Diff {
+        DoesntExist
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Simulate {
+        BadTxn(txn::Error)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Attest {
+        Stale, // not for our head
+        NotCommittee,
+        BadVote
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum GetTxns {
+        DoesntExist,
+        BadPosition(u32)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Vote {
+        Stale, // not for our head
+        NotValidator,
+        BadSig
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Headers {
+        Unknown // not on our chain, or too far back to walk to
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Bodies {
+        TooMany,
+        DoesntExist([u8; 32])
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum GetBlocks {
+        TooMany,
+        DoesntExist([u8; 32])
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Checkpoint {
+        NotSaved
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum State {
+        DoesntExist, // not a checkpoint we still hold
+        BadPart
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Time {}
+
+    // With what we're on, so they can tell they've dialed the wrong net.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub enum Handshake {
+        WrongVersion(u32),
+        WrongGenesis([u8; 32])
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Peers {}
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Addrs {
+        TooMany
+    }
+
+    // Any message, from a peer we've banned for sending invalid blocks. Txns and blocks,
+    // from a peer sending them faster than its rate limit. Anything too big for its kind or
+    // that doesn't parse, before it's looked at. Anything, while our core is too far behind.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Refused {
+        Banned,
+        Throttled,
+        TooBig { limit: usize, actual: usize },
+        Malformed,
+        Busy
+    }
+
+    // Any message, once the node has started shutting down.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Closed {
+        ShuttingDown
+    }
+}
+
+// Caps on a message's serialized size, checked before it's parsed so one huge message can't
+// run us out of memory. By variant, which is read off the front of the json.
+pub const MAX_CHAIN_BYTES: usize = 16 << 20;
+pub const MAX_TXN_BYTES: usize = 1 << 20;
+pub const MAX_BATCH_BYTES: usize = 1 << 10; // just a hash and a number
+pub const MAX_MESSAGE_BYTES: usize = 1 << 20; // anything else
+pub const MAX_RESPONSE_BYTES: usize = 32 << 20; // answers from peers we asked
+
+// Variant name of a serialized message, `{"Chain": ...}` gives "Chain", without parsing the rest.
+pub fn variant(body: &[u8]) -> Option<&str> {
+    let rest = body.trim_ascii_start().strip_prefix(b"{")?.trim_ascii_start().strip_prefix(b"\"")?;
+    let end = rest.iter().take(32).position(|b| *b == b'"')?;
+    std::str::from_utf8(&rest[..end]).ok()
+}
+
+pub fn max_bytes(variant: &str) -> usize {
+    match variant {
+        "Chain" => MAX_CHAIN_BYTES,
+        "Txn" => MAX_TXN_BYTES,
+        "Batch" => MAX_BATCH_BYTES,
+        _ => MAX_MESSAGE_BYTES
+    }
+}
+
+// A message off the wire. Turned away unparsed if it's bigger than its variant allows.
+pub fn parse(body: &[u8]) -> Result<Message, error::Refused> {
+    parse_with(Codec::Json, body)
+}
+
+pub fn parse_with(codec: Codec, body: &[u8]) -> Result<Message, error::Refused> {
+    let limit = codec.variant(body).map_or(MAX_MESSAGE_BYTES, max_bytes);
+    if body.len() > limit {
+        return Err(error::Refused::TooBig { limit, actual: body.len() });
+    }
+    codec.decode(body, limit).ok_or(error::Refused::Malformed)
+}
+
+fn to_bincode<T: Serialize + DeserializeOwned>(resp: &str) -> Option<Vec<u8>> {
+    serde_json::from_str::<T>(resp).ok().map(|x| Codec::Bincode.encode(&x))
+}
+
+// Our json answer to a message of `variant`, in bincode. None if it isn't one of that
+// message's own results, like a refusal, which then goes back as json.
+pub fn recode(variant: &str, resp: &str) -> Option<Vec<u8>> {
+    match variant {
+        "Txn" => to_bincode::<Result<ok::Txn, error::Txn>>(resp),
+        "Chain" | "Compact" => to_bincode::<Result<ok::Chain, error::Chain>>(resp),
+        "Resync" => to_bincode::<Result<ok::Resync, error::Resync>>(resp),
+        "Batch" => to_bincode::<Result<ok::Batch, error::Batch>>(resp),
+        "Diff" => to_bincode::<Result<ok::Diff, error::Diff>>(resp),
+        "Simulate" => to_bincode::<Result<ok::Simulate, error::Simulate>>(resp),
+        "Attest" => to_bincode::<Result<ok::Attest, error::Attest>>(resp),
+        "Vote" => to_bincode::<Result<ok::Vote, error::Vote>>(resp),
+        "GetTxns" => to_bincode::<Result<ok::GetTxns, error::GetTxns>>(resp),
+        "Headers" => to_bincode::<Result<ok::Headers, error::Headers>>(resp),
+        "Bodies" => to_bincode::<Result<ok::Bodies, error::Bodies>>(resp),
+        "Checkpoint" => to_bincode::<Result<ok::Checkpoint, error::Checkpoint>>(resp),
+        "State" => to_bincode::<Result<ok::State, error::State>>(resp),
+        "Time" => to_bincode::<Result<ok::Time, error::Time>>(resp),
+        "GetBlocks" => to_bincode::<Result<ok::GetBlocks, error::GetBlocks>>(resp),
+        "Handshake" => to_bincode::<Result<ok::Handshake, error::Handshake>>(resp),
+        "Peers" => to_bincode::<Result<ok::Peers, error::Peers>>(resp),
+        "Addrs" => to_bincode::<Result<ok::Addrs, error::Addrs>>(resp),
+        _ => None
+    }
 }
 
 pub fn ser<T: Serialize>(x: &T) -> String {
@@ -99,3 +584,54 @@ pub fn deser<'a, T: Deserialize<'a>>(s: &'a str) -> T {
 
 pub type Response = String;
 pub type Bcasts = Vec<String>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        assert_eq!(variant(b" { \"Chain\": []}"), Some("Chain"));
+        assert_eq!(variant(b"[1, 2]"), None);
+        let time = ser(&Message::Time());
+        assert!(parse(time.as_bytes()).unwrap().time().is_some());
+        assert!(matches!(parse(b"{\"Time\": "), Err(error::Refused::Malformed)));
+        // Too big for a Batch is turned away without being parsed, junk and all
+        let mut batch = ser(&Message::Batch([0; 32], 0)).into_bytes();
+        batch.resize(MAX_BATCH_BYTES + 1, b'x');
+        assert!(matches!(
+            parse(&batch),
+            Err(error::Refused::TooBig { limit: MAX_BATCH_BYTES, actual }) if actual == MAX_BATCH_BYTES + 1
+        ));
+    }
+
+    #[test]
+    fn codecs() {
+        let (authority, gen) = block::genesis();
+        let next = block::Builder::new(&authority, 1, &gen).finalize(&authority);
+        let chain = Message::Chain(Vec::from([next.block.clone()]));
+        let (json, binary) = (Codec::Json.encode(&chain), Codec::Bincode.encode(&chain));
+        assert!(binary.len() * 2 < json.len());
+        assert_eq!(parse_with(Codec::Bincode, &binary).unwrap().chain(), Some(Vec::from([next.block.clone()])));
+        for message in [chain, Message::Time(), Message::Headers([0; 32]), Message::GetBlocks([0; 32], 3)] {
+            assert_eq!(Codec::Bincode.variant(&Codec::Bincode.encode(&message)), Codec::Json.variant(&Codec::Json.encode(&message)));
+        }
+        // Answers that are the message's own go back in bincode, refusals stay json
+        let answer = ser(&Ok::<_, error::Resync>(ok::Resync { snap: next.clone(), next: next.block.sheader.clone() }));
+        let recoded = recode("Resync", &answer).unwrap();
+        let decoded = Codec::Bincode.decode::<Result<ok::Resync, error::Resync>>(&recoded, MAX_RESPONSE_BYTES);
+        assert_eq!(decoded.unwrap().unwrap().snap, next);
+        assert_eq!(recode("Resync", &ser(&Err::<(), _>(error::Refused::Busy))), None);
+        // Only with a peer that reads it too
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            genesis: gen.block_hash,
+            head_round: 0,
+            node_id: authority.kp.public,
+            codecs: CODECS.to_vec(),
+            framed: None
+        };
+        assert_eq!(hello.codec(&hello), Codec::Bincode);
+        assert_eq!(hello.codec(&Hello { codecs: Vec::default(), ..hello.clone() }), Codec::Json);
+    }
+}
@@ -1,283 +1,2305 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::borrow::Cow;
 use std::mem;
-use core::array;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde::{Serialize, Deserialize};
-use tokio::sync::Mutex;
+use sha2::{Sha256, Digest};
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use rayon::prelude::*;
 use std::fmt::Debug;
 
 use crate::rollup;
 use crate::senator;
-use crate::{block, state, txn, account, app, msg};
+use crate::{block, state, txn, account, msg, archive, attest, validator, txpool, store, metrics, evidence, peers, ratelimit, genesis, behavior, liveness, export};
+use crate::deadline::Deadline;
 
 
-const MAX_FORK: u32 = 256;
-const MAX_PROP_TIME: u64 = 250; 
-const MAX_CLOCK_GAP: u64 = 300; 
+pub const FORK_WINDOW: u32 = 256; // rounds of snaps kept for reorgs, unless set otherwise
+pub const FINALITY_DEPTH: u32 = 64; // rounds behind head a block is final at, votes or not
+pub const FULL_ROUNDS: u32 = 8; // rounds behind head whose snaps keep a whole state, older ones keep deltas
+const STALL_TICKS: u64 = 5; // block times without a new head before we try to resync
+const POOL_FEED_SIZE: usize = 1024; // txns a slow pool subscriber can fall behind before it lags
+pub const MAX_SYNC_HEADERS: usize = 512; // headers handed out per request in header first sync
+pub const MAX_SYNC_BODIES: usize = 16; // blocks handed out per request
+pub const MAX_GET_BLOCKS: u32 = 64; // blocks handed out per backfill request
+pub const STATE_CHUNK_SIZE: usize = 1024; // trie entries handed out per request in state sync
+const SEEN_SIZE: usize = 1 << 14; // block and txn hashes remembered for not relaying twice
+const MAX_EVIDENCE: usize = 256; // caught misbehaviour waiting for us to lead
+const MAX_ORPHANS: usize = 64; // blocks held until their parent turns up
+const MAX_UNCLES: usize = 8; // competing blocks kept per round besides our own
+pub const COMMAND_QUEUE: usize = 1024; // commands waiting on the core before peers are turned away
 
 // compute and build on only one chain
 // have code to resync on a fork: if longer chain pops up process seq of blocks
 // to start resync just need to see longer valid header chain
 
+// Consensus state lives in a Core owned by its own thread, which runs commands against it in
+// the order they're sent. Node methods are commands, so there's no lock order to get wrong,
+// and slow block verification never holds up the tasks serving http. The queue of commands
+// is bounded: callers wait for room, peers are turned away. Only archive reads happen
+// outside, since they go over the network.
 #[derive(Debug)]
-pub struct Node { // TODO: acquire locks in total order so we never deadcock
-    pub kp: account::Keypair,
+pub struct Node {
+    kp: std::sync::RwLock<Arc<account::Keypair>>, // swapped by rotate_key
+    genesis: [u8; 32], // hash, for handshakes
+    pub framed: Option<u16>, // port we take framed asks on, if any. Goes in our hello
     pub nonce: Mutex<u32>, // own nonce. may be ahead of nonce on chain
-    pub snaps: [Mutex<HashMap<[u8; 32], block::Snap>>; MAX_FORK as usize], // self hash indexed.
-    pub head: Mutex<block::Snap>, // largest round valid block received in correct time window
-    pub opt_builder: Mutex<Option<block::Builder>>,
-    pub txpool: Mutex<BTreeSet<account::Signed<txn::Txn>>>, // cached txns
-    pub rollups: Mutex<BTreeSet<rollup::State>>, // rollups we are working on
-    pub reputations: Mutex<BTreeMap<senator::Id, ()>> // TODO this is a thing we should have doe
+    pub archive: Option<archive::Archive>, // cold storage for snaps leaving the fork window
+    pub pool_feed: broadcast::Sender<account::Signed<txn::Txn>>, // every txn we accept, for external builders
+    pub metrics: Arc<metrics::Registry>,
+    closing: AtomicBool, // set by shutdown, messages are turned away after
+    offenders: Mutex<peers::Peers>, // whoever's sent us invalid blocks, by address
+    book: Mutex<peers::Book>, // addresses of nodes we've heard of, for finding neighbors
+    limiter: Mutex<ratelimit::Limiter>, // txns and blocks each peer may still send, by address
+    commands: mpsc::Sender<Command>,
+    idle: std::sync::Mutex<Option<(Core, mpsc::Receiver<Command>)>> // until the first command starts it
+}
+
+type Command = Box<dyn FnOnce(&mut Core) + Send>;
+
+type Snaps = BTreeMap<u32, HashMap<[u8; 32], Kept>>; // by round, then self hash
+
+// A snap in the fork window. Only those near head or with no parent here keep their state.
+#[derive(Debug, Clone)]
+enum Kept {
+    Full(Box<block::Snap>),
+    Delta(Box<block::Delta>)
+}
+
+impl Kept {
+    fn block(&self) -> &block::Block {
+        match self {
+            Kept::Full(snap) => &snap.block,
+            Kept::Delta(delta) => &delta.block
+        }
+    }
+
+    fn block_hash(&self) -> [u8; 32] {
+        match self {
+            Kept::Full(snap) => snap.block_hash,
+            Kept::Delta(delta) => delta.block_hash
+        }
+    }
+
+    fn position(&self, hash: &txn::Hash) -> Option<u32> {
+        match self {
+            Kept::Full(snap) => snap.position(hash),
+            Kept::Delta(delta) => delta.position(hash)
+        }
+    }
+
+    fn epoch(&self) -> &block::ValidatorSet {
+        match self {
+            Kept::Full(snap) => &snap.epoch,
+            Kept::Delta(delta) => &delta.epoch
+        }
+    }
+
+    fn finalize(&mut self) {
+        match self {
+            Kept::Full(snap) => snap.finalized = true,
+            Kept::Delta(delta) => delta.finalized = true
+        }
+    }
+}
+
+// A snap with its state, rebuilt from the nearest whole ancestor if it was thinned.
+fn lookup<'a>(snaps: &'a Snaps, round: u32, block_hash: &[u8; 32]) -> Option<Cow<'a, block::Snap>> {
+    let (mut round, mut hash) = (round, *block_hash);
+    let mut deltas = Vec::default();
+    let mut state = loop {
+        match snaps.get(&round)?.get(&hash)? {
+            Kept::Full(snap) if deltas.is_empty() => return Some(Cow::Borrowed(snap)),
+            Kept::Full(snap) => break snap.state.clone(),
+            Kept::Delta(delta) => {
+                (round, hash) = (delta.block.sheader.msg.data.prev_round(), delta.block.sheader.msg.data.prev_hash);
+                deltas.push(delta);
+            }
+        }
+    };
+    let (target, between) = deltas.split_first()?;
+    for delta in between.iter().rev() {
+        state.replay(&delta.updates).ok()?;
+    }
+    target.fill(state).ok().map(Cow::Owned)
+}
+
+#[derive(Debug)]
+struct Core {
+    kp: Arc<account::Keypair>,
+    role: Role,
+    snaps: Snaps,
+    fork_window: u32,
+    pruning: Pruning,
+    finality_depth: u32,
+    head: block::Snap, // largest round valid block received in correct time window
+    opt_builder: Option<block::Builder>,
+    txpool: txpool::Pool, // cached txns
+    archive: Option<archive::Archive>,
+    builder_path: Option<PathBuf>, // where our in-progress block is kept across restarts
+    store: Option<store::Store>, // local copy of the fork window, reloaded on restart
+    best_round: u32, // highest round any peer has sent us, valid or not
+    last_resync: u64, // timestamp of last watchdog triggered resync
+    clock_offset: i64, // ms the network's clock is ahead of ours, going by our peers
+    pool_feed: broadcast::Sender<account::Signed<txn::Txn>>,
+    signed_round: u32, // highest round we've signed a block for. Never sign twice!
+    votes: BTreeMap<usize, Vec<u8>>, // committee votes for head, by committee index
+    finality_votes: BTreeMap<validator::Id, u32>, // slot weight behind head so far
+    finalized: (u32, [u8; 32]), // round and hash of the last finalized block
+    seen_blocks: Seen, // recently relayed, so peers don't pass them back and forth forever
+    seen_txns: Seen,
+    proposals: BTreeMap<(u32, u32, validator::Id), account::Signed<block::Header>>, // first header per round, proposal and signer
+    evidence: Vec<evidence::Evidence>, // for our next block, like the txpool
+    orphans: VecDeque<Vec<block::Block>>, // live chains that came in before their parent, oldest first
+    rollups: BTreeMap<rollup::Id, rollup::Working>, // rollups we sequence or senate for
+    reputations: BTreeMap<senator::Id, senator::Reputation>, // senators on rollups we work on
+    behavior: Option<Box<dyn behavior::Behavior>>, // misbehaving on purpose, for tests. None is honest
+    liveness: liveness::Liveness, // who's been proposing on our chain
+    metrics: Arc<metrics::Registry>
+}
+
+// Until the node, and with it the sender, is dropped.
+fn run(mut core: Core, mut commands: mpsc::Receiver<Command>) {
+    while let Some(command) = commands.blocking_recv() {
+        command(&mut core);
+    }
+}
+
+// Hashes we've relayed lately, dropping whichever was seen least recently once full.
+#[derive(Debug, Default)]
+struct Seen {
+    ticks: HashMap<[u8; 32], u64>,
+    by_tick: BTreeMap<u64, [u8; 32]>,
+    tick: u64
+}
+
+impl Seen {
+    // True the first time. Seeing it again makes it the most recent.
+    fn insert(&mut self, hash: [u8; 32]) -> bool {
+        self.tick += 1;
+        let fresh = match self.ticks.insert(hash, self.tick) {
+            Some(old) => {
+                self.by_tick.remove(&old);
+                false
+            },
+            None => true
+        };
+        self.by_tick.insert(self.tick, hash);
+        if self.by_tick.len() > SEEN_SIZE {
+            if let Some((_, oldest)) = self.by_tick.pop_first() {
+                self.ticks.remove(&oldest);
+            }
+        }
+        fresh
+    }
+}
+
+// Hand the builder every pool txn that can run on it, best paying first. Used nonces are
+// dropped. A sender waiting on a gap, or whose next txn fails, sits out the rest.
+fn feed(builder: &mut block::Builder, txpool: &mut txpool::Pool) {
+    while let Some((from, txn)) = txpool.best() {
+        match (txn.msg.nonce, builder.nonce(&from)) {
+            (nonce, Some(next)) if nonce < next => {
+                txpool.pop_best();
+            },
+            (nonce, Some(next)) if nonce == next => {
+                let txn = txpool.pop_best().expect("just looked");
+                if let Err((txn, _)) = builder.add(txn) {
+                    let _ = txpool.insert(txn);
+                    txpool.park(&from);
+                }
+            },
+            _ => txpool.park(&from)
+        }
+    }
+    txpool.unpark();
+    // Whatever a protocol txn put in ahead pushed out waits for another block.
+    for txn in builder.displaced.drain(..) {
+        let _ = txpool.insert(txn);
+    }
+}
+
+// What a node does besides following the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Validator, // proposes when it leads and votes with whatever slots it holds
+    Full, // checks and relays blocks and txns but never signs, so needs no stake
+    Observer // only follows the chain: no pool, nothing relayed or signed
+}
+
+// What besides the fork window decides when snaps are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pruning {
+    Window,
+    Finality // nothing before the last finalized block can be reorged to, so drop it too
+}
+
+// Settings for a node opened from a data directory, kept in config.json there unless
+// they come from a config file, see config.rs.
+// Anything left out takes its default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub role: Role,
+    pub fork_window: u32,
+    pub pruning: Pruning,
+    pub finality_depth: u32,
+    pub pool: txpool::Limits,
+    pub txn_rate: ratelimit::Limit,
+    pub block_rate: ratelimit::Limit
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            role: Role::Validator,
+            fork_window: FORK_WINDOW,
+            pruning: Pruning::Window,
+            finality_depth: FINALITY_DEPTH,
+            pool: txpool::Limits::default(),
+            txn_rate: ratelimit::TXNS,
+            block_rate: ratelimit::BLOCKS
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenError {
+    Io,
+    BadConfig,
+    Genesis(genesis::Error),
+    Store(store::Error)
+}
+
+// How far a txn has got. Ordered, so the furthest copy of it wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TxnStatus {
+    Pending, // in our pool
+    Forked, // in a block on a branch off our chain, which could still become it
+    Included, // on our chain, though it could still be reorged away
+    Finalized
+}
+
+#[derive(Debug, Clone)]
+pub struct TxnLookup {
+    pub stxn: account::Signed<txn::Txn>,
+    pub status: TxnStatus,
+    pub at: Option<(u32, [u8; 32], u32)> // round, block hash and position, once it's in one
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportError {
+    File(export::Error),
+    Missing([u8; 32]) // a block on our chain that's neither in the fork window nor archived
+}
+
+#[derive(Debug, Clone)]
+pub enum ImportError {
+    File(export::Error),
+    Chain(Box<msg::error::Chain>)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubmitError {
+    NotLeader,
+    BadTxn(account::Signed<txn::Txn>, txn::Error),
+    BadState
 }
 
 impl Node {
     pub fn new(kp: account::Keypair, genesis: block::Snap, nonce: u32) -> Self {
-        let snaps = array::from_fn(|i| {
-            let mut map = HashMap::default();
-            if i == 0 { 
-                map.insert(genesis.block_hash, genesis.clone());
-            }
-            Mutex::new(map)
-        });
+        let kp = Arc::new(kp);
+        let finalized = (genesis.block.sheader.msg.data.round, genesis.block_hash);
+        let snaps = BTreeMap::from([(finalized.0, HashMap::from([(genesis.block_hash, Kept::Full(Box::new(genesis.clone())))]))]);
+        let pool_feed = broadcast::channel(POOL_FEED_SIZE).0;
+        let metrics = Arc::new(metrics::Registry::default());
+        let core = Core {
+            kp: kp.clone(),
+            role: Role::Validator,
+            snaps,
+            fork_window: FORK_WINDOW,
+            pruning: Pruning::Window,
+            finality_depth: FINALITY_DEPTH,
+            head: genesis,
+            opt_builder: None,
+            txpool: txpool::Pool::default(),
+            archive: None,
+            builder_path: None,
+            store: None,
+            best_round: 0,
+            last_resync: 0,
+            clock_offset: 0,
+            pool_feed: pool_feed.clone(),
+            signed_round: 0,
+            votes: BTreeMap::default(),
+            finality_votes: BTreeMap::default(),
+            finalized,
+            seen_blocks: Seen::default(),
+            seen_txns: Seen::default(),
+            proposals: BTreeMap::default(),
+            evidence: Vec::default(),
+            orphans: VecDeque::default(),
+            rollups: BTreeMap::default(),
+            reputations: BTreeMap::default(),
+            behavior: None,
+            liveness: liveness::Liveness::default(),
+            metrics: metrics.clone()
+        };
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE);
         Self {
-            kp,
+            kp: std::sync::RwLock::new(kp),
+            genesis: finalized.1,
+            framed: None,
             nonce: Mutex::new(nonce),
-            snaps,
-            head: Mutex::new(genesis),
-            opt_builder: Mutex::new(None),
-            txpool: Mutex::new(BTreeSet::default())
+            archive: None,
+            pool_feed,
+            metrics,
+            closing: AtomicBool::new(false),
+            offenders: Mutex::new(peers::Peers::default()),
+            book: Mutex::new(peers::Book::default()),
+            limiter: Mutex::new(ratelimit::Limiter::default()),
+            commands,
+            idle: std::sync::Mutex::new(Some((core, receiver)))
+        }
+    }
+
+    // Core before it's started, for the with_ builders.
+    fn idle_core(&mut self) -> &mut Core {
+        &mut self.idle.get_mut().unwrap().as_mut().expect("builders run before the node is used").0
+    }
+
+    // Run f against consensus state once everything sent before it has run.
+    async fn call<R: Send + 'static>(&self, f: impl FnOnce(&mut Core) -> R + Send + 'static) -> R {
+        if let Some((core, receiver)) = self.idle.lock().unwrap().take() {
+            // Commands spawn tasks, like archiving, so the thread needs the runtime.
+            let runtime = tokio::runtime::Handle::current();
+            std::thread::spawn(move || {
+                let _guard = runtime.enter();
+                run(core, receiver);
+            });
+        }
+        let (tx, rx) = oneshot::channel();
+        let command: Command = Box::new(move |core| {
+            let _ = tx.send(f(core));
+        });
+        if self.commands.send(command).await.is_err() {
+            panic!("core runs as long as the node");
+        }
+        rx.await.expect("core runs as long as the node")
+    }
+
+    // A node kept in `dir`: genesis.json, config.json, the store and our in-progress block.
+    // On first run the directory is made, with default settings and, unless a genesis file
+    // from the ceremony was put there, a fresh chain that we're the authority of.
+    pub fn open(dir: &Path, kp: account::Keypair) -> Result<Self, OpenError> {
+        fs::create_dir_all(dir).map_err(|_| OpenError::Io)?;
+        let config_path = dir.join("config.json");
+        let config: Config = match fs::read(&config_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|_| OpenError::BadConfig)?,
+            Err(_) => {
+                let config = Config::default();
+                fs::write(&config_path, serde_json::to_vec_pretty(&config).unwrap()).map_err(|_| OpenError::Io)?;
+                config
+            }
+        };
+        Self::open_with(dir, kp, config)
+    }
+
+    // As open, with settings from elsewhere rather than config.json.
+    pub fn open_with(dir: &Path, kp: account::Keypair, config: Config) -> Result<Self, OpenError> {
+        fs::create_dir_all(dir).map_err(|_| OpenError::Io)?;
+        let genesis_path = dir.join("genesis.json");
+        let genesis = if genesis_path.exists() {
+            genesis::read(&genesis_path).map_err(OpenError::Genesis)?
+        } else {
+            let snap = genesis::build(&kp, &genesis::Config::new(state::timestamp()));
+            genesis::write(&genesis_path, &snap).map_err(OpenError::Genesis)?;
+            snap
+        };
+        if config.fork_window == 0 || config.finality_depth == 0 {
+            return Err(OpenError::BadConfig);
+        }
+        // The store takes it from here if we've run before.
+        let id: account::Id = Sha256::digest(kp.kp.public.to_bytes()).into();
+        let nonce = genesis.state.accounts.get(&id).ok().flatten().map_or(0, |data| data.nonce);
+        let store = store::Store::open(dir.to_path_buf()).map_err(OpenError::Store)?;
+        Node::new(kp, genesis, nonce)
+            .with_role(config.role)
+            .with_fork_window(config.fork_window, config.pruning)
+            .with_finality_depth(config.finality_depth)
+            .with_pool_limits(config.pool)
+            .with_rate_limits(config.txn_rate, config.block_rate)
+            .with_builder_path(dir.join("builder.json"))
+            .with_store(store)
+            .map_err(OpenError::Store)
+    }
+
+    pub fn with_archive(mut self, archive: archive::Archive) -> Self {
+        self.idle_core().archive = Some(archive.clone());
+        self.archive = Some(archive);
+        self
+    }
+
+    // Save accepted snaps under `store` and carry on from whatever's already there.
+    // Only for a fresh node: anything it took on since genesis is replaced.
+    pub fn with_store(mut self, store: store::Store) -> Result<Self, store::Error> {
+        let mut nonce = *self.nonce.get_mut();
+        let core = self.idle_core();
+        let genesis = core.head.clone();
+        match store.meta() {
+            None => {
+                store.put_snap(&genesis)?;
+                let at = (genesis.block.sheader.msg.data.round, genesis.block_hash);
+                store.init(store::Meta { genesis: genesis.block_hash, head: at, finalized: at, signed_round: 0, nonce })?;
+            },
+            Some(meta) => {
+                if meta.genesis != genesis.block_hash {
+                    return Err(store::Error::WrongChain);
+                }
+                for snap in store.snaps()? {
+                    core.keep(snap);
+                }
+                let head = core.snap(meta.head.0, &meta.head.1)
+                    .map(Cow::into_owned)
+                    .ok_or(store::Error::NoHead)?;
+                // Our own txns may have gone in since we last started, or still be on their way.
+                let id: account::Id = Sha256::digest(core.kp.kp.public.to_bytes()).into();
+                if let Ok(Some(data)) = head.state.accounts.get(&id) {
+                    nonce = nonce.max(data.nonce);
+                }
+                nonce = nonce.max(meta.nonce);
+                core.head = head;
+                core.finalized = meta.finalized;
+                core.signed_round = meta.signed_round;
+                for txn in store.pool()? {
+                    let _ = core.txpool.insert(txn);
+                }
+            }
+        }
+        core.store = Some(store);
+        *self.nonce.get_mut() = nonce;
+        Ok(self)
+    }
+
+    // Keeps whatever's in the pool already, as far as the limits allow.
+    pub fn with_pool_limits(mut self, limits: txpool::Limits) -> Self {
+        let txpool = &mut self.idle_core().txpool;
+        for txn in mem::replace(txpool, txpool::Pool::new(limits)).iter() {
+            let _ = txpool.insert(txn.clone());
+        }
+        self
+    }
+
+    // Rounds of snaps kept behind head: the deepest reorg we can follow.
+    pub fn with_fork_window(mut self, rounds: u32, pruning: Pruning) -> Self {
+        assert!(rounds > 0, "the fork window has to hold head");
+        let core = self.idle_core();
+        core.fork_window = rounds;
+        core.pruning = pruning;
+        self
+    }
+
+    // Rounds behind head past which nothing is reorged, when the finality votes don't come in sooner.
+    pub fn with_finality_depth(mut self, rounds: u32) -> Self {
+        assert!(rounds > 0, "head itself can still be reorged");
+        self.idle_core().finality_depth = rounds;
+        self
+    }
+
+    pub fn with_command_queue(mut self, size: usize) -> Self {
+        let (commands, receiver) = mpsc::channel(size);
+        self.commands = commands;
+        self.idle.get_mut().unwrap().as_mut().expect("builders run before the node is used").1 = receiver;
+        self
+    }
+
+    pub fn with_rate_limits(mut self, txns: ratelimit::Limit, blocks: ratelimit::Limit) -> Self {
+        *self.limiter.get_mut() = ratelimit::Limiter::new(txns, blocks);
+        self
+    }
+
+    // Only for testing how other nodes cope with a byzantine one.
+    pub fn with_behavior(mut self, behavior: impl behavior::Behavior + 'static) -> Self {
+        self.idle_core().behavior = Some(Box::new(behavior));
+        self
+    }
+
+    pub fn with_framed(mut self, port: u16) -> Self {
+        self.framed = Some(port);
+        self
+    }
+
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.idle_core().role = role;
+        self
+    }
+
+    pub fn with_builder_path(mut self, path: PathBuf) -> Self {
+        self.idle_core().builder_path = Some(path);
+        self
+    }
+
+    // Stop taking messages and save what a restart would otherwise lose: the pool, and our
+    // block if we're leader. Anything already sent to the node, like a tick, finishes first.
+    pub async fn shutdown(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        self.call(Core::flush).await
+    }
+
+    pub fn kp(&self) -> Arc<account::Keypair> {
+        self.kp.read().unwrap().clone()
+    }
+
+    // Sign as `kp` from here on, say once the txn moving our stake to it is in. Takes effect
+    // between blocks: whatever we were building under the old key goes back to the pool and
+    // the leader check is rerun. Our nonce starts over from the new account's on head.
+    pub async fn rotate_key(&self, kp: account::Keypair) {
+        let kp = Arc::new(kp);
+        let mut nonce = self.nonce.lock().await;
+        let core_kp = kp.clone();
+        *nonce = self.call(move |core| core.rotate_key(core_kp)).await;
+        *self.kp.write().unwrap() = kp;
+    }
+
+    // Take our next nonce for a txn of our own.
+    pub async fn next_nonce(&self) -> u32 {
+        let mut nonce = self.nonce.lock().await;
+        self.take_nonce(&mut nonce).await
+    }
+
+    // A txn of our own signed with our next nonce. The key is read under the nonce lock, so
+    // a rotation can't come in between.
+    pub async fn sign_own(&self, sign: impl FnOnce(&account::Keypair, u32) -> account::Signed<txn::Txn>) ->
+        account::Signed<txn::Txn>
+    {
+        let mut nonce = self.nonce.lock().await;
+        let nonce = self.take_nonce(&mut nonce).await;
+        sign(&self.kp(), nonce)
+    }
+
+    // It's saved before it's handed out. Never behind head, as an account opened after we
+    // started doesn't begin at zero.
+    async fn take_nonce(&self, nonce: &mut u32) -> u32 {
+        let ours = *nonce;
+        let taken = self.call(move |core| {
+            let taken = ours.max(core.own_nonce());
+            core.persist(|meta| meta.nonce = meta.nonce.max(taken + 1));
+            taken
+        }).await;
+        *nonce = taken + 1;
+        taken
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    pub async fn get_head(&self) -> block::Snap {
+        self.call(|core| core.head.clone()).await
+    }
+
+    // A snap in the fork window or the archive.
+    async fn snap_at(&self, round: u32, block_hash: &[u8; 32]) -> Option<block::Snap> {
+        let hash = *block_hash;
+        let opt_snap = self.call(move |core| core.snap(round, &hash).map(Cow::into_owned)).await;
+        match (opt_snap, &self.archive) {
+            (None, Some(archive)) => archive.get_snap(block_hash).await.ok().flatten(),
+            (opt_snap, _) => opt_snap
+        }
+    }
+
+    // A header and the set after it, in the fork window or the archive. Thinned snaps aren't rebuilt.
+    async fn header_at(&self, round: u32, block_hash: &[u8; 32]) -> Option<(account::Signed<block::Header>, block::ValidatorSet)> {
+        let hash = *block_hash;
+        let opt_header = self.call(move |core| core.header(round, &hash)).await;
+        match (opt_header, &self.archive) {
+            (None, Some(archive)) => archive.get_snap(block_hash).await.ok().flatten().map(|snap| (snap.block.sheader, snap.epoch)),
+            (opt_header, _) => opt_header
+        }
+    }
+
+    // A block in the fork window or the archive.
+    async fn block_at(&self, round: u32, block_hash: &[u8; 32]) -> Option<block::Block> {
+        let hash = *block_hash;
+        let opt_block = self.call(move |core| core.block(round, &hash).cloned()).await;
+        match (opt_block, &self.archive) {
+            (None, Some(archive)) => archive.get_block(block_hash).await.ok().flatten(),
+            (opt_block, _) => opt_block
+        }
+    }
+
+    // Walk back from head through stored snaps. Newest first.
+    pub async fn recent_blocks(&self, count: usize, deadline: &Deadline) -> Vec<block::Block> {
+        let mut block = self.call(|core| core.head.block.clone()).await;
+        let mut blocks = Vec::default();
+        while blocks.len() < count && !deadline.expired() {
+            let round = block.sheader.msg.data.round;
+            let prev_round = block.sheader.msg.data.prev_round();
+            let prev_hash = block.sheader.msg.data.prev_hash;
+            blocks.push(block);
+            if round == 0 { break; }
+            let opt_prev = self.call(move |core| core.block(prev_round, &prev_hash).cloned()).await;
+            block = match (opt_prev, &self.archive) {
+                (Some(prev), _) => prev,
+                // Past the fork window, fetch lazily from cold storage.
+                (None, Some(archive)) => match archive.get_block(&prev_hash).await {
+                    Ok(Some(prev)) => prev,
+                    _ => break
+                },
+                (None, None) => break
+            };
+        }
+        blocks
+    }
+
+    // Any snap still inside the fork window.
+    pub async fn find_snap(&self, block_hash: &[u8; 32]) -> Option<block::Snap> {
+        let hash = *block_hash;
+        self.call(move |core| core.find_snap(&hash).map(Cow::into_owned)).await
+    }
+
+    // A txn anywhere in the fork window, by the txn index of each block it's in, or in our
+    // pool. Past the fork window it's finalized, but we no longer know of it.
+    pub async fn lookup_txn(&self, hash: &txn::Hash) -> Option<TxnLookup> {
+        let hash = *hash;
+        self.call(move |core| {
+            let chain = core.chain_hashes();
+            let mut found: Option<TxnLookup> = None;
+            for (round, snaps) in core.snaps.iter() {
+                for (block_hash, kept) in snaps {
+                    let Some(pos) = kept.position(&hash) else { continue };
+                    let status = match chain.contains(block_hash) {
+                        true if *round <= core.finalized.0 => TxnStatus::Finalized,
+                        true => TxnStatus::Included,
+                        false => TxnStatus::Forked
+                    };
+                    if found.as_ref().is_some_and(|found| found.status >= status) {
+                        continue;
+                    }
+                    if let Ok(Some(stxn)) = kept.block().txnseq.get(pos) {
+                        found = Some(TxnLookup { stxn: stxn.clone(), status, at: Some((*round, *block_hash, pos)) });
+                    }
+                }
+            }
+            found.or_else(|| {
+                let stxn = core.txpool.iter().find(|stxn| txn::hash(stxn) == hash)?;
+                Some(TxnLookup { stxn: stxn.clone(), status: TxnStatus::Pending, at: None })
+            })
+        }).await
+    }
+
+    // timestamp tick!
+    // may return block to prop
+    // time can be a little bit after exact tick moment
+    pub async fn tick(&self) -> msg::Bcasts {
+        self.call(Core::tick).await
+    }
+
+    // Current pool contents followed by a live feed of new txns.
+    pub async fn subscribe_pool(&self) -> (Vec<account::Signed<txn::Txn>>, broadcast::Receiver<account::Signed<txn::Txn>>) {
+        self.call(|core| {
+            let mut pending: Vec<_> = core.txpool.iter().cloned().collect();
+            if let Some(ref builder) = core.opt_builder {
+                pending.extend(builder.txnseq.iter().cloned());
+            }
+            (pending, core.pool_feed.subscribe())
+        }).await
+    }
+
+    // Swap our own block for one an external builder put together.
+    // Only allowed while we're leader; it's signed and sent out on the next tick as usual.
+    pub async fn submit_block(&self, txns: Vec<account::Signed<txn::Txn>>, state_commit: Option<[u8; 32]>) -> 
+        Result<(), SubmitError> 
+    {
+        self.call(move |core| core.submit_block(txns, state_commit)).await
+    }
+
+    // Watchdog: head hasn't advanced for STALL_TICKS block times while peers
+    // have shown us higher rounds. Fires at most once per stall period.
+    pub async fn stalled(&self) -> bool {
+        self.call(Core::stalled).await
+    }
+
+    // Rollup txns go to that rollup's pool rather than ours, and aren't relayed. Only the
+    // sequencer pools them, since only its headers take them in.
+    pub async fn receive_txns(&self, txns: Vec<account::Signed<txn::Txn>>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        let (rollup_txns, txns): (Vec<_>, Vec<_>) = txns.into_iter().partition(|txn| txn.msg.opt_rollup.is_some());
+        // Signatures are the slow part, so they're checked here in parallel rather than in the
+        // core, which is left only applying what passed and isn't held up while we lead.
+        let (txns, forged): (Vec<_>, Vec<_>) = tokio::task::spawn_blocking(move || {
+            txns.into_par_iter().partition(|txn| txn.verify())
+        }).await.expect("signature checks don't panic");
+        let mut rejected: Vec<_> = forged.into_iter().map(|txn| (txn, txn::Error::BadSig)).collect();
+        self.call(move |core| {
+            let mut pooled = Vec::default();
+            for txn in rollup_txns {
+                match core.pool_rollup(txn.clone()) {
+                    Ok(()) => pooled.push((txn::hash(&txn), msg::ok::Outcome::Pooled)),
+                    Err(err) => rejected.push((txn, err))
+                }
+            }
+            core.receive_txns(txns, rejected, pooled)
+        }).await
+    }
+
+    // Start keeping a rollup's state, as of its last header. We have to be its sequencer or
+    // one of its senators, and the state has to match the hash on chain.
+    pub async fn track_rollup(&self, id: rollup::Id, state: rollup::State) -> bool {
+        let me: senator::Id = Sha256::digest(self.kp().kp.public.to_bytes()).into();
+        self.call(move |core| {
+            let Some(data) = core.head.state.rollups.get(&id).ok().flatten().cloned() else {
+                return false;
+            };
+            let sequencer = data.sequencer.id == me;
+            if !sequencer && !data.senators.iter().any(|senator| senator.id == me) {
+                return false;
+            }
+            if state.accounts.commit() != data.state_hash {
+                return false;
+            }
+            core.rollups.insert(id, rollup::Working::new(sequencer, state));
+            core.watch(&data, me);
+            true
+        }).await
+    }
+
+    pub async fn rollup(&self, id: rollup::Id) -> Option<rollup::Working> {
+        self.call(move |core| core.rollups.get(&id).cloned()).await
+    }
+
+    // How often a validator's produced when due, over the last liveness::WINDOW proposals on our chain.
+    pub async fn uptime(&self, id: validator::Id) -> Option<liveness::Uptime> {
+        self.call(move |core| core.liveness.uptime(&id)).await
+    }
+
+    // Who was due and whether they produced, newest first.
+    pub async fn recent_proposals(&self, count: usize) -> Vec<liveness::Proposal> {
+        self.call(move |core| core.liveness.recent(count)).await
+    }
+
+    pub async fn reputations(&self) -> BTreeMap<senator::Id, senator::Reputation> {
+        self.call(|core| core.reputations.clone()).await
+    }
+
+    // A challenge against one of the senators we watch went against them.
+    pub async fn challenge_lost(&self, id: senator::Id) {
+        self.call(move |core| {
+            if let Some(rep) = core.reputations.get_mut(&id) {
+                rep.challenges_lost += 1;
+            }
+        }).await
+    }
+
+    // Signed Oppose votes for senators we watch that have fallen below our thresholds, once
+    // each. They only count for validators, and lock our stake until we Support them again.
+    pub async fn oppositions(&self) -> Vec<account::Signed<txn::Txn>> {
+        let failing = self.call(|core| {
+            core.reputations.iter_mut()
+                .filter(|(_, rep)| !rep.opposed && rep.failing())
+                .map(|(id, rep)| { rep.opposed = true; *id })
+                .collect::<Vec<_>>()
+        }).await;
+        let mut txns = Vec::default();
+        for id in failing {
+            txns.push(self.sign_own(|kp, nonce| kp.oppose(id, nonce)).await);
+        }
+        txns
+    }
+
+    // Our own txns for this tick: a header for each rollup we sequence that has anything
+    // pooled, then any oppositions.
+    pub async fn upkeep(&self) -> Vec<account::Signed<txn::Txn>> {
+        let sequencing: Vec<_> = self.call(|core| {
+            core.rollups.iter()
+                .filter(|(_, working)| working.sequencer)
+                .map(|(id, _)| *id)
+                .collect()
+        }).await;
+        let mut txns = Vec::default();
+        for id in sequencing {
+            txns.extend(self.seal_rollup(id).await);
+        }
+        txns.extend(self.oppositions().await);
+        txns
+    }
+
+    pub async fn untrack_rollup(&self, id: rollup::Id) {
+        self.call(move |core| { core.rollups.remove(&id); }).await
+    }
+
+    // As sequencer, a signed Header with whatever's pooled for the rollup since the last one,
+    // or None if there's nothing to seal.
+    pub async fn seal_rollup(&self, id: rollup::Id) -> Option<account::Signed<txn::Txn>> {
+        let (txns, state_hash) = self.call(move |core| {
+            let working = core.rollups.get_mut(&id)
+                .filter(|working| working.sequencer && working.pool.len() > working.sealed)?;
+            Some(working.seal())
+        }).await?;
+        Some(self.sign_own(|kp, nonce| kp.sign_txn(txn::Txn {
+            payload: txn::Payload::Header(id, txns, state_hash),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        })).await)
+    }
+
+    pub async fn receive_chain(&self, chain: Vec<block::Block>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        self.call(move |core| core.receive_chain(chain)).await
+    }
+
+    // Blocks for headers already checked with `block::verify_headers`, however old.
+    pub async fn sync_chain(&self, chain: Vec<block::Block>) -> Result<msg::Bcasts, msg::error::Chain> {
+        self.call(move |core| core.process_chain(chain, false)).await
+    }
+
+    // Blocks on our chain from from_round to to_round, oldest first, to a file for
+    // import_chain. Returns how many were written.
+    pub async fn export_chain(&self, path: &Path, from_round: u32, to_round: u32) -> Result<usize, ExportError> {
+        let mut block = self.call(|core| core.head.block.clone()).await;
+        let mut blocks = Vec::default();
+        loop {
+            let (round, prev_hash) = (block.sheader.msg.data.round, block.sheader.msg.data.prev_hash);
+            if round < from_round {
+                break;
+            }
+            if round <= to_round {
+                blocks.push(block);
+            }
+            if round == 0 || round == from_round {
+                break;
+            }
+            block = self.find_block(&prev_hash).await.ok_or(ExportError::Missing(prev_hash))?;
+        }
+        blocks.reverse();
+        export::write(path, &blocks).map_err(ExportError::File)?;
+        Ok(blocks.len())
+    }
+
+    // Replay a file from export_chain through the same checks as blocks synced off a peer.
+    // Ones we already have are skipped. Returns how many were new.
+    pub async fn import_chain(&self, path: &Path) -> Result<usize, ImportError> {
+        let blocks = export::read(path).map_err(ImportError::File)?;
+        let mut imported = 0;
+        for chunk in blocks.chunks(MAX_GET_BLOCKS as usize) {
+            let chunk = chunk.to_vec();
+            imported += self.call(move |core| {
+                let fresh = chunk.iter()
+                    .filter(|block| core.block(block.sheader.msg.data.round, &block.sheader.msg.hash()).is_none())
+                    .count();
+                match core.process_chain(chunk, false) {
+                    Ok(_) => Ok(fresh),
+                    Err(msg::error::Chain::AlreadyHave) => Ok(0),
+                    Err(e) => Err(ImportError::Chain(Box::new(e)))
+                }
+            }).await?;
+        }
+        Ok(imported)
+    }
+
+    // Headers of every valid block we've seen for round other than the one on our chain.
+    pub async fn uncles(&self, round: u32) -> Vec<account::Signed<block::Header>> {
+        self.call(move |core| core.uncles(round)).await
+    }
+
+    // Headers on our chain after `after`, for a peer catching up. Walks back from head through
+    // the fork window then the archive, but no further than one response's worth so a peer
+    // can't have us walk the whole chain. Peers further behind checkpoint sync instead.
+    pub async fn receive_headers(&self, after: [u8; 32]) -> (msg::Response, msg::Bcasts) {
+        let (mut round, mut hash) = self.call(|core| (core.head.block.sheader.msg.data.round, core.head.block_hash)).await;
+        let mut headers: Vec<(account::Signed<block::Header>, Option<block::ValidatorSet>)> = Vec::default();
+        let result = loop {
+            if hash == after {
+                headers.reverse();
+                break Ok(msg::ok::Headers { headers });
+            }
+            if headers.len() == MAX_SYNC_HEADERS {
+                break Err(msg::error::Headers::Unknown);
+            }
+            let Some((sheader, set)) = self.header_at(round, &hash).await else {
+                break Err(msg::error::Headers::Unknown);
+            };
+            // If it ends an epoch, the set its child is checked under.
+            let next_set = (set.epoch != sheader.msg.data.epoch()).then_some(set);
+            if let Some(child) = headers.last_mut() {
+                child.1 = next_set;
+            }
+            if round == 0 {
+                break Err(msg::error::Headers::Unknown);
+            }
+            (round, hash) = (sheader.msg.data.prev_round(), sheader.msg.data.prev_hash);
+            headers.push((sheader, None));
+        };
+        (msg::ser(&result), Vec::default())
+    }
+
+    pub async fn receive_bodies(&self, block_hashes: Vec<[u8; 32]>) -> (msg::Response, msg::Bcasts) {
+        if block_hashes.len() > MAX_SYNC_BODIES {
+            return (msg::ser(&Err::<msg::ok::Bodies, _>(msg::error::Bodies::TooMany)), Vec::default());
+        }
+        let mut blocks = Vec::default();
+        for block_hash in block_hashes {
+            match self.find_block(&block_hash).await {
+                Some(block) => blocks.push(block),
+                None => return (
+                    msg::ser(&Err::<msg::ok::Bodies, _>(msg::error::Bodies::DoesntExist(block_hash))), 
+                    Vec::default()
+                )
+            }
+        }
+        (msg::ser(&Ok::<_, msg::error::Bodies>(msg::ok::Bodies { blocks })), Vec::default())
+    }
+
+    // A block on any branch in the fork window, or in the archive.
+    pub async fn find_block(&self, block_hash: &[u8; 32]) -> Option<block::Block> {
+        let hash = *block_hash;
+        match (self.call(move |core| core.find_block(&hash).cloned()).await, &self.archive) {
+            (Some(block), _) => Some(block),
+            (None, Some(archive)) => archive.get_block(block_hash).await.ok().flatten(),
+            (None, None) => None
+        }
+    }
+
+    // The blocks up to and including `block_hash`, for a peer whose chain didn't connect to theirs.
+    pub async fn receive_get_blocks(&self, block_hash: [u8; 32], count: u32) -> (msg::Response, msg::Bcasts) {
+        if count > MAX_GET_BLOCKS {
+            return (msg::ser(&Err::<msg::ok::GetBlocks, _>(msg::error::GetBlocks::TooMany)), Vec::default());
+        }
+        let Some(last) = self.find_block(&block_hash).await else {
+            return (msg::ser(&Err::<msg::ok::GetBlocks, _>(msg::error::GetBlocks::DoesntExist(block_hash))), Vec::default());
+        };
+        let mut blocks = Vec::from([last]);
+        while blocks.len() < count as usize {
+            let data = &blocks.last().expect("starts with one").sheader.msg.data;
+            if data.round == 0 {
+                break;
+            }
+            let Some(prev) = self.find_block(&data.prev_hash).await else {
+                break;
+            };
+            blocks.push(prev);
+        }
+        blocks.reverse();
+        (msg::ser(&Ok::<_, msg::error::GetBlocks>(msg::ok::GetBlocks { blocks })), Vec::default())
+    }
+
+    // Ancestors fetched for a chain we're holding, run in together with it.
+    pub async fn backfill(&self, ancestors: Vec<block::Block>) -> (msg::Response, msg::Bcasts) {
+        self.call(move |core| core.backfill(ancestors)).await
+    }
+
+    // Rebuild a compact block from the pool plus any txns fetched for it, then handle it as a chain.
+    pub async fn receive_compact(&self, compact: block::Compact, fetched: Vec<account::Signed<txn::Txn>>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        self.call(move |core| core.receive_compact(compact, fetched)).await
+    }
+
+    // Txns of a block we hold, for a peer filling in a compact block.
+    pub async fn receive_get_txns(&self, block_hash: [u8; 32], positions: Vec<u32>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        let result = self.call(move |core| match core.find_block(&block_hash) {
+            None => Err(msg::error::GetTxns::DoesntExist),
+            Some(block) => {
+                let txns: Vec<_> = block.txnseq.iter().collect();
+                positions.iter()
+                    .map(|pos| txns.get(*pos as usize).map(|txn| (*txn).clone()).ok_or(msg::error::GetTxns::BadPosition(*pos)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|txns| msg::ok::GetTxns { txns })
+            }
+        }).await;
+        (msg::ser(&result), Vec::default())
+    }
+
+    pub async fn receive_batch(&self, block_hash: [u8; 32], batch_no: u32) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        let result = self.call(move |core| core.find_block(&block_hash)
+            .and_then(|block| block.batch(batch_no).map(|txns| msg::ok::Batch { len: block.txnseq.len(), txns }))
+            .ok_or(msg::error::Batch::DoesntExist)
+        ).await;
+        (msg::ser(&result), Vec::default())
+    }
+
+    // Fast sync off a trusted peer's snaps extending our head: their states are taken on
+    // spot checks and every block is replayed in full in the background. The handle gives
+    // the first block whose replay failed, if any did.
+    pub async fn fast_sync(&self, chain: Vec<block::Snap>) -> 
+        Result<JoinHandle<Result<(), ([u8; 32], block::Error)>>, msg::error::Chain> 
+    {
+        let audits = self.call(move |core| {
+            let mut prev = core.head.clone();
+            let mut audits = Vec::default();
+            for theirs in chain {
+                if theirs.block.sheader.msg.data.prev_hash != prev.block_hash {
+                    return Err(msg::error::Chain::BadPrev);
+                }
+                let snap = block::Verifier::new(&prev, theirs.block)
+                    .with_trusted_state(theirs.state)
+                    .finalize()
+                    .map_err(|(b, e)| msg::error::Chain::BadBlock(b, e))?;
+                audits.push((prev, snap.block.clone()));
+                prev = snap.clone();
+                core.add_snap(snap);
+            }
+            Ok(audits)
+        }).await?;
+        Ok(tokio::task::spawn_blocking(move || {
+            for (prev, block) in audits {
+                let hash = block.sheader.msg.hash();
+                block::Verifier::new(&prev, block).finalize().map_err(|(_, e)| (hash, e))?;
+            }
+            Ok(())
+        }))
+    }
+
+    // Our latest checkpoint, for a peer syncing state instead of replaying every block.
+    pub async fn receive_checkpoint(&self) -> (msg::Response, msg::Bcasts) {
+        (msg::ser(&self.checkpoint().await), Vec::default())
+    }
+
+    // The latest checkpoint with a block after it in the same epoch, whose attestation vouches for it.
+    async fn checkpoint(&self) -> Result<msg::ok::Checkpoint, msg::error::Checkpoint> {
+        let (mut hash, mut sheader, mut set) = self.call(|core| (core.head.block_hash, core.head.block.sheader.clone(), core.head.epoch.clone())).await;
+        let mut next: Option<account::Signed<block::Header>> = None;
+        loop {
+            let round = sheader.msg.data.round;
+            if let Some(next) = next.take().filter(|next| block::is_checkpoint(round) && next.msg.data.epoch() == sheader.msg.data.epoch()) {
+                let block = self.block_at(round, &hash).await.ok_or(msg::error::Checkpoint::NotSaved)?;
+                break self.link(block, set, next).await;
+            }
+            if round == 0 {
+                break Err(msg::error::Checkpoint::NotSaved);
+            }
+            let data = &sheader.msg.data;
+            let (prev_round, prev_hash) = (data.prev_round(), data.prev_hash);
+            let (parent, parent_set) = self.header_at(prev_round, &prev_hash).await.ok_or(msg::error::Checkpoint::NotSaved)?;
+            next = Some(mem::replace(&mut sheader, parent));
+            (hash, set) = (prev_hash, parent_set);
+        }
+    }
+
+    async fn link(&self, block: block::Block, epoch: block::ValidatorSet, next: account::Signed<block::Header>) -> 
+        Result<msg::ok::Checkpoint, msg::error::Checkpoint>
+    {
+        // Back past the start of the next block's epoch, and far enough for a median timestamp.
+        let after = block::epoch_of(block.sheader.msg.data.round + 1);
+        let mut link = Vec::default();
+        let mut data = block.sheader.msg.data.clone();
+        while data.round > 0 && (link.len() + 1 < block::MEDIAN_BLOCKS || data.epoch() >= after) {
+            let (parent, _) = self.header_at(data.prev_round(), &data.prev_hash).await.ok_or(msg::error::Checkpoint::NotSaved)?;
+            data = parent.msg.data.clone();
+            link.push(parent);
+        }
+        link.reverse();
+        Ok(msg::ok::Checkpoint { checkpoint: block::Checkpoint { block, epoch, link, next } })
+    }
+
+    // A chunk of the state at one of our checkpoints, for a peer syncing state.
+    pub async fn receive_state(&self, block_hash: [u8; 32], part: state::Part, after: Option<Vec<u8>>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        let mut opt_snap = self.find_snap(&block_hash).await;
+        if let (None, Some(archive)) = (&opt_snap, &self.archive) {
+            opt_snap = archive.get_snap(&block_hash).await.ok().flatten();
+        }
+        let result = match opt_snap {
+            Some(snap) if block::is_checkpoint(snap.block.sheader.msg.data.round) => snap.state
+                .chunk(part, after.as_deref(), STATE_CHUNK_SIZE)
+                .map(|updates| msg::ok::State { updates })
+                .map_err(|_| msg::error::State::BadPart),
+            _ => Err(msg::error::State::DoesntExist)
+        };
+        (msg::ser(&result), Vec::default())
+    }
+
+    // Our own clock, uncorrected, so peers correcting by each other don't drift together.
+    pub async fn receive_time(&self) -> (msg::Response, msg::Bcasts) {
+        let result: Result<_, msg::error::Time> = Ok(msg::ok::Time { timestamp: state::timestamp() });
+        (msg::ser(&result), Vec::default())
+    }
+
+    // What we tell a peer in a handshake.
+    pub async fn hello(&self) -> msg::Hello {
+        msg::Hello {
+            version: msg::PROTOCOL_VERSION,
+            genesis: self.genesis,
+            head_round: self.call(|core| core.head.block.sheader.msg.data.round).await,
+            node_id: self.kp().kp.public,
+            codecs: msg::CODECS.to_vec(),
+            framed: self.framed
+        }
+    }
+
+    // A peer that wants us as a neighbor. Ours back if we'd have it as one too.
+    pub async fn receive_handshake(&self, theirs: msg::Hello) -> (msg::Response, msg::Bcasts) {
+        let ours = self.hello().await;
+        let result = ours.check(&theirs).map(|_| msg::ok::Handshake { hello: ours });
+        (msg::ser(&result), Vec::default())
+    }
+
+    // Addresses we've heard of, for a peer looking for more neighbors.
+    pub async fn receive_peers(&self) -> (msg::Response, msg::Bcasts) {
+        let addrs = self.book.lock().await.iter().take(peers::MAX_SHARED).cloned().collect();
+        let result: Result<_, msg::error::Peers> = Ok(msg::ok::Peers { addrs });
+        (msg::ser(&result), Vec::default())
+    }
+
+    // Only the ones new to us are passed on, so an address goes round once.
+    pub async fn receive_addrs(&self, addrs: Vec<String>) -> (msg::Response, msg::Bcasts) {
+        if addrs.len() > peers::MAX_SHARED {
+            return (msg::ser(&Err::<msg::ok::Addrs, _>(msg::error::Addrs::TooMany)), Vec::default());
+        }
+        let new: Vec<_> = {
+            let mut book = self.book.lock().await;
+            addrs.into_iter().filter(|addr| book.learn(addr.clone())).collect()
+        };
+        let result: Result<_, msg::error::Addrs> = Ok(msg::ok::Addrs { new: new.len() });
+        let bcasts = match new.is_empty() {
+            true => Vec::default(),
+            false => Vec::from([msg::ser(&msg::Message::Addrs(new))])
+        };
+        (msg::ser(&result), bcasts)
+    }
+
+    // False if we'd heard of it already.
+    pub async fn learn(&self, addr: String) -> bool {
+        self.book.lock().await.learn(addr)
+    }
+
+    pub async fn forget(&self, addr: &str) -> bool {
+        self.book.lock().await.forget(addr)
+    }
+
+    pub async fn known_addrs(&self) -> Vec<String> {
+        self.book.lock().await.iter().cloned().collect()
+    }
+
+    // Taken into account when checking a block arrived on time.
+    pub async fn set_clock_offset(&self, offset: i64) {
+        self.call(move |core| core.clock_offset = offset).await
+    }
+
+    // Our head's parent for a peer that's fallen behind, with head's header attesting to it.
+    // They check it before taking it.
+    pub async fn receive_resync(&self) -> (msg::Response, msg::Bcasts) {
+        let next = self.get_head().await.block.sheader;
+        let data = &next.msg.data;
+        let result = match self.snap_at(data.prev_round(), &data.prev_hash).await {
+            Some(snap) if data.round > 0 => Ok(msg::ok::Resync { snap, next }),
+            _ => Err(msg::error::Resync::NotSaved)
+        };
+        (msg::ser(&result), Vec::default())
+    }
+
+    // Whether we'd jump to a peer's block, without jumping.
+    pub async fn check_resync(&self, snap: block::Snap, next: account::Signed<block::Header>) -> Result<(), block::LoadError> {
+        self.call(move |core| snap.verify_resync(&core.trusted(), &next)).await
+    }
+
+    // Jump to a peer's block, once it's checked out against our last finalized block.
+    pub async fn accept_resync(&self, snap: block::Snap, next: account::Signed<block::Header>) -> Result<(), block::LoadError> {
+        self.call(move |core| core.accept_resync(snap, next)).await
+    }
+
+    // Jump to the snap checkpoint_sync put together. Checkpoint::verify already held it to an
+    // attestation from the committee our head trusts, so there's nothing left to check here.
+    pub async fn accept_checkpoint(&self, snap: block::Snap) {
+        self.call(move |core| core.adopt(snap)).await
+    }
+
+    pub async fn receive_diff(&self, block_hash: [u8; 32]) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        let snap = match self.find_snap(&block_hash).await {
+            Some(snap) => snap,
+            None => return (
+                msg::ser(&Err::<msg::ok::Diff, _>(msg::error::Diff::DoesntExist)), 
+                Vec::default()
+            )
+        };
+        let updates = snap.updates
+            .iter()
+            .map(|up| (up.clone(), snap.state.prove(up).expect("snap state holds its own updates")))
+            .collect();
+        let diff = msg::ok::Diff { state_commit: snap.state.commit(), updates };
+        (msg::ser(&Ok::<_, msg::error::Diff>(diff)), Vec::default())
+    }
+
+    // Dry run a txn against head as if it were in the next block.
+    pub async fn simulate(&self, stxn: &account::Signed<txn::Txn>) -> Result<msg::ok::Simulate, msg::error::Simulate> {
+        let stxn = stxn.clone();
+        self.call(move |core| {
+            let meta = block::Metadata::new(&core.kp, 1, &core.head);
+            core.head.state.simulate(&stxn, &meta)
+                .map(|simulation| msg::ok::Simulate { round: meta.round, simulation })
+                .map_err(msg::error::Simulate::BadTxn)
+        }).await
+    }
+
+    // A committee member's vote for head. New ones are passed on.
+    pub async fn receive_attest(&self, block_hash: [u8; 32], from: account::PublicKey, vote: Vec<u8>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        self.call(move |core| core.receive_attest(block_hash, from, vote)).await
+    }
+
+    // A slot owner's finality vote for head. New ones are passed on.
+    pub async fn receive_vote(&self, svote: account::Signed<block::Vote>) -> 
+        (msg::Response, msg::Bcasts)
+    {
+        self.call(move |core| core.receive_vote(svote)).await
+    }
+
+    // A message from the peer at `from`. Enough invalid blocks and it's banned for a while.
+    pub async fn receive_from(&self, from: &str, msg: msg::Message) -> (msg::Response, msg::Bcasts) {
+        if let Some(resp) = self.refuse(from, ratelimit::cost(&msg)).await {
+            return (resp, Vec::default());
+        }
+        let (resp, bcasts) = self.receive(msg).await;
+        self.judge(from, &resp).await;
+        (resp, bcasts)
+    }
+
+    // The response for a banned peer, one over its rate limit for what it's sending, or any
+    // while the core's queue is full.
+    pub async fn refuse(&self, from: &str, cost: Option<(ratelimit::Kind, u64)>) -> Option<msg::Response> {
+        if self.commands.capacity() == 0 {
+            return Some(msg::ser(&Err::<(), _>(msg::error::Refused::Busy)));
+        }
+        let now = state::timestamp();
+        if self.offenders.lock().await.get(from).is_some_and(|peer| peer.is_banned(now)) {
+            return Some(msg::ser(&Err::<(), _>(msg::error::Refused::Banned)));
+        }
+        match cost {
+            Some((kind, amount)) if !self.limiter.lock().await.take(from, kind, amount, now) => {
+                Some(msg::ser(&Err::<(), _>(msg::error::Refused::Throttled)))
+            },
+            _ => None
+        }
+    }
+
+    // Count it against `from` if our response turned away an invalid block.
+    pub async fn judge(&self, from: &str, resp: &msg::Response) {
+        if let Ok(Err(msg::error::Chain::BadBlock(..))) = serde_json::from_str::<Result<msg::ok::Chain, msg::error::Chain>>(resp) {
+            let mut offenders = self.offenders.lock().await;
+            offenders.add(from.to_string());
+            offenders.record(from, peers::Event::Invalid, state::timestamp());
+        }
+    }
+
+    pub async fn receive(&self, msg: msg::Message) -> (msg::Response, msg::Bcasts) {
+        if self.is_closing() {
+            return (msg::ser(&Err::<(), _>(msg::error::Closed::ShuttingDown)), Vec::default());
+        }
+        match msg {
+            msg::Message::Txn(txns) => self.receive_txns(txns).await,
+            msg::Message::Chain(chain) => self.receive_chain(chain).await,
+            msg::Message::Resync() => self.receive_resync().await,
+            msg::Message::Batch(block_hash, batch) => self.receive_batch(block_hash, batch).await,
+            msg::Message::Diff(block_hash) => self.receive_diff(block_hash).await,
+            msg::Message::Simulate(stxn) => (msg::ser(&self.simulate(&stxn).await), Vec::default()),
+            msg::Message::Attest(block_hash, from, vote) => self.receive_attest(block_hash, from, vote).await,
+            msg::Message::Vote(svote) => self.receive_vote(svote).await,
+            msg::Message::Compact(compact) => self.receive_compact(compact, Vec::default()).await,
+            msg::Message::GetTxns(block_hash, positions) => self.receive_get_txns(block_hash, positions).await,
+            msg::Message::Headers(after) => self.receive_headers(after).await,
+            msg::Message::Bodies(block_hashes) => self.receive_bodies(block_hashes).await,
+            msg::Message::Checkpoint() => self.receive_checkpoint().await,
+            msg::Message::State(block_hash, part, after) => self.receive_state(block_hash, part, after).await,
+            msg::Message::Time() => self.receive_time().await,
+            msg::Message::GetBlocks(block_hash, count) => self.receive_get_blocks(block_hash, count).await,
+            msg::Message::Handshake(hello) => self.receive_handshake(hello).await,
+            msg::Message::Peers() => self.receive_peers().await,
+            msg::Message::Addrs(addrs) => self.receive_addrs(addrs).await
+        }
+    }
+}
+
+impl Core {
+    fn snap(&self, round: u32, block_hash: &[u8; 32]) -> Option<Cow<'_, block::Snap>> {
+        lookup(&self.snaps, round, block_hash)
+    }
+
+    fn find_snap(&self, block_hash: &[u8; 32]) -> Option<Cow<'_, block::Snap>> {
+        let round = self.snaps.iter().find(|(_, snaps)| snaps.contains_key(block_hash))?.0;
+        self.snap(*round, block_hash)
+    }
+
+    // A header and the set after it, which every snap keeps, so nothing is rebuilt.
+    fn header(&self, round: u32, block_hash: &[u8; 32]) -> Option<(account::Signed<block::Header>, block::ValidatorSet)> {
+        let kept = self.snaps.get(&round)?.get(block_hash)?;
+        Some((kept.block().sheader.clone(), kept.epoch().clone()))
+    }
+
+    // Just the block, which every snap keeps, so nothing is rebuilt.
+    fn block(&self, round: u32, block_hash: &[u8; 32]) -> Option<&block::Block> {
+        self.snaps.get(&round)?.get(block_hash).map(Kept::block)
+    }
+
+    // Our last finalized block and its ancestors, back as far as the fork window goes.
+    fn finalized_chain(&self) -> HashSet<[u8; 32]> {
+        let mut hashes = HashSet::new();
+        let (mut round, mut hash) = self.finalized;
+        while let Some(block) = self.block(round, &hash) {
+            hashes.insert(hash);
+            if round == 0 {
+                break;
+            }
+            (round, hash) = (block.sheader.msg.data.prev_round(), block.sheader.msg.data.prev_hash);
+        }
+        hashes
+    }
+
+    // Head and its ancestors, back as far as the fork window goes.
+    fn chain_hashes(&self) -> HashSet<[u8; 32]> {
+        let mut hashes = HashSet::from([self.head.block_hash]);
+        let mut block = &self.head.block;
+        while let Some(prev) = self.block(block.sheader.msg.data.prev_round(), &block.sheader.msg.data.prev_hash) {
+            hashes.insert(block.sheader.msg.data.prev_hash);
+            block = prev;
+        }
+        hashes
+    }
+
+    fn find_block(&self, block_hash: &[u8; 32]) -> Option<&block::Block> {
+        self.snaps.values().find_map(|snaps| snaps.get(block_hash)).map(Kept::block)
+    }
+
+    fn keep(&mut self, snap: block::Snap) {
+        self.snaps.entry(snap.block.sheader.msg.data.round).or_default().insert(snap.block_hash, Kept::Full(Box::new(snap)));
+    }
+
+    // Our clock corrected by the offset our peers suggest.
+    fn now(&self) -> u64 {
+        state::timestamp().saturating_add_signed(self.clock_offset)
+    }
+
+    fn persist(&self, f: impl FnOnce(&mut store::Meta)) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.update(f) {
+                println!("couldn't save chain meta {:?}", e);
+            }
+        }
+    }
+
+    fn persist_snap(&self, snap: &block::Snap) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.put_snap(snap) {
+                println!("couldn't save snap {:?}", e);
+            }
+        }
+    }
+
+    fn unpersist_snap(&self, block_hash: &[u8; 32]) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.remove_snap(block_hash) {
+                println!("couldn't remove snap {:?}", e);
+            }
+        }
+    }
+
+    // Write out the builder so a crash mid-slot doesn't lose the block.
+    fn save_builder(&self) {
+        if let Some(ref path) = self.builder_path {
+            let draft = self.opt_builder.as_ref().map(block::Builder::save);
+            if fs::write(path, serde_json::to_vec(&draft).unwrap()).is_err() {
+                println!("couldn't save builder to {:?}", path);
+            }
         }
     }
 
-    pub async fn get_head(&self) -> block::Snap {
-        self.head.lock().await.clone()
+    fn flush(&mut self) {
+        self.save_builder();
+        if let Some(ref store) = self.store {
+            let txns: Vec<_> = self.txpool.iter().cloned().collect();
+            if let Err(e) = store.put_pool(&txns) {
+                println!("couldn't save pool {:?}", e);
+            }
+        }
     }
 
-    // timestamp tick!
-    // may return block to prop
-    // time can be a little bit after exact tick moment
-    pub async fn tick(&self) -> msg::Bcasts {
-        let mut empty_builder = None;
-        {
-            let mut opt_builder = self.opt_builder.lock().await;
-            mem::swap(&mut empty_builder, &mut *opt_builder);
+    // A saved builder for this proposal on head, if we were partway through one.
+    fn load_builder(&self, proposal: u32) -> Option<block::Builder> {
+        let bytes = fs::read(self.builder_path.as_ref()?).ok()?;
+        let draft = serde_json::from_slice::<Option<block::Draft>>(&bytes).ok()??;
+        if draft.metadata.proposal != proposal {
+            return None;
         }
-        let ret = match empty_builder {
-            Some(builder) => {
-                let snap = builder.finalize(&self.kp);
-                let msg = msg::Message::Chain(
-                    Vec::from([snap.block.clone()])
+        block::Builder::resume(&self.kp, &self.head, draft).ok()
+    }
+
+    fn tick(&mut self) -> msg::Bcasts {
+        let now = state::timestamp();
+        let block_time = self.head.state.clock.block_time;
+        let ret = match self.opt_builder.take() {
+            // Ticks came late, say the process stalled, and the slot we built for is over.
+            // Peers would take it as being from the past, so drop it and build for the slot we're in,
+            // with its txns back in the pool.
+            Some(builder) if now >= builder.metadata.timestamp.saturating_add(block_time) => {
+                println!(
+                    "missed our slot for proposal {}, now at proposal {}", 
+                    builder.metadata.proposal, self.proposal_at(now)
                 );
-                let msg = msg::ser(&msg);
-                self.add_snap(snap).await;
-                Vec::from([msg])
+                self.metrics.inc(metrics::SLOTS_MISSED);
+                for txn in builder.txnseq.iter() {
+                    let _ = self.txpool.insert(txn.clone());
+                }
+                Vec::default()
+            },
+            Some(builder) if !self.sign_round(builder.metadata.round) => {
+                println!("refusing to sign round {} twice", builder.metadata.round);
+                Vec::default()
+            },
+            Some(mut builder) => {
+                // Whatever votes for head made it in time.
+                if let Ok(attestation) = attest::Attestation::aggregate(&self.votes) {
+                    builder.attestation = attestation;
+                }
+                let snap = builder.finalize(&self.kp);
+                self.metrics.inc(metrics::BLOCKS_PROPOSED);
+                let msgs = match self.behavior {
+                    Some(ref mut behavior) => behavior.propose(&self.kp, &self.head, &snap),
+                    None => Vec::from([msg::Message::Compact(block::Compact::new(&snap.block))])
+                };
+                let mut bcasts = self.add_snap(snap);
+                bcasts.extend(msgs.iter().map(msg::ser));
+                bcasts
             },
             None => Vec::default()
         };
-        self.check_leader().await;
-        ret
-    }
-
-    async fn check_leader(&self) {
-        let time = state::timestamp() as u64;
-        let head = self.head.lock().await;
-        let gap = time - head.block.sheader.msg.data.timestamp.min(time);
-        let proposal = (gap / block::BLOCK_TIME) as u32 + 1;
-        let leader = head.leader(proposal).unwrap();
-        let mut new_builder = if leader == &self.kp.kp.public {
-            let mut builder = block::Builder::new(
-                &self.kp, proposal, &head
-            );
-            let mut empty_pool = BTreeSet::default();
-            let mut txpool = self.txpool.lock().await;
-            std::mem::swap(&mut empty_pool, &mut *txpool);
-            // TODO: this pool 
-            for txn in empty_pool {
-                let _ = builder.add(txn);
+        self.check_leader();
+        self.metrics.set(metrics::HEAD_ROUND, self.head.block.sheader.msg.data.round as u64);
+        self.metrics.set(metrics::TXPOOL_TXNS, self.txpool.len() as u64);
+        self.metrics.set(metrics::TXPOOL_BYTES, self.txpool.bytes() as u64);
+        match self.behavior {
+            Some(ref mut behavior) => behavior.outgoing(ret),
+            None => ret
+        }
+    }
+
+    // Slashing protection: claim round for signing, false if already claimed.
+    fn sign_round(&mut self, round: u32) -> bool {
+        if round <= self.signed_round {
+            return false;
+        }
+        self.signed_round = round;
+        // On disk before anything's signed, or a restart could sign the round again.
+        self.persist(|meta| meta.signed_round = round);
+        true
+    }
+
+    fn submit_block(&mut self, txns: Vec<account::Signed<txn::Txn>>, state_commit: Option<[u8; 32]>) ->
+        Result<(), SubmitError> 
+    {
+        let proposal = match self.opt_builder {
+            Some(ref builder) => builder.metadata.proposal,
+            None => return Err(SubmitError::NotLeader)
+        };
+        let mut builder = block::Builder::new(&self.kp, proposal, &self.head);
+        for txn in txns {
+            builder.add(txn).map_err(|(txn, e)| SubmitError::BadTxn(txn, e))?;
+        }
+        if let Some(commit) = state_commit {
+            if builder.current_state().commit() != commit {
+                return Err(SubmitError::BadState);
+            }
+        }
+        // Anything we'd picked up that they left out goes back in the pool.
+        let ours = self.opt_builder.replace(builder).expect("checked above");
+        let theirs = &self.opt_builder.as_ref().expect("just set").txnseq;
+        let included: BTreeSet<_> = theirs.iter().map(txn::hash).collect();
+        for txn in ours.txnseq.iter() {
+            if !included.contains(&txn::hash(txn)) {
+                let _ = self.txpool.insert(txn.clone());
+            }
+        }
+        self.save_builder();
+        Ok(())
+    }
+
+    fn stalled(&mut self) -> bool {
+        let now = state::timestamp();
+        let head_round = self.head.block.sheader.msg.data.round;
+        let head_time = self.head.block.sheader.msg.data.timestamp;
+        let stall_time = STALL_TICKS * self.head.state.clock.block_time;
+        if now < head_time + stall_time || self.best_round <= head_round {
+            return false;
+        }
+        if now < self.last_resync + stall_time {
+            return false;
+        }
+        self.last_resync = now;
+        println!(
+            "stalled at round {} for {}ms, peers are at round {}",
+            head_round, now - head_time, self.best_round
+        );
+        true
+    }
+
+    // Proposal after head whose slot `time` falls in. Saturates when head is very old.
+    fn proposal_at(&self, time: u64) -> u32 {
+        let gap = time.saturating_sub(self.head.block.sheader.msg.data.timestamp);
+        u32::try_from(gap / self.head.state.clock.block_time).unwrap_or(u32::MAX).saturating_add(1)
+    }
+
+    // Returns our nonce under the new key.
+    fn rotate_key(&mut self, kp: Arc<account::Keypair>) -> u32 {
+        if let Some(builder) = self.opt_builder.take() {
+            for txn in builder.txnseq.iter() {
+                let _ = self.txpool.insert(txn.clone());
+            }
+        }
+        self.kp = kp;
+        self.check_leader();
+        let nonce = self.own_nonce();
+        self.persist(|meta| meta.nonce = nonce);
+        nonce
+    }
+
+    // Our account's nonce on head, or zero if it isn't there.
+    fn own_nonce(&self) -> u32 {
+        let id: account::Id = Sha256::digest(self.kp.kp.public.to_bytes()).into();
+        self.head.state.accounts.get(&id).ok().flatten().map_or(0, |data| data.nonce)
+    }
+
+    fn check_leader(&mut self) {
+        // Anything our last builder picked up that head left out goes back in the pool.
+        if let Some(old) = self.opt_builder.take() {
+            let included: BTreeSet<_> = self.head.block.txnseq.iter().map(txn::hash).collect();
+            for txn in old.txnseq.iter() {
+                if !included.contains(&txn::hash(txn)) {
+                    let _ = self.txpool.insert(txn.clone());
+                }
+            }
+        }
+        let proposal = self.proposal_at(state::timestamp());
+        let leader = self.head.leader(proposal).unwrap();
+        self.opt_builder = if self.role == Role::Validator && leader == &self.kp.kp.public {
+            let mut builder = match self.load_builder(proposal) {
+                Some(builder) => builder,
+                None => block::Builder::new(&self.kp, proposal, &self.head)
+            };
+            feed(&mut builder, &mut self.txpool);
+            for ev in self.evidence.iter() {
+                let _ = builder.add_evidence(ev.clone());
             }
             Some(builder)
         } else {
             None
         };
-        {
-            let mut opt_builder = self.opt_builder.lock().await;
-            mem::swap(&mut new_builder, &mut *opt_builder);
-        }
-    }
-
-    async fn add_snap(&self, snap: block::Snap) {
-        let mut new_head = false;
-        {
-            let mut head = self.head.lock().await;
-            assert!(snap.block.sheader.msg.data.round <= head.block.sheader.msg.data.round + 1);
-            // New head!
-            if snap.block.sheader.msg.data.round == head.block.sheader.msg.data.round + 1 {
-                new_head = true;
-                let mut arr = self.snaps[(snap.block.sheader.msg.data.round % MAX_FORK) as usize].lock().await;
-                *arr = HashMap::default();
-                *head = snap.clone();
-                {
-                    let mut txpool = self.txpool.lock().await;
-                    for txn in head.block.txnseq.iter() {
-                        txpool.remove(txn);
-                    }
+        self.save_builder();
+    }
+
+    // Returns our votes for the snap if it became head and we hold slots or sit on the committee.
+    fn add_snap(&mut self, mut snap: block::Snap) -> msg::Bcasts {
+        let mut bcasts = Vec::default();
+        self.persist_snap(&snap);
+        // New head! Possibly rounds ahead if some were skipped, or a sibling winning the tiebreak.
+        let new_head = snap.block.beats(&self.head.block);
+        if new_head {
+            let same_round = snap.block.sheader.msg.data.round == self.head.block.sheader.msg.data.round;
+            self.finality_votes.clear();
+            let weight = if self.role == Role::Validator { snap.epoch.weight(&self.kp.kp.public) } else { 0 };
+            // One finality vote per round, never for two siblings.
+            if weight > 0 && !same_round {
+                let vote = block::Vote { round: snap.block.sheader.msg.data.round, block_hash: snap.block_hash };
+                let sig = self.kp.sign(&vote);
+                self.finality_votes.insert(Sha256::digest(self.kp.kp.public.to_bytes()).into(), weight);
+                if validator::supermajority(weight, snap.epoch.total_weight()) {
+                    snap.finalized = true;
+                    self.finalized = (vote.round, vote.block_hash);
+                    self.persist(|meta| meta.finalized = (vote.round, vote.block_hash));
                 }
+                let svote = account::Signed { msg: vote, from: self.kp.kp.public, sig };
+                bcasts.push(msg::ser(&msg::Message::Vote(svote)));
             }
+            let data = &snap.block.sheader.msg.data;
+            if let Some(prev) = lookup(&self.snaps, data.prev_round(), &data.prev_hash) {
+                self.liveness.record(&prev, &snap.block);
+            }
+            self.head = snap.clone();
+            let at = (self.head.block.sheader.msg.data.round, self.head.block_hash);
+            self.persist(|meta| meta.head = at);
+            self.finalize_deep();
+            for txn in self.head.block.txnseq.iter() {
+                self.txpool.remove(txn);
+            }
+            self.flush_pool();
+            self.include_headers();
+            self.rate_senators();
+            let included = &self.head.block.evidence;
+            self.evidence.retain(|ev| !included.iter().any(|old| old.same_offence(ev)));
+            self.votes.clear();
+            let committee = self.head.epoch.committee();
+            let seat = committee.iter().position(|val| val.pk == self.kp.kp.public);
+            if let (Role::Validator, Some(idx)) = (self.role, seat) {
+                let vote = attest::vote(&self.kp, &self.head.block_hash);
+                self.votes.insert(idx, vote.clone());
+                bcasts.push(msg::ser(&msg::Message::Attest(self.head.block_hash, self.kp.kp.public, vote)));
+            }
+        }
+        if new_head {
+            self.check_leader();
         }
+        self.keep(snap);
         if new_head {
-            self.check_leader().await;
+            self.thin();
+            self.prune();
         }
-        let mut arr = self.snaps[(snap.block.sheader.msg.data.round % MAX_FORK) as usize].lock().await;
-        arr.insert(snap.block.sheader.msg.hash(), snap);
+        bcasts
     }
 
-    pub async fn receive_txns(&self, txns: Vec<account::Signed<txn::Txn>>) -> 
-        (msg::Response, msg::Bcasts)
-    {
-        let head = self.head.lock().await;
-        let meta = block::Metadata::new(&self.kp, 1, &head);
+    // Start watching a rollup's senators, other than us.
+    fn watch(&mut self, data: &rollup::Data, me: senator::Id) {
+        let round = self.head.block.sheader.msg.data.round;
+        let senators = data.senators.iter().map(|senator| (senator.id, false));
+        for (id, sequencer) in senators.chain([(data.sequencer.id, true)]).filter(|(id, _)| *id != me) {
+            let rep = self.reputations.entry(id).or_insert_with(|| senator::Reputation::new(sequencer, round));
+            rep.sequencer |= sequencer;
+        }
+    }
+
+    // Pools a txn for a rollup we sequence.
+    fn pool_rollup(&mut self, stxn: account::Signed<txn::Txn>) -> Result<(), txn::Error> {
+        let id = stxn.msg.opt_rollup.ok_or(txn::Error::NoRollup)?;
+        match self.rollups.get_mut(&id) {
+            Some(working) if working.sequencer => stxn.msg.check_size().and_then(|()| working.add(stxn)),
+            Some(_) => Err(txn::Error::NotSequencer(id)),
+            None => Err(txn::Error::NoRollup)
+        }
+    }
+
+    // Moves the rollups we sequence up past our headers in the new head.
+    fn include_headers(&mut self) {
+        if self.rollups.is_empty() {
+            return;
+        }
+        for stxn in self.head.block.txnseq.iter() {
+            if let txn::Payload::Header(id, ref txns, state_hash) = stxn.msg.payload {
+                if let Some(working) = self.rollups.get_mut(&id) {
+                    working.include(txns, state_hash);
+                }
+            }
+        }
+    }
+
+    // Credit the senators we watch for headers in the new head, and dock the ones gone quiet.
+    fn rate_senators(&mut self) {
+        if self.reputations.is_empty() {
+            return;
+        }
+        let round = self.head.block.sheader.msg.data.round;
+        for stxn in self.head.block.txnseq.iter() {
+            if let txn::Payload::Header(..) = stxn.msg.payload {
+                let id: senator::Id = Sha256::digest(stxn.from.to_bytes()).into();
+                if let Some(rep) = self.reputations.get_mut(&id) {
+                    rep.header(round);
+                }
+            }
+        }
+        for rep in self.reputations.values_mut() {
+            rep.tick(round);
+        }
+    }
+
+    // Anything finality_depth rounds under head is final, so a chain that stalls without
+    // enough votes still can't be rewritten from far back.
+    fn finalize_deep(&mut self) {
+        let target = self.head.block.sheader.msg.data.round.saturating_sub(self.finality_depth);
+        if target <= self.finalized.0 {
+            return;
+        }
+        let mut block = &self.head.block;
+        while block.sheader.msg.data.round > target {
+            let data = &block.sheader.msg.data;
+            let Some(prev) = self.block(data.prev_round(), &data.prev_hash) else {
+                return;
+            };
+            block = prev;
+        }
+        // Skipped rounds can leave us under target.
+        let at = (block.sheader.msg.data.round, block.sheader.msg.hash());
+        if at.0 > self.finalized.0 {
+            self.finalized = at;
+            self.persist(|meta| meta.finalized = at);
+        }
+    }
+
+    // Swap the state of snaps more than FULL_ROUNDS behind head for their updates. Snaps with
+    // no parent here to rebuild from stay whole, as do trusted ones whose updates we never
+    // worked out: those have none, yet a different state from their parent.
+    fn thin(&mut self) {
+        let cutoff = self.head.block.sheader.msg.data.round.saturating_sub(FULL_ROUNDS);
+        let rebuildable = |snap: &block::Snap| {
+            let data = &snap.block.sheader.msg.data;
+            self.block(data.prev_round(), &data.prev_hash).is_some_and(|prev| {
+                !snap.updates.is_empty() || prev.sheader.msg.commits.state == snap.block.sheader.msg.commits.state
+            })
+        };
+        let thinned: Vec<_> = self.snaps.range(..cutoff)
+            .flat_map(|(round, snaps)| snaps.iter().map(move |(hash, kept)| (*round, *hash, kept)))
+            .filter(|(_, _, kept)| matches!(kept, Kept::Full(snap) if rebuildable(snap)))
+            .map(|(round, hash, _)| (round, hash))
+            .collect();
+        for (round, hash) in thinned {
+            let snaps = self.snaps.get_mut(&round).expect("just found");
+            if let Some(Kept::Full(snap)) = snaps.remove(&hash) {
+                snaps.insert(hash, Kept::Delta(Box::new(snap.thin())));
+            }
+        }
+    }
+
+    // Drop snaps from before the fork window, or before the last finalized block when pruning
+    // by finality. They go to the archive first if we have one.
+    fn prune(&mut self) {
+        let mut cutoff = (self.head.block.sheader.msg.data.round + 1).saturating_sub(self.fork_window);
+        if self.pruning == Pruning::Finality {
+            cutoff = cutoff.max(self.finalized.0);
+        }
+        self.proposals.retain(|(round, _, _), _| *round >= cutoff);
+        // Only the finalized chain is archived, not forks off it that were given up on.
+        let archived = match self.archive {
+            Some(_) => self.finalized_chain(),
+            None => HashSet::new()
+        };
+        // Thinned snaps losing the parent they're rebuilt from get their state back first, as
+        // does anything on its way to the archive. Oldest first, so each rebuild is one step.
+        let refill: Vec<_> = self.snaps.iter()
+            .flat_map(|(round, snaps)| snaps.iter().map(move |(hash, kept)| (*round, *hash, kept)))
+            .filter(|(round, hash, kept)| match kept {
+                Kept::Delta(delta) => (*round < cutoff && archived.contains(hash)) || delta.block.sheader.msg.data.prev_round() < cutoff,
+                Kept::Full(_) => false
+            })
+            .map(|(round, hash, _)| (round, hash))
+            .collect();
+        for (round, hash) in refill {
+            if let Some(snap) = self.snap(round, &hash).map(Cow::into_owned) {
+                self.keep(snap);
+            }
+        }
+        let kept = self.snaps.split_off(&cutoff);
+        let evicted = mem::replace(&mut self.snaps, kept);
+        for hash in evicted.values().flat_map(HashMap::keys) {
+            self.unpersist_snap(hash);
+        }
+        if let (false, Some(archive)) = (evicted.is_empty(), &self.archive) {
+            let archive = archive.clone();
+            tokio::spawn(async move {
+                let old = evicted.into_values().flat_map(HashMap::into_values).filter_map(|kept| match kept {
+                    Kept::Full(snap) if archived.contains(&snap.block_hash) => Some(*snap),
+                    _ => None
+                });
+                for old in old {
+                    if let Err(e) = archive.put_snap(&old).await {
+                        println!("failed to archive snap {:?}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    // Drop pooled txns the head has left unable to run: nonces it's used up, or more than
+    // the sender can pay for once their earlier txns in the pool are counted.
+    fn flush_pool(&mut self) {
+        let round = self.head.block.sheader.msg.data.round + 1;
+        let accounts = &self.head.state.accounts;
+        let mut budgets: HashMap<account::Id, Option<(u32, u64)>> = HashMap::default();
+        self.txpool.retain(|txn| {
+            let from: account::Id = Sha256::digest(txn.from.to_bytes()).into();
+            // Not ours to judge without the account's shard.
+            let Ok(acc) = accounts.get(&from) else {
+                return true;
+            };
+            let budget = budgets.entry(from).or_insert_with(|| acc.map(|acc| (acc.nonce, acc.spendable(round) as u64)));
+            match budget {
+                Some((nonce, left)) if txn.msg.nonce >= *nonce && txn.msg.min_cost() <= *left => {
+                    *left -= txn.msg.min_cost();
+                    true
+                },
+                _ => false
+            }
+        });
+    }
+
+    // Signatures were checked by the node on the way in.
+    fn receive_txns(
+        &mut self, 
+        txns: Vec<account::Signed<txn::Txn>>, 
+        mut rejected: Vec<(account::Signed<txn::Txn>, txn::Error)>, // already turned away by the node
+        mut outcomes: Vec<(txn::Hash, msg::ok::Outcome)> // and what it took, for rollups
+    ) -> (msg::Response, msg::Bcasts) {
+        if self.role == Role::Observer {
+            return (msg::ser(&Err::<msg::ok::Txn, _>(msg::error::Txn::Observer)), Vec::default());
+        }
+        let meta = block::Metadata::new(&self.kp, 1, &self.head);
+        let checked = state::Overlay::default().with_sigs_checked();
         let mut valid = Vec::default();
-        let mut txpool = self.txpool.lock().await;
-        // Keep txns which pass or have big nonce (TODO: need to flush txpool...)
-        match *self.opt_builder.lock().await {
+        // Turn away oversized txns before they reach the builder or pool.
+        let txns: Vec<_> = txns.into_iter()
+            .filter(|txn| match txn.msg.check_size() {
+                Ok(()) => true,
+                Err(err) => { rejected.push((txn.clone(), err)); false }
+            })
+            .collect();
+        // Keep txns which pass or have big nonce. Stale ones get flushed on the next head.
+        match self.opt_builder {
             Some(ref mut builder) => {
                 println!("I AM BUILDING!");
+                // Checked on the way in, as was everything pooled.
+                builder.overlay.set_sigs_checked(true);
                 for txn in txns {
-                    if let Err((txn, err)) = builder.add(txn.clone()) {
-                        println!("bad txn");
-                        if err == txn::Error::BigNonce {
-                            if !(*txpool).contains(&txn) {
-                                if head.state.verify(&txn, &meta).is_ok() {
-                                    valid.push(txn);
+                    match builder.add(txn.clone()) {
+                        Ok(()) => {
+                            outcomes.push((txn::hash(&txn), msg::ok::Outcome::Included));
+                            // No subscribers is fine.
+                            let _ = self.pool_feed.send(txn);
+                        },
+                        Err((txn, err)) => {
+                            println!("bad txn");
+                            if matches!(err, txn::Error::BigNonce { .. }) {
+                                if self.txpool.contains(&txn) {
+                                    outcomes.push((txn::hash(&txn), msg::ok::Outcome::Known));
+                                } else {
+                                    match checked.verify(&self.head.state, &txn, &meta) {
+                                        Ok(_) => valid.push(txn),
+                                        Err(err) => rejected.push((txn, err))
+                                    }
                                 }
+                            } else {
+                                rejected.push((txn, err));
                             }
                         }
                     }
                 }
+                // What we just added may have unblocked some waiting txns.
+                feed(builder, &mut self.txpool);
+                builder.overlay.set_sigs_checked(false);
             },
             None => {
                 println!("I AM NOT BUILDING!");
                 for txn in txns {
-                    if !(*txpool).contains(&txn) {
-                        match head.state.verify(&txn, &meta) {
-                            Ok(_) | Err(txn::Error::BigNonce) => valid.push(txn),
-                            _ => {} ,
-                        }
+                    if self.txpool.contains(&txn) {
+                        outcomes.push((txn::hash(&txn), msg::ok::Outcome::Known));
+                        continue;
+                    }
+                    match checked.verify(&self.head.state, &txn, &meta) {
+                        Ok(_) | Err(txn::Error::BigNonce { .. }) => valid.push(txn),
+                        Err(err) => rejected.push((txn, err))
                     }
                 }
             }
         }
-        let result: Result<msg::ok::Txn, msg::error::Txn> = Ok(msg::ok::Txn {});
-        let resp = msg::ser(&result);
-        if valid.is_empty() {
-            (resp, Vec::default())
+        if self.opt_builder.is_some() {
+            self.save_builder();
+        }
+        // Only pass on what the pool had room for, and hadn't passed on already.
+        let mut kept = Vec::default();
+        for txn in valid {
+            match self.txpool.insert(txn.clone()) {
+                Ok(_) => {
+                    outcomes.push((txn::hash(&txn), msg::ok::Outcome::Pooled));
+                    let _ = self.pool_feed.send(txn.clone());
+                    kept.push(txn);
+                },
+                Err(err) => outcomes.push((txn::hash(&txn), msg::ok::Outcome::Dropped(err)))
+            }
+        }
+        let result: Result<msg::ok::Txn, msg::error::Txn> = if outcomes.is_empty() && !rejected.is_empty() {
+            Err(msg::error::Txn::Rejected(rejected))
         } else {
-            let msg = msg::Message::Txn(valid);
-            let ser = msg::ser(&msg);
-            for txn in msg.txn().unwrap() {
-                (*txpool).insert(txn);
+            outcomes.extend(rejected.into_iter().map(|(txn, err)| (txn::hash(&txn), msg::ok::Outcome::Rejected(err))));
+            Ok(msg::ok::Txn { outcomes })
+        };
+        let resp = msg::ser(&result);
+        let fresh: Vec<_> = kept.into_iter().filter(|txn| self.seen_txns.insert(txn::hash(txn))).collect();
+        if fresh.is_empty() {
+            return (resp, Vec::default());
+        }
+        (resp, Vec::from([msg::ser(&msg::Message::Txn(fresh))]))
+    }
+
+    fn receive_chain(&mut self, chain: Vec<block::Block>) ->
+        (msg::Response, msg::Bcasts)
+    {
+        match self.process_chain(chain, true) {
+            Ok(opt) => {
+                (msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {})), opt)
+            },
+            Err(e) => {
+                (msg::ser(&Err::<msg::ok::Txn, _>(e)), Vec::default())
+            }
+        }
+    }
+
+    // Live chains have to arrive inside the clock window. Header first sync catches up on
+    // old blocks whose headers it's already checked, so skips that.
+    fn process_chain(&mut self, chain: Vec<block::Block>, live: bool) ->
+        Result<msg::Bcasts, msg::error::Chain> 
+    {
+        let result = self.apply_chain(chain, live);
+        if let Err(ref e) = result {
+            let reason = match e {
+                msg::error::Chain::BadBlock(_, e) => metrics::reason(e),
+                e => metrics::reason(e)
+            };
+            self.metrics.inc_with(metrics::BLOCKS_REJECTED, reason);
+        }
+        result.map(|mut bcasts| {
+            bcasts.extend(self.adopt_orphans());
+            bcasts
+        })
+    }
+
+    // Keep a chain whose parent we don't have yet, dropping the oldest held once over the limit.
+    fn hold_orphan(&mut self, chain: Vec<block::Block>) {
+        let hash = chain[0].sheader.msg.hash();
+        if chain.len() > MAX_ORPHANS || self.orphans.iter().any(|held| held[0].sheader.msg.hash() == hash) {
+            return;
+        }
+        self.orphans.push_back(chain);
+        while self.orphans.iter().map(Vec::len).sum::<usize>() > MAX_ORPHANS {
+            self.orphans.pop_front();
+        }
+    }
+
+    fn backfill(&mut self, mut chain: Vec<block::Block>) -> (msg::Response, msg::Bcasts) {
+        if let Some(last) = chain.last() {
+            let hash = last.sheader.msg.hash();
+            if let Some(idx) = self.orphans.iter().position(|held| held[0].sheader.msg.data.prev_hash == hash) {
+                chain.extend(self.orphans.remove(idx).expect("just found"));
             }
-            (resp, Vec::from([ser]))
         }
+        self.receive_chain(chain)
     }
 
-    async fn process_chain(&self, mut chain: Vec<block::Block>) -> 
+    // Try again any held chain whose parent we now have. Their timing was checked on arrival.
+    fn adopt_orphans(&mut self) -> msg::Bcasts {
+        let mut bcasts = Vec::default();
+        let has_parent = |core: &Self, chain: &[block::Block]| {
+            let data = &chain[0].sheader.msg.data;
+            core.block(data.prev_round(), &data.prev_hash).is_some()
+        };
+        while let Some(idx) = self.orphans.iter().position(|chain| has_parent(self, chain)) {
+            let chain = self.orphans.remove(idx).expect("just found");
+            if let Ok(more) = self.process_chain(chain, false) {
+                bcasts.extend(more);
+            }
+        }
+        bcasts
+    }
+
+    fn apply_chain(&mut self, mut chain: Vec<block::Block>, live: bool) ->
         Result<msg::Bcasts, msg::error::Chain> 
     {
         // Drop anything that isn't new.
-        let mut first = chain.get(0).ok_or(msg::error::Chain::AlreadyHave)?;
-        while self.snaps[(first.sheader.msg.data.round % MAX_FORK) as usize]
-            .lock()
-            .await
-            .contains_key(&first.sheader.msg.hash()) {
-                chain.remove(0);
-                first = chain.get(0).ok_or(msg::error::Chain::AlreadyHave)?;
+        let mut first = chain.first().ok_or(msg::error::Chain::AlreadyHave)?;
+        while self.block(first.sheader.msg.data.round, &first.sheader.msg.hash()).is_some() {
+            chain.remove(0);
+            first = chain.first().ok_or(msg::error::Chain::AlreadyHave)?;
+        }
+        // Before anything else, so a double proposal is caught even if the chain isn't taken.
+        for block in chain.iter() {
+            self.witness(&block.sheader);
         }
         let last = chain.last().unwrap();
-        let (forked, new_head) = {
-            let head = self.head.lock().await;
-            // println!("received {:#?} and head is {:#?}", first.sheader.msg, head.block.sheader.msg);
-            if last.sheader.msg.data.round <= head.block.sheader.msg.data.round {
-                return Err(msg::error::Chain::TooShort);
+        self.best_round = self.best_round.max(last.sheader.msg.data.round);
+        if !last.beats(&self.head.block) {
+            // Won't be head, but a competing proposal is still worth keeping.
+            if let [block] = chain.as_slice() {
+                self.add_uncle(block)?;
             }
-            (
-                first.sheader.msg.data.prev_hash != head.block_hash, 
-                last.sheader.msg.data.round > head.block.sheader.msg.data.round
-            )
-        };
+            return Err(msg::error::Chain::TooShort);
+        }
+        let forked = first.sheader.msg.data.prev_hash != self.head.block_hash;
+        let clock = self.head.state.clock;
         // last block has to be received at correct time
-        let timestamp = state::timestamp();
-        if timestamp > last.sheader.msg.data.timestamp + MAX_CLOCK_GAP + MAX_PROP_TIME {
+        let timestamp = self.now();
+        if live && timestamp > last.sheader.msg.data.timestamp + clock.max_clock_gap + clock.max_prop_time {
             return Err(msg::error::Chain::SmallTimestamp);
         }
-        if timestamp + MAX_CLOCK_GAP < last.sheader.msg.data.timestamp {
+        if timestamp + clock.max_clock_gap < last.sheader.msg.data.timestamp {
             return Err(msg::error::Chain::BigTimestamp);
         }
-        let arr = self.snaps
-            [((first.sheader.msg.data.round - 1) % MAX_FORK) as usize]
-            .lock()
-            .await;
-        let mut prev = arr
-            .get(&first.sheader.msg.data.prev_hash)
-            .ok_or(msg::error::Chain::BadPrev)?;
+        let (first_prev_round, first_prev_hash) = (first.sheader.msg.data.prev_round(), first.sheader.msg.data.prev_hash);
+        if forked && !self.extends_finalized(first_prev_round, first_prev_hash) {
+            return Err(msg::error::Chain::PastFinality);
+        }
+        let Some(base) = self.snap(first_prev_round, &first_prev_hash) else {
+            // The parent may just be running late.
+            if live {
+                self.hold_orphan(chain);
+            }
+            return Err(msg::error::Chain::BadPrev);
+        };
         let mut snaps = Vec::default();
-        // serialize
-        let msg = msg::Message::Chain(chain.clone());
+        // serialize. Peers most likely have a single new block's txns already
+        let msg = match chain.as_slice() {
+            [block] => msg::Message::Compact(block::Compact::new(block)),
+            _ => msg::Message::Chain(chain.clone())
+        };
         let ser = msg::ser(&msg);
         for block in chain {
-            let verif = block::Verifier::new(prev, block);
+            let start = std::time::Instant::now();
+            let verif = block::Verifier::new(snaps.last().unwrap_or(&*base), block);
             let snap = verif.finalize().map_err(|(b, e)| msg::error::Chain::BadBlock(b, e))?;
+            self.metrics.observe(metrics::VERIFY_MS, start.elapsed().as_millis() as u64);
             snaps.push(snap);
-            prev = snaps.last().unwrap();
         }
-        // Now it's good!
+        drop(base);
+        // Relayed already if we've seen every block in it.
+        let mut fresh = false;
+        for snap in snaps.iter() {
+            fresh |= self.seen_blocks.insert(snap.block_hash);
+        }
+        fresh &= self.role != Role::Observer;
+        // Now it's good! Txns only the abandoned branch had go back in the pool.
         if forked {
-            self.txpool.lock().await.clear();
+            self.metrics.inc(metrics::REORGS);
+            let old_head = (self.head.block.sheader.msg.data.round, self.head.block_hash);
+            let base = (first_prev_round, first_prev_hash);
+            for txn in self.orphaned(old_head, base, &snaps) {
+                let _ = self.txpool.insert(txn);
+            }
         }
+        let mut votes = Vec::default();
         for snap in snaps {
-            self.add_snap(snap).await;
+            self.metrics.inc(metrics::BLOCKS_ACCEPTED);
+            votes = self.add_snap(snap);
         }
-        if new_head {
-            Ok(Vec::from([ser]))
-        } else {
-            Ok(Vec::default())
+        Ok(fresh.then_some(ser).into_iter().chain(votes).collect())
+    }
+
+    // Store a valid block for a round we're already past, off to the side of our chain.
+    // Past the cap the block is turned away before we pay to verify it.
+    fn add_uncle(&mut self, block: &block::Block) -> Result<(), msg::error::Chain> {
+        let round = block.sheader.msg.data.round;
+        if round == 0 { return Ok(()); }
+        if self.snaps.get(&round).map_or(0, HashMap::len) > MAX_UNCLES {
+            return Err(msg::error::Chain::TooManyUncles);
         }
+        let data = &block.sheader.msg.data;
+        let verified = self.snap(data.prev_round(), &data.prev_hash)
+            .and_then(|prev| block::Verifier::new(&prev, block.clone()).finalize().ok());
+        if let Some(snap) = verified {
+            self.persist_snap(&snap);
+            self.keep(snap);
+        }
+        Ok(())
     }
 
-    pub async fn receive_chain(&self, chain: Vec<block::Block>) -> 
-        (msg::Response, msg::Bcasts)
+    // Note which validator signed a header. A second, different one for the same round and
+    // proposal is equivocation, held as evidence for our next block.
+    fn witness(&mut self, sheader: &account::Signed<block::Header>) {
+        let data = &sheader.msg.data;
+        if self.head.epoch.weight(&sheader.from) == 0 {
+            return;
+        }
+        let key = (data.round, data.proposal, Sha256::digest(sheader.from.to_bytes()).into());
+        let Some(first) = self.proposals.get(&key) else {
+            if sheader.verify() {
+                self.proposals.insert(key, sheader.clone());
+            }
+            return;
+        };
+        let ev = evidence::Evidence::Equivocation(first.clone(), sheader.clone());
+        if first.msg.hash() == sheader.msg.hash()
+            || self.evidence.len() >= MAX_EVIDENCE
+            || self.evidence.iter().any(|old| old.same_offence(&ev))
+            || !sheader.verify() {
+            return;
+        }
+        println!("caught a double proposal for round {}", data.round);
+        if let Some(ref mut builder) = self.opt_builder {
+            let _ = builder.add_evidence(ev.clone());
+            self.save_builder();
+        }
+        self.evidence.push(ev);
+    }
+
+    fn uncles(&self, round: u32) -> Vec<account::Signed<block::Header>> {
+        let (mut at, mut hash) = (self.head.block.sheader.msg.data.round, self.head.block_hash);
+        if round > at || at - round >= self.fork_window {
+            return Vec::default();
+        }
+        while at > round {
+            match self.block(at, &hash) {
+                Some(block) => (at, hash) = (block.sheader.msg.data.prev_round(), block.sheader.msg.data.prev_hash),
+                None => return Vec::default()
+            }
+        }
+        // If our chain skipped the round, every block seen for it counts.
+        let canonical = if at == round { Some(hash) } else { None };
+        self.snaps.get(&round).into_iter()
+            .flat_map(HashMap::values)
+            .filter(|kept| Some(kept.block_hash()) != canonical)
+            .map(|kept| kept.block().sheader.clone())
+            .collect()
+    }
+
+    // Is the block at round with this hash, or one of its ancestors, our last finalized block?
+    // Blocks we don't have are let through, the chain gets turned away as BadPrev anyway.
+    fn extends_finalized(&self, mut round: u32, mut hash: [u8; 32]) -> bool {
+        let (final_round, final_hash) = self.finalized;
+        while round > final_round {
+            match self.block(round, &hash) {
+                Some(block) => (round, hash) = (block.sheader.msg.data.prev_round(), block.sheader.msg.data.prev_hash),
+                None => return true
+            }
+        }
+        round == final_round && hash == final_hash
+    }
+
+    // Txns in the branch from old back to where it meets the branch from new, leaving out any
+    // that branch or the blocks in `added` on top of it include.
+    fn orphaned(&self, mut old: (u32, [u8; 32]), mut new: (u32, [u8; 32]), added: &[block::Snap]) ->
+        Vec<account::Signed<txn::Txn>> 
     {
-        match self.process_chain(chain).await {
-            Ok(opt) => {
-                (msg::ser(&Ok::<_, msg::error::Txn>(msg::ok::Txn {})), opt)
-            },
-            Err(e) => {
-                (msg::ser(&Err::<msg::ok::Txn, _>(e)), Vec::default())
+        let mut orphans = Vec::default();
+        let mut kept: BTreeSet<_> = added.iter().flat_map(|snap| snap.block.txnseq.iter().map(txn::hash)).collect();
+        while old.1 != new.1 {
+            let on_old = old.0 >= new.0;
+            let side = if on_old { &mut old } else { &mut new };
+            let Some(block) = self.block(side.0, &side.1) else {
+                break;
+            };
+            if on_old {
+                orphans.extend(block.txnseq.iter().cloned());
+            } else {
+                kept.extend(block.txnseq.iter().map(txn::hash));
+            }
+            if side.0 == 0 {
+                break;
             }
+            *side = (block.sheader.msg.data.prev_round(), block.sheader.msg.data.prev_hash);
         }
+        orphans.into_iter().filter(|txn| !kept.contains(&txn::hash(txn))).collect()
     }
 
-    // for now super dummy impl: just take the snap and make it head!
-    pub async fn accept_resync(&mut self, snap: block::Snap) {
-        for snap in &mut self.snaps {
-            snap.lock().await.clear();
+    fn receive_compact(&mut self, compact: block::Compact, fetched: Vec<account::Signed<txn::Txn>>) ->
+        (msg::Response, msg::Bcasts)
+    {
+        let mut pool: HashMap<_, _> = self.txpool.iter()
+            .chain(fetched.iter())
+            .map(|txn| (txn::short_id(txn), txn.clone()))
+            .collect();
+        if let Some(ref builder) = self.opt_builder {
+            pool.extend(builder.txnseq.iter().map(|txn| (txn::short_id(txn), txn.clone())));
+        }
+        match compact.fill(&pool) {
+            Ok(block) => self.receive_chain(Vec::from([block])),
+            Err(missing) => {
+                // Don't have anyone fetch txns for a block we'd turn away anyway.
+                let round = compact.sheader.msg.data.round;
+                let err = if self.block(round, &compact.sheader.msg.hash()).is_some() {
+                    msg::error::Chain::AlreadyHave
+                } else if round <= self.head.block.sheader.msg.data.round {
+                    msg::error::Chain::TooShort
+                } else {
+                    msg::error::Chain::Missing(missing)
+                };
+                (msg::ser(&Err::<msg::ok::Chain, _>(err)), Vec::default())
+            }
         }
-        *self.head.lock().await = snap.clone();
-        self.snaps[(snap.block.sheader.msg.data.round % MAX_FORK) as usize]
-            .lock()
-            .await
-            .insert(snap.block_hash, snap);
     }
 
-    pub async fn receive(&self, msg: msg::Message) -> (msg::Response, msg::Bcasts) {
-        match msg {
-            msg::Message::Txn(txns) => self.receive_txns(txns).await,
-            msg::Message::Chain(chain) => self.receive_chain(chain).await,
-            msg::Message::Resync() => todo!(),
-            msg::Message::Batch(block_hash, batch) => todo!()
+    // The set as of our last finalized block, or head's if we no longer keep it.
+    fn trusted(&self) -> block::ValidatorSet {
+        let (round, hash) = self.finalized;
+        self.snap(round, &hash).map_or_else(|| self.head.epoch.clone(), |snap| snap.epoch.clone())
+    }
+
+    fn accept_resync(&mut self, snap: block::Snap, next: account::Signed<block::Header>) -> Result<(), block::LoadError> {
+        snap.verify_resync(&self.trusted(), &next)?;
+        self.adopt(snap);
+        Ok(())
+    }
+
+    // Take the snap as head outright.
+    fn adopt(&mut self, snap: block::Snap) {
+        for hash in mem::take(&mut self.snaps).values().flat_map(HashMap::keys) {
+            self.unpersist_snap(hash);
         }
+        self.persist_snap(&snap);
+        self.head = snap.clone();
+        self.votes.clear();
+        self.finality_votes.clear();
+        // Taken on trust, so nothing before it can be reorged either.
+        self.finalized = (snap.block.sheader.msg.data.round, snap.block_hash);
+        self.persist(|meta| {
+            meta.head = (snap.block.sheader.msg.data.round, snap.block_hash);
+            meta.finalized = meta.head;
+        });
+        self.keep(snap);
+        self.flush_pool();
+    }
+
+    fn receive_attest(&mut self, block_hash: [u8; 32], from: account::PublicKey, vote: Vec<u8>) ->
+        (msg::Response, msg::Bcasts)
+    {
+        let result = if block_hash != self.head.block_hash {
+            Err(msg::error::Attest::Stale)
+        } else {
+            let committee = self.head.epoch.committee();
+            match committee.iter().position(|val| val.pk == from) {
+                None => Err(msg::error::Attest::NotCommittee),
+                Some(idx) => {
+                    let bls = committee[idx].bls.as_ref().expect("committee members have keys");
+                    if attest::check_vote(bls, &block_hash, &vote) {
+                        Ok(self.votes.insert(idx, vote.clone()).is_none())
+                    } else {
+                        Err(msg::error::Attest::BadVote)
+                    }
+                }
+            }
+        };
+        let bcasts = match result {
+            Ok(true) => Vec::from([msg::ser(&msg::Message::Attest(block_hash, from, vote))]),
+            _ => Vec::default()
+        };
+        (msg::ser(&result.map(|_| msg::ok::Attest {})), bcasts)
+    }
+
+    fn receive_vote(&mut self, svote: account::Signed<block::Vote>) ->
+        (msg::Response, msg::Bcasts)
+    {
+        let weight = self.head.epoch.weight(&svote.from);
+        let result = if svote.msg.block_hash != self.head.block_hash || svote.msg.round != self.head.block.sheader.msg.data.round {
+            Err(msg::error::Vote::Stale)
+        } else if weight == 0 {
+            Err(msg::error::Vote::NotValidator)
+        } else if !svote.verify() {
+            Err(msg::error::Vote::BadSig)
+        } else {
+            let new = self.finality_votes.insert(Sha256::digest(svote.from.to_bytes()).into(), weight).is_none();
+            let total = self.finality_votes.values().sum();
+            if !self.head.finalized && validator::supermajority(total, self.head.epoch.total_weight()) {
+                self.head.finalized = true;
+                self.finalized = (svote.msg.round, svote.msg.block_hash);
+                self.persist(|meta| meta.finalized = (svote.msg.round, svote.msg.block_hash));
+                if let Some(kept) = self.snaps.get_mut(&svote.msg.round).and_then(|snaps| snaps.get_mut(&svote.msg.block_hash)) {
+                    kept.finalize();
+                }
+            }
+            Ok((new, self.head.finalized))
+        };
+        let bcasts = match result {
+            Ok((true, _)) => Vec::from([msg::ser(&msg::Message::Vote(svote))]),
+            _ => Vec::default()
+        };
+        (msg::ser(&result.map(|(_, finalized)| msg::ok::Vote { finalized })), bcasts)
     }
 }
 
@@ -288,32 +2310,245 @@ pub mod tests {
     
     use tokio::time;
 
-    use crate::block::BLOCK_TIME;
+    use super::*;
+
+    // Every test here runs on the default genesis clock.
+    fn clock() -> state::Clock {
+        state::Clock::default()
+    }
+
+    async fn add_snap(node: &Node, snap: block::Snap) -> msg::Bcasts {
+        node.call(move |core| core.add_snap(snap)).await
+    }
+
+    // Hand a block from one node to another, fetching whatever txns the receiver's pool lacks.
+    async fn relay(from: &Node, to: &Node, bcast: msg::Message) -> msg::Response {
+        let compact = bcast.compact().expect("blocks are relayed compact");
+        let (resp, _) = to.receive_compact(compact.clone(), Vec::default()).await;
+        match serde_json::from_str::<Result<msg::ok::Chain, msg::error::Chain>>(&resp) {
+            Ok(Err(msg::error::Chain::Missing(missing))) => {
+                let (resp, _) = from.receive_get_txns(compact.sheader.msg.hash(), missing).await;
+                let fetched = serde_json::from_str::<Result<msg::ok::GetTxns, msg::error::GetTxns>>(&resp)
+                    .unwrap()
+                    .unwrap();
+                to.receive_compact(compact, fetched.txns).await.0
+            },
+            _ => resp
+        }
+    }
+
+    async fn setup() -> (time::Interval, Node, Node) {
+        let now = time::Instant::now();
+        let (authority, gen) = block::genesis();
+        /*
+        // Block time sync!
+        let now =  SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let wait_for = ((clock().block_time - (now - gen.block.sheader.msg.data.timestamp) % clock().block_time)) % clock().block_time;
+        sleep(Duration::from_millis(wait_for));
+        */
+        // Now synced!
+        let mut interval = time::interval_at(now, Duration::from_millis(clock().block_time));
+        println!("init gang {:?}", state::timestamp());
+        interval.tick().await;
+        println!("block0 gang {:?}", state::timestamp());
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        alice.tick().await;
+        bob.tick().await;
+        (interval, alice, bob)
+    }
+
+    #[tokio::test]
+    async fn stalled() {
+        let (authority, gen) = block::genesis();
+        let alice = Node::new(authority, gen, state::GENESIS_SLOTS);
+        alice.call(|core| core.head.block.sheader.msg.data.timestamp -= STALL_TICKS * clock().block_time + 1).await;
+        // Nobody is ahead of us.
+        assert!(!alice.stalled().await);
+        alice.call(|core| core.best_round = 5).await;
+        assert!(alice.stalled().await);
+        // Don't fire again until another stall period passes.
+        assert!(!alice.stalled().await);
+    }
+
+    #[tokio::test]
+    async fn restart() {
+        let (authority, gen) = block::genesis();
+        let path = std::env::temp_dir().join(format!("tam-builder-{:x}.json", u64::from_be_bytes(gen.block_hash[..8].try_into().unwrap())));
+        let kp_bytes = authority.kp.to_bytes();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS).with_builder_path(path.clone());
+        alice.call(Core::check_leader).await;
+        let txn = alice.kp().send(account::Keypair::gen().kp.public, 1 << 10, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn.clone()])).await;
+        // Crash and come back: the txn is still in our block.
+        drop(alice);
+        let kp = account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&kp_bytes).unwrap() };
+        let alice = Node::new(kp, gen, state::GENESIS_SLOTS).with_builder_path(path.clone());
+        alice.call(Core::check_leader).await;
+        let txnseq = alice.call(|core| core.opt_builder.as_ref().unwrap().txnseq.clone()).await;
+        assert_eq!(txnseq.iter().collect::<Vec<_>>(), vec![&txn]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn resync() {
+        let (authority, gen) = block::genesis();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let (resp, _) = alice.receive(msg::Message::Resync()).await;
+        assert!(matches!(serde_json::from_str(&resp).unwrap(), Err::<msg::ok::Resync, _>(msg::error::Resync::NotSaved)));
+        let snap = block::Builder::new(&alice.kp(), 1, &gen).finalize(&alice.kp());
+        add_snap(&alice, snap.clone()).await;
+        // Offered along with the block after it, which has to carry the committee's attestation
+        let unattested = block::Builder::new(&alice.kp(), 1, &snap).finalize(&alice.kp());
+        assert_eq!(bob.accept_resync(snap.clone(), unattested.block.sheader).await, Err(block::LoadError::NotAttested));
+        let mut builder = block::Builder::new(&alice.kp(), 1, &snap);
+        let votes = BTreeMap::from([(0, attest::vote(&alice.kp(), &snap.block_hash))]);
+        builder.attestation = attest::Attestation::aggregate(&votes).unwrap();
+        add_snap(&alice, builder.finalize(&alice.kp())).await;
+        let (resp, _) = alice.receive(msg::Message::Resync()).await;
+        let ok = serde_json::from_str::<Result<msg::ok::Resync, msg::error::Resync>>(&resp).unwrap().unwrap();
+        assert_eq!(ok.snap.block_hash, snap.block_hash);
+        assert_eq!(ok.snap.check(), Ok(()));
+        // A peer can't hand over a state the header doesn't commit to.
+        let mut bad = snap.clone();
+        let bob_id: account::Id = Sha256::digest(bob.kp().kp.public.to_bytes()).into();
+        assert!(bad.state.accounts.insert(&bob_id, account::Data { bal: 1 << 20, ..Default::default() }).is_ok());
+        assert_eq!(bad.check(), Err(block::LoadError::BadState));
+        assert_eq!(bob.accept_resync(bad, ok.next.clone()).await, Err(block::LoadError::BadState));
+        // Nor a block signed by someone holding no slots as of our last finalized block
+        let stranger = account::Keypair::gen();
+        let forged = block::Builder::new(&stranger, 1, &gen).finalize(&stranger);
+        assert_eq!(forged.check(), Ok(()));
+        assert_eq!(bob.accept_resync(forged, ok.next.clone()).await, Err(block::LoadError::NotLeader));
+        assert_eq!(bob.check_resync(ok.snap.clone(), ok.next.clone()).await, Ok(()));
+        assert_eq!(bob.get_head().await.block_hash, gen.block_hash);
+        assert_eq!(bob.accept_resync(ok.snap, ok.next).await, Ok(()));
+        assert_eq!(bob.get_head().await.block_hash, snap.block_hash);
+    }
+
+    #[tokio::test]
+    async fn fastsync() {
+        let (authority, gen) = block::genesis();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let mut snaps = Vec::default();
+        let mut head = gen;
+        for _ in 0..3 {
+            let mut builder = block::Builder::new(&alice.kp(), 1, &head);
+            let nonce = builder.metadata.round + state::GENESIS_SLOTS - 1;
+            assert!(builder.add(alice.kp().send(bob.kp().kp.public, 1 << 10, nonce, None)).is_ok());
+            head = builder.finalize(&alice.kp());
+            snaps.push(head.clone());
+        }
+        let audit = bob.fast_sync(snaps.clone()).await.unwrap();
+        assert_eq!(bob.get_head().await.block_hash, head.block_hash);
+        assert_eq!(audit.await.unwrap(), Ok(()));
+        // Not off our head.
+        assert!(matches!(bob.fast_sync(snaps).await, Err(msg::error::Chain::BadPrev)));
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let (authority, gen) = block::genesis();
+        let clone = |kp: &account::Keypair| account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&kp.kp.to_bytes()).unwrap() };
+        let alice = Node::new(clone(&authority), gen, state::GENESIS_SLOTS);
+        alice.call(Core::check_leader).await;
+        let txn = authority.send(account::Keypair::gen().kp.public, 1 << 10, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn.clone()])).await;
+        // A new key holds no slots, so our block is given up and its txn pooled again
+        let fresh = account::Keypair::gen();
+        let fresh_pk = fresh.kp.public;
+        alice.rotate_key(fresh).await;
+        assert_eq!(alice.kp().kp.public, fresh_pk);
+        assert!(alice.call(move |core| core.opt_builder.is_none() && core.kp.kp.public == fresh_pk).await);
+        assert!(alice.call(move |core| core.txpool.contains(&txn)).await);
+        let own = alice.sign_own(|kp, nonce| kp.send(authority.kp.public, 1, nonce, None)).await;
+        assert_eq!((own.from, own.msg.nonce), (fresh_pk, 0));
+        // Back to the old one, we lead again and pick the txn back up
+        alice.rotate_key(clone(&authority)).await;
+        assert_eq!(alice.next_nonce().await, state::GENESIS_SLOTS);
+        let txnseq = alice.call(|core| core.opt_builder.as_ref().map(|builder| builder.txnseq.len())).await;
+        assert_eq!(txnseq, Some(1));
+    }
 
-    use super::*;
+    #[tokio::test]
+    async fn txn_outcomes() {
+        let (_, alice, bob) = setup().await;
+        let bob_pk = bob.kp().kp.public;
+        let send = |nonce| alice.kp().send(bob_pk, state::DUST_BALANCE, nonce, None);
+        let (next, later, stale) = (send(state::GENESIS_SLOTS), send(state::GENESIS_SLOTS + 1), send(state::GENESIS_SLOTS - 1));
+        let outcomes = |resp: msg::Response| match msg::deser::<Result<msg::ok::Txn, msg::error::Txn>>(&resp) {
+            Ok(ok) => ok.outcomes,
+            Err(e) => panic!("{:?}", e)
+        };
+        // Bob isn't building, so they wait in his pool
+        assert_eq!(outcomes(bob.receive_txns(Vec::from([next.clone(), later.clone(), stale.clone()])).await.0), Vec::from([
+            (txn::hash(&next), msg::ok::Outcome::Pooled),
+            (txn::hash(&later), msg::ok::Outcome::Pooled),
+            (txn::hash(&stale), msg::ok::Outcome::Rejected(txn::Error::SmallNonce { expected: state::GENESIS_SLOTS, actual: state::GENESIS_SLOTS - 1 }))
+        ]));
+        assert_eq!(outcomes(bob.receive_txns(Vec::from([next.clone()])).await.0), Vec::from([(txn::hash(&next), msg::ok::Outcome::Known)]));
+        // Alice leads, so hers go straight into her block
+        assert_eq!(outcomes(alice.receive_txns(Vec::from([next.clone()])).await.0), Vec::from([(txn::hash(&next), msg::ok::Outcome::Included)]));
+    }
 
-    async fn setup<'a>() -> (time::Interval, Node, Node) {
-        let now = time::Instant::now();
-        let gen = block::Snap::default();
-        /*
-        // Block time sync!
-        let now =  SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-        let wait_for = ((BLOCK_TIME - (now - gen.block.sheader.msg.data.timestamp) % BLOCK_TIME)) % BLOCK_TIME;
-        sleep(Duration::from_millis(wait_for));
-        */
-        // Now synced!
-        let mut interval = time::interval_at(now, Duration::from_millis(BLOCK_TIME));
-        println!("init gang {:?}", state::timestamp());
-        interval.tick().await;
-        println!("block0 gang {:?}", state::timestamp());
-        let alice = Node::new(account::Keypair::default(), gen.clone(), state::JENNY_SLOTS);
-        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
-        alice.tick().await;
-        bob.tick().await;
-        (interval, alice, bob)
+    #[tokio::test]
+    async fn submit() {
+        let (_, alice, bob) = setup().await;
+        let bob_pk = bob.kp().kp.public;
+        assert_eq!(bob.submit_block(Vec::default(), None).await, Err(SubmitError::NotLeader));
+        let (pending, mut feed) = alice.subscribe_pool().await;
+        assert!(pending.is_empty());
+        let txn = alice.kp().send(bob_pk, 1 << 10, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn.clone()])).await;
+        assert_eq!(feed.recv().await, Ok(txn.clone()));
+        // Tampered with after signing, so turned away before the builder sees it.
+        let mut forged = alice.kp().send(bob_pk, 1 << 10, state::GENESIS_SLOTS + 1, None);
+        forged.msg.nonce += 1;
+        assert_eq!(
+            alice.receive_txns(Vec::from([forged.clone()])).await.0,
+            msg::ser(&Err::<msg::ok::Txn, _>(msg::error::Txn::Rejected(Vec::from([(forged, txn::Error::BadSig)]))))
+        );
+        // Replayed nonce.
+        let stale = alice.kp().send(bob_pk, 1 << 10, state::GENESIS_SLOTS - 1, None);
+        let rejected = Vec::from([(
+            stale.clone(), 
+            txn::Error::SmallNonce { expected: state::GENESIS_SLOTS + 1, actual: state::GENESIS_SLOTS - 1 }
+        )]);
+        assert_eq!(
+            alice.receive_txns(Vec::from([stale.clone()])).await.0,
+            msg::ser(&Err::<msg::ok::Txn, _>(msg::error::Txn::Rejected(rejected)))
+        );
+        assert_eq!(
+            alice.submit_block(Vec::from([stale.clone()]), None).await, 
+            Err(SubmitError::BadTxn(stale, txn::Error::SmallNonce { expected: state::GENESIS_SLOTS, actual: state::GENESIS_SLOTS - 1 }))
+        );
+        assert_eq!(alice.submit_block(Vec::default(), Some([0u8; 32])).await, Err(SubmitError::BadState));
+        // An empty block bumps our txn back into the pool.
+        assert_eq!(alice.submit_block(Vec::default(), None).await, Ok(()));
+        assert!(alice.call(move |core| core.txpool.contains(&txn)).await);
+        let bcast: msg::Message = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
+        assert!(bcast.compact().unwrap().ids.is_empty());
+        assert!(!alice.call(|core| core.sign_round(1)).await);
+    }
+
+    #[tokio::test]
+    async fn rebuild() {
+        let (_, alice, bob) = setup().await;
+        let txn = alice.kp().send(bob.kp().kp.public, 1 << 10, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn.clone()])).await;
+        let picked = txn.clone();
+        assert!(alice.call(move |core| core.opt_builder.as_ref().is_some_and(|builder| builder.txnseq.iter().any(|ours| ours == &picked))).await);
+        // A head that leaves our txn out doesn't lose it.
+        let head = alice.get_head().await;
+        add_snap(&alice, block::Builder::new(&alice.kp(), 1, &head).finalize(&alice.kp())).await;
+        assert!(alice.call(move |core| {
+            core.txpool.contains(&txn) || core.opt_builder.as_ref().is_some_and(|builder| builder.txnseq.iter().any(|ours| ours == &txn))
+        }).await);
     }
 
     #[tokio::test]
@@ -321,23 +2556,26 @@ pub mod tests {
         let (_, alice, bob) = setup().await;
         println!("It's {:?}", state::timestamp());
         // Don't wait long enough.
-        sleep(Duration::from_millis((block::BLOCK_TIME - MAX_CLOCK_GAP) >> 1));
+        sleep(Duration::from_millis((clock().block_time - clock().max_clock_gap) >> 1));
         let bcast: msg::Message = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
         assert_eq!(bob.tick().await, msg::Bcasts::default());
         assert_eq!(
-            bob.receive(bcast).await, 
+            bob.receive(bcast.clone()).await, 
             (
                 msg::ser(&Err::<msg::ok::Chain,_>(msg::error::Chain::BigTimestamp)),
                 msg::Bcasts::default()
             )
         );
+        // Fine once we know our clock is behind
+        bob.set_clock_offset((clock().block_time >> 1) as i64).await;
+        assert_eq!(bob.receive(bcast).await.0, msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {})));
     }
 
     #[tokio::test]
     async fn smalltimestamp() {
         let (_, alice, bob) = setup().await;
         // Wait too long.
-        sleep(Duration::from_millis(BLOCK_TIME + MAX_CLOCK_GAP + MAX_PROP_TIME + 1_000));
+        sleep(Duration::from_millis(clock().block_time + clock().max_clock_gap + clock().max_prop_time + 1_000));
         let bcast: msg::Message = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
         assert_eq!(bob.tick().await, msg::Bcasts::default());
         assert_eq!(
@@ -365,21 +2603,761 @@ pub mod tests {
                 msg::Bcasts::default()
             )
         );
+        assert_eq!(alice.metrics.counter(metrics::BLOCKS_PROPOSED, None), 2);
+        assert_eq!(bob.metrics.counter(metrics::BLOCKS_REJECTED, Some("BadPrev")), 1);
+    }
+
+    #[tokio::test]
+    async fn reorg() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - 2 * clock().block_time));
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let txns: Vec<_> = (0..2)
+            .map(|i| authority.send(bob.kp().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS + i, None))
+            .collect();
+        let mut builder = block::Builder::new(&authority, 1, &gen);
+        for txn in txns.clone() {
+            assert!(builder.add(txn).is_ok());
+        }
+        add_snap(&bob, builder.finalize(&authority)).await;
+        // A longer branch that only has the first txn
+        let mut builder = block::Builder::new(&authority, 1, &gen);
+        assert!(builder.add(txns[0].clone()).is_ok());
+        let fork = builder.finalize(&authority);
+        let tip = block::Builder::new(&authority, 1, &fork).finalize(&authority);
+        assert_eq!(
+            bob.receive(msg::Message::Chain(Vec::from([fork.block, tip.block]))).await.0,
+            msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
+        );
+        assert_eq!(bob.get_head().await.block_hash, tip.block_hash);
+        assert_eq!(bob.metrics.counter(metrics::REORGS, None), 1);
+        assert_eq!(bob.metrics.counter(metrics::BLOCKS_ACCEPTED, None), 2);
+        assert_eq!(bob.metrics.summary(metrics::VERIFY_MS).count, 2);
+        let pool = bob.call(|core| core.txpool.iter().cloned().collect::<Vec<_>>()).await;
+        assert_eq!(pool, txns[1..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn lookup_txn() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - 2 * clock().block_time));
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let txns: Vec<_> = (0..3)
+            .map(|i| authority.send(bob.kp().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS + i, None))
+            .collect();
+        let mut builder = block::Builder::new(&authority, 1, &gen);
+        for txn in txns[..2].iter().cloned() {
+            assert!(builder.add(txn).is_ok());
+        }
+        let orphan = builder.finalize(&authority);
+        add_snap(&bob, orphan.clone()).await;
+        let mut builder = block::Builder::new(&authority, 1, &gen);
+        assert!(builder.add(txns[0].clone()).is_ok());
+        let fork = builder.finalize(&authority);
+        let tip = block::Builder::new(&authority, 1, &fork).finalize(&authority);
+        bob.receive(msg::Message::Chain(Vec::from([fork.block.clone(), tip.block]))).await;
+        bob.receive(msg::Message::Txn(txns[2..].to_vec())).await;
+        // On our chain beats the branch we left, which beats the pool
+        let found = bob.lookup_txn(&txn::hash(&txns[0])).await.unwrap();
+        assert_eq!((found.status, found.at), (TxnStatus::Included, Some((1, fork.block_hash, 0))));
+        let found = bob.lookup_txn(&txn::hash(&txns[1])).await.unwrap();
+        assert_eq!((found.status, found.at), (TxnStatus::Forked, Some((1, orphan.block_hash, 1))));
+        assert_eq!(found.stxn, txns[1]);
+        let found = bob.lookup_txn(&txn::hash(&txns[2])).await.unwrap();
+        assert_eq!((found.status, found.at), (TxnStatus::Pending, None));
+        assert!(bob.lookup_txn(&[0; 32]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn roles() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - clock().block_time));
+        let twin = || account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&authority.kp.to_bytes()).unwrap() };
+        // Every slot, but never leads or votes with them.
+        let full = Node::new(twin(), gen.clone(), state::GENESIS_SLOTS).with_role(Role::Full);
+        let observer = Node::new(twin(), gen.clone(), state::GENESIS_SLOTS).with_role(Role::Observer);
+        full.call(Core::check_leader).await;
+        assert!(full.call(|core| core.opt_builder.is_none()).await);
+        assert_eq!(full.tick().await, msg::Bcasts::default());
+        let block = block::Builder::new(&authority, 1, &gen).finalize(&authority).block;
+        let relay = msg::ser(&msg::Message::Compact(block::Compact::new(&block)));
+        assert_eq!(full.receive_chain(Vec::from([block.clone()])).await.1, Vec::from([relay]));
+        assert_eq!(observer.receive_chain(Vec::from([block.clone()])).await.1, msg::Bcasts::default());
+        assert_eq!(observer.get_head().await.block.sheader, block.sheader);
+        let txn = authority.send(full.kp().kp.public, 1, state::GENESIS_SLOTS, None);
+        assert_eq!(full.receive_txns(Vec::from([txn.clone()])).await.1.len(), 1);
+        assert_eq!(
+            observer.receive_txns(Vec::from([txn])).await, 
+            (msg::ser(&Err::<msg::ok::Txn, _>(msg::error::Txn::Observer)), msg::Bcasts::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn missed() {
+        let fast = state::Clock { block_time: 100, ..clock() };
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp()).with_clock(fast));
+        let alice = Node::new(authority, gen, state::GENESIS_SLOTS);
+        assert_eq!(alice.tick().await, msg::Bcasts::default());
+        let txn = alice.kp().send(account::Keypair::gen().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn.clone()])).await;
+        assert!(alice.call(|core| core.txpool.is_empty()).await);
+        // Stall through a few slots. The stale proposal is dropped, not signed.
+        sleep(Duration::from_millis(3 * fast.block_time));
+        assert_eq!(alice.tick().await, msg::Bcasts::default());
+        assert_eq!(alice.metrics.counter(metrics::SLOTS_MISSED, None), 1);
+        // And we're straight back on the proposal for the slot we're in.
+        let bcast: msg::Message = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
+        assert!(bcast.compact().unwrap().sheader.msg.data.proposal >= 4);
+        assert_eq!(alice.metrics.counter(metrics::SLOTS_MISSED, None), 1);
+        // With what the dropped one had in it
+        assert!(alice.get_head().await.block.txnseq.iter().any(|stxn| *stxn == txn));
+        // Which shows in our uptime
+        let uptime = alice.uptime(Sha256::digest(alice.kp().kp.public.to_bytes()).into()).await.unwrap();
+        assert_eq!(uptime.produced, 1);
+        assert!(uptime.missed >= 3);
+    }
+
+    #[tokio::test]
+    async fn orphans() {
+        // Loose enough that the child, a round ahead of our clock, is never turned away for it.
+        let loose = state::Clock { max_clock_gap: 60_000, ..clock() };
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - loose.block_time).with_clock(loose));
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let parent = block::Builder::new(&authority, 1, &gen).finalize(&authority);
+        let child = block::Builder::new(&authority, 1, &parent).finalize(&authority);
+        assert_eq!(
+            bob.receive_chain(Vec::from([child.block.clone()])).await.0,
+            msg::ser(&Err::<msg::ok::Chain, _>(msg::error::Chain::BadPrev))
+        );
+        // The child goes on as soon as its parent turns up.
+        let (_, bcasts) = bob.receive_chain(Vec::from([parent.block])).await;
+        assert_eq!(bcasts.len(), 2);
+        assert_eq!(bob.get_head().await.block_hash, child.block_hash);
+        assert!(bob.call(|core| core.orphans.is_empty()).await);
+        // Held only up to a limit, oldest dropped first.
+        let carol = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let mut lates = Vec::default();
+        for amount in 0..MAX_ORPHANS as u32 + 1 {
+            let mut builder = block::Builder::new(&authority, 1, &gen);
+            assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE + amount, state::GENESIS_SLOTS, None)).is_ok());
+            let parent = builder.finalize(&authority);
+            let late = block::Builder::new(&authority, 1, &parent).finalize(&authority).block;
+            let chain = Vec::from([late.clone()]);
+            carol.call(move |core| core.hold_orphan(chain)).await;
+            lates.push(late);
+        }
+        let held = carol.call(|core| core.orphans.iter().map(|chain| chain[0].clone()).collect::<Vec<_>>()).await;
+        assert_eq!(held, lates[1..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn backfill() {
+        let fast = state::Clock { block_time: 100, ..clock() };
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - fast.block_time).with_clock(fast));
+        let alice = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let parent = block::Builder::new(&authority, 1, &gen).finalize(&authority);
+        let child = block::Builder::new(&authority, 1, &parent).finalize(&authority);
+        add_snap(&alice, parent.clone()).await;
+        add_snap(&alice, child.clone()).await;
+        bob.receive_chain(Vec::from([child.block.clone()])).await;
+        assert_eq!(
+            alice.receive(msg::Message::GetBlocks(parent.block_hash, MAX_GET_BLOCKS + 1)).await.0,
+            msg::ser(&Err::<msg::ok::GetBlocks, _>(msg::error::GetBlocks::TooMany))
+        );
+        // Runs out at genesis.
+        let (resp, _) = alice.receive(msg::Message::GetBlocks(parent.block_hash, 3)).await;
+        let fetched = serde_json::from_str::<Result<msg::ok::GetBlocks, msg::error::GetBlocks>>(&resp).unwrap().unwrap();
+        assert_eq!(fetched.blocks, Vec::from([gen.block, parent.block]));
+        assert_eq!(bob.backfill(fetched.blocks).await.0, msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {})));
+        assert_eq!(bob.get_head().await.block_hash, child.block_hash);
+    }
+
+    #[tokio::test]
+    async fn tiebreak() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - clock().block_time));
+        let siblings: Vec<_> = (1..3)
+            .map(|amount| {
+                let mut builder = block::Builder::new(&authority, 1, &gen);
+                assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE + amount, state::GENESIS_SLOTS, None)).is_ok());
+                builder.finalize(&authority).block
+            })
+            .collect();
+        let winner = if siblings[0].beats(&siblings[1]) { &siblings[0] } else { &siblings[1] };
+        // Same head whichever came first
+        for order in [[0, 1], [1, 0]] {
+            let node = Node::new(account::Keypair::gen(), gen.clone(), 0);
+            for i in order {
+                node.receive(msg::Message::Chain(Vec::from([siblings[i].clone()]))).await;
+            }
+            assert_eq!(node.get_head().await.block, *winner);
+            assert_eq!(node.uncles(1).await.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn equivocation() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - clock().block_time));
+        let siblings: Vec<_> = (1..3)
+            .map(|amount| {
+                let mut builder = block::Builder::new(&authority, 1, &gen);
+                assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE + amount, state::GENESIS_SLOTS, None)).is_ok());
+                builder.finalize(&authority).block
+            })
+            .collect();
+        let kp = account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&authority.kp.to_bytes()).unwrap() };
+        let alice = Node::new(kp, gen, 0);
+        for sibling in siblings.iter() {
+            alice.receive(msg::Message::Chain(Vec::from([sibling.clone()]))).await;
+        }
+        let expected = evidence::Evidence::Equivocation(siblings[0].sheader.clone(), siblings[1].sheader.clone());
+        assert_eq!(alice.call(|core| core.evidence.clone()).await, Vec::from([expected.clone()]));
+        // Seeing it again isn't a second offence
+        alice.receive(msg::Message::Chain(Vec::from([siblings[0].clone()]))).await;
+        assert_eq!(alice.call(|core| core.evidence.len()).await, 1);
+        // Goes in our next block, and isn't pending once that's head
+        assert!(!alice.tick().await.is_empty());
+        let head = alice.get_head().await;
+        assert_eq!(head.block.evidence.iter().cloned().collect::<Vec<_>>(), Vec::from([expected]));
+        assert!(alice.call(|core| core.evidence.is_empty()).await);
+    }
+
+    #[tokio::test]
+    async fn byzantine() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - clock().block_time));
+        let twin = || account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&authority.kp.to_bytes()).unwrap() };
+        // Whatever blocks the leader sends on a tick, once it's had one to build
+        let blocks = |bcasts: msg::Bcasts| bcasts.iter()
+            .map(|bcast| msg::deser::<msg::Message>(bcast))
+            .filter(|msg| matches!(msg, msg::Message::Compact(..) | msg::Message::Chain(..)))
+            .collect::<Vec<_>>();
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        // Proposals are for the slot after the one we're in
+        bob.set_clock_offset(clock().block_time as i64).await;
+        // A txn the header doesn't commit to gets the block turned away
+        let corrupt = Node::new(twin(), gen.clone(), 0).with_behavior(behavior::Corrupt);
+        corrupt.tick().await;
+        let sent = blocks(corrupt.tick().await);
+        assert_eq!(sent.len(), 1);
+        let (resp, _) = bob.receive(sent[0].clone()).await;
+        assert!(matches!(msg::deser(&resp), Err::<msg::ok::Chain, _>(msg::error::Chain::BadBlock(..))));
+        assert_eq!(bob.get_head().await.block_hash, gen.block_hash);
+        // Two blocks for one proposal: one is taken and the other is evidence against the leader
+        let equivocate = Node::new(twin(), gen.clone(), 0).with_behavior(behavior::Equivocate);
+        equivocate.tick().await;
+        let sent = blocks(equivocate.tick().await);
+        assert_eq!(sent.len(), 2);
+        for msg in sent {
+            bob.receive(msg).await;
+        }
+        assert_ne!(bob.get_head().await.block_hash, gen.block_hash);
+        assert!(matches!(bob.call(|core| core.evidence.clone()).await.as_slice(), [evidence::Evidence::Equivocation(..)]));
+        // Nothing sent, or only later
+        let withhold = Node::new(twin(), gen.clone(), 0).with_behavior(behavior::Withhold);
+        withhold.tick().await;
+        assert!(blocks(withhold.tick().await).is_empty());
+        let delay = Node::new(twin(), gen.clone(), 0).with_behavior(behavior::Delay::new(1));
+        delay.tick().await;
+        assert!(blocks(delay.tick().await).is_empty());
+        assert_eq!(blocks(delay.tick().await).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pruning() {
+        let (authority, gen) = block::genesis();
+        let mut chain = Vec::from([gen.clone()]);
+        for _ in 0..3 {
+            let snap = block::Builder::new(&authority, 1, chain.last().unwrap()).finalize(&authority);
+            chain.push(snap);
+        }
+        let clone = |kp: &account::Keypair| account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&kp.kp.to_bytes()).unwrap() };
+        // Bob has no stake so finalizes nothing, alice finalizes every block she takes
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0).with_fork_window(2, Pruning::Finality);
+        let alice = Node::new(clone(&authority), gen, 0).with_fork_window(FORK_WINDOW, Pruning::Finality);
+        for snap in chain[1..].iter() {
+            add_snap(&bob, snap.clone()).await;
+            add_snap(&alice, snap.clone()).await;
+        }
+        let (mut bob_kept, mut alice_kept) = (Vec::default(), Vec::default());
+        for snap in chain.iter() {
+            bob_kept.push(bob.find_snap(&snap.block_hash).await.is_some());
+            alice_kept.push(alice.find_snap(&snap.block_hash).await.is_some());
+        }
+        assert_eq!(bob_kept, [false, false, true, true]);
+        assert_eq!(alice_kept, [false, false, false, true]);
+        assert!(bob.uncles(1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn thinning() {
+        let (authority, gen) = block::genesis();
+        let mut chain = Vec::from([gen.clone()]);
+        for i in 0..FULL_ROUNDS + 4 {
+            let mut builder = block::Builder::new(&authority, 1, chain.last().unwrap());
+            builder.add(authority.send(account::Keypair::gen().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS + i, None)).unwrap();
+            chain.push(builder.finalize(&authority));
+        }
+        let node = Node::new(account::Keypair::gen(), gen, 0);
+        let small = Node::new(account::Keypair::gen(), chain[0].clone(), 0).with_fork_window(FULL_ROUNDS + 3, Pruning::Window);
+        for snap in chain[1..].iter() {
+            add_snap(&node, snap.clone()).await;
+            add_snap(&small, snap.clone()).await;
+        }
+        async fn whole(node: &Node) -> Vec<bool> {
+            node.call(|core| core.snaps.values().flat_map(HashMap::values).map(|kept| matches!(kept, Kept::Full(_))).collect()).await
+        }
+        // Genesis has no parent to rebuild from, the last FULL_ROUNDS blocks are close to head
+        let mut expected = Vec::from([true, false, false, false]);
+        expected.extend([true; FULL_ROUNDS as usize + 1]);
+        assert_eq!(whole(&node).await, expected);
+        for snap in chain.iter() {
+            assert_eq!(node.find_snap(&snap.block_hash).await.as_ref(), Some(snap));
+        }
+        // Once its parent is pruned, the oldest snap left is whole again
+        assert_eq!(whole(&small).await, [true, false].into_iter().chain([true; FULL_ROUNDS as usize + 1]).collect::<Vec<_>>());
+        assert_eq!(small.find_snap(&chain[3].block_hash).await.as_ref(), Some(&chain[3]));
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - 100 * clock().block_time));
+        let alice = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let mut chain = Vec::from([gen.clone()]);
+        for i in 0..3 {
+            let mut builder = block::Builder::new(&authority, 1, chain.last().unwrap());
+            builder.add(authority.send(account::Keypair::gen().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS + i, None)).unwrap();
+            chain.push(builder.finalize(&authority));
+            add_snap(&alice, chain.last().unwrap().clone()).await;
+        }
+        let path = std::env::temp_dir().join(format!("tam-export-{:x}.chain", u64::from_be_bytes(gen.block_hash[..8].try_into().unwrap())));
+        assert_eq!(alice.export_chain(&path, 1, 3).await, Ok(3));
+        // A fresh node on the same genesis catches up from the file, blocks too old to take live and all
+        let bob = Node::new(account::Keypair::gen(), gen, 0);
+        assert!(matches!(bob.import_chain(&path).await, Ok(3)));
+        assert_eq!(bob.get_head().await, alice.get_head().await);
+        assert!(matches!(bob.import_chain(&path).await, Ok(0)));
+        assert_eq!(alice.export_chain(&path, 2, 2).await, Ok(1));
+        assert_eq!(export::read(&path).map(|blocks| blocks[0].clone()), Ok(chain[2].block.clone()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(bob.import_chain(&path).await, Err(ImportError::File(export::Error::Io))));
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (_, gen) = block::genesis();
+        let alice = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let bob = Node::new(account::Keypair::gen(), gen, 0);
+        let answer = |resp: msg::Response| serde_json::from_str::<Result<msg::ok::Handshake, msg::error::Handshake>>(&resp).unwrap();
+        let hello = bob.hello().await;
+        let ok = answer(alice.receive(msg::Message::Handshake(hello.clone())).await.0).unwrap();
+        assert_eq!(ok.hello, alice.hello().await);
+        assert_eq!(hello.check(&ok.hello), Ok(()));
+        // Someone else's chain, or a protocol we don't speak, is turned away with ours
+        let (_, other) = block::genesis();
+        let carol = Node::new(account::Keypair::gen(), other, 0);
+        assert_eq!(
+            answer(alice.receive(msg::Message::Handshake(carol.hello().await)).await.0).unwrap_err(),
+            msg::error::Handshake::WrongGenesis(hello.genesis)
+        );
+        let old = msg::Hello { version: msg::PROTOCOL_VERSION - 1, ..hello };
+        assert_eq!(
+            answer(alice.receive(msg::Message::Handshake(old)).await.0).unwrap_err(),
+            msg::error::Handshake::WrongVersion(msg::PROTOCOL_VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn addrs() {
+        let (_, gen) = block::genesis();
+        let node = Node::new(account::Keypair::gen(), gen, 0);
+        let addrs = Vec::from([String::from("10.0.0.1:3000"), String::from("10.0.0.2:3000")]);
+        // New ones are passed on, and only those
+        node.learn(addrs[0].clone()).await;
+        let (resp, bcasts) = node.receive(msg::Message::Addrs(addrs.clone())).await;
+        assert_eq!(serde_json::from_str::<Result<msg::ok::Addrs, msg::error::Addrs>>(&resp).unwrap().unwrap().new, 1);
+        assert_eq!(bcasts, [msg::ser(&msg::Message::Addrs(addrs[1..].to_vec()))]);
+        assert!(node.receive(msg::Message::Addrs(addrs.clone())).await.1.is_empty());
+        let too_many = Vec::from_iter((0..=peers::MAX_SHARED).map(|i| format!("10.0.1.{}:3000", i)));
+        assert!(matches!(
+            serde_json::from_str::<Result<msg::ok::Addrs, msg::error::Addrs>>(&node.receive(msg::Message::Addrs(too_many)).await.0).unwrap(),
+            Err(msg::error::Addrs::TooMany)
+        ));
+        // Everything we know of, for a peer that asks
+        let resp = node.receive(msg::Message::Peers()).await.0;
+        assert_eq!(serde_json::from_str::<Result<msg::ok::Peers, msg::error::Peers>>(&resp).unwrap().unwrap().addrs, addrs);
+        assert!(node.forget(&addrs[0]).await);
+        assert_eq!(node.known_addrs().await, addrs[1..]);
+    }
+
+    #[tokio::test]
+    async fn busy() {
+        let (_, gen) = block::genesis();
+        let node = Arc::new(Node::new(account::Keypair::gen(), gen, 0).with_command_queue(1));
+        // One command holds the core up, the next fills the queue
+        for _ in 0..2 {
+            let node = node.clone();
+            tokio::spawn(async move { node.call(|_| sleep(Duration::from_millis(300))).await });
+        }
+        time::sleep(Duration::from_millis(50)).await;
+        let busy = msg::ser(&Err::<(), _>(msg::error::Refused::Busy));
+        assert_eq!(node.receive_from("alice", msg::Message::Time()).await.0, busy);
+        // Our own calls wait their turn instead
+        assert_eq!(node.get_head().await.block.sheader.msg.data.round, 0);
+        assert_ne!(node.receive_from("alice", msg::Message::Time()).await.0, busy);
+    }
+
+    #[tokio::test]
+    async fn throttled() {
+        let (authority, gen) = block::genesis();
+        let bob = Node::new(account::Keypair::gen(), gen, 0)
+            .with_rate_limits(ratelimit::Limit { rate: 1, burst: 2 }, ratelimit::BLOCKS);
+        let txns: Vec<_> = (0..3).map(|i| authority.send(bob.kp().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS + i, None)).collect();
+        let throttled = msg::ser(&Err::<(), _>(msg::error::Refused::Throttled));
+        // Three at once is over the burst, two isn't
+        assert_eq!(bob.receive_from("mallory", msg::Message::Txn(txns.clone())).await.0, throttled);
+        assert_ne!(bob.receive_from("mallory", msg::Message::Txn(txns[..2].to_vec())).await.0, throttled);
+        assert_eq!(bob.receive_from("mallory", msg::Message::Txn(txns[2..].to_vec())).await.0, throttled);
+        // Other peers and other messages aren't held up
+        assert_ne!(bob.receive_from("alice", msg::Message::Txn(txns[2..].to_vec())).await.0, throttled);
+        assert_ne!(bob.receive_from("mallory", msg::Message::Time()).await.0, throttled);
+    }
+
+    #[tokio::test]
+    async fn rollups() {
+        let (authority, gen) = block::genesis();
+        let bob = Node::new(authority, gen, state::GENESIS_SLOTS);
+        let (alice, carol) = (account::Keypair::gen(), account::Keypair::gen());
+        let alice_id: account::Id = Sha256::digest(alice.kp.public.to_bytes()).into();
+        let mut state = rollup::State::default();
+        state.accounts.insert(&alice_id, account::Data { bal: 10, ..Default::default() }).unwrap();
+        let id = [7; 32];
+        let data = rollup::Data {
+            state_hash: state.accounts.commit(),
+            senators: Vec::from([senator::Verifier { id: [9; 32], at_round: 0 }]),
+            sequencer: senator::Verifier { id: Sha256::digest(bob.kp().kp.public.to_bytes()).into(), at_round: 0 },
+            bal: 0
+        };
+        // One we only senate for
+        let other = [6; 32];
+        let other_data = rollup::Data {
+            state_hash: rollup::State::default().accounts.commit(),
+            senators: Vec::from([senator::Verifier { id: Sha256::digest(bob.kp().kp.public.to_bytes()).into(), at_round: 0 }]),
+            sequencer: senator::Verifier { id: [9; 32], at_round: 0 },
+            bal: 0
+        };
+        bob.call(move |core| {
+            core.head.state.rollups.insert(&id, data).unwrap();
+            core.head.state.rollups.insert(&other, other_data).unwrap();
+        }).await;
+        // Only rollups on chain, at the state on chain
+        assert!(!bob.track_rollup([8; 32], state.clone()).await);
+        assert!(!bob.track_rollup(id, rollup::State::default()).await);
+        assert!(bob.track_rollup(id, state).await);
+        assert!(bob.track_rollup(other, rollup::State::default()).await);
+        // Rollup txns are pooled for the rollup and not relayed, and only if we sequence it
+        let pay = alice.send(carol.kp.public, 3, 0, Some(id));
+        let broke = alice.send(carol.kp.public, 30, 1, Some(id));
+        let elsewhere = alice.send(carol.kp.public, 3, 0, Some([8; 32]));
+        let senated = alice.send(carol.kp.public, 3, 0, Some(other));
+        assert_eq!(
+            bob.receive_txns(Vec::from([pay.clone(), broke.clone(), elsewhere.clone(), senated.clone()])).await,
+            (msg::ser(&Ok::<_, msg::error::Txn>(msg::ok::Txn { outcomes: Vec::from([
+                (txn::hash(&pay), msg::ok::Outcome::Pooled),
+                (txn::hash(&broke), msg::ok::Outcome::Rejected(txn::Error::InsuffBal { required: 30, available: 7 })),
+                (txn::hash(&elsewhere), msg::ok::Outcome::Rejected(txn::Error::NoRollup)),
+                (txn::hash(&senated), msg::ok::Outcome::Rejected(txn::Error::NotSequencer(other)))
+            ]) })), msg::Bcasts::default())
+        );
+        assert_eq!(bob.call(|core| core.txpool.len()).await, 0);
+        // Sealing hands the pool over as a header, but the state only moves on once it's on chain
+        let header = bob.seal_rollup(id).await.unwrap();
+        assert!(header.verify());
+        let working = bob.rollup(id).await.unwrap();
+        assert_eq!(header.msg.payload, txn::Payload::Header(id, Vec::from([pay.msg]), working.pending.accounts.commit()));
+        assert_eq!(working.state.accounts.get(&alice_id).unwrap().unwrap().bal, 10);
+        assert_eq!(bob.seal_rollup(id).await, None);
+        let mut builder = block::Builder::new(&bob.kp(), 1, &bob.get_head().await);
+        assert!(builder.add(header.clone()).is_ok());
+        add_snap(&bob, builder.finalize(&bob.kp())).await;
+        let working = bob.rollup(id).await.unwrap();
+        assert_eq!(working.state.accounts.get(&alice_id).unwrap().unwrap().bal, 7);
+        assert!(working.pool.is_empty());
+        // We watch the other senators, and oppose them once they lose a challenge
+        assert_eq!(bob.reputations().await.into_keys().collect::<Vec<_>>(), Vec::from([[9; 32]]));
+        assert_eq!(bob.oppositions().await, Vec::default());
+        bob.challenge_lost([9; 32]).await;
+        let oppose = bob.oppositions().await;
+        assert_eq!(oppose.iter().map(|stxn| stxn.msg.payload.clone()).collect::<Vec<_>>(), Vec::from([txn::Payload::Oppose([9; 32])]));
+        assert_eq!(oppose[0].msg.nonce, header.msg.nonce + 1);
+        assert_eq!(bob.oppositions().await, Vec::default());
+    }
+
+    #[tokio::test]
+    async fn offenders() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - clock().block_time));
+        let mallory = account::Keypair::gen();
+        let bad = block::Builder::new(&mallory, 1, &gen).finalize(&mallory).block;
+        let good = block::Builder::new(&authority, 1, &gen).finalize(&authority).block;
+        let bob = Node::new(account::Keypair::gen(), gen, 0);
+        // Five bad blocks and you're out
+        for _ in 0..5 {
+            let (resp, _) = bob.receive_from("mallory", msg::Message::Chain(Vec::from([bad.clone()]))).await;
+            assert!(matches!(msg::deser(&resp), Err::<msg::ok::Chain, _>(msg::error::Chain::BadBlock(..))));
+        }
+        let refused = msg::ser(&Err::<(), _>(msg::error::Refused::Banned));
+        assert_eq!(bob.receive_from("mallory", msg::Message::Chain(Vec::from([good.clone()]))).await.0, refused);
+        // Everyone else is still heard
+        assert_eq!(
+            bob.receive_from("alice", msg::Message::Chain(Vec::from([good]))).await.0,
+            msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
+        );
+    }
+
+    #[tokio::test]
+    async fn persist() {
+        let (authority, gen) = block::genesis();
+        let dir = std::env::temp_dir().join(format!("tam-node-{:x}", u64::from_be_bytes(gen.block_hash[..8].try_into().unwrap())));
+        let clone = |kp: &account::Keypair| account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&kp.kp.to_bytes()).unwrap() };
+        let alice = Node::new(clone(&authority), gen.clone(), state::GENESIS_SLOTS)
+            .with_store(store::Store::open(dir.clone()).unwrap())
+            .unwrap();
+        let mut builder = block::Builder::new(&authority, 1, &gen);
+        assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE, state::GENESIS_SLOTS, None)).is_ok());
+        let snap = builder.finalize(&authority);
+        add_snap(&alice, snap.clone()).await;
+        assert!(alice.call(|core| core.sign_round(2)).await);
+        drop(alice);
+        // Back up where it left off
+        let alice = Node::new(clone(&authority), gen.clone(), state::GENESIS_SLOTS)
+            .with_store(store::Store::open(dir.clone()).unwrap())
+            .unwrap();
+        assert_eq!(alice.get_head().await.block_hash, snap.block_hash);
+        assert_eq!(alice.call(|core| core.finalized).await, (1, snap.block_hash));
+        assert_eq!(*alice.nonce.lock().await, state::GENESIS_SLOTS + 1);
+        assert!(!alice.call(|core| core.sign_round(2)).await);
+        assert!(alice.find_snap(&gen.block_hash).await.is_some());
+        // Nonces handed out but not on chain yet aren't handed out again
+        assert_eq!(alice.next_nonce().await, state::GENESIS_SLOTS + 1);
+        assert_eq!(alice.next_nonce().await, state::GENESIS_SLOTS + 2);
+        drop(alice);
+        let alice = Node::new(clone(&authority), gen.clone(), state::GENESIS_SLOTS)
+            .with_store(store::Store::open(dir.clone()).unwrap())
+            .unwrap();
+        assert_eq!(alice.next_nonce().await, state::GENESIS_SLOTS + 3);
+        let (other, other_gen) = block::genesis();
+        assert_eq!(
+            Node::new(other, other_gen, 0).with_store(store::Store::open(dir.clone()).unwrap()).err(),
+            Some(store::Error::WrongChain)
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn open() {
+        let kp = account::Keypair::gen();
+        let dir = std::env::temp_dir().join(format!("tam-open-{:x}", u64::from_be_bytes(kp.kp.public.to_bytes()[..8].try_into().unwrap())));
+        let clone = |kp: &account::Keypair| account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&kp.kp.to_bytes()).unwrap() };
+        // First run makes a chain of our own
+        let alice = Node::open(&dir, clone(&kp)).unwrap();
+        let gen = alice.get_head().await;
+        assert_eq!(genesis::read(&dir.join("genesis.json")).unwrap().block_hash, gen.block_hash);
+        assert_eq!(*alice.nonce.lock().await, state::GENESIS_SLOTS);
+        let snap = block::Builder::new(&kp, 1, &gen).finalize(&kp);
+        add_snap(&alice, snap.clone()).await;
+        drop(alice);
+        // After that it's the same chain, settings as written
+        let config = Config { role: Role::Full, ..Config::default() };
+        fs::write(dir.join("config.json"), serde_json::to_vec(&config).unwrap()).unwrap();
+        let alice = Node::open(&dir, clone(&kp)).unwrap();
+        assert_eq!(alice.get_head().await.block_hash, snap.block_hash);
+        assert_eq!(alice.call(|core| core.role).await, Role::Full);
+        drop(alice);
+        fs::write(dir.join("config.json"), b"{\"fork_window\": 0}").unwrap();
+        assert_eq!(Node::open(&dir, clone(&kp)).err(), Some(OpenError::BadConfig));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let (authority, gen) = block::genesis();
+        let dir = std::env::temp_dir().join(format!("tam-shutdown-{:x}", u64::from_be_bytes(gen.block_hash[..8].try_into().unwrap())));
+        let kp_bytes = authority.kp.to_bytes();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS)
+            .with_store(store::Store::open(dir.clone()).unwrap())
+            .unwrap();
+        let txn = alice.kp().send(account::Keypair::gen().kp.public, 1 << 10, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn.clone()])).await;
+        alice.shutdown().await;
+        let (resp, bcasts) = alice.receive(msg::Message::Txn(Vec::from([txn.clone()]))).await;
+        assert_eq!(resp, msg::ser(&Err::<(), _>(msg::error::Closed::ShuttingDown)));
+        assert!(bcasts.is_empty());
+        drop(alice);
+        // The pool comes back on restart
+        let kp = account::Keypair { kp: ed25519_dalek::Keypair::from_bytes(&kp_bytes).unwrap() };
+        let alice = Node::new(kp, gen, state::GENESIS_SLOTS)
+            .with_store(store::Store::open(dir.clone()).unwrap())
+            .unwrap();
+        assert!(alice.call(move |core| core.txpool.contains(&txn)).await);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn headersync() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - 100 * clock().block_time));
+        let alice = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let mut snap = gen.clone();
+        let mut blocks = Vec::default();
+        // Across an epoch boundary
+        while snap.block.sheader.msg.data.round < block::EPOCH_ROUNDS + 4 {
+            snap = block::Builder::new(&authority, 1, &snap).finalize(&authority);
+            blocks.push(snap.block.clone());
+            add_snap(&alice, snap.clone()).await;
+        }
+        // Too old to go through as a live chain
+        assert_eq!(
+            bob.receive(msg::Message::Chain(blocks[..1].to_vec())).await.0,
+            msg::ser(&Err::<msg::ok::Chain, _>(msg::error::Chain::SmallTimestamp))
+        );
+        let (resp, _) = alice.receive(msg::Message::Headers(gen.block_hash)).await;
+        let mut headers = serde_json::from_str::<Result<msg::ok::Headers, msg::error::Headers>>(&resp).unwrap().unwrap().headers;
+        assert_eq!(headers.len(), blocks.len());
+        let boundary = block::EPOCH_ROUNDS as usize - 1;
+        assert!(headers.iter().enumerate().all(|(i, (_, set))| set.is_some() == (i == boundary)));
+        assert_eq!(block::verify_headers(&gen.block.sheader.msg, &gen.epoch, &headers), Ok(()));
+        let set = headers[boundary].1.take();
+        assert_eq!(
+            block::verify_headers(&gen.block.sheader.msg, &gen.epoch, &headers), 
+            Err((boundary, block::Error::BadValidators))
+        );
+        headers[boundary].1 = set;
+        for chunk in headers.chunks(MAX_SYNC_BODIES) {
+            let hashes = chunk.iter().map(|(sheader, _)| sheader.msg.hash()).collect();
+            let (resp, _) = alice.receive(msg::Message::Bodies(hashes)).await;
+            let bodies = serde_json::from_str::<Result<msg::ok::Bodies, msg::error::Bodies>>(&resp).unwrap().unwrap();
+            assert!(bob.sync_chain(bodies.blocks).await.is_ok());
+        }
+        assert_eq!(bob.get_head().await.block_hash, snap.block_hash);
+        assert_eq!(
+            alice.receive(msg::Message::Headers(snap.block_hash)).await.0,
+            msg::ser(&Ok::<_, msg::error::Headers>(msg::ok::Headers { headers: Vec::default() }))
+        );
+        assert_eq!(
+            alice.receive(msg::Message::Headers([1; 32])).await.0,
+            msg::ser(&Err::<msg::ok::Headers, _>(msg::error::Headers::Unknown))
+        );
+        assert_eq!(
+            alice.receive(msg::Message::Bodies(Vec::from([gen.block_hash; MAX_SYNC_BODIES + 1]))).await.0,
+            msg::ser(&Err::<msg::ok::Bodies, _>(msg::error::Bodies::TooMany))
+        );
+    }
+
+    #[tokio::test]
+    async fn statesync() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - 100 * clock().block_time));
+        let alice = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let mut snap = gen.clone();
+        let mut blocks = Vec::default();
+        while snap.block.sheader.msg.data.round < block::EPOCH_ROUNDS + 4 {
+            let mut builder = block::Builder::new(&authority, 1, &snap);
+            let votes = BTreeMap::from([(0, attest::vote(&authority, &snap.block_hash))]);
+            builder.attestation = attest::Attestation::aggregate(&votes).unwrap();
+            snap = builder.finalize(&authority);
+            blocks.push(snap.block.clone());
+            add_snap(&alice, snap.clone()).await;
+        }
+        let (resp, _) = alice.receive(msg::Message::Checkpoint()).await;
+        let checkpoint = serde_json::from_str::<Result<msg::ok::Checkpoint, msg::error::Checkpoint>>(&resp).unwrap().unwrap().checkpoint;
+        let header = checkpoint.block.sheader.msg.clone();
+        assert_eq!(header.data.round, block::EPOCH_ROUNDS);
+        assert_eq!(checkpoint.link.len(), block::MEDIAN_BLOCKS - 1);
+        assert_eq!(checkpoint.next.msg.data.prev_hash, header.hash());
+        assert_eq!(checkpoint.verify(&gen.epoch), Ok(()));
+        // The set electing the next leader has to be the one its epoch started with
+        let mut bad = checkpoint.clone();
+        bad.epoch = gen.epoch.clone();
+        assert_eq!(bad.verify(&gen.epoch), Err(block::Error::BadValidators));
+        // Nor will a chain someone made up on their own do, however well it hangs together
+        let mallory = account::Keypair::gen();
+        let fake = crate::genesis::build(&mallory, &crate::genesis::Config::new(gen.block.sheader.msg.data.timestamp));
+        let mut fake_snap = fake.clone();
+        let fake_alice = Node::new(account::Keypair::gen(), fake.clone(), 0);
+        while fake_snap.block.sheader.msg.data.round < block::EPOCH_ROUNDS + 4 {
+            let mut builder = block::Builder::new(&mallory, 1, &fake_snap);
+            let votes = BTreeMap::from([(0, attest::vote(&mallory, &fake_snap.block_hash))]);
+            builder.attestation = attest::Attestation::aggregate(&votes).unwrap();
+            fake_snap = builder.finalize(&mallory);
+            add_snap(&fake_alice, fake_snap.clone()).await;
+        }
+        let (resp, _) = fake_alice.receive(msg::Message::Checkpoint()).await;
+        let forged = serde_json::from_str::<Result<msg::ok::Checkpoint, msg::error::Checkpoint>>(&resp).unwrap().unwrap().checkpoint;
+        assert_eq!(forged.verify(&fake.epoch), Ok(()));
+        assert_eq!(forged.verify(&gen.epoch), Err(block::Error::BadAttestation));
+        // or a real one whose link was tampered with
+        let mut tampered = checkpoint.clone();
+        tampered.link[3].msg.data.timestamp += 1;
+        assert!(tampered.verify(&gen.epoch).is_err());
+        let mut unsigned = checkpoint.clone();
+        unsigned.link[0].sig = mallory.sign(&unsigned.link[0].msg);
+        assert_eq!(unsigned.verify(&gen.epoch), Err(block::Error::BadSig));
+        let import = state::Import::new(header.checkpoint.unwrap(), header.commits.shards.clone(), gen.state.clock).unwrap();
+        // A chunk that doesn't add up to its root is caught once the part's in
+        let mut tampered = import.clone();
+        let (part, after) = tampered.next().unwrap();
+        let (resp, _) = alice.receive(msg::Message::State(header.hash(), part, after)).await;
+        let mut updates = serde_json::from_str::<Result<msg::ok::State, msg::error::State>>(&resp).unwrap().unwrap().updates;
+        if let state::Update::Account(_, Some(ref mut data)) = updates[0] {
+            data.bal += 1;
+        }
+        assert_eq!(tampered.add(updates, STATE_CHUNK_SIZE), Err(state::Part::Shard(0)));
+        let mut import = import;
+        while let Some((part, after)) = import.next() {
+            let (resp, _) = alice.receive(msg::Message::State(header.hash(), part, after)).await;
+            let ok = serde_json::from_str::<Result<msg::ok::State, msg::error::State>>(&resp).unwrap().unwrap();
+            assert_eq!(import.add(ok.updates, STATE_CHUNK_SIZE), Ok(()));
+        }
+        let synced = checkpoint.into_snap(import.finish().unwrap()).unwrap();
+        assert_eq!(synced.block_hash, header.hash());
+        bob.accept_checkpoint(synced).await;
+        // and carries on validating from there
+        assert!(bob.sync_chain(blocks[block::EPOCH_ROUNDS as usize..].to_vec()).await.is_ok());
+        assert_eq!(bob.get_head().await.block_hash, snap.block_hash);
+        assert_eq!(
+            alice.receive(msg::Message::State(snap.block_hash, state::Part::Slots, None)).await.0,
+            msg::ser(&Err::<msg::ok::State, _>(msg::error::State::DoesntExist))
+        );
+        assert_eq!(
+            alice.receive(msg::Message::State(header.hash(), state::Part::Shard(1), None)).await.0,
+            msg::ser(&Err::<msg::ok::State, _>(msg::error::State::BadPart))
+        );
     }
 
     #[tokio::test]
     async fn tooshort() {
         let (mut interval, alice, bob) = setup().await;
-        let head = { alice.head.lock().await.clone() };
-        let evil_alice = Node::new(account::Keypair::default(), head, 0);
+        let head = alice.get_head().await;
+        let alice_kp = ed25519_dalek::Keypair::from_bytes(&alice.kp().kp.to_bytes()).unwrap();
+        let evil_alice = Node::new(account::Keypair { kp: alice_kp }, head, 0);
         evil_alice.tick().await;
         evil_alice.receive(
             msg::Message::Txn(
                 Vec::from([
-                    alice.kp.send(
-                        bob.kp.kp.public, 
-                        1, 
-                        state::JENNY_SLOTS
+                    alice.kp().send(
+                        bob.kp().kp.public, 
+                        state::DUST_BALANCE, 
+                        state::GENESIS_SLOTS,
+                        None
                     )
                 ])
             )
@@ -395,7 +3373,13 @@ pub mod tests {
         interval.tick().await;
         let bcast = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
         println!("alice second bcast {:?}", bcast);
-        let evil_bcast = msg::deser(&evil_alice.tick().await.pop().expect("Alice should lead"));
+        // Evil Alice signs her first proposal after its slot is over, which tick won't do.
+        let evil_bcast = evil_alice.call(|core| {
+            let snap = core.opt_builder.take().expect("Alice should lead").finalize(&core.kp);
+            let bcast = msg::Message::Compact(block::Compact::new(&snap.block));
+            core.add_snap(snap);
+            bcast
+        }).await;
         println!("evil alice bcast {:?}", evil_bcast);
         assert_eq!(bob.tick().await, msg::Bcasts::default());
         assert_eq!(
@@ -409,6 +3393,229 @@ pub mod tests {
                 msg::Bcasts::default()
             )
         );
+        // With the whole block Bob keeps it to the side as a competing proposal.
+        let evil_block = evil_alice.get_head().await.block;
+        assert_eq!(
+            bob.receive_chain(Vec::from([evil_block.clone()])).await.0,
+            msg::ser(&Err::<msg::ok::Chain,_>(msg::error::Chain::TooShort))
+        );
+        assert_eq!(bob.uncles(evil_block.sheader.msg.data.round).await, Vec::from([evil_block.sheader]));
+        let head = bob.get_head().await;
+        assert!(bob.uncles(head.block.sheader.msg.data.round + 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn uncle_cap() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - clock().block_time));
+        let mut siblings: Vec<_> = (1..MAX_UNCLES as u32 + 3)
+            .map(|amount| {
+                let mut builder = block::Builder::new(&authority, 1, &gen);
+                assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE + amount, state::GENESIS_SLOTS, None)).is_ok());
+                builder.finalize(&authority).block
+            })
+            .collect();
+        // Best first, so every later one is an uncle.
+        siblings.sort_by(|a, b| if a.beats(b) { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater });
+        let node = Node::new(account::Keypair::gen(), gen.clone(), 0);
+        let mut results = Vec::default();
+        for sibling in siblings.iter() {
+            results.push(node.receive_chain(Vec::from([sibling.clone()])).await.0);
+        }
+        assert_eq!(node.get_head().await.block, siblings[0]);
+        assert_eq!(node.uncles(1).await.len(), MAX_UNCLES);
+        assert_eq!(results.last().unwrap(), &msg::ser(&Err::<msg::ok::Chain, _>(msg::error::Chain::TooManyUncles)));
+    }
+
+    #[tokio::test]
+    async fn compact() {
+        let (mut interval, alice, bob) = setup().await;
+        let txns: Vec<_> = (0..3)
+            .map(|i| alice.kp().send(bob.kp().kp.public, 1 << 10, state::GENESIS_SLOTS + i, None))
+            .collect();
+        alice.receive_txns(txns.clone()).await;
+        // Bob heard about the first one.
+        bob.receive_txns(Vec::from([txns[0].clone()])).await;
+        interval.tick().await;
+        let compact = msg::deser::<msg::Message>(&alice.tick().await.pop().expect("Alice should lead"))
+            .compact()
+            .unwrap();
+        assert_eq!(compact.ids.len(), 3);
+        assert_eq!(bob.tick().await, msg::Bcasts::default());
+        assert_eq!(
+            bob.receive(msg::Message::Compact(compact.clone())).await.0,
+            msg::ser(&Err::<msg::ok::Chain, _>(msg::error::Chain::Missing(Vec::from([1, 2]))))
+        );
+        let block_hash = compact.sheader.msg.hash();
+        assert_eq!(
+            alice.receive(msg::Message::GetTxns(block_hash, Vec::from([7]))).await.0,
+            msg::ser(&Err::<msg::ok::GetTxns, _>(msg::error::GetTxns::BadPosition(7)))
+        );
+        let (resp, _) = alice.receive(msg::Message::GetTxns(block_hash, Vec::from([1, 2]))).await;
+        let fetched = serde_json::from_str::<Result<msg::ok::GetTxns, msg::error::GetTxns>>(&resp).unwrap().unwrap();
+        assert_eq!(fetched.txns, txns[1..].to_vec());
+        assert_eq!(
+            bob.receive_compact(compact, fetched.txns).await.0, 
+            msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
+        );
+        assert_eq!(bob.get_head().await.block_hash, block_hash);
+    }
+
+    #[tokio::test]
+    async fn flush() {
+        let (authority, gen) = block::genesis();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = Node::new(account::Keypair::gen(), gen, 0);
+        let bob_pk = bob.kp().kp.public;
+        let next = alice.kp().send(bob_pk, 1 << 10, state::GENESIS_SLOTS, None);
+        let later = alice.kp().send(bob_pk, 1 << 10, state::GENESIS_SLOTS + 2, None);
+        let used = alice.kp().send(bob_pk, 1 << 10, state::GENESIS_SLOTS - 1, None);
+        let broke = alice.kp().send(bob_pk, u32::MAX, state::GENESIS_SLOTS + 1, None);
+        let stranger = account::Keypair::gen().send(bob_pk, state::DUST_BALANCE, 0, None);
+        let pool = [next.clone(), later.clone(), used, broke, stranger];
+        let kept = bob.call(move |core| {
+            for txn in pool {
+                core.txpool.insert(txn).unwrap();
+            }
+            core.flush_pool();
+            (core.txpool.iter().cloned().collect::<Vec<_>>(), core.txpool.metrics.stale)
+        }).await;
+        // A gap in nonces can still fill, so the later one waits.
+        assert_eq!(kept, (Vec::from([next, later]), 3));
+    }
+
+    #[tokio::test]
+    async fn seen() {
+        let (authority, gen) = block::genesis();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = Node::new(account::Keypair::gen(), gen, 0);
+        let txn = alice.kp().send(bob.kp().kp.public, 1 << 10, state::GENESIS_SLOTS, None);
+        let (_, bcasts) = bob.receive_txns(Vec::from([txn.clone()])).await;
+        assert_eq!(bcasts, Vec::from([msg::ser(&msg::Message::Txn(Vec::from([txn.clone()])))]));
+        // Back again after it's left the pool, it isn't passed on a second time
+        bob.call(|core| core.txpool.clear()).await;
+        assert_eq!(bob.receive_txns(Vec::from([txn.clone()])).await.1, msg::Bcasts::default());
+        assert!(bob.call(move |core| core.txpool.contains(&txn)).await);
+        // Least recently seen goes first
+        let mut seen = Seen::default();
+        for i in 0..SEEN_SIZE {
+            assert!(seen.insert(Sha256::digest(i.to_be_bytes()).into()));
+        }
+        assert!(!seen.insert(Sha256::digest(0usize.to_be_bytes()).into()));
+        assert!(seen.insert([0; 32]));
+        assert!(seen.insert(Sha256::digest(1usize.to_be_bytes()).into()));
+        assert!(!seen.insert(Sha256::digest(0usize.to_be_bytes()).into()));
+    }
+
+    #[tokio::test]
+    async fn batch() {
+        let (authority, gen) = block::genesis();
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = account::Keypair::gen();
+        let mut builder = block::Builder::new(&alice.kp(), 1, &gen);
+        let txns: Vec<_> = (0..block::TXN_BATCH_SIZE as u32 + 2)
+            .map(|i| alice.kp().send(bob.kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS + i, None))
+            .collect();
+        for txn in txns.clone() {
+            assert!(builder.add(txn).is_ok());
+        }
+        let snap = builder.finalize(&alice.kp());
+        add_snap(&alice, snap.clone()).await;
+        let mut fetched = Vec::default();
+        for batch_no in 0..2 {
+            let (resp, _) = alice.receive(msg::Message::Batch(snap.block_hash, batch_no)).await;
+            let ok = serde_json::from_str::<Result<msg::ok::Batch, msg::error::Batch>>(&resp).unwrap().unwrap();
+            assert_eq!(ok.len, txns.len() as u32);
+            fetched.extend(ok.txns);
+        }
+        assert_eq!(fetched, txns);
+        assert_eq!(
+            alice.receive(msg::Message::Batch(snap.block_hash, 2)).await.0,
+            msg::ser(&Err::<msg::ok::Batch, _>(msg::error::Batch::DoesntExist))
+        );
+        assert_eq!(
+            alice.receive(msg::Message::Batch([0; 32], 0)).await.0,
+            msg::ser(&Err::<msg::ok::Batch, _>(msg::error::Batch::DoesntExist))
+        );
+    }
+
+    #[tokio::test]
+    async fn finality() {
+        let (mut interval, alice, bob) = setup().await;
+        let head = alice.get_head().await;
+        let alice_kp = ed25519_dalek::Keypair::from_bytes(&alice.kp().kp.to_bytes()).unwrap();
+        let evil_alice = Node::new(account::Keypair { kp: alice_kp }, head, 0);
+        evil_alice.tick().await;
+        evil_alice.receive(
+            msg::Message::Txn(Vec::from([alice.kp().send(bob.kp().kp.public, state::DUST_BALANCE, state::GENESIS_SLOTS, None)]))
+        ).await;
+        interval.tick().await;
+        let mut bcasts: Vec<msg::Message> = alice.tick().await.iter().map(|bcast| msg::deser(bcast)).collect();
+        evil_alice.tick().await.pop().expect("Alice should lead");
+        let evil_first = evil_alice.get_head().await.block;
+        // Alice holds every slot so her own vote finalizes.
+        assert!(alice.get_head().await.finalized);
+        let block = bcasts.pop().unwrap();
+        let vote = bcasts.into_iter().find_map(|bcast| bcast.vote()).expect("Alice holds slots");
+        assert_eq!(
+            bob.receive(block).await.0, 
+            msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
+        );
+        assert!(!bob.get_head().await.finalized);
+        let mut forged = vote.clone();
+        forged.msg.round += 1;
+        assert_eq!(
+            bob.receive(msg::Message::Vote(forged)).await.0,
+            msg::ser(&Err::<msg::ok::Vote, _>(msg::error::Vote::Stale))
+        );
+        assert_eq!(
+            bob.receive(msg::Message::Vote(vote.clone())).await.0,
+            msg::ser(&Ok::<_, msg::error::Vote>(msg::ok::Vote { finalized: true }))
+        );
+        assert!(bob.get_head().await.finalized);
+        // A longer fork off genesis would undo the finalized block.
+        interval.tick().await;
+        evil_alice.tick().await.pop().expect("Alice should lead");
+        let fork = Vec::from([evil_first, evil_alice.get_head().await.block]);
+        assert_eq!(
+            bob.receive(msg::Message::Chain(fork)).await, 
+            (
+                msg::ser(&Err::<msg::ok::Chain,_>(msg::error::Chain::PastFinality)),
+                msg::Bcasts::default()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn depth() {
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp() - 6 * clock().block_time));
+        // No slots, so no votes: only depth finalizes.
+        let bob = Node::new(account::Keypair::gen(), gen.clone(), 0).with_finality_depth(2);
+        let mut chain = Vec::from([gen.clone()]);
+        for _ in 0..4 {
+            let snap = block::Builder::new(&authority, 1, chain.last().unwrap()).finalize(&authority);
+            add_snap(&bob, snap.clone()).await;
+            chain.push(snap);
+        }
+        assert_eq!(bob.call(|core| core.finalized).await, (2, chain[2].block_hash));
+        // Only blocks up to the finalized one on its own branch would be archived.
+        let mut builder = block::Builder::new(&authority, 1, &chain[0]);
+        assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE, state::GENESIS_SLOTS, None)).is_ok());
+        let sibling = builder.finalize(&authority);
+        add_snap(&bob, sibling.clone()).await;
+        let archived = bob.call(|core| core.finalized_chain()).await;
+        assert_eq!(archived, chain[..3].iter().map(|snap| snap.block_hash).collect());
+        assert!(!archived.contains(&sibling.block_hash));
+        // Longer, but forks off under the finalized block.
+        let mut builder = block::Builder::new(&authority, 1, &chain[1]);
+        assert!(builder.add(authority.send_acc([0; 32], state::DUST_BALANCE, state::GENESIS_SLOTS, None)).is_ok());
+        let mut fork = Vec::from([builder.finalize(&authority)]);
+        for _ in 0..3 {
+            fork.push(block::Builder::new(&authority, 1, fork.last().unwrap()).finalize(&authority));
+        }
+        let blocks = fork.into_iter().map(|snap| snap.block).collect();
+        assert!(matches!(bob.sync_chain(blocks).await, Err(msg::error::Chain::PastFinality)));
     }
 
     #[tokio::test]
@@ -431,12 +3638,13 @@ pub mod tests {
             msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
         );
         let mut txns = Vec::default();
-        let state = { alice.head.lock().await.state.clone() };
+        let state = alice.get_head().await.state;
         txns.push(
-            alice.kp.send(
-                bob.kp.kp.public, 
-                state.accounts.get(&Sha256::digest(alice.kp.kp.public.to_bytes())).unwrap().unwrap().bal,
-                state::JENNY_SLOTS
+            alice.kp().send(
+                bob.kp().kp.public, 
+                state.accounts.get(&Sha256::digest(alice.kp().kp.public.to_bytes())).unwrap().unwrap().bal,
+                state::GENESIS_SLOTS,
+                None
             )
         );
         alice.receive(
@@ -447,16 +3655,17 @@ pub mod tests {
         let bcast = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
         assert_eq!(bob.tick().await, msg::Bcasts::default());
         assert_eq!(
-            bob.receive(bcast).await.0, 
+            relay(&alice, &bob, bcast).await, 
             msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
         );
         let (mut state, meta) = {
-            let head = bob.head.lock().await;
+            let head = bob.get_head().await;
             (head.state.clone(), head.block.sheader.msg.data.clone())
         };
         let mut txns = Vec::default();
+        let bob_nonce = state.accounts.get(&Sha256::digest(bob.kp().kp.public.to_bytes())).unwrap().unwrap().nonce;
         for i in 0..state::VALIDATOR_SLOTS >> 1 {
-            let stake = bob.kp.stake(&state, i);
+            let (_, stake) = bob.kp().stake(&state.slots, 0..state::VALIDATOR_SLOTS, bob_nonce + i).unwrap();
             txns.push(stake.clone());
             assert!(
                 state.apply(
@@ -473,9 +3682,19 @@ pub mod tests {
         let bcast = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
         assert_eq!(bob.tick().await, msg::Bcasts::default());
         assert_eq!(
-            bob.receive(bcast).await.0, 
+            relay(&alice, &bob, bcast).await, 
             msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
         );
+        // Stake only counts for leader election from the next epoch.
+        while block::epoch_of(bob.get_head().await.block.sheader.msg.data.round + 1) == 0 {
+            interval.tick().await;
+            let bcast = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
+            assert_eq!(bob.tick().await, msg::Bcasts::default());
+            assert_eq!(
+                bob.receive(bcast).await.0, 
+                msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {}))
+            );
+        }
         // Now they should lead evenly.
         let mut alice_ctr = 0;
         for _ in 0..25 {
@@ -506,4 +3725,46 @@ pub mod tests {
         // Stdev is sqrt(N) / 2, so w.h.p. should be within sqrt(N) of N
         assert!((7..=18).contains(&alice_ctr));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn shards() {
+        let now = time::Instant::now();
+        let authority = account::Keypair::gen();
+        let gen = crate::genesis::build(&authority, &crate::genesis::Config::new(state::timestamp()).with_shards(4));
+        assert_eq!(gen.state.accounts.len(), 4);
+        let mut interval = time::interval_at(now, Duration::from_millis(clock().block_time));
+        interval.tick().await;
+        // Bob's account lives in another shard from Alice's.
+        let addy = |kp: &account::Keypair| -> account::Id { Sha256::digest(kp.kp.public.to_bytes()).into() };
+        let bob_kp = loop {
+            let kp = account::Keypair::gen();
+            if gen.state.accounts.shard_of(&addy(&kp)) != gen.state.accounts.shard_of(&addy(&authority)) {
+                break kp;
+            }
+        };
+        let bob_id = addy(&bob_kp);
+        let alice = Node::new(authority, gen.clone(), state::GENESIS_SLOTS);
+        let bob = Node::new(bob_kp, gen, 0);
+        alice.tick().await;
+        bob.tick().await;
+        let txn = alice.kp().send(bob.kp().kp.public, 1 << 10, state::GENESIS_SLOTS, None);
+        alice.receive_txns(Vec::from([txn])).await;
+        // The payment goes out in block 1 as a receipt...
+        interval.tick().await;
+        let bcast = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
+        assert_eq!(relay(&alice, &bob, bcast).await, msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {})));
+        let head = bob.get_head().await;
+        assert_eq!(head.block.sheader.msg.data.round, 1);
+        assert_eq!(head.state.accounts.get(&bob_id), Ok(None));
+        assert_eq!(head.state.receipts.iter().map(|receipt| (receipt.to, receipt.amount)).collect::<Vec<_>>(), vec![(bob_id, 1 << 10)]);
+        // ...and lands in Bob's shard with block 2.
+        interval.tick().await;
+        let bcast = msg::deser(&alice.tick().await.pop().expect("Alice should lead"));
+        assert_eq!(relay(&alice, &bob, bcast).await, msg::ser(&Ok::<_, msg::error::Chain>(msg::ok::Chain {})));
+        let head = bob.get_head().await;
+        assert_eq!(head.block.sheader.msg.data.round, 2);
+        assert_eq!(head.state.accounts.get(&bob_id).unwrap().map(|acc| acc.bal), Some(1 << 10));
+        assert_eq!(head.state.receipts.iter().count(), 0);
+        assert_eq!(alice.get_head().await.state, head.state);
+    }
+}
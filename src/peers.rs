@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+
+// What we know about each neighbor: how quickly it answers, how often it's sent us something
+// that didn't check out and how often it's been worth asking. A peer's score goes up when it
+// helps and down when it misbehaves. Low scorers are asked last and past BAN_SCORE they're
+// dropped for a while. Sync requests go to the best scored peers first.
+// We also keep how far each peer's clock is from ours, to correct our own by.
+
+pub const BAN_SCORE: i64 = -100;
+pub const BAN_TIME: u64 = 10 * 60 * 1_000; // ms a banned peer sits out
+const MAX_SCORE: i64 = 100;
+const USEFUL: i64 = 1;
+const INVALID: i64 = -20; // a few bad messages and they're out
+const UNREACHABLE: i64 = -5;
+pub const MAX_CLOCK_OFFSET: i64 = 30_000; // ms, a peer claiming more is ignored
+pub const MAX_ADDRS: usize = 1024; // addresses we'll keep in a book
+pub const MAX_SHARED: usize = 64; // addresses we'll pass on at once
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    Answered(u64), // ms to answer
+    Useful, // gave us something we kept
+    Invalid, // gave us something that failed a check
+    Unreachable,
+    Clock(i64), // ms their clock is ahead of ours
+    Sent(u64), // bytes we sent them
+    Received(u64) // bytes they sent us
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    pub latency: Option<u64>, // moving average, ms
+    pub useful: u64,
+    pub invalid: u64,
+    pub unreachable: u64,
+    pub score: i64,
+    pub offset: Option<i64>, // ms their clock is ahead of ours, last we asked
+    pub banned_until: Option<u64>, // timestamp, ms
+    pub bytes_out: u64,
+    pub bytes_in: u64
+}
+
+impl Peer {
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Peers {
+    peers: BTreeMap<String, Peer> // by address
+}
+
+impl Peers {
+    // False if we already had it.
+    pub fn add(&mut self, addr: String) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        self.peers.insert(addr, Peer::default());
+        true
+    }
+
+    pub fn remove(&mut self, addr: &str) -> Option<Peer> {
+        self.peers.remove(addr)
+    }
+
+    pub fn get(&self, addr: &str) -> Option<&Peer> {
+        self.peers.get(addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Peer)> {
+        self.peers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    // Unknown peers are ignored. A ban that's run out starts the peer back at half the ban score,
+    // so it doesn't take much to send it back.
+    pub fn record(&mut self, addr: &str, event: Event, now: u64) {
+        let Some(peer) = self.peers.get_mut(addr) else {
+            return;
+        };
+        if peer.banned_until.is_some_and(|until| now >= until) {
+            peer.banned_until = None;
+            peer.score = BAN_SCORE / 2;
+        }
+        match event {
+            Event::Answered(ms) => {
+                peer.latency = Some(peer.latency.map_or(ms, |avg| (3 * avg + ms) / 4));
+            },
+            Event::Useful => {
+                peer.useful += 1;
+                peer.score = (peer.score + USEFUL).min(MAX_SCORE);
+            },
+            Event::Invalid => {
+                peer.invalid += 1;
+                peer.score += INVALID;
+            },
+            Event::Unreachable => {
+                peer.unreachable += 1;
+                peer.score += UNREACHABLE;
+            },
+            Event::Clock(ms) => {
+                peer.offset = Some(ms);
+            },
+            Event::Sent(bytes) => {
+                peer.bytes_out += bytes;
+            },
+            Event::Received(bytes) => {
+                peer.bytes_in += bytes;
+            }
+        }
+        if peer.score <= BAN_SCORE && !peer.is_banned(now) {
+            peer.banned_until = Some(now + BAN_TIME);
+        }
+    }
+
+    // Peers not banned, best first: highest score, then quickest to answer.
+    pub fn ranked(&self, now: u64) -> Vec<String> {
+        let mut ranked: Vec<_> = self.peers.iter()
+            .filter(|(_, peer)| !peer.is_banned(now))
+            .collect();
+        ranked.sort_by_key(|(_, peer)| (-peer.score, peer.latency.unwrap_or(u64::MAX)));
+        ranked.into_iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+    // Median of our own clock and the ones peers not banned have told us about, as an offset
+    // from ours, the lower one if there's an even number. Counting ourselves means a single
+    // peer can't move us on its own.
+    pub fn clock_offset(&self, now: u64) -> i64 {
+        let mut offsets: Vec<_> = self.peers.values()
+            .filter(|peer| !peer.is_banned(now))
+            .filter_map(|peer| peer.offset)
+            .filter(|offset| offset.abs() <= MAX_CLOCK_OFFSET)
+            .chain([0])
+            .collect();
+        offsets.sort();
+        offsets[(offsets.len() - 1) / 2]
+    }
+}
+
+// Addresses we've heard of, from bootstrap peers' lists and gossip, that we might take on as
+// neighbors. Just addresses: whoever's there hasn't been shaken hands with. Once full, new
+// ones are turned away until some are forgotten.
+#[derive(Debug, Clone, Default)]
+pub struct Book {
+    addrs: BTreeSet<String>
+}
+
+impl Book {
+    // False if we already had it or we're full.
+    pub fn learn(&mut self, addr: String) -> bool {
+        if self.addrs.len() >= MAX_ADDRS {
+            return false;
+        }
+        self.addrs.insert(addr)
+    }
+
+    pub fn forget(&mut self, addr: &str) -> bool {
+        self.addrs.remove(addr)
+    }
+
+    pub fn contains(&self, addr: &str) -> bool {
+        self.addrs.contains(addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.addrs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoring() {
+        let mut peers = Peers::default();
+        for addr in ["a", "b", "c"] {
+            assert!(peers.add(addr.to_string()));
+        }
+        assert!(!peers.add("a".to_string()));
+        // Ties go to the quicker peer, peers we haven't timed go last
+        peers.record("b", Event::Answered(40), 0);
+        peers.record("a", Event::Answered(80), 0);
+        assert_eq!(peers.ranked(0), ["b", "a", "c"]);
+        peers.record("b", Event::Answered(200), 0);
+        assert_eq!(peers.get("b").unwrap().latency, Some(80));
+        // Being useful beats being quick
+        peers.record("c", Event::Useful, 0);
+        assert_eq!(peers.ranked(0), ["c", "a", "b"]);
+        // Enough junk and they're banned until the ban runs out
+        peers.record("a", Event::Unreachable, 0);
+        assert_eq!(peers.ranked(0), ["c", "b", "a"]);
+        for _ in 0..5 {
+            peers.record("a", Event::Invalid, 0);
+        }
+        assert!(peers.get("a").unwrap().is_banned(0));
+        assert_eq!(peers.ranked(BAN_TIME - 1), ["c", "b"]);
+        assert_eq!(peers.ranked(BAN_TIME), ["c", "b", "a"]);
+        // and come back on thin ice
+        peers.record("a", Event::Useful, BAN_TIME);
+        assert_eq!(peers.get("a").unwrap().score, BAN_SCORE / 2 + USEFUL);
+        for _ in 0..3 {
+            peers.record("a", Event::Invalid, BAN_TIME);
+        }
+        assert!(peers.get("a").unwrap().is_banned(BAN_TIME));
+        assert_eq!(peers.get("a").unwrap().invalid, 8);
+        // Traffic is counted but doesn't move the score
+        let score = peers.get("b").unwrap().score;
+        peers.record("b", Event::Sent(100), 0);
+        peers.record("b", Event::Received(30), 0);
+        peers.record("b", Event::Received(20), 0);
+        let b = peers.get("b").unwrap();
+        assert_eq!((b.bytes_out, b.bytes_in, b.score), (100, 50, score));
+    }
+
+    #[test]
+    fn clock() {
+        let mut peers = Peers::default();
+        for addr in ["a", "b", "c"] {
+            peers.add(addr.to_string());
+        }
+        // Just one peer is outvoted by our own clock
+        peers.record("a", Event::Clock(2_000), 0);
+        assert_eq!(peers.clock_offset(0), 0);
+        peers.record("b", Event::Clock(1_500), 0);
+        assert_eq!(peers.clock_offset(0), 1_500);
+        // Wild claims don't count
+        peers.record("c", Event::Clock(MAX_CLOCK_OFFSET + 1), 0);
+        assert_eq!(peers.clock_offset(0), 1_500);
+        // Nor do banned peers
+        for _ in 0..5 {
+            peers.record("b", Event::Invalid, 0);
+        }
+        assert_eq!(peers.clock_offset(0), 0);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+use crate::msg;
+
+// Token buckets per peer for the messages that cost us verification work. A bucket refills
+// at `rate` a second up to `burst` and each txn or block a peer sends takes one. A message
+// that would overdraw it is turned away whole, so one noisy neighbor can't keep us busy
+// checking signatures.
+
+pub const TXNS: Limit = Limit { rate: 200, burst: 1_000 };
+pub const BLOCKS: Limit = Limit { rate: 20, burst: 100 };
+const MAX_BUCKETS: usize = 1 << 12; // past this, buckets that have filled back up are forgotten
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limit {
+    pub rate: u64, // per second
+    pub burst: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Kind {
+    Txns,
+    Blocks
+}
+
+// Which bucket a message draws on and how much, if any.
+pub fn cost(msg: &msg::Message) -> Option<(Kind, u64)> {
+    match msg {
+        msg::Message::Txn(txns) => Some((Kind::Txns, txns.len() as u64)),
+        msg::Message::Chain(chain) => Some((Kind::Blocks, chain.len() as u64)),
+        msg::Message::Compact(_) => Some((Kind::Blocks, 1)),
+        _ => None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    milli: u64, // thousandths of a token, so slow rates still refill between messages
+    last: u64 // timestamp, ms
+}
+
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    txns: Limit,
+    blocks: Limit,
+    buckets: BTreeMap<(String, Kind), Bucket>
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(TXNS, BLOCKS)
+    }
+}
+
+impl Limiter {
+    pub fn new(txns: Limit, blocks: Limit) -> Self {
+        Self { txns, blocks, buckets: BTreeMap::default() }
+    }
+
+    fn refilled(limit: Limit, bucket: Bucket, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(bucket.last);
+        bucket.milli.saturating_add(elapsed.saturating_mul(limit.rate)).min(limit.burst * 1_000)
+    }
+
+    // Takes `amount` tokens from the peer's bucket, or nothing and false if there aren't enough.
+    pub fn take(&mut self, peer: &str, kind: Kind, amount: u64, now: u64) -> bool {
+        let (txns, blocks) = (self.txns, self.blocks);
+        let limit_of = |kind: Kind| match kind {
+            Kind::Txns => txns,
+            Kind::Blocks => blocks
+        };
+        if self.buckets.len() >= MAX_BUCKETS {
+            self.buckets.retain(|(_, kind), bucket| Self::refilled(limit_of(*kind), *bucket, now) < limit_of(*kind).burst * 1_000);
+        }
+        let limit = limit_of(kind);
+        let full = Bucket { milli: limit.burst * 1_000, last: now };
+        let bucket = self.buckets.entry((peer.to_string(), kind)).or_insert(full);
+        let milli = Self::refilled(limit, *bucket, now);
+        bucket.last = now;
+        let Some(left) = milli.checked_sub(amount.saturating_mul(1_000)) else {
+            bucket.milli = milli;
+            return false;
+        };
+        bucket.milli = left;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let mut limiter = Limiter::new(Limit { rate: 2, burst: 4 }, BLOCKS);
+        // A full burst, then nothing until it refills
+        assert!(limiter.take("a", Kind::Txns, 4, 0));
+        assert!(!limiter.take("a", Kind::Txns, 1, 0));
+        assert!(!limiter.take("a", Kind::Txns, 1, 499));
+        assert!(limiter.take("a", Kind::Txns, 1, 500));
+        // Other peers and kinds have their own
+        assert!(limiter.take("b", Kind::Txns, 4, 500));
+        assert!(limiter.take("a", Kind::Blocks, 1, 500));
+        // Too big a message takes nothing
+        assert!(!limiter.take("a", Kind::Txns, 5, 10_000));
+        assert!(limiter.take("a", Kind::Txns, 4, 10_000));
+    }
+}
@@ -1,13 +1,9 @@
-use ed25519_dalek::{self, Verifier, Signer};
-use rand::Rng;
 use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
-use std::collections::BTreeMap;
 use std::fmt::Debug;
-use rand::rngs::OsRng;
 use serde_big_array::BigArray;
 
-use crate::{merkle, account, senator};
+use crate::{merkle, account, senator, txn};
 
 pub type Id = [u8; 32];
 
@@ -23,7 +19,100 @@ pub struct Data {
     pub bal: u32
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct State {
     pub accounts: merkle::Map<account::Data>
-}
\ No newline at end of file
+}
+
+impl State {
+    // Runs a rollup txn. Only payments for now, and there's no vesting or freezing on rollups.
+    pub fn apply(&mut self, stxn: &account::Signed<txn::Txn>) -> Result<(), txn::Error> {
+        let from_addy: [u8; 32] = Sha256::digest(stxn.from.to_bytes()).into();
+        let mut from_account = self.accounts.get(&from_addy)
+            .map_err(|_| txn::Error::NoPreimage)?
+            .ok_or(txn::Error::BadFromPk(from_addy))?
+            .clone();
+        if !stxn.verify() {
+            return Err(txn::Error::BadSig);
+        }
+        if from_account.nonce > stxn.msg.nonce {
+            return Err(txn::Error::SmallNonce { expected: from_account.nonce, actual: stxn.msg.nonce });
+        } else if from_account.nonce < stxn.msg.nonce {
+            return Err(txn::Error::BigNonce { expected: from_account.nonce, actual: stxn.msg.nonce });
+        }
+        let txn::Payload::Payment(to_id, amount) = stxn.msg.payload else {
+            return Err(txn::Error::NotOnRollup);
+        };
+        let required = stxn.msg.fee.saturating_add(amount);
+        if from_account.bal < required {
+            return Err(txn::Error::InsuffBal { required, available: from_account.bal });
+        }
+        from_account.bal -= required;
+        from_account.nonce += 1;
+        self.accounts.insert(&from_addy, from_account).map_err(|_| txn::Error::NoPreimage)?;
+        let mut to_account = self.accounts.get(&to_id)
+            .map_err(|_| txn::Error::NoPreimage)?
+            .cloned()
+            .unwrap_or_default();
+        to_account.bal += amount;
+        self.accounts.insert(&to_id, to_account).map_err(|_| txn::Error::NoPreimage)?;
+        Ok(())
+    }
+}
+
+// A rollup this node sequences or senates for. `state` is as of the last of our headers on
+// chain; `pending` is that with `pool` run on top, ready to go in the next header. The first
+// `sealed` txns of the pool are in headers we've sent that aren't on chain yet.
+#[derive(Debug, Clone)]
+pub struct Working {
+    pub sequencer: bool, // we propose its headers, rather than just checking them
+    pub state: State,
+    pub pending: State,
+    pub pool: Vec<account::Signed<txn::Txn>>,
+    pub sealed: usize
+}
+
+impl Working {
+    pub fn new(sequencer: bool, state: State) -> Self {
+        Self { sequencer, pending: state.clone(), state, pool: Vec::default(), sealed: 0 }
+    }
+
+    // Pools a txn for the next header if it runs on top of the ones already there.
+    pub fn add(&mut self, stxn: account::Signed<txn::Txn>) -> Result<(), txn::Error> {
+        if self.pool.len() >= txn::MAX_HEADER_TXNS {
+            return Err(txn::Error::FullBlock);
+        }
+        self.pending.apply(&stxn)?;
+        self.pool.push(stxn);
+        Ok(())
+    }
+
+    // Takes the pooled txns not in a header yet, and the state hash they leave it at.
+    // `state` stays put until the header is on chain, see `include`.
+    pub fn seal(&mut self) -> (Vec<txn::Txn>, [u8; 32]) {
+        let txns = self.pool[self.sealed..].iter().map(|stxn| stxn.msg.clone()).collect();
+        self.sealed = self.pool.len();
+        (txns, self.pending.accounts.commit())
+    }
+
+    // A header on our head: if it's the next one we sealed, move `state` up to where it
+    // leaves it and drop its txns from the pool. Anything else leaves us be.
+    pub fn include(&mut self, txns: &[txn::Txn], state_hash: [u8; 32]) {
+        let n = txns.len();
+        if n > self.sealed || !self.pool.iter().zip(txns).all(|(stxn, txn)| &stxn.msg == txn) {
+            return;
+        }
+        let mut state = self.state.clone();
+        for stxn in self.pool[..n].iter() {
+            if state.apply(stxn).is_err() {
+                return;
+            }
+        }
+        if state.accounts.commit() != state_hash {
+            return;
+        }
+        self.state = state;
+        self.pool.drain(..n);
+        self.sealed -= n;
+    }
+}
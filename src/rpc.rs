@@ -0,0 +1,53 @@
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{account, app, msg, txn};
+
+// Talking to a node over its http api, as a wallet or the command line does, rather than as
+// a peer. Reads are as of the node's head, so a txn we just sent may not show yet.
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Account {
+    pub round: u32, // of the head it was read at
+    pub data: Option<account::Data> // none if it's never been paid
+}
+
+// Account ids under a prefix, from offset on, and how many there are in all.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SearchPage {
+    pub total: usize,
+    pub offset: usize,
+    pub matches: Vec<String> // 0x and the id in hex
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Unreachable,
+    Status(u16),
+    BadResponse
+}
+
+async fn answer<T: DeserializeOwned>(resp: Result<reqwest::Response, reqwest::Error>) -> Result<T, Error> {
+    let resp = resp.map_err(|_| Error::Unreachable)?;
+    if !resp.status().is_success() {
+        return Err(Error::Status(resp.status().as_u16()));
+    }
+    let body = resp.bytes().await.map_err(|_| Error::Unreachable)?;
+    serde_json::from_slice(&body).map_err(|_| Error::BadResponse)
+}
+
+pub async fn account(http: &reqwest::Client, node: &str, id: &account::Id) -> Result<Account, Error> {
+    let address: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let resp = http.get(format!("{}/api/rpc/account", app::url(node)))
+        .query(&[("address", format!("0x{}", address))])
+        .send().await;
+    answer(resp).await
+}
+
+// Into the node's pool and on to its peers, if it takes it.
+pub async fn submit(http: &reqwest::Client, node: &str, stxn: &account::Signed<txn::Txn>) -> Result<Result<msg::ok::Txn, msg::error::Txn>, Error> {
+    let resp = http.post(format!("{}/api/rpc/txn", app::url(node)))
+        .header("Content-type", msg::Codec::Json.content_type())
+        .body(msg::ser(stxn))
+        .send().await;
+    answer(resp).await
+}
@@ -1,8 +1,7 @@
-use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 
-use crate::{account, merkle, state, txn, validator};
+use crate::validator;
 
 pub type Id = [u8; 32];
 
@@ -11,11 +10,92 @@ pub struct Data {
     // If a majority of validators vote against guy gets removed.
     // TODO: collateral validator slots
     pub votes_against: u32,
-    pub owner: validator::Id
+    pub owner: validator::Id,
+    // For weighting the leader schedule by performance. On once most senators are for it.
+    pub weighting: bool
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Verifier {
-    id: Id,
-    at_round: u32
-}
\ No newline at end of file
+    pub id: Id,
+    pub at_round: u32
+}
+
+pub const HEADER_INTERVAL: u32 = 16; // rounds a sequencer can go without posting a header
+pub const MAX_MISSED: u32 = 3; // intervals in a row without a header before we oppose
+pub const MAX_CHALLENGES_LOST: u32 = 1; // one proven bad header is enough
+
+// How a senator we work with has been doing, going by what we've seen on chain.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Reputation {
+    pub sequencer: bool, // expected to post headers
+    pub headers: u32,
+    pub missed: u32, // intervals in a row without a header
+    pub challenges_lost: u32,
+    pub opposed: bool, // we've already voted against them
+    last_header: u32 // round, or when we started watching
+}
+
+impl Reputation {
+    pub fn new(sequencer: bool, round: u32) -> Self {
+        Self { sequencer, headers: 0, missed: 0, challenges_lost: 0, opposed: false, last_header: round }
+    }
+
+    pub fn header(&mut self, round: u32) {
+        self.headers += 1;
+        self.missed = 0;
+        self.last_header = round;
+    }
+
+    // Counts each whole interval that's gone by without a header.
+    pub fn tick(&mut self, round: u32) {
+        if !self.sequencer {
+            return;
+        }
+        while round.saturating_sub(self.last_header) > HEADER_INTERVAL {
+            self.missed += 1;
+            self.last_header += HEADER_INTERVAL;
+        }
+    }
+
+    pub fn failing(&self) -> bool {
+        self.missed >= MAX_MISSED || self.challenges_lost >= MAX_CHALLENGES_LOST
+    }
+}
+
+impl fmt::Display for Reputation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} headers, {} missed, {} challenges lost", self.headers, self.missed, self.challenges_lost)?;
+        if self.opposed {
+            write!(f, ", opposed")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reputation() {
+        let mut rep = Reputation::new(true, 0);
+        rep.tick(HEADER_INTERVAL);
+        rep.header(HEADER_INTERVAL);
+        assert_eq!((rep.headers, rep.missed), (1, 0));
+        // Three quiet intervals and they're out
+        rep.tick(HEADER_INTERVAL * 3 + 1);
+        assert_eq!(rep.missed, 2);
+        assert!(!rep.failing());
+        rep.tick(HEADER_INTERVAL * 4 + 1);
+        assert!(rep.failing());
+        rep.header(HEADER_INTERVAL * 4 + 2);
+        assert!(!rep.failing());
+        // Senators that don't sequence only lose out on challenges
+        let mut rep = Reputation::new(false, 0);
+        rep.tick(HEADER_INTERVAL * 10);
+        assert!(!rep.failing());
+        rep.challenges_lost += 1;
+        assert!(rep.failing());
+    }
+}
@@ -1,23 +1,131 @@
-use std::collections::BTreeMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::Deserialize;
 use serde::Serialize;
 use sha2::{Sha256, Digest};
-use crate::{merkle, account, validator, txn, block, senator, rollup};
+use crate::{merkle, account, validator, txn, block, senator, rollup, attest};
 
 pub const VALIDATOR_SLOTS: u32 = 256;
 pub const VALIDATOR_STAKE: u32 = 1024;
-pub const JENNY_COINS: u32 = VALIDATOR_SLOTS * VALIDATOR_STAKE >> 1;
-pub const JENNY_SLOTS: u32 = VALIDATOR_SLOTS >> 1;
+// Initial allocation to the genesis authority.
+pub const GENESIS_COINS: u32 = (VALIDATOR_SLOTS * VALIDATOR_STAKE) >> 1;
+pub const GENESIS_SLOTS: u32 = VALIDATOR_SLOTS >> 1;
+// Shard count for chains whose genesis config doesn't pick one.
 pub const NUM_SHARDS: u8 = 1;
+// Least a non-validator account may hold. Payments that would open an account with less, or
+// leave their sender with less but not nothing, are turned away; drained accounts are deleted.
+pub const DUST_BALANCE: u32 = 16;
 
 const _MAX_FORK: u32 = 128;
 
+// Timing every node has to agree on. Set at genesis and never changed, so testnets can tick faster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clock {
+    pub block_time: u64, // ms between proposals
+    pub max_prop_time: u64, // ms a block may take to reach us
+    pub max_clock_gap: u64, // ms our clock may be off from the leader's
+}
+
+impl Clock {
+    pub fn commit(&self) -> [u8; 32] {
+        Sha256::digest(serde_json::to_vec(self).expect("clocks serialize")).into()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self { block_time: 2_000, max_prop_time: 250, max_clock_gap: 300 }
+    }
+}
+
+// A shard's subtrie under a prefix, and the path it hangs from.
+pub type Subtrie = (merkle::Map<account::Data>, Vec<u8>);
+
+// Accounts split into shards by leading address bits. Each shard is its own trie
+// so a validator can check just the shards it's assigned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shards {
+    shards: Vec<merkle::Map<account::Data>>
+}
+
+pub fn shard_of(k: &[u8], num_shards: usize) -> usize {
+    (k[0] as usize * num_shards) >> 8
+}
+
+fn commit_shards(commits: &[[u8; 32]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for commit in commits {
+        hasher.update(commit);
+    }
+    hasher.finalize().into()
+}
+
+impl Shards {
+    // Power of two up to 16 so a one nibble prefix always lands in a single shard.
+    pub fn new(num_shards: u8) -> Self {
+        assert!(num_shards.is_power_of_two() && num_shards <= 16);
+        Self { shards: (0..num_shards).map(|_| merkle::Map::default()).collect() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    pub fn shard_of(&self, k: &[u8]) -> usize {
+        shard_of(k, self.shards.len())
+    }
+
+    pub fn shard(&self, i: usize) -> &merkle::Map<account::Data> {
+        &self.shards[i]
+    }
+
+    pub fn get(&self, k: &[u8]) -> Result<Option<&account::Data>, ()> {
+        self.shards[self.shard_of(k)].get(k)
+    }
+
+    pub fn insert(&mut self, k: &[u8], v: account::Data) -> Result<Option<account::Data>, ()> {
+        let i = self.shard_of(k);
+        self.shards[i].insert(k, v)
+    }
+
+    pub fn remove(&mut self, k: &[u8]) -> Result<Option<account::Data>, ()> {
+        let i = self.shard_of(k);
+        self.shards[i].remove(k)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &account::Data> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    // Takes a nibble prefix like merkle::Map::get_subtrie.
+    pub fn get_subtrie(&self, k: &[u8]) -> Result<Option<Subtrie>, ()> {
+        let first = k.first().ok_or(())?;
+        self.shards[self.shard_of(&[first << 4])].get_subtrie(k)
+    }
+
+    pub fn commits(&self) -> Vec<[u8; 32]> {
+        self.shards.iter().map(|shard| shard.commit()).collect()
+    }
+
+    pub fn commit(&self) -> [u8; 32] {
+        commit_shards(&self.commits())
+    }
+
+    pub fn prove(&self, k: &[u8]) -> Result<merkle::Proof, ()> {
+        self.shards[self.shard_of(k)].prove(k)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct State {
     // Accounts and balances. Indexed by hash of pk.
-    pub accounts: merkle::Map<account::Data>,
+    pub accounts: Shards,
     // Validator slots.
     // One slot is randomly chosen to lead each tick.
     pub slots: merkle::Map<validator::SlotData>,
@@ -27,33 +135,36 @@ pub struct State {
     pub senators: merkle::Map<senator::Data>,
     // Arbitrary index?
     pub rollups: merkle::Map<rollup::Data>,
+    // Cross shard credits waiting for the next block.
+    pub receipts: merkle::Map<account::Receipt>,
+    // Fixed by the genesis config. Not a trie, but hashed in with the roots so it's in the commit.
+    pub clock: Clock,
 }
 
-impl Default for State {
-    fn default() -> Self {
+impl State {
+    // Genesis state funding `authority` and staking it into the bottom GENESIS_SLOTS slots.
+    pub fn genesis(authority: &account::Keypair) -> Self {
+        Self::genesis_sharded(authority, NUM_SHARDS)
+    }
+
+    // Same, with accounts split `num_shards` ways. Fixed for the life of the chain.
+    pub fn genesis_sharded(authority: &account::Keypair, num_shards: u8) -> Self {
         let mut state = Self {
-            accounts: merkle::Map::default(),
+            accounts: Shards::new(num_shards),
             slots: merkle::Map::default(),
             validators: merkle::Map::default(),
             senators: merkle::Map::default(),
-            rollups: merkle::Map::default()
+            rollups: merkle::Map::default(),
+            receipts: merkle::Map::default(),
+            clock: Clock::default()
         };
-        let jenny_acc = account::Keypair::default();
-        assert!(
-            state.accounts.insert(
-                &Sha256::digest(jenny_acc.kp.public.to_bytes()),
-                account::Data { 
-                    bal: JENNY_COINS + JENNY_SLOTS * VALIDATOR_STAKE, 
-                    nonce: 0 
-                }
-            ).is_ok()
-        );
         assert!(
             state.accounts.insert(
-                &Sha256::digest(jenny_acc.kp.public.to_bytes()),
+                &Sha256::digest(authority.kp.public.to_bytes()),
                 account::Data { 
-                    bal: JENNY_COINS + JENNY_SLOTS * VALIDATOR_STAKE, 
-                    nonce: 0 
+                    bal: GENESIS_COINS + GENESIS_SLOTS * VALIDATOR_STAKE, 
+                    nonce: 0,
+                    ..Default::default()
                 }
             ).is_ok()
         );
@@ -63,96 +174,221 @@ impl Default for State {
             proposal: 1,
             timestamp: timestamp(),
             seed: [0u8; 32],
-            beacon: jenny_acc.sign(&[0u8; 32])
+            beacon: authority.sign(&[0u8; 32])
         };
-        for i in 0..VALIDATOR_SLOTS >> 1 {
+        for i in 0..GENESIS_SLOTS {
             assert!(
                 state.apply(
-                    &jenny_acc.stake(&state.validators, i), 
+                    &authority.stake_slot(i, i), 
                     &meta
                 ).is_ok()
             );
         }
+        // Attesting from the start, without spending a nonce on it.
+        let id: validator::Id = Sha256::digest(authority.kp.public.to_bytes()).into();
+        let mut val = state.validators.get(&id).unwrap().unwrap().clone();
+        val.bls = Some(attest::public(authority));
+        assert!(state.validators.insert(&id, val).is_ok());
         state
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Update {
     Account(account::Id, Option<account::Data>),
     Slot(validator::Slot, Option<validator::SlotData>),
     Validator(validator::Id, Option<validator::Data>),
     Senator(senator::Id, Option<senator::Data>),
-    Rollup(rollup::Id, Option<rollup::Data>)
+    Rollup(rollup::Id, Option<rollup::Data>),
+    Receipt(account::ReceiptId, Option<account::Receipt>)
 }
 
-impl State {
-    pub fn verify(&self, stxn: &account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<Vec<Update>, txn::Error> {
-        let from_addy: [u8; 32] = Sha256::digest(&stxn.from.to_bytes()).into();
-        let mut from_account = self.accounts.get(&from_addy)
-            .map_err(|_| txn::Error::NoPreimage)?
-            .ok_or(txn::Error::BadFromPk)?
+// Pending writes over a base State. Txns within a block read through it
+// and the merkle maps are only touched once per block on `flush`.
+#[derive(Debug, Clone, Default)]
+pub struct Overlay {
+    accounts: HashMap<account::Id, Option<account::Data>>,
+    slots: HashMap<validator::Slot, Option<validator::SlotData>>,
+    validators: HashMap<validator::Id, Option<validator::Data>>,
+    senators: HashMap<senator::Id, Option<senator::Data>>,
+    rollups: HashMap<rollup::Id, Option<rollup::Data>>,
+    receipts: HashMap<account::ReceiptId, Option<account::Receipt>>,
+    log: Vec<Update>, // every write in application order
+    sigs_checked: bool, // signatures were already verified up front
+}
+
+fn lookup<'a, K: Eq + Hash + AsRef<[u8]>, V: Serialize + Clone>(
+    writes: &'a HashMap<K, Option<V>>,
+    base: &'a merkle::Map<V>,
+    k: &K
+) -> Result<Option<&'a V>, txn::Error> {
+    match writes.get(k) {
+        Some(opt_v) => Ok(opt_v.as_ref()),
+        None => base.get(k.as_ref()).map_err(|_| txn::Error::NoPreimage)
+    }
+}
+
+impl Overlay {
+    pub fn with_sigs_checked(mut self) -> Self {
+        self.sigs_checked = true;
+        self
+    }
+
+    pub fn set_sigs_checked(&mut self, checked: bool) {
+        self.sigs_checked = checked;
+    }
+
+    pub fn sigs_checked(&self) -> bool {
+        self.sigs_checked
+    }
+
+    pub fn account<'a>(&'a self, base: &'a State, k: &account::Id) -> Result<Option<&'a account::Data>, txn::Error> {
+        match self.accounts.get(k) {
+            Some(opt_v) => Ok(opt_v.as_ref()),
+            None => base.accounts.get(k).map_err(|_| txn::Error::NoPreimage)
+        }
+    }
+
+    pub fn slot<'a>(&'a self, base: &'a State, k: &validator::Slot) -> Result<Option<&'a validator::SlotData>, txn::Error> {
+        lookup(&self.slots, &base.slots, k)
+    }
+
+    pub fn validator<'a>(&'a self, base: &'a State, k: &validator::Id) -> Result<Option<&'a validator::Data>, txn::Error> {
+        lookup(&self.validators, &base.validators, k)
+    }
+
+    pub fn senator<'a>(&'a self, base: &'a State, k: &senator::Id) -> Result<Option<&'a senator::Data>, txn::Error> {
+        lookup(&self.senators, &base.senators, k)
+    }
+
+    pub fn rollup<'a>(&'a self, base: &'a State, k: &rollup::Id) -> Result<Option<&'a rollup::Data>, txn::Error> {
+        lookup(&self.rollups, &base.rollups, k)
+    }
+
+    pub fn receipt<'a>(&'a self, base: &'a State, k: &account::ReceiptId) -> Result<Option<&'a account::Receipt>, txn::Error> {
+        lookup(&self.receipts, &base.receipts, k)
+    }
+
+    pub fn verify(&self, base: &State, stxn: &account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<Vec<Update>, txn::Error> {
+        stxn.msg.check_size()?;
+        if stxn.msg.payload.version() > block::version_at(headerdata.round) {
+            return Err(txn::Error::Inactive(stxn.msg.payload.version()));
+        }
+        let from_addy: [u8; 32] = Sha256::digest(stxn.from.to_bytes()).into();
+        let mut from_account = self.account(base, &from_addy)?
+            .ok_or(txn::Error::BadFromPk(from_addy))?
             .clone();
-        if !stxn.verify() {
+        if !self.sigs_checked && !stxn.verify() {
             return Err(txn::Error::BadSig);
         }
         if from_account.nonce > stxn.msg.nonce {
-            return Err(txn::Error::SmallNonce);
+            return Err(txn::Error::SmallNonce { expected: from_account.nonce, actual: stxn.msg.nonce });
         } else if from_account.nonce < stxn.msg.nonce {
-            return Err(txn::Error::BigNonce);
+            return Err(txn::Error::BigNonce { expected: from_account.nonce, actual: stxn.msg.nonce });
         }
+        if from_account.spendable(headerdata.round) < stxn.msg.fee {
+            return Err(txn::Error::InsuffBal { 
+                required: stxn.msg.fee, 
+                available: from_account.spendable(headerdata.round) 
+            });
+        }
+        from_account.bal -= stxn.msg.fee;
         from_account.nonce += 1;
+        let charged = from_account.clone();
         let mut ups = Vec::default();
         match stxn.msg.payload {
             txn::Payload::Payment(to_id, amount) => {
-                if from_account.bal < amount {
-                    return Err(txn::Error::InsuffBal);
+                if from_account.frozen {
+                    return Err(txn::Error::Frozen(from_addy));
                 }
-                match self.accounts.get(&to_id).map_err(|_| txn::Error::NoPreimage)? {
-                    Some(to_account) => {
-                        let mut to_account = to_account.clone();
-                        if from_addy != to_id {
-                            from_account.nonce += 1;
-                            from_account.bal -= amount;
-                            to_account.bal += amount;
-                            ups.push(
-                                Update::Account(to_id, Some(to_account))
-                            );
-                        }
-                        ups.push(
-                            Update::Account(from_addy, Some(from_account))
-                        );
-                    }
-                    None => {
-                        from_account.nonce += 1;
-                        from_account.bal -= amount;
-                        ups.push(
-                            Update::Account(from_addy, Some(from_account))
-                        );
-                        let to_account = account::Data {
-                            bal: amount,
-                            nonce: 0
-                        };
-                        ups.push(
-                            Update::Account(to_id, Some(to_account))
-                        );
-                    }
+                if from_account.spendable(headerdata.round) < amount {
+                    return Err(txn::Error::InsuffBal { 
+                        required: amount, 
+                        available: from_account.spendable(headerdata.round) 
+                    });
                 }
+                if base.accounts.shard_of(&from_addy) != base.accounts.shard_of(&to_id) {
+                    check_receipt(amount)?;
+                    from_account.bal -= amount;
+                    ups.push(self.payer_update(base, from_addy, from_account, headerdata.round)?);
+                    ups.push(receipt(from_addy, stxn.msg.nonce, to_id, amount, 0));
+                    return Ok(ups);
+                }
+                if from_addy != to_id {
+                    let mut to_account = self.payee(base, &to_id, amount, headerdata.round)?;
+                    from_account.bal -= amount;
+                    to_account.bal += amount;
+                    ups.push(
+                        Update::Account(to_id, Some(to_account))
+                    );
+                }
+                ups.push(
+                    self.payer_update(base, from_addy, from_account, headerdata.round)?
+                );
             },
-            txn::Payload::Stake(slot) => {
+            txn::Payload::VestedPayment(to_id, amount, unlock_round) => {
+                if from_account.frozen {
+                    return Err(txn::Error::Frozen(from_addy));
+                }
+                if from_account.spendable(headerdata.round) < amount {
+                    return Err(txn::Error::InsuffBal { 
+                        required: amount, 
+                        available: from_account.spendable(headerdata.round) 
+                    });
+                }
+                let to_account = match from_addy == to_id {
+                    true => Some(&from_account),
+                    false => self.account(base, &to_id)?
+                };
+                let pending = self.pending_locks(&to_id, headerdata.round);
+                if !to_account.unwrap_or(&account::Data::default()).can_vest(headerdata.round, unlock_round, pending) {
+                    return Err(txn::Error::TooManyLocks(to_id));
+                }
+                if from_addy == to_id {
+                    from_account.vest(amount, headerdata.round, unlock_round);
+                    ups.push(
+                        self.payer_update(base, from_addy, from_account, headerdata.round)?
+                    );
+                } else if base.accounts.shard_of(&from_addy) != base.accounts.shard_of(&to_id) {
+                    check_receipt(amount)?;
+                    from_account.bal -= amount;
+                    ups.push(self.payer_update(base, from_addy, from_account, headerdata.round)?);
+                    ups.push(receipt(from_addy, stxn.msg.nonce, to_id, amount, unlock_round));
+                } else {
+                    let mut to_account = self.payee(base, &to_id, amount, headerdata.round)?;
+                    from_account.bal -= amount;
+                    to_account.bal += amount;
+                    to_account.vest(amount, headerdata.round, unlock_round);
+                    ups.push(
+                        self.payer_update(base, from_addy, from_account, headerdata.round)?
+                    );
+                    ups.push(
+                        Update::Account(to_id, Some(to_account))
+                    );
+                }
+            },
+            txn::Payload::Stake(_) | txn::Payload::StakeIn(..) => {
+                let slot = match stxn.msg.payload {
+                    txn::Payload::StakeIn(start, end) => self.first_free_slot(base, start, end)?
+                        .ok_or(txn::Error::NoFreeSlot(start, end))?,
+                    txn::Payload::Stake(slot) => slot,
+                    _ => unreachable!()
+                };
                 if from_account.bal < VALIDATOR_STAKE {
-                    return Err(txn::Error::InsuffBal);
+                    return Err(txn::Error::InsuffStake);
                 }
-                if self.slots.get(&slot).map_err(|_| txn::Error::NoPreimage)?.is_some() {
-                    return Err(txn::Error::BadStakeIdx);
+                if self.slot(base, &slot)?.is_some() {
+                    return Err(txn::Error::BadStakeIdx(slot));
                 }
                 let slot_data = validator::SlotData { 
                     round: headerdata.round, 
-                    owner: from_addy
+                    owner: from_addy,
+                    nonce: stxn.msg.nonce
                 };
                 ups.push(
                     Update::Slot(slot, Some(slot_data))
                 );
-                let val_data = match self.validators.get(&from_addy).map_err(|_| txn::Error::NoPreimage)? {
+                let val_data = match self.validator(base, &from_addy)? {
                     Some(val) => {
                         let mut val = val.clone();
                         val.slots += 1;
@@ -162,35 +398,39 @@ impl State {
                         validator::Data {
                             opposed: merkle::Map::default(),
                             slots: 1,
-                            pk: stxn.from.clone()
+                            pk: stxn.from,
+                            missed: 0,
+                            bls: None
                         }
                     }
                 };
                 if !val_data.opposed.is_empty() {
-                    return Err(txn::Error::LockedStake)
+                    return Err(txn::Error::LockedStake(from_addy))
                 }
                 ups.push(
                     Update::Validator(from_addy, Some(val_data))
                 );
+                // Held for as long as the slot is
+                from_account.bal -= VALIDATOR_STAKE;
+                ups.push(Update::Account(from_addy, Some(from_account)));
             },
             txn::Payload::Unstake(slot) => {
-                match self.slots.get(&slot).map_err(|_| txn::Error::NoPreimage)? {
+                match self.slot(base, &slot)? {
                     Some(stake_data) => {
                         if stake_data.owner != from_addy {
-                            return Err(txn::Error::BadStakeIdx)
+                            return Err(txn::Error::BadStakeIdx(slot))
                         }
                     }
-                    _ => return Err(txn::Error::BadStakeIdx)
+                    _ => return Err(txn::Error::BadStakeIdx(slot))
                 }
                 ups.push(
                     Update::Slot(slot, None)
                 );
-                let mut val = self.validators.get(&from_addy)
-                    .map_err(|_| txn::Error::NoPreimage)?
+                let mut val = self.validator(base, &from_addy)?
                     .unwrap()
                     .clone();
                 if !val.opposed.is_empty() {
-                    return Err(txn::Error::LockedStake)
+                    return Err(txn::Error::LockedStake(from_addy))
                 }
                 if val.slots == 1 {
                     ups.push(
@@ -202,69 +442,595 @@ impl State {
                         Update::Validator(from_addy, Some(val))
                     );
                 }
+                from_account.bal += VALIDATOR_STAKE;
+                ups.push(Update::Account(from_addy, Some(from_account)));
             },
-            txn::Payload::Debit(acc_id, opt_rollup, amount) => {
-                todo!()
+            // Not run on the base chain yet
+            txn::Payload::Debit(..) | txn::Payload::Credit(..) => {
+                return Err(txn::Error::Unsupported);
             },
-            txn::Payload::Credit(acc_id, amount) => {
-                todo!()
+            // The txns are left to the rollup's senators, the chain just keeps its state hash.
+            txn::Payload::Header(id, _, state_hash) => {
+                let mut rollup = self.rollup(base, &id)?
+                    .ok_or(txn::Error::NoRollup)?
+                    .clone();
+                if rollup.sequencer.id != from_addy {
+                    return Err(txn::Error::NotSenator(from_addy));
+                }
+                rollup.state_hash = state_hash;
+                rollup.sequencer.at_round = headerdata.round;
+                ups.push(Update::Rollup(id, Some(rollup)));
+            },
+            txn::Payload::Oppose(id) => {
+                let mut val = self.validator(base, &from_addy)?
+                    .ok_or(txn::Error::NotValidator(from_addy))?
+                    .clone();
+                let mut senator = self.senator(base, &id)?
+                    .ok_or(txn::Error::NotSenator(id))?
+                    .clone();
+                if val.opposed.get(&id).map_err(|_| txn::Error::NoPreimage)?.is_some() {
+                    return Err(txn::Error::AlreadyOpposed(id));
+                }
+                val.opposed.insert(&id, ()).map_err(|_| txn::Error::NoPreimage)?;
+                // Weighed by slots, which stay put while the validator opposes anyone.
+                senator.votes_against += val.slots;
+                if senator.votes_against > VALIDATOR_SLOTS / 2 {
+                    ups.push(Update::Senator(id, None));
+                } else {
+                    ups.push(Update::Senator(id, Some(senator)));
+                }
+                ups.push(Update::Validator(from_addy, Some(val)));
             },
-            txn::Payload::Header(rollup, txns) => {
-                todo!()
+            txn::Payload::Support(id) => {
+                let mut val = self.validator(base, &from_addy)?
+                    .ok_or(txn::Error::NotValidator(from_addy))?
+                    .clone();
+                if val.opposed.remove(&id).map_err(|_| txn::Error::NoPreimage)?.is_none() {
+                    return Err(txn::Error::NotOpposed(id));
+                }
+                // Also how stake is freed once the senator's been voted out.
+                if let Some(senator) = self.senator(base, &id)? {
+                    let mut senator = senator.clone();
+                    senator.votes_against = senator.votes_against.saturating_sub(val.slots);
+                    ups.push(Update::Senator(id, Some(senator)));
+                }
+                ups.push(Update::Validator(from_addy, Some(val)));
             },
-            txn::Payload::Oppose(senator_id) => {
-                todo!()
+            txn::Payload::Freeze(acc_id) => {
+                ups.extend(self.freeze_vote(base, from_addy, from_account, acc_id, true)?);
             },
-            txn::Payload::Support(senator_id) => {
-                todo!()
+            txn::Payload::Unfreeze(acc_id) => {
+                ups.extend(self.freeze_vote(base, from_addy, from_account, acc_id, false)?);
             },
+            // Counted when the next epoch's set is snapshotted, see validator::weighting.
+            txn::Payload::Weighting(on) => {
+                let mut senator = self.senator(base, &from_addy)?
+                    .ok_or(txn::Error::NotSenator(from_addy))?
+                    .clone();
+                senator.weighting = on;
+                ups.push(Update::Senator(from_addy, Some(senator)));
+            },
+            txn::Payload::Attester(ref bls, ref pop) => {
+                let mut val = self.validator(base, &from_addy)?
+                    .ok_or(txn::Error::NotValidator(from_addy))?
+                    .clone();
+                if !attest::check_pop(bls, pop) {
+                    return Err(txn::Error::BadAttester);
+                }
+                val.bls = Some(bls.clone());
+                ups.push(Update::Validator(from_addy, Some(val)));
+                ups.push(Update::Account(from_addy, Some(from_account)));
+            }
+        }
+        // Payloads that don't otherwise touch the sender still owe the nonce bump and fee.
+        if !ups.iter().any(|up| matches!(up, Update::Account(k, _) if *k == from_addy)) {
+            ups.push(Update::Account(from_addy, Some(charged)));
         }
         Ok(ups)
     }
 
-    pub fn apply<'a> (&mut self, stxn: &'a account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<(), txn::Error> {
-        for up in self.verify(stxn, headerdata)? {
-            match up { // TODO lots of boilerplate!
-                Update::Account(addy, opt_data) => {
-                    match opt_data {
-                        Some(data) => self.accounts.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
-                        None => self.accounts.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
-                    };
-                },
-                Update::Validator(addy, opt_data) => {
-                    match opt_data {
-                        Some(data) => self.validators.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
-                        None => self.validators.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
-                    };
-                },
-                Update::Slot(slot, opt_data) => {
-                    match opt_data {
-                        Some(data) => self.slots.insert(&slot, data).map_err(|_| txn::Error::NoPreimage)?,
-                        None => self.slots.remove(&slot).map_err(|_| txn::Error::NoPreimage)?
-                    };
-                },
-                Update::Senator(addy, opt_data) => {
-                    match opt_data {
-                        Some(data) => self.senators.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
-                        None => self.senators.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
-                    };
+    fn first_free_slot(&self, base: &State, start: u32, end: u32) -> Result<Option<validator::Slot>, txn::Error> {
+        for i in start..end.min(VALIDATOR_SLOTS) {
+            if self.slot(base, &i.to_be_bytes())?.is_none() {
+                return Ok(Some(i.to_be_bytes()));
+            }
+        }
+        Ok(None)
+    }
+
+    // Record a senator's vote to set acc_id's frozen flag. Flips once a majority of senators agree.
+    fn freeze_vote(&self, base: &State, from_addy: account::Id, from_account: account::Data, acc_id: account::Id, freeze: bool) -> Result<Vec<Update>, txn::Error> {
+        if self.senator(base, &from_addy)?.is_none() {
+            return Err(txn::Error::NotSenator(from_addy));
+        }
+        if base.accounts.shard_of(&from_addy) != base.accounts.shard_of(&acc_id) {
+            return Err(txn::Error::CrossShard(acc_id));
+        }
+        let mut ups = Vec::default();
+        let mut target = if acc_id == from_addy {
+            from_account
+        } else {
+            ups.push(Update::Account(from_addy, Some(from_account)));
+            self.account(base, &acc_id)?
+                .ok_or(txn::Error::NoAccount(acc_id))?
+                .clone()
+        };
+        // A vote for the way it already is only takes back one to flip it.
+        let retracted = target.votes.remove(&(from_addy, !freeze));
+        if target.frozen == freeze && !retracted {
+            return Err(match freeze {
+                true => txn::Error::Frozen(acc_id),
+                false => txn::Error::NotFrozen(acc_id)
+            });
+        }
+        target.votes.insert((from_addy, freeze));
+        // Votes from senators who've since been voted out don't count.
+        let mut tally = 0;
+        for (id, vote) in target.votes.iter() {
+            if *vote != target.frozen && self.senator(base, id)?.is_some() {
+                tally += 1;
+            }
+        }
+        if tally >= self.senator_quorum(base)? {
+            target.frozen = !target.frozen;
+            target.votes.clear();
+        }
+        ups.push(Update::Account(acc_id, Some(target)));
+        Ok(ups)
+    }
+
+    // Strict majority of all senators, including any voted in or out earlier in the block.
+    fn senator_quorum(&self, base: &State) -> Result<usize, txn::Error> {
+        let mut count = base.senators.iter().count();
+        for (id, data) in self.senators.iter() {
+            match (base.senators.get(id).map_err(|_| txn::Error::NoPreimage)?.is_some(), data.is_some()) {
+                (true, false) => count -= 1,
+                (false, true) => count += 1,
+                _ => {}
+            }
+        }
+        Ok(count / 2 + 1)
+    }
+
+    // A payment's sender after paying out. Non-validators can't be left holding dust, so the
+    // trie can't be griefed with tiny balances, and are deleted once there's nothing left.
+    // Validators keep their entry so they can still unstake.
+    fn payer_update(&self, base: &State, addy: account::Id, data: account::Data, round: u32) -> Result<Update, txn::Error> {
+        if data.bal >= DUST_BALANCE || self.validator(base, &addy)?.is_some() {
+            return Ok(Update::Account(addy, Some(data)));
+        }
+        if data.bal > 0 {
+            return Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: data.bal });
+        }
+        // Nothing is locked once nothing's left, so all that's lost is the nonce. It has to be
+        // one a new account would start past, or the old txns could be replayed once paid again.
+        if data.nonce > first_nonce(round) {
+            return Err(txn::Error::CantClose { nonce: data.nonce, from_round: data.nonce.saturating_add(1) });
+        }
+        Ok(Update::Account(addy, None))
+    }
+
+    // Account `amount` is about to be paid into, opening it if it has to be big enough not to be dust.
+    fn payee(&self, base: &State, to_id: &account::Id, amount: u32, round: u32) -> Result<account::Data, txn::Error> {
+        match self.account(base, to_id)? {
+            Some(to_account) => Ok(to_account.clone()),
+            None if amount < DUST_BALANCE => Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: amount }),
+            None => Ok(account::Data { nonce: first_nonce(round), ..Default::default() })
+        }
+    }
+
+    // Returns what it wrote.
+    pub fn apply(&mut self, base: &State, stxn: &account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<Vec<Update>, txn::Error> {
+        let ups = self.verify(base, stxn, headerdata)?;
+        self.write_all(&ups);
+        Ok(ups)
+    }
+
+    pub fn write_all(&mut self, ups: &[Update]) {
+        for up in ups {
+            self.write(up.clone());
+        }
+    }
+
+    // Charge every leader whose proposal lapsed before this block, then decay the producer's record.
+    // Must run before any txns so both builder and verifier see the same leaders.
+    // Leaders come from the epoch's validator set, misses are charged to the live one.
+    pub fn record_misses(&mut self, base: &State, epoch: &block::ValidatorSet, head_seed: &[u8; 32], proposal: u32, producer: &account::PublicKey) -> Result<(), txn::Error> {
+        for lapsed in 1..proposal {
+            let pk = block::leader_at(head_seed, epoch, lapsed)?;
+            let id: validator::Id = Sha256::digest(pk.to_bytes()).into();
+            // Could have unstaked since the epoch started.
+            if let Some(val) = self.validator(base, &id)? {
+                let mut val = val.clone();
+                val.missed = val.missed.saturating_add(1);
+                self.write(Update::Validator(id, Some(val)));
+            }
+        }
+        let id: validator::Id = Sha256::digest(producer.to_bytes()).into();
+        if let Some(val) = self.validator(base, &id)? {
+            if val.missed > 0 {
+                let mut val = val.clone();
+                val.missed >>= 1;
+                self.write(Update::Validator(id, Some(val)));
+            }
+        }
+        Ok(())
+    }
+
+    // Receipts sent this block that will lock a tranche of `to` once delivered.
+    fn pending_locks(&self, to: &account::Id, round: u32) -> usize {
+        self.receipts.values()
+            .flatten()
+            .filter(|receipt| &receipt.to == to && receipt.unlock_round > round)
+            .count()
+    }
+
+    // Credit everything sent across shards last block. Runs before any txns.
+    pub fn deliver_receipts(&mut self, base: &State, round: u32) -> Result<(), txn::Error> {
+        for receipt in base.receipts.iter() {
+            // Checked against dust when sent, so opening the account here is fine.
+            let mut to_account = self.account(base, &receipt.to)?
+                .cloned()
+                .unwrap_or(account::Data { nonce: first_nonce(round), ..Default::default() });
+            to_account.bal += receipt.amount;
+            to_account.vest(receipt.amount, round, receipt.unlock_round);
+            self.write(Update::Account(receipt.to, Some(to_account)));
+            self.write(Update::Receipt(receipt.id, None));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, up: Update) {
+        self.log.push(up.clone());
+        match up {
+            Update::Account(k, opt_data) => { self.accounts.insert(k, opt_data); },
+            Update::Slot(k, opt_data) => { self.slots.insert(k, opt_data); },
+            Update::Validator(k, opt_data) => { self.validators.insert(k, opt_data); },
+            Update::Senator(k, opt_data) => { self.senators.insert(k, opt_data); },
+            Update::Rollup(k, opt_data) => { self.rollups.insert(k, opt_data); },
+            Update::Receipt(k, opt_data) => { self.receipts.insert(k, opt_data); }
+        }
+    }
+
+    // Write everything into state. Returns the net updates, ordered by last write.
+    pub fn flush(self, state: &mut State) -> Result<Vec<Update>, txn::Error> {
+        let mut seen = HashSet::new();
+        let mut ups = Vec::default();
+        for up in self.log.into_iter().rev() {
+            if seen.insert(up.key()) {
+                ups.push(up);
+            }
+        }
+        ups.reverse();
+        for up in ups.iter() {
+            state.write(up.clone())?;
+        }
+        Ok(ups)
+    }
+}
+
+// What a txn would do if it were included. Nothing is written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Simulation {
+    pub updates: Vec<Update>,
+    pub balances: Vec<(account::Id, u32, u32)> // every account touched: before, after
+}
+
+impl State {
+    pub fn verify(&self, stxn: &account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<Vec<Update>, txn::Error> {
+        Overlay::default().verify(self, stxn, headerdata)
+    }
+
+    pub fn simulate(&self, stxn: &account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<Simulation, txn::Error> {
+        let updates = self.verify(stxn, headerdata)?;
+        let balances = updates.iter()
+            .filter_map(|up| match up {
+                Update::Account(k, after) => {
+                    let before = self.accounts.get(k).ok().flatten().map_or(0, |acc| acc.bal);
+                    Some((*k, before, after.as_ref().map_or(0, |acc| acc.bal)))
                 },
-                Update::Rollup(addy, opt_data) => {
-                    match opt_data {
-                        Some(data) => self.rollups.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
-                        None => self.rollups.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
-                    };
-                }
+                _ => None
+            })
+            .collect();
+        Ok(Simulation { updates, balances })
+    }
+
+    pub fn apply(&mut self, stxn: &account::Signed<txn::Txn>, headerdata: &block::Metadata) -> Result<(), txn::Error> {
+        for up in self.verify(stxn, headerdata)? {
+            self.write(up)?;
+        }
+        Ok(())
+    }
+
+    // A block's net updates, on top of the state before it.
+    pub fn replay(&mut self, updates: &[Update]) -> Result<(), txn::Error> {
+        for up in updates {
+            self.write(up.clone())?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, up: Update) -> Result<(), txn::Error> {
+        match up { // TODO lots of boilerplate!
+            Update::Account(addy, opt_data) => {
+                match opt_data {
+                    Some(data) => self.accounts.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
+                    None => self.accounts.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
+                };
+            },
+            Update::Validator(addy, opt_data) => {
+                match opt_data {
+                    Some(data) => self.validators.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
+                    None => self.validators.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
+                };
+            },
+            Update::Slot(slot, opt_data) => {
+                match opt_data {
+                    Some(data) => self.slots.insert(&slot, data).map_err(|_| txn::Error::NoPreimage)?,
+                    None => self.slots.remove(&slot).map_err(|_| txn::Error::NoPreimage)?
+                };
+            },
+            Update::Senator(addy, opt_data) => {
+                match opt_data {
+                    Some(data) => self.senators.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
+                    None => self.senators.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
+                };
+            },
+            Update::Rollup(addy, opt_data) => {
+                match opt_data {
+                    Some(data) => self.rollups.insert(&addy, data).map_err(|_| txn::Error::NoPreimage)?,
+                    None => self.rollups.remove(&addy).map_err(|_| txn::Error::NoPreimage)?
+                };
+            },
+            Update::Receipt(id, opt_data) => {
+                match opt_data {
+                    Some(data) => self.receipts.insert(&id, data).map_err(|_| txn::Error::NoPreimage)?,
+                    None => self.receipts.remove(&id).map_err(|_| txn::Error::NoPreimage)?
+                };
             }
         }
         Ok(())
     }
 
+    pub fn roots(&self) -> Roots {
+        [
+            self.accounts.commit(),
+            self.slots.commit(),
+            self.validators.commit(),
+            self.senators.commit(),
+            self.rollups.commit(),
+            self.receipts.commit(),
+            self.clock.commit()
+        ]
+    }
+
+    // Every node in every trie commits to what's under it, for a state a peer handed over whole.
+    // The roots alone don't vouch for the nodes below them. Errs with the first trie that's off.
+    pub fn valid_commits(&self) -> Result<(), Part> {
+        for i in 0..self.accounts.len() {
+            self.accounts.shard(i).valid_commits().map_err(|_| Part::Shard(i as u8))?;
+        }
+        self.slots.valid_commits().map_err(|_| Part::Slots)?;
+        self.validators.valid_commits().map_err(|_| Part::Validators)?;
+        self.senators.valid_commits().map_err(|_| Part::Senators)?;
+        self.rollups.valid_commits().map_err(|_| Part::Rollups)?;
+        self.receipts.valid_commits().map_err(|_| Part::Receipts)
+    }
+
     pub fn commit(&self) -> [u8; 32] {
-        let mut hasher = Sha256::new();
-        hasher.update(self.accounts.commit());
-        hasher.update(self.validators.commit());
-        hasher.finalize().into()
+        commit_roots(&self.roots())
+    }
+
+    // Proof that the state this commits to holds up's value.
+    pub fn prove(&self, up: &Update) -> Result<Proof, ()> {
+        let path = match up {
+            Update::Account(k, _) => self.accounts.prove(k)?,
+            Update::Slot(k, _) => self.slots.prove(k)?,
+            Update::Validator(k, _) => self.validators.prove(k)?,
+            Update::Senator(k, _) => self.senators.prove(k)?,
+            Update::Rollup(k, _) => self.rollups.prove(k)?,
+            Update::Receipt(k, _) => self.receipts.prove(k)?
+        };
+        Ok(Proof { roots: self.roots(), shards: self.accounts.commits(), path })
+    }
+}
+
+// One of the tries behind a state commit. State is exported a trie at a time so each
+// can be checked against its own root as soon as it's all in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Part {
+    Shard(u8),
+    Slots,
+    Validators,
+    Senators,
+    Rollups,
+    Receipts
+}
+
+// Entries of a trie after a key, as the writes that put them back.
+fn chunk<K: TryFrom<Vec<u8>>, V: Serialize + Clone>(
+    map: &merkle::Map<V>,
+    after: Option<&[u8]>,
+    count: usize,
+    up: fn(K, Option<V>) -> Update
+) -> Result<Vec<Update>, ()> {
+    map.chunk(after, count).into_iter()
+        .map(|(k, v)| Ok(up(k.try_into().map_err(|_| ())?, Some(v))))
+        .collect()
+}
+
+impl State {
+    // Up to `count` entries of one part keyed after `after`, in key order.
+    pub fn chunk(&self, part: Part, after: Option<&[u8]>, count: usize) -> Result<Vec<Update>, ()> {
+        match part {
+            Part::Shard(i) => chunk(self.accounts.shards.get(i as usize).ok_or(())?, after, count, Update::Account),
+            Part::Slots => chunk(&self.slots, after, count, Update::Slot),
+            Part::Validators => chunk(&self.validators, after, count, Update::Validator),
+            Part::Senators => chunk(&self.senators, after, count, Update::Senator),
+            Part::Rollups => chunk(&self.rollups, after, count, Update::Rollup),
+            Part::Receipts => chunk(&self.receipts, after, count, Update::Receipt)
+        }
+    }
+}
+
+// Puts a state back together from another node's chunks, checking each part against
+// the roots in a checkpoint header once it's complete.
+#[derive(Debug, Clone)]
+pub struct Import {
+    state: State,
+    roots: Roots,
+    shards: Vec<[u8; 32]>,
+    parts: Vec<Part>, // still to fetch, current first
+    after: Option<Vec<u8>> // last key in so far of the current part
+}
+
+impl Import {
+    pub fn new(roots: Roots, shards: Vec<[u8; 32]>, clock: Clock) -> Result<Self, ()> {
+        let num_shards = u8::try_from(shards.len()).map_err(|_| ())?;
+        if !num_shards.is_power_of_two() || num_shards > 16 || commit_shards(&shards) != roots[0] || clock.commit() != roots[6] {
+            return Err(());
+        }
+        let state = State {
+            accounts: Shards::new(num_shards),
+            slots: merkle::Map::default(),
+            validators: merkle::Map::default(),
+            senators: merkle::Map::default(),
+            rollups: merkle::Map::default(),
+            receipts: merkle::Map::default(),
+            clock
+        };
+        let parts = (0..num_shards).map(Part::Shard)
+            .chain([Part::Slots, Part::Validators, Part::Senators, Part::Rollups, Part::Receipts])
+            .collect();
+        Ok(Self { state, roots, shards, parts, after: None })
+    }
+
+    // The part and key to ask for next. None once everything's in.
+    pub fn next(&self) -> Option<(Part, Option<Vec<u8>>)> {
+        self.parts.first().map(|part| (*part, self.after.clone()))
+    }
+
+    // Takes the answer to `next` asked with `count`. A short chunk ends the part, which then
+    // has to match its root. Entries must be in the part and come in key order.
+    pub fn add(&mut self, ups: Vec<Update>, count: usize) -> Result<(), Part> {
+        let part = *self.parts.first().ok_or(Part::Receipts)?;
+        if ups.len() > count {
+            return Err(part);
+        }
+        for up in ups.iter() {
+            let k = up.key().1;
+            let fits = match (part, up) {
+                (Part::Shard(shard), Update::Account(k, Some(_))) => shard_of(k, self.shards.len()) == shard as usize,
+                (Part::Slots, Update::Slot(_, Some(_))) | (Part::Validators, Update::Validator(_, Some(_)))
+                    | (Part::Senators, Update::Senator(_, Some(_))) | (Part::Rollups, Update::Rollup(_, Some(_)))
+                    | (Part::Receipts, Update::Receipt(_, Some(_))) => true,
+                _ => false
+            };
+            if !fits || self.after.as_ref().is_some_and(|after| k <= *after) {
+                return Err(part);
+            }
+            self.after = Some(k);
+        }
+        for up in ups.iter() {
+            self.state.write(up.clone()).map_err(|_| part)?;
+        }
+        if ups.len() == count {
+            return Ok(());
+        }
+        let (root, expected) = match part {
+            Part::Shard(i) => (self.state.accounts.shard(i as usize).commit(), self.shards[i as usize]),
+            Part::Slots => (self.state.slots.commit(), self.roots[1]),
+            Part::Validators => (self.state.validators.commit(), self.roots[2]),
+            Part::Senators => (self.state.senators.commit(), self.roots[3]),
+            Part::Rollups => (self.state.rollups.commit(), self.roots[4]),
+            Part::Receipts => (self.state.receipts.commit(), self.roots[5])
+        };
+        if root != expected {
+            return Err(part);
+        }
+        self.parts.remove(0);
+        self.after = None;
+        Ok(())
+    }
+
+    // The state, once every part is in and checked.
+    pub fn finish(self) -> Option<State> {
+        self.parts.is_empty().then_some(self.state)
+    }
+}
+
+// Nonce a new account starts at. Accounts are only deleted while their nonce isn't past it,
+// and it never goes down, so one opened again starts past every nonce it used before.
+fn first_nonce(round: u32) -> u32 {
+    round.saturating_sub(1)
+}
+
+// Whether the account on the other shard is there is only known once it's delivered,
+// so a payment across shards has to be big enough to open one.
+fn check_receipt(amount: u32) -> Result<(), txn::Error> {
+    if amount < DUST_BALANCE {
+        return Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: amount });
+    }
+    Ok(())
+}
+
+fn receipt(from_addy: account::Id, nonce: u32, to: account::Id, amount: u32, unlock_round: u32) -> Update {
+    let mut hasher = Sha256::new();
+    hasher.update(from_addy);
+    hasher.update(nonce.to_be_bytes());
+    let id: account::ReceiptId = hasher.finalize().into();
+    Update::Receipt(id, Some(account::Receipt { id, to, amount, unlock_round }))
+}
+
+// The trie roots in Part order after the account shards, then the clock's hash.
+pub type Roots = [[u8; 32]; 7];
+
+pub fn commit_roots(roots: &Roots) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for root in roots {
+        hasher.update(root);
+    }
+    hasher.finalize().into()
+}
+
+impl Update {
+    pub fn key(&self) -> (u8, Vec<u8>) {
+        match self {
+            Update::Account(k, _) => (0, k.to_vec()),
+            Update::Slot(k, _) => (1, k.to_vec()),
+            Update::Validator(k, _) => (2, k.to_vec()),
+            Update::Senator(k, _) => (3, k.to_vec()),
+            Update::Rollup(k, _) => (4, k.to_vec()),
+            Update::Receipt(k, _) => (5, k.to_vec())
+        }
+    }
+}
+
+// Lets a light client check one update against a state commit without the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proof {
+    pub roots: Roots,
+    pub shards: Vec<[u8; 32]>, // account shard commits, hashed into roots[0]
+    pub path: merkle::Proof
+}
+
+impl Proof {
+    pub fn verify(&self, commit: [u8; 32], up: &Update) -> bool {
+        if commit_roots(&self.roots) != commit {
+            return false;
+        }
+        match up {
+            Update::Account(k, v) => {
+                commit_shards(&self.shards) == self.roots[0]
+                    && !self.shards.is_empty()
+                    && self.path.verify(self.shards[shard_of(k, self.shards.len())], k, v.as_ref())
+            },
+            Update::Slot(k, v) => self.path.verify(self.roots[1], k, v.as_ref()),
+            Update::Validator(k, v) => self.path.verify(self.roots[2], k, v.as_ref()),
+            Update::Senator(k, v) => self.path.verify(self.roots[3], k, v.as_ref()),
+            Update::Rollup(k, v) => self.path.verify(self.roots[4], k, v.as_ref()),
+            Update::Receipt(k, v) => self.path.verify(self.roots[5], k, v.as_ref())
+        }
     }
 }
 
@@ -277,26 +1043,26 @@ pub fn timestamp() -> u64 {
 
 #[cfg(test)]
 pub mod tests {
-    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
 
     use super::*;
 
     #[test]
     fn payments() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
         let old = builder.state.clone();
         let bob = account::Keypair::gen();
         let charlie = account::Keypair::gen();
         assert!(
             builder.add(
-                alice.send(bob.kp.public, 1 << 15, JENNY_SLOTS, None)
+                alice.send(bob.kp.public, 1 << 15, GENESIS_SLOTS, None)
             )
             .is_ok()
         );
         assert!(
             builder.add(
-                alice.send(charlie.kp.public, 1 << 5, JENNY_SLOTS + 1, None)
+                alice.send(charlie.kp.public, 1 << 5, GENESIS_SLOTS + 1, None)
             )
             .is_ok()
         );
@@ -306,24 +1072,498 @@ pub mod tests {
             )
             .is_ok()
         );
-        assert!(
+        assert_eq!(
             builder.add(
                 charlie.send(bob.kp.public, (1 << 5) + (1 << 1), 0, None)
             )
+            .map_err(|e| e.1),
+            Err(txn::Error::CantClose { nonce: 1, from_round: 2 })
+        );
+        assert!(
+            builder.add(
+                charlie.send(bob.kp.public, (1 << 5) + (1 << 1) - DUST_BALANCE, 0, None)
+            )
             .is_ok()
         );
         assert!(
             builder.add(
-                alice.send(bob.kp.public, 1 << 8, JENNY_SLOTS + 2, None)
+                alice.send(bob.kp.public, 1 << 8, GENESIS_SLOTS + 2, None)
             )
             .is_ok()
         );
         let old_accs = old.accounts.iter().collect::<Vec<&account::Data>>();
-        assert!(old_accs.contains(&&account::Data { bal: (VALIDATOR_SLOTS * VALIDATOR_STAKE) >> 1, nonce: VALIDATOR_SLOTS >> 1 })); // alice
-        let new_accs = builder.state.accounts.iter().collect::<Vec<&account::Data>>();
-        assert!(new_accs.contains(&&account::Data { bal: ((VALIDATOR_SLOTS * VALIDATOR_STAKE) >> 1) - (1 << 15) - (1 << 5) - (1 << 8), nonce: 3 + (VALIDATOR_SLOTS >> 1) })); // alice
-        assert!(new_accs.contains(&&account::Data { bal: (1 << 15) + (1 << 5) + (1 << 8), nonce: 1 })); // bob
-        assert!(new_accs.contains(&&account::Data { bal: 0, nonce: 1 })); // charlie
+        assert!(old_accs.contains(&&account::Data { bal: (VALIDATOR_SLOTS * VALIDATOR_STAKE) >> 1, nonce: VALIDATOR_SLOTS >> 1, ..Default::default() })); // alice
+        let new_state = builder.current_state();
+        let new_accs = new_state.accounts.iter().collect::<Vec<&account::Data>>();
+        assert!(new_accs.contains(&&account::Data { bal: ((VALIDATOR_SLOTS * VALIDATOR_STAKE) >> 1) - (1 << 15) - (1 << 5) - (1 << 8), nonce: 3 + (VALIDATOR_SLOTS >> 1), ..Default::default() })); // alice
+        assert!(new_accs.contains(&&account::Data { bal: (1 << 15) + (1 << 5) + (1 << 8) - DUST_BALANCE, nonce: 1, ..Default::default() })); // bob
+        assert!(new_accs.contains(&&account::Data { bal: DUST_BALANCE, nonce: 1, ..Default::default() })); // charlie
+    }
+
+    #[test]
+    fn overlay() {
+        let (alice, snap) = block::genesis();
+        let meta = block::Metadata::new(&alice, 1, &snap);
+        let bob = account::Keypair::gen();
+        let mut direct = snap.state.clone();
+        let mut overlay = Overlay::default();
+        for i in 0..4 {
+            let stxn = alice.send(bob.kp.public, DUST_BALANCE << i, GENESIS_SLOTS + i, None);
+            assert!(direct.apply(&stxn, &meta).is_ok());
+            assert!(overlay.apply(&snap.state, &stxn, &meta).is_ok());
+        }
+        // Base untouched until flushed.
+        assert_eq!(overlay.account(&snap.state, &Sha256::digest(bob.kp.public.to_bytes()).into()).unwrap().unwrap().bal, DUST_BALANCE * 15);
+        assert_eq!(snap.state.accounts.get(&Sha256::digest(bob.kp.public.to_bytes())), Ok(None));
+        let mut flushed = snap.state.clone();
+        assert!(overlay.flush(&mut flushed).is_ok());
+        assert_eq!(flushed.commit(), direct.commit());
+    }
+
+    #[test]
+    fn diff() {
+        let (alice, snap) = block::genesis();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let bob = account::Keypair::gen();
+        for i in 0..3 {
+            assert!(builder.add(alice.send(bob.kp.public, 100, GENESIS_SLOTS + i, None)).is_ok());
+        }
+        let next = builder.finalize(&alice);
+        // Alice and bob each touched once despite three writes.
+        assert_eq!(next.updates.len(), 2);
+        let commit = next.block.sheader.msg.commits.state;
+        for up in next.updates.iter() {
+            let proof = next.state.prove(up).unwrap();
+            assert!(proof.verify(commit, up));
+            assert!(!proof.verify(snap.block.sheader.msg.commits.state, up));
+        }
+        // Stale values don't verify.
+        let bob_addy = Sha256::digest(bob.kp.public.to_bytes()).into();
+        let stale = Update::Account(bob_addy, snap.state.accounts.get(&bob_addy).unwrap().cloned());
+        assert!(!next.state.prove(&stale).unwrap().verify(commit, &stale));
+    }
+
+    #[test]
+    fn freeze() {
+        let (alice, mut snap) = block::genesis();
+        let senators: Vec<account::Keypair> = (0..3).map(|_| account::Keypair::gen()).collect();
+        for senator in senators.iter() {
+            let addy = Sha256::digest(senator.kp.public.to_bytes());
+            let data = senator::Data { votes_against: 0, owner: addy.into(), weighting: false };
+            assert!(snap.state.senators.insert(&addy, data).is_ok());
+            let acc = account::Data { bal: 100, ..Default::default() };
+            assert!(snap.state.accounts.insert(&addy, acc).is_ok());
+        }
+        let bob = account::Keypair::gen();
+        let bob_addy: account::Id = Sha256::digest(bob.kp.public.to_bytes()).into();
+        // Votes go ahead of payments in a block, so fund bob in one first.
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.send(bob.kp.public, 100, GENESIS_SLOTS, None)).is_ok());
+        let snap = builder.finalize(&alice);
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert_eq!(
+            builder.add(alice.freeze(bob_addy, GENESIS_SLOTS + 1)).map_err(|(_, e)| e), 
+            Err(txn::Error::NotSenator(Sha256::digest(alice.kp.public.to_bytes()).into()))
+        );
+        assert_eq!(
+            builder.add(senators[0].unfreeze(bob_addy, 0)).map_err(|(_, e)| e), 
+            Err(txn::Error::NotFrozen(bob_addy))
+        );
+        // One vote of three isn't a majority.
+        assert!(builder.add(senators[0].freeze(bob_addy, 0)).is_ok());
+        assert!(!builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().frozen);
+        assert!(builder.add(senators[1].freeze(bob_addy, 0)).is_ok());
+        assert_eq!(
+            builder.add(bob.send(alice.kp.public, 20, 0, None)).map_err(|(_, e)| e), 
+            Err(txn::Error::Frozen(bob_addy))
+        );
+        assert_eq!(
+            builder.add(senators[2].freeze(bob_addy, 0)).map_err(|(_, e)| e), 
+            Err(txn::Error::Frozen(bob_addy))
+        );
+        // Thawing needs a fresh majority.
+        assert!(builder.add(senators[2].unfreeze(bob_addy, 0)).is_ok());
+        assert!(builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().frozen);
+        assert!(builder.add(senators[0].unfreeze(bob_addy, 1)).is_ok());
+        assert!(builder.add(bob.send(alice.kp.public, 20, 0, None)).is_ok());
+        // A senator can change their mind before there's a majority.
+        assert!(builder.add(senators[1].freeze(bob_addy, 1)).is_ok());
+        assert!(builder.add(senators[1].unfreeze(bob_addy, 2)).is_ok());
+        assert!(builder.add(senators[2].freeze(bob_addy, 1)).is_ok());
+        let bob_acc = builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().clone();
+        assert!(!bob_acc.frozen);
+        assert_eq!(bob_acc.votes, BTreeSet::from([
+            (Sha256::digest(senators[1].kp.public.to_bytes()).into(), false),
+            (Sha256::digest(senators[2].kp.public.to_bytes()).into(), true)
+        ]));
+        assert!(builder.add(senators[0].freeze(bob_addy, 2)).is_ok());
+        assert!(builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().frozen);
+    }
+
+    #[test]
+    fn freeze_removed() {
+        let (alice, mut snap) = block::genesis();
+        let addy = |kp: &account::Keypair| -> account::Id { Sha256::digest(kp.kp.public.to_bytes()).into() };
+        let senators: Vec<account::Keypair> = (0..3).map(|_| account::Keypair::gen()).collect();
+        for senator in senators.iter() {
+            let data = senator::Data { votes_against: 0, owner: addy(senator), weighting: false };
+            assert!(snap.state.senators.insert(&addy(senator), data).is_ok());
+            let acc = account::Data { bal: 100, ..Default::default() };
+            assert!(snap.state.accounts.insert(&addy(senator), acc).is_ok());
+        }
+        let bob = account::Keypair::gen();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.stake_slot(GENESIS_SLOTS, GENESIS_SLOTS)).is_ok());
+        assert!(builder.add(alice.send(bob.kp.public, 100, GENESIS_SLOTS + 1, None)).is_ok());
+        let snap = builder.finalize(&alice);
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(senators[0].freeze(addy(&bob), 0)).is_ok());
+        // Alice holds a majority of slots now, so her opposing is enough to vote senators[0] out.
+        assert!(builder.add(alice.oppose(addy(&senators[0]), GENESIS_SLOTS + 2)).is_ok());
+        assert_eq!(builder.current_state().senators.get(&addy(&senators[0])), Ok(None));
+        // Its vote is still on the account, but it takes both senators left to freeze.
+        assert!(builder.add(senators[1].freeze(addy(&bob), 0)).is_ok());
+        assert!(!builder.current_state().accounts.get(&addy(&bob)).unwrap().unwrap().frozen);
+        assert!(builder.add(senators[2].freeze(addy(&bob), 0)).is_ok());
+        assert!(builder.current_state().accounts.get(&addy(&bob)).unwrap().unwrap().frozen);
+    }
+
+    #[test]
+    fn vesting() {
+        let (alice, snap) = block::genesis();
+        let bob = account::Keypair::gen();
+        let charlie = account::Keypair::gen();
+        let bob_addy: account::Id = Sha256::digest(bob.kp.public.to_bytes()).into();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        // Fully locked from round 1, free at round 5.
+        assert!(builder.add(alice.vest(bob.kp.public, 400, 5, GENESIS_SLOTS)).is_ok());
+        assert_eq!(
+            builder.add(bob.send(charlie.kp.public, 100, 0, None)).map_err(|(_, e)| e),
+            Err(txn::Error::InsuffBal { required: 100, available: 0 })
+        );
+        let bob_acc = builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().clone();
+        assert_eq!(bob_acc.bal, 400);
+        assert_eq!(bob_acc.spendable(1), 0);
+        assert_eq!(bob_acc.spendable(2), 100);
+        assert_eq!(bob_acc.spendable(4), 300);
+        assert_eq!(bob_acc.spendable(5), 400);
+        // Round 3: 200 unlocked.
+        let snap = builder.finalize(&alice);
+        let snap = block::Builder::new(&alice, 1, &snap).finalize(&alice);
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert_eq!(builder.metadata.round, 3);
+        assert!(builder.add(bob.send(charlie.kp.public, 150, 0, None)).is_ok());
+        assert_eq!(
+            builder.add(bob.send(charlie.kp.public, 100, 1, None)).map_err(|(_, e)| e),
+            Err(txn::Error::InsuffBal { required: 100, available: 50 })
+        );
+        // Topping up is a tranche of its own, so a far off unlock doesn't hold back what's here
+        assert!(builder.add(alice.vest(bob.kp.public, 100, 1_000, GENESIS_SLOTS + 1)).is_ok());
+        let bob_acc = builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().clone();
+        assert_eq!(bob_acc.vesting, Vec::from([
+            account::Vesting { amount: 400, start_round: 1, unlock_round: 5 },
+            account::Vesting { amount: 100, start_round: 3, unlock_round: 1_000 }
+        ]));
+        assert_eq!(bob_acc.spendable(3), 50);
+        assert_eq!(bob_acc.locked(5), bob_acc.vesting[1].locked(5));
+        // Once the tranches are taken, a lock only goes in if it can join one unlocking later
+        for i in 0..account::MAX_TRANCHES as u32 - 2 {
+            assert!(builder.add(alice.vest(bob.kp.public, 1, 10 + i, GENESIS_SLOTS + 2 + i)).is_ok());
+        }
+        let nonce = GENESIS_SLOTS + account::MAX_TRANCHES as u32;
+        assert_eq!(
+            builder.add(alice.vest(bob.kp.public, 1, 2_000, nonce)).map_err(|(_, e)| e),
+            Err(txn::Error::TooManyLocks(bob_addy))
+        );
+        assert!(builder.add(alice.vest(bob.kp.public, 10, 500, nonce)).is_ok());
+        let bob_acc = builder.current_state().accounts.get(&bob_addy).unwrap().unwrap().clone();
+        assert_eq!(bob_acc.vesting.len(), account::MAX_TRANCHES);
+        assert_eq!(bob_acc.vesting[1], account::Vesting { amount: 110, start_round: 3, unlock_round: 1_000 });
+    }
+
+    #[test]
+    fn shards() {
+        let (alice, mut snap) = block::genesis();
+        let addy = |kp: &account::Keypair| -> account::Id { Sha256::digest(kp.kp.public.to_bytes()).into() };
+        let alice_acc = snap.state.accounts.get(&addy(&alice)).unwrap().unwrap().clone();
+        snap.state.accounts = Shards::new(4);
+        assert!(snap.state.accounts.insert(&addy(&alice), alice_acc).is_ok());
+        let alice_shard = snap.state.accounts.shard_of(&addy(&alice));
+        let gen_in = |same: bool| loop {
+            let kp = account::Keypair::gen();
+            if (snap.state.accounts.shard_of(&addy(&kp)) == alice_shard) == same {
+                break kp;
+            }
+        };
+        let (bob, charlie) = (gen_in(false), gen_in(true));
+        let bob_shard = snap.state.accounts.shard_of(&addy(&bob));
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.send(bob.kp.public, 100, GENESIS_SLOTS, None)).is_ok());
+        assert!(builder.add(alice.send(charlie.kp.public, 100, GENESIS_SLOTS + 1, None)).is_ok());
+        // Same shard lands now, across shards waits for a receipt.
+        let state = builder.current_state();
+        assert_eq!(state.accounts.get(&addy(&charlie)).unwrap().unwrap().bal, 100);
+        assert_eq!(state.accounts.get(&addy(&bob)), Ok(None));
+        assert_eq!(state.receipts.iter().count(), 1);
+        let next = builder.finalize(&alice);
+        assert!(block::Verifier::new(&snap, next.block.clone()).finalize().is_ok());
+        let last = block::Builder::new(&alice, 1, &next).finalize(&alice);
+        assert_eq!(last.state.accounts.get(&addy(&bob)).unwrap().unwrap().bal, 100);
+        assert_eq!(last.state.receipts.iter().count(), 0);
+        let verifier = block::Verifier::new(&next, last.block.clone());
+        assert_eq!(verifier.verify_shards(&[bob_shard]), Ok(()));
+        assert!(verifier.finalize().is_ok());
+        // Every update still proves against the header, shard and all.
+        for up in last.updates.iter() {
+            assert!(last.state.prove(up).unwrap().verify(last.block.sheader.msg.commits.state, up));
+        }
+    }
+
+    #[test]
+    fn vesting_receipts() {
+        let (alice, mut snap) = block::genesis();
+        let addy = |kp: &account::Keypair| -> account::Id { Sha256::digest(kp.kp.public.to_bytes()).into() };
+        let alice_acc = snap.state.accounts.get(&addy(&alice)).unwrap().unwrap().clone();
+        snap.state.accounts = Shards::new(4);
+        assert!(snap.state.accounts.insert(&addy(&alice), alice_acc).is_ok());
+        let bob = loop {
+            let kp = account::Keypair::gen();
+            if snap.state.accounts.shard_of(&addy(&kp)) != snap.state.accounts.shard_of(&addy(&alice)) {
+                break kp;
+            }
+        };
+        // Each lock is on its way in a receipt, but still counts against Bob's tranches.
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        for i in 0..account::MAX_TRANCHES as u32 {
+            assert!(builder.add(alice.vest(bob.kp.public, DUST_BALANCE, 10 + i, GENESIS_SLOTS + i)).is_ok());
+        }
+        let nonce = GENESIS_SLOTS + account::MAX_TRANCHES as u32;
+        assert_eq!(
+            builder.add(alice.vest(bob.kp.public, DUST_BALANCE, 100, nonce)).map_err(|(_, e)| e),
+            Err(txn::Error::TooManyLocks(addy(&bob)))
+        );
+        let next = builder.finalize(&alice);
+        assert_eq!(next.state.receipts.iter().count(), account::MAX_TRANCHES);
+        let last = block::Builder::new(&alice, 1, &next).finalize(&alice);
+        let bob_acc = last.state.accounts.get(&addy(&bob)).unwrap().unwrap().clone();
+        assert_eq!(bob_acc.vesting.len(), account::MAX_TRANCHES);
+    }
+
+    #[test]
+    fn simulate() {
+        let (alice, snap) = block::genesis();
+        let meta = block::Metadata::new(&alice, 1, &snap);
+        let bob = account::Keypair::gen();
+        let alice_addy: account::Id = Sha256::digest(alice.kp.public.to_bytes()).into();
+        let bob_addy: account::Id = Sha256::digest(bob.kp.public.to_bytes()).into();
+        let alice_bal = snap.state.accounts.get(&alice_addy).unwrap().unwrap().bal;
+        let sim = snap.state.simulate(&alice.send(bob.kp.public, 100, GENESIS_SLOTS, None), &meta).unwrap();
+        assert!(sim.balances.contains(&(alice_addy, alice_bal, alice_bal - 100)));
+        assert!(sim.balances.contains(&(bob_addy, 0, 100)));
+        assert_eq!(snap.state.accounts.get(&bob_addy), Ok(None));
+        assert_eq!(
+            snap.state.simulate(&bob.send(alice.kp.public, 1, 0, None), &meta), 
+            Err(txn::Error::BadFromPk(Sha256::digest(bob.kp.public.to_bytes()).into()))
+        );
+    }
+
+    #[test]
+    fn stakein() {
+        let (alice, snap) = block::genesis();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        // Genesis fills the bottom half.
+        assert_eq!(validator::first_free(&snap.state.slots, 0..VALIDATOR_SLOTS), Some(GENESIS_SLOTS));
+        assert_eq!(alice.stake(&snap.state.slots, 0..GENESIS_SLOTS, GENESIS_SLOTS), None);
+        assert_eq!(
+            builder.add(alice.stake_any(0..GENESIS_SLOTS, GENESIS_SLOTS)).map_err(|(_, e)| e),
+            Err(txn::Error::NoFreeSlot(0, GENESIS_SLOTS))
+        );
+        assert!(builder.add(alice.stake_any(GENESIS_SLOTS + 4..VALIDATOR_SLOTS, GENESIS_SLOTS)).is_ok());
+        assert!(builder.add(alice.stake_any(GENESIS_SLOTS + 4..VALIDATOR_SLOTS, GENESIS_SLOTS + 1)).is_ok());
+        let state = builder.current_state();
+        // Which slot each got, by the nonce it was sent with.
+        let alice_id: validator::Id = Sha256::digest(alice.kp.public.to_bytes()).into();
+        assert_eq!(validator::claimed(&state.slots, &alice_id, GENESIS_SLOTS), Some(GENESIS_SLOTS + 4));
+        assert_eq!(validator::claimed(&state.slots, &alice_id, GENESIS_SLOTS + 1), Some(GENESIS_SLOTS + 5));
+        assert_eq!(validator::claimed(&state.slots, &alice_id, GENESIS_SLOTS + 2), None);
+        assert_eq!(validator::first_free(&state.slots, GENESIS_SLOTS + 4..VALIDATOR_SLOTS), Some(GENESIS_SLOTS + 6));
+        let (idx, unstake) = alice.unstake(&state.slots, GENESIS_SLOTS + 2).unwrap();
+        assert_eq!(idx, 0);
+        assert!(builder.add(unstake).is_ok());
+        assert_eq!(validator::first_free(&builder.current_state().slots, 0..VALIDATOR_SLOTS), Some(0));
+    }
+
+    #[test]
+    fn toobig() {
+        let (alice, snap) = block::genesis();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let rollup_txn = txn::Txn {
+            payload: txn::Payload::Payment([255u8; 32], u32::MAX),
+            opt_rollup: None,
+            nonce: u32::MAX,
+            fee: 0
+        };
+        let header = |len: usize| {
+            let msg = txn::Txn {
+                payload: txn::Payload::Header([0u8; 32], vec![rollup_txn.clone(); len], [0u8; 32]),
+                opt_rollup: None,
+                nonce: GENESIS_SLOTS,
+                fee: 0
+            };
+            account::Signed::<txn::Txn> { sig: alice.sign(&msg), msg, from: alice.kp.public }
+        };
+        assert_eq!(
+            builder.add(header(txn::MAX_HEADER_TXNS + 1)).map_err(|e| e.1),
+            Err(txn::Error::LongHeader { limit: txn::MAX_HEADER_TXNS, actual: txn::MAX_HEADER_TXNS + 1 })
+        );
+        assert!(matches!(
+            builder.add(header(txn::MAX_HEADER_TXNS)).map_err(|e| e.1),
+            Err(txn::Error::TooBig { limit: txn::MAX_TXN_SIZE, .. })
+        ));
+        assert!(header(1).msg.check_size().is_ok());
+    }
+
+    #[test]
+    fn attester() {
+        let (alice, snap) = block::genesis();
+        let bob = account::Keypair::gen();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.send(bob.kp.public, VALIDATOR_STAKE << 1, GENESIS_SLOTS, None)).is_ok());
+        let snap = builder.finalize(&alice);
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let bob_id: validator::Id = Sha256::digest(bob.kp.public.to_bytes()).into();
+        assert_eq!(builder.add(bob.attester(0)).map_err(|e| e.1), Err(txn::Error::NotValidator(bob_id)));
+        assert!(builder.add(bob.stake_slot(GENESIS_SLOTS, 0)).is_ok());
+        // Someone else's key fails the proof of possession.
+        let msg = txn::Txn {
+            payload: txn::Payload::Attester(attest::public(&alice), attest::pop(&bob)),
+            opt_rollup: None,
+            nonce: 1,
+            fee: 0
+        };
+        let stolen = account::Signed::<txn::Txn> { sig: bob.sign(&msg), msg, from: bob.kp.public };
+        assert_eq!(builder.add(stolen).map_err(|e| e.1), Err(txn::Error::BadAttester));
+        assert!(builder.add(bob.attester(1)).is_ok());
+        let state = builder.current_state();
+        assert_eq!(state.validators.get(&bob_id).unwrap().unwrap().bls, Some(attest::public(&bob)));
+    }
+
+    #[test]
+    fn header() {
+        let (alice, mut snap) = block::genesis();
+        let bob = account::Keypair::gen();
+        let bob_id: senator::Id = Sha256::digest(bob.kp.public.to_bytes()).into();
+        let id = [7; 32];
+        let data = rollup::Data {
+            state_hash: [0; 32],
+            senators: Vec::default(),
+            sequencer: senator::Verifier { id: bob_id, at_round: 0 },
+            bal: 0
+        };
+        assert!(snap.state.rollups.insert(&id, data).is_ok());
+        let header = |kp: &account::Keypair, rollup: rollup::Id, nonce: u32| kp.sign_txn(txn::Txn {
+            payload: txn::Payload::Header(rollup, Vec::default(), [1; 32]),
+            opt_rollup: None,
+            nonce,
+            fee: 0
+        });
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.send(bob.kp.public, 100, GENESIS_SLOTS, None)).is_ok());
+        // Only the sequencer posts headers, and only for rollups on chain.
+        let alice_id = Sha256::digest(alice.kp.public.to_bytes()).into();
+        assert_eq!(builder.add(header(&alice, id, GENESIS_SLOTS + 1)).map_err(|e| e.1), Err(txn::Error::NotSenator(alice_id)));
+        assert_eq!(builder.add(header(&bob, [8; 32], 0)).map_err(|e| e.1), Err(txn::Error::NoRollup));
+        assert!(builder.add(header(&bob, id, 0)).is_ok());
+        let rollup = builder.current_state().rollups.get(&id).unwrap().unwrap().clone();
+        assert_eq!((rollup.state_hash, rollup.sequencer.at_round), ([1; 32], 1));
+    }
+
+    #[test]
+    fn oppose() {
+        let (alice, mut snap) = block::genesis();
+        let senator: senator::Id = [9; 32];
+        let data = senator::Data { votes_against: 0, owner: [0; 32], weighting: false };
+        assert!(snap.state.senators.insert(&senator, data).is_ok());
+        let bob = account::Keypair::gen();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert!(builder.add(alice.send(bob.kp.public, VALIDATOR_STAKE, GENESIS_SLOTS, None)).is_ok());
+        let snap = builder.finalize(&alice);
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let alice_id: validator::Id = Sha256::digest(alice.kp.public.to_bytes()).into();
+        assert!(builder.add(alice.oppose(senator, GENESIS_SLOTS + 1)).is_ok());
+        assert_eq!(
+            builder.add(alice.oppose(senator, GENESIS_SLOTS + 2)).map_err(|e| e.1),
+            Err(txn::Error::AlreadyOpposed(senator))
+        );
+        // Half the slots against isn't a majority.
+        let state = builder.current_state();
+        assert_eq!(state.senators.get(&senator).unwrap().unwrap().votes_against, GENESIS_SLOTS);
+        assert!(builder.add(bob.stake_slot(GENESIS_SLOTS, 0)).is_ok());
+        assert!(builder.add(bob.oppose(senator, 1)).is_ok());
+        assert_eq!(builder.current_state().senators.get(&senator).unwrap(), None);
+        // Supporting takes the vote back and frees the stake, senator or not.
+        assert!(builder.add(alice.support(senator, GENESIS_SLOTS + 2)).is_ok());
+        assert_eq!(
+            builder.add(alice.support(senator, GENESIS_SLOTS + 3)).map_err(|e| e.1),
+            Err(txn::Error::NotOpposed(senator))
+        );
+        assert!(builder.current_state().validators.get(&alice_id).unwrap().unwrap().opposed.is_empty());
+    }
+
+    #[test]
+    fn dust() {
+        let (alice, snap) = block::genesis();
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let bob = account::Keypair::gen();
+        let bob_addy = Sha256::digest(bob.kp.public.to_bytes());
+        // Accounts aren't opened with dust, on this shard or another.
+        assert_eq!(
+            builder.add(alice.send(bob.kp.public, DUST_BALANCE - 1, GENESIS_SLOTS, None)).map_err(|e| e.1),
+            Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: DUST_BALANCE - 1 })
+        );
+        assert_eq!(
+            builder.add(alice.vest(bob.kp.public, DUST_BALANCE - 1, 5, GENESIS_SLOTS)).map_err(|e| e.1),
+            Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: DUST_BALANCE - 1 })
+        );
+        assert_eq!(check_receipt(DUST_BALANCE - 1), Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: DUST_BALANCE - 1 }));
+        assert!(
+            builder.add(alice.send(bob.kp.public, DUST_BALANCE + 2, GENESIS_SLOTS, None)).is_ok()
+        );
+        // Nor left holding it.
+        assert_eq!(
+            builder.add(bob.send(alice.kp.public, 3, 0, None)).map_err(|e| e.1),
+            Err(txn::Error::Dust { minimum: DUST_BALANCE, actual: DUST_BALANCE - 1 })
+        );
+        assert!(
+            builder.add(bob.send(alice.kp.public, 2, 0, None)).is_ok()
+        );
+        // Drained, it goes once a new account would start past its nonce.
+        assert_eq!(
+            builder.add(bob.send(alice.kp.public, DUST_BALANCE, 1, None)).map_err(|e| e.1),
+            Err(txn::Error::CantClose { nonce: 2, from_round: 3 })
+        );
+        let snap = builder.finalize(&alice);
+        let snap = block::Builder::new(&alice, 1, &snap).finalize(&alice);
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        assert_eq!(builder.metadata.round, 3);
+        assert!(
+            builder.add(bob.send(alice.kp.public, DUST_BALANCE, 1, None)).is_ok()
+        );
+        assert_eq!(builder.current_state().accounts.get(&bob_addy), Ok(None));
+        // Paid again, the old txns still can't be replayed
+        assert!(
+            builder.add(alice.send(bob.kp.public, DUST_BALANCE + 2, GENESIS_SLOTS + 1, None)).is_ok()
+        );
+        assert_eq!(
+            builder.add(bob.send(alice.kp.public, 1, 0, None)).map_err(|e| e.1),
+            Err(txn::Error::SmallNonce { expected: 2, actual: 0 })
+        );
+        // Validators are never pruned.
+        let alice_bal = builder.current_state().accounts.get(&Sha256::digest(alice.kp.public.to_bytes())).unwrap().unwrap().bal;
+        assert!(
+            builder.add(alice.send(bob.kp.public, alice_bal, GENESIS_SLOTS + 2, None)).is_ok()
+        );
+        assert!(builder.current_state().accounts.get(&Sha256::digest(alice.kp.public.to_bytes())).unwrap().is_some());
     }
 
     /*
@@ -371,7 +1611,7 @@ pub mod tests {
 
     #[test]
     fn badfrompk() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
         let bob = account::Keypair::gen();
         // BadFromPk
@@ -381,7 +1621,8 @@ pub mod tests {
                     1
                 ),
             nonce: 0,
-            opt_rollup: None
+            opt_rollup: None,
+            fee: 0
         };
         assert_eq!(
             builder.add(account::Signed::<txn::Txn> {
@@ -389,13 +1630,13 @@ pub mod tests {
                 sig: bob.sign(&msg),
                 from: bob.kp.public
             }).map_err(|e| e.1), 
-            Err(txn::Error::BadFromPk)
+            Err(txn::Error::BadFromPk(Sha256::digest(bob.kp.public.to_bytes()).into()))
         );
     }
 
     #[test]
     fn badsig() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
         let bob = account::Keypair::gen();
         // BadSig
@@ -404,8 +1645,9 @@ pub mod tests {
                     Sha256::digest(bob.kp.public.to_bytes()).into(),
                     1
                 ),
-            nonce: JENNY_SLOTS,
-            opt_rollup: None
+            nonce: GENESIS_SLOTS,
+            opt_rollup: None,
+            fee: 0
         };
         assert_eq!(
             builder.add(account::Signed::<txn::Txn> {
@@ -420,16 +1662,18 @@ pub mod tests {
                     Sha256::digest(bob.kp.public.to_bytes()).into(),
                     1
                 ),
-            nonce: JENNY_SLOTS,
-            opt_rollup: None
+            nonce: GENESIS_SLOTS,
+            opt_rollup: None,
+            fee: 0
         };
         let other_msg = txn::Txn {
             payload: txn::Payload::Payment(
                     Sha256::digest(bob.kp.public.to_bytes()).into(),
                     2
                 ),
-            nonce: JENNY_SLOTS,
-            opt_rollup: None
+            nonce: GENESIS_SLOTS,
+            opt_rollup: None,
+            fee: 0
         };
         assert_eq!(
             builder.add(account::Signed::<txn::Txn> {
@@ -443,18 +1687,15 @@ pub mod tests {
 
     #[test]
     fn badstakeidx() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
-        let unstake = alice.unstake(&builder.state.validators, 0);
-        let slot = if let txn::Payload::Unstake(slot) = unstake.msg.payload {
-            slot
-        } else {
-            panic!("unreachable")
-        };
+        let (idx, _) = alice.unstake(&builder.state.slots, GENESIS_SLOTS).unwrap();
+        let slot = idx.to_be_bytes();
         let msg = txn::Txn {
             payload: txn::Payload::Stake(slot),
             opt_rollup: None,
-            nonce: JENNY_SLOTS
+            nonce: GENESIS_SLOTS,
+            fee: 0
         };
         assert_eq!(
             builder.add(account::Signed::<txn::Txn> {
@@ -462,18 +1703,15 @@ pub mod tests {
                 sig: alice.sign(&msg),
                 from: alice.kp.public
             }).map_err(|e| e.1), 
-            Err(txn::Error::BadStakeIdx)
+            Err(txn::Error::BadStakeIdx(slot))
         );
-        let stake = alice.stake(&builder.state.validators, 0);
-        let slot = if let txn::Payload::Stake(slot) = unstake.msg.payload {
-            slot
-        } else {
-            panic!("unreachable")
-        };
+        let (idx, _) = alice.stake(&builder.state.slots, 0..VALIDATOR_SLOTS, GENESIS_SLOTS).unwrap();
+        let slot = idx.to_be_bytes();
         let msg = txn::Txn {
             payload: txn::Payload::Unstake(slot),
             opt_rollup: None,
-            nonce: JENNY_SLOTS
+            nonce: GENESIS_SLOTS,
+            fee: 0
         };
         assert_eq!(
             builder.add(account::Signed::<txn::Txn> {
@@ -481,27 +1719,29 @@ pub mod tests {
                 sig: alice.sign(&msg),
                 from: alice.kp.public
             }).map_err(|e| e.1), 
-            Err(txn::Error::BadStakeIdx)
+            Err(txn::Error::BadStakeIdx(slot))
         );
     }
 
     #[test]
     fn insuffbal() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
         let bob = account::Keypair::gen();
         assert_eq!(
-            builder.add(alice.send(bob.kp.public, JENNY_COINS + 1, JENNY_SLOTS, None)).map_err(|e| e.1), 
-            Err(txn::Error::InsuffBal)
+            builder.add(alice.send(bob.kp.public, GENESIS_COINS + 1, GENESIS_SLOTS, None)).map_err(|e| e.1), 
+            Err(txn::Error::InsuffBal { required: GENESIS_COINS + 1, available: GENESIS_COINS })
         );
     }
 
     #[test]
     fn insuffstake() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
-        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let (alice, mut snap) = block::genesis();
         let bob = account::Keypair::gen();
-        let txn = bob.stake(&builder.state.validators, 0);
+        let funded = account::Data { bal: VALIDATOR_STAKE - 1, ..Default::default() };
+        assert!(snap.state.accounts.insert(&Sha256::digest(bob.kp.public.to_bytes()), funded).is_ok());
+        let mut builder = block::Builder::new(&alice, 1, &snap);
+        let (_, txn) = bob.stake(&builder.state.slots, 0..VALIDATOR_SLOTS, 0).unwrap();
         assert_eq!(
             builder.add(txn).map_err(|e| e.1), 
             Err(txn::Error::InsuffStake)
@@ -510,30 +1750,30 @@ pub mod tests {
 
     #[test]
     fn smallnonce() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
         let bob = account::Keypair::gen();
         assert!(
-            builder.add(alice.send(bob.kp.public, 1, JENNY_SLOTS, None)).is_ok()
+            builder.add(alice.send(bob.kp.public, DUST_BALANCE, GENESIS_SLOTS, None)).is_ok()
         );
         assert_eq!(
-            builder.add(alice.send(bob.kp.public, 1, JENNY_SLOTS, None)).map_err(|e| e.1), 
-            Err(txn::Error::SmallNonce)
+            builder.add(alice.send(bob.kp.public, 1, GENESIS_SLOTS, None)).map_err(|e| e.1), 
+            Err(txn::Error::SmallNonce { expected: GENESIS_SLOTS + 1, actual: GENESIS_SLOTS })
         );
     }
 
     #[test]
     fn bignonce() {
-        let (alice, snap) = <(account::Keypair, block::Snap)>::default();
+        let (alice, snap) = block::genesis();
         let mut builder = block::Builder::new(&alice, 1, &snap);
         let bob = account::Keypair::gen();
         let mut old = builder.clone();
         assert!(
-            builder.add(alice.send(bob.kp.public, 1, JENNY_SLOTS, None)).is_ok()
+            builder.add(alice.send(bob.kp.public, DUST_BALANCE, GENESIS_SLOTS, None)).is_ok()
         );
         assert_eq!(
-            old.add(alice.send(bob.kp.public, 1, JENNY_SLOTS + 1, None)).map_err(|e| e.1), 
-            Err(txn::Error::BigNonce)
+            old.add(alice.send(bob.kp.public, 1, GENESIS_SLOTS + 1, None)).map_err(|e| e.1), 
+            Err(txn::Error::BigNonce { expected: GENESIS_SLOTS, actual: GENESIS_SLOTS + 1 })
         );
     }
     
@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+
+use crate::{account, block, txn};
+
+// Local disk copy of the fork window so a restarted node carries on from its head
+// instead of starting over from genesis. One file per snap, plus meta.json saying which
+// one is head and pool.json with the txns we were holding at shutdown. Files are written
+// aside and renamed in, so a crash never leaves half of one.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Io,
+    BadMeta,
+    BadPool,
+    Corrupt(PathBuf, block::LoadError),
+    NoHead, // meta names a head we have no snap for
+    WrongChain // saved under a different genesis
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meta {
+    pub genesis: [u8; 32],
+    pub head: (u32, [u8; 32]),
+    pub finalized: (u32, [u8; 32]),
+    pub signed_round: u32, // slashing protection has to survive restarts too
+    #[serde(default)]
+    pub nonce: u32 // next for our own txns, so a restart doesn't reuse one that's still in flight
+}
+
+#[derive(Debug)]
+pub struct Store {
+    pub dir: PathBuf,
+    meta: Mutex<Option<Meta>> // None until something's been saved
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes).map_err(|_| Error::Io)?;
+    fs::rename(&tmp, path).map_err(|_| Error::Io)
+}
+
+impl Store {
+    pub fn open(dir: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(dir.join("snaps")).map_err(|_| Error::Io)?;
+        let meta = match fs::read(dir.join("meta.json")) {
+            Ok(bytes) => Some(serde_json::from_slice(&bytes).map_err(|_| Error::BadMeta)?),
+            Err(_) => None
+        };
+        Ok(Self { dir, meta: Mutex::new(meta) })
+    }
+
+    pub fn meta(&self) -> Option<Meta> {
+        *self.meta.lock().unwrap()
+    }
+
+    pub fn init(&self, meta: Meta) -> Result<(), Error> {
+        *self.meta.lock().unwrap() = Some(meta);
+        write_atomic(&self.dir.join("meta.json"), &serde_json::to_vec(&meta).unwrap())
+    }
+
+    // No-op before `init`.
+    pub fn update(&self, f: impl FnOnce(&mut Meta)) -> Result<(), Error> {
+        let mut opt_meta = self.meta.lock().unwrap();
+        match opt_meta.as_mut() {
+            Some(meta) => {
+                f(meta);
+                write_atomic(&self.dir.join("meta.json"), &serde_json::to_vec(meta).unwrap())
+            },
+            None => Ok(())
+        }
+    }
+
+    pub fn put_pool(&self, txns: &[account::Signed<txn::Txn>]) -> Result<(), Error> {
+        write_atomic(&self.dir.join("pool.json"), &serde_json::to_vec(txns).unwrap())
+    }
+
+    // Empty if there's nothing saved.
+    pub fn pool(&self) -> Result<Vec<account::Signed<txn::Txn>>, Error> {
+        match fs::read(self.dir.join("pool.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|_| Error::BadPool),
+            Err(_) => Ok(Vec::default())
+        }
+    }
+
+    fn snap_path(&self, block_hash: &[u8; 32]) -> PathBuf {
+        self.dir.join("snaps").join(hex(block_hash))
+    }
+
+    pub fn put_snap(&self, snap: &block::Snap) -> Result<(), Error> {
+        write_atomic(&self.snap_path(&snap.block_hash), &snap.store())
+    }
+
+    pub fn remove_snap(&self, block_hash: &[u8; 32]) -> Result<(), Error> {
+        match fs::remove_file(self.snap_path(block_hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::Io),
+            _ => Ok(())
+        }
+    }
+
+    // Every saved snap. Leftovers from an interrupted write are skipped.
+    pub fn snaps(&self) -> Result<Vec<block::Snap>, Error> {
+        let mut snaps = Vec::default();
+        for entry in fs::read_dir(self.dir.join("snaps")).map_err(|_| Error::Io)? {
+            let path = entry.map_err(|_| Error::Io)?.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            let bytes = fs::read(&path).map_err(|_| Error::Io)?;
+            snaps.push(block::Snap::load(&bytes).map_err(|e| Error::Corrupt(path, e))?);
+        }
+        Ok(snaps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reopen() {
+        let (_, gen) = block::genesis();
+        let dir = std::env::temp_dir().join(format!("tam-store-{:x}", u64::from_be_bytes(gen.block_hash[..8].try_into().unwrap())));
+        let store = Store::open(dir.clone()).unwrap();
+        assert_eq!(store.meta(), None);
+        assert_eq!(store.put_snap(&gen), Ok(()));
+        let init = Meta { genesis: gen.block_hash, head: (0, gen.block_hash), finalized: (0, gen.block_hash), signed_round: 0, nonce: 0 };
+        assert_eq!(store.init(init), Ok(()));
+        assert_eq!(store.update(|meta| meta.signed_round = 3), Ok(()));
+        assert_eq!(store.update(|meta| meta.nonce = 7), Ok(()));
+        let store = Store::open(dir.clone()).unwrap();
+        assert_eq!(store.meta(), Some(Meta { signed_round: 3, nonce: 7, ..init }));
+        assert_eq!(store.snaps(), Ok(Vec::from([gen.clone()])));
+        // A flipped byte is caught on load
+        let path = store.snap_path(&gen.block_hash);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(store.snaps(), Err(Error::Corrupt(path, block::LoadError::BadChecksum)));
+        assert_eq!(store.remove_snap(&gen.block_hash), Ok(()));
+        assert_eq!(store.snaps(), Ok(Vec::default()));
+        // Pool
+        assert_eq!(store.pool(), Ok(Vec::default()));
+        let txn = account::Keypair::gen().send_acc([0; 32], 1, 0, None);
+        assert_eq!(store.put_pool(std::slice::from_ref(&txn)), Ok(()));
+        assert_eq!(Store::open(dir.clone()).unwrap().pool(), Ok(Vec::from([txn])));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
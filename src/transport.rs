@@ -0,0 +1,163 @@
+use std::time::Duration;
+use libp2p::{gossipsub, mdns, noise, tcp, yamux, swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent}};
+use futures::StreamExt;
+use sha2::{Sha256, Digest};
+use tokio::sync::mpsc;
+
+pub use libp2p::{Multiaddr, PeerId};
+
+use crate::msg;
+
+// Broadcasts go out over libp2p gossipsub: each node passes a message on to a few peers in its
+// mesh rather than posting it to every neighbor, so a net can grow past a handful of statically
+// configured peers. Peers on the local network turn up over mDNS, others by dialing the addresses
+// we're given. Asking one peer something in particular still goes over http.
+
+pub const TOPIC: &str = "tam";
+const QUEUE: usize = 1024; // messages waiting either way before the other side has to wait
+const IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Transport, // couldn't set up tcp, noise or yamux
+    Behaviour, // gossipsub or mdns refused the config
+    Listen,
+    Dial
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Multiaddr,
+    pub bootstrap: Vec<Multiaddr>, // dialed at startup
+    pub mdns: bool
+}
+
+impl Config {
+    pub fn new(listen: Multiaddr) -> Self {
+        Self { listen, bootstrap: Vec::default(), mdns: true }
+    }
+
+    pub fn with_bootstrap(mut self, bootstrap: Vec<Multiaddr>) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    pub fn without_mdns(mut self) -> Self {
+        self.mdns = false;
+        self
+    }
+}
+
+// A message off the topic, from whoever first published it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub from: String,
+    pub data: Vec<u8>
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>
+}
+
+// Handle on the swarm, which runs on its own task until this and the inbox are both dropped.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    pub peer_id: PeerId,
+    outbox: mpsc::Sender<String>
+}
+
+impl Transport {
+    pub fn spawn(config: Config) -> Result<(Self, mpsc::Receiver<Received>), Error> {
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)
+            .map_err(|_| Error::Transport)?
+            .with_behaviour(|key| {
+                let gossip_config = gossipsub::ConfigBuilder::default()
+                    .max_transmit_size(msg::MAX_CHAIN_BYTES)
+                    // The same message published twice, by us or anyone, only goes round once.
+                    .message_id_fn(|message| gossipsub::MessageId::from(Sha256::digest(&message.data).to_vec()))
+                    .build()?;
+                let gossipsub = gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), gossip_config)?;
+                let mdns = match config.mdns {
+                    true => Some(mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?),
+                    false => None
+                };
+                Ok(Behaviour { gossipsub, mdns: Toggle::from(mdns) })
+            })
+            .map_err(|_| Error::Behaviour)?
+            .with_swarm_config(|swarm_config| swarm_config.with_idle_connection_timeout(IDLE))
+            .build();
+        let topic = gossipsub::IdentTopic::new(TOPIC);
+        swarm.behaviour_mut().gossipsub.subscribe(&topic).map_err(|_| Error::Behaviour)?;
+        swarm.listen_on(config.listen).map_err(|_| Error::Listen)?;
+        for addr in config.bootstrap {
+            swarm.dial(addr).map_err(|_| Error::Dial)?;
+        }
+        let peer_id = *swarm.local_peer_id();
+        let (outbox, mut outgoing) = mpsc::channel::<String>(QUEUE);
+        let (incoming, inbox) = mpsc::channel(QUEUE);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    message = outgoing.recv() => {
+                        let Some(message) = message else { break };
+                        // No one to send to yet, or it's already been round. Gossip either way.
+                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), message.into_bytes()) {
+                            println!("gossip not published: {:?}", e);
+                        }
+                    },
+                    event = swarm.select_next_some() => match event {
+                        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                            for (_, addr) in found {
+                                let _ = swarm.dial(addr);
+                            }
+                        },
+                        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message, .. })) => {
+                            let from = message.source.unwrap_or(propagation_source).to_string();
+                            if incoming.send(Received { from, data: message.data }).await.is_err() {
+                                break;
+                            }
+                        },
+                        _ => ()
+                    }
+                }
+            }
+        });
+        Ok((Self { peer_id, outbox }, inbox))
+    }
+
+    // Waits for room if the swarm's behind. False once it's stopped.
+    pub async fn publish(&self, message: String) -> bool {
+        self.outbox.send(message).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn gossip() {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/3104".parse().unwrap();
+        let (alice, _alice_inbox) = Transport::spawn(Config::new(addr.clone()).without_mdns()).unwrap();
+        let (_bob, mut bob_inbox) = Transport::spawn(
+            Config::new("/ip4/127.0.0.1/tcp/0".parse().unwrap()).with_bootstrap(Vec::from([addr])).without_mdns()
+        ).unwrap();
+        // Keeps trying until bob's connected and subscribed
+        let mut received = None;
+        for i in 0..100 {
+            assert!(alice.publish(format!("{{\"Txn\": {}}}", i)).await);
+            if let Ok(Some(message)) = time::timeout(time::Duration::from_millis(100), bob_inbox.recv()).await {
+                received = Some(message);
+                break;
+            }
+        }
+        let received = received.expect("gossip reaches bob");
+        assert_eq!(received.from, alice.peer_id.to_string());
+        assert_eq!(msg::variant(&received.data), Some("Txn"));
+    }
+}
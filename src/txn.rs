@@ -1,42 +1,142 @@
 use serde::{Serialize, Deserialize};
-use std::{fmt::Debug, collections::BTreeMap};
-use serde_big_array::BigArray;
+use sha2::{Sha256, Digest};
+use std::fmt::Debug;
 
 use crate::{account, merkle, validator, rollup, txn, senator};
 
+// Caps so a single txn can't bloat a block or stall json parsing.
+pub const MAX_TXN_SIZE: usize = 8192; // serialized bytes
+pub const MAX_HEADER_TXNS: usize = 64; // rollup txns in one Header
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Txn {
     pub payload: Payload,
     pub opt_rollup: Option<rollup::Id>,
     pub nonce: u32,
+    pub fee: u32, // burned. Breaks ties between txns with the same nonce
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Payload {
     Payment(account::Id, u32),
+    VestedPayment(account::Id, u32, u32), // to, amount, round it's fully unlocked
     Stake(validator::Slot),
+    StakeIn(u32, u32), // first free slot in [start, end)
     Unstake(validator::Slot),
     Debit(account::Id, Option<rollup::Id>, u32),
     Credit(account::Id, u32),
-    Header(rollup::Id, Vec<txn::Txn>), // TODO add more things
+    Header(rollup::Id, Vec<txn::Txn>, [u8; 32]), // rollup txns and the state hash they leave it at
     Oppose(senator::Id),
-    Support(senator::Id)
+    Support(senator::Id),
+    // Senator votes on an account's frozen flag.
+    Freeze(account::Id),
+    Unfreeze(account::Id),
+    // Senator votes on weighting the leader schedule by performance.
+    Weighting(bool),
+    // Validator registers a BLS key and its proof of possession for attesting.
+    Attester(Vec<u8>, Vec<u8>)
+}
+
+impl Txn {
+    pub fn check_size(&self) -> Result<(), Error> {
+        // Count first so a huge header is turned away before we serialize it.
+        if let Payload::Header(_, ref txns, _) = self.payload {
+            if txns.len() > MAX_HEADER_TXNS {
+                return Err(Error::LongHeader { limit: MAX_HEADER_TXNS, actual: txns.len() });
+            }
+        }
+        let size = serde_json::to_vec(self).map_or(usize::MAX, |ser| ser.len());
+        if size > MAX_TXN_SIZE {
+            return Err(Error::TooBig { limit: MAX_TXN_SIZE, actual: size });
+        }
+        Ok(())
+    }
+}
+
+impl Txn {
+    // Least this takes from the sender's balance: the fee and whatever it pays out.
+    pub fn min_cost(&self) -> u64 {
+        let amount = match self.payload {
+            Payload::Payment(_, amount) | Payload::VestedPayment(_, amount, _) => amount,
+            _ => 0
+        };
+        self.fee as u64 + amount as u64
+    }
+}
+
+impl Payload {
+    // Validator set and senator upkeep. These go at the front of a block whatever the fee.
+    // Evidence has its own section so isn't a txn at all.
+    pub fn is_protocol(&self) -> bool {
+        matches!(self, 
+            Payload::Stake(..) | Payload::StakeIn(..) | Payload::Unstake(..) | Payload::Attester(..) |
+            Payload::Oppose(..) | Payload::Support(..) | Payload::Freeze(..) | Payload::Unfreeze(..) | Payload::Weighting(..)
+        )
+    }
+
+    // Protocol version a payload type arrived in. Payloads added later return
+    // the version that activates them and are rejected in blocks before that.
+    pub fn version(&self) -> u16 {
+        1
+    }
 }
 
-pub type Seq = merkle::Map::<account::Signed::<Txn>>;
+impl account::Signable for Txn {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("txns serialize")
+    }
+}
+
+pub type Seq = merkle::List<account::Signed<Txn>>;
+
+pub type Hash = [u8; 32];
+
+// Canonical txn hash, over the signer and the signed bytes. Leaves out the sig
+// so a malleated or re-signed copy of the same txn hashes the same.
+pub fn hash(stxn: &account::Signed<Txn>) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(stxn.from.to_bytes());
+    hasher.update(account::Signable::signing_bytes(&stxn.msg));
+    hasher.finalize().into()
+}
+
+// Enough to pick a txn out of a pool when relaying compact blocks.
+pub type ShortId = [u8; 8];
+
+pub fn short_id(stxn: &account::Signed<Txn>) -> ShortId {
+    hash(stxn)[..8].try_into().expect("hashes are 32 bytes")
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Error {
-    BadFromPk,
+    BadFromPk(account::Id),
     BadSig,
-    BadStakeIdx,
-    InsuffBal,
+    BadStakeIdx(validator::Slot),
+    InsuffBal { required: u32, available: u32 },
     InsuffStake,
-    SmallNonce,
-    BigNonce,
+    SmallNonce { expected: u32, actual: u32 },
+    BigNonce { expected: u32, actual: u32 },
     FullBlock,
     NoRollup,
-    NotSenator,
+    NotOnRollup, // payload doesn't run on rollups
+    NotSequencer(rollup::Id), // we don't propose that rollup's headers, so don't pool for it
+    NotSenator(senator::Id),
     NoPreimage,
-    LockedStake
+    LockedStake(validator::Id),
+    NoAccount(account::Id),
+    Frozen(account::Id),
+    NotFrozen(account::Id),
+    CrossShard(account::Id), // other side of the txn
+    NoFreeSlot(u32, u32),
+    TooBig { limit: usize, actual: usize },
+    LongHeader { limit: usize, actual: usize },
+    Inactive(u16), // payload's protocol version isn't active yet
+    NotValidator(validator::Id),
+    BadAttester,
+    Dust { minimum: u32, actual: u32 }, // would open an account with, or leave its sender, less than the minimum
+    CantClose { nonce: u32, from_round: u32 }, // drains an account whose nonce a new one wouldn't start past yet
+    TooManyLocks(account::Id), // every vesting tranche is taken and none unlocks late enough to join
+    Unsupported, // payload isn't run on the base chain
+    AlreadyOpposed(senator::Id),
+    NotOpposed(senator::Id)
 }
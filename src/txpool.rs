@@ -0,0 +1,361 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::{account, txn};
+
+// Txns waiting for a block, capped by count, bytes and per sender.
+// Once full a new txn pushes out the lowest fee ones, oldest first among equal fees, as long
+// as it outbids each of them, so nobody can churn the pool by resending at the same fee.
+// Builders take from the other end: each sender's lowest nonce txn is indexed by fee, so the
+// best one to run next is always a lookup away however big the pool gets.
+
+pub const MAX_TXNS: usize = 1 << 14;
+pub const MAX_BYTES: usize = 1 << 25; // serialized
+pub const MAX_PER_SENDER: usize = 256;
+pub const FEE_BUMP_PERCENT: u64 = 10; // over the fee being pushed out, and always at least 1 more
+
+// Whether a txn paying `fee` may push out one paying `old`.
+pub fn outbids(fee: u32, old: u32) -> bool {
+    fee as u64 >= old as u64 + (old as u64 * FEE_BUMP_PERCENT / 100).max(1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    pub txns: usize,
+    pub bytes: usize,
+    pub per_sender: usize
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { txns: MAX_TXNS, bytes: MAX_BYTES, per_sender: MAX_PER_SENDER }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metrics {
+    pub evicted: u64, // pushed out to make room
+    pub evicted_bytes: u64,
+    pub full: u64, // turned away, it doesn't outbid enough of the pool
+    pub over_quota: u64, // turned away, sender already has its quota in
+    pub stale: u64 // dropped once a new head left them unable to run
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Error {
+    Full,
+    OverQuota,
+    Underpriced // same sender and nonce as one we have, without outbidding it
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    txn: account::Signed<txn::Txn>,
+    hash: txn::Hash,
+    arrival: u64,
+    size: usize
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pool {
+    pub limits: Limits,
+    pub metrics: Metrics,
+    queues: BTreeMap<account::Id, BTreeMap<u32, Entry>>, // by sender then nonce
+    by_fee: BTreeMap<(u32, u64), (account::Id, u32)>, // eviction order
+    heads: BTreeSet<(u32, Reverse<u64>, account::Id)>, // each sender's lowest nonce, best paying last
+    parked: BTreeSet<account::Id>, // left out of heads until unparked
+    len: usize,
+    bytes: usize,
+    arrivals: u64
+}
+
+fn sender(txn: &account::Signed<txn::Txn>) -> account::Id {
+    Sha256::digest(txn.from.to_bytes()).into()
+}
+
+fn size(txn: &account::Signed<txn::Txn>) -> usize {
+    serde_json::to_vec(txn).map_or(usize::MAX, |ser| ser.len())
+}
+
+impl Pool {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, ..Default::default() }
+    }
+
+    fn entry(&self, from: &account::Id, nonce: u32) -> Option<&Entry> {
+        self.queues.get(from)?.get(&nonce)
+    }
+
+    fn head(&self, from: &account::Id) -> Option<(u32, Reverse<u64>, account::Id)> {
+        let (_, entry) = self.queues.get(from)?.first_key_value()?;
+        Some((entry.txn.msg.fee, Reverse(entry.arrival), *from))
+    }
+
+    // Around anything that changes a sender's queue, to keep heads in step.
+    fn unindex(&mut self, from: &account::Id) {
+        if let Some(head) = self.head(from) {
+            self.heads.remove(&head);
+        }
+    }
+
+    fn index(&mut self, from: &account::Id) {
+        if let (false, Some(head)) = (self.parked.contains(from), self.head(from)) {
+            self.heads.insert(head);
+        }
+    }
+
+    // Returns whatever was pushed out to make room, including a lower fee txn with the same nonce.
+    pub fn insert(&mut self, txn: account::Signed<txn::Txn>) -> Result<Vec<account::Signed<txn::Txn>>, Error> {
+        let from = sender(&txn);
+        let nonce = txn.msg.nonce;
+        let hash = txn::hash(&txn);
+        let mut victims = Vec::default();
+        let mut freed = 0;
+        match self.entry(&from, nonce) {
+            Some(old) if old.hash == hash => return Ok(Vec::default()),
+            Some(old) if !outbids(txn.msg.fee, old.txn.msg.fee) => return Err(Error::Underpriced),
+            Some(old) => {
+                freed += old.size;
+                victims.push((from, nonce));
+            },
+            None => if self.queues.get(&from).map_or(0, BTreeMap::len) >= self.limits.per_sender {
+                self.metrics.over_quota += 1;
+                return Err(Error::OverQuota);
+            }
+        }
+        let (replaced, replaced_bytes) = (victims.len(), freed);
+        let size = size(&txn);
+        if size > self.limits.bytes {
+            self.metrics.full += 1;
+            return Err(Error::Full);
+        }
+        let mut cheapest = self.by_fee.iter().filter(|(_, key)| **key != (from, nonce));
+        while self.len - victims.len() >= self.limits.txns || self.bytes - freed + size > self.limits.bytes {
+            match cheapest.next() {
+                Some(((fee, _), key)) if outbids(txn.msg.fee, *fee) => {
+                    freed += self.entry(&key.0, key.1).expect("indexed").size;
+                    victims.push(*key);
+                },
+                _ => {
+                    self.metrics.full += 1;
+                    return Err(Error::Full);
+                }
+            }
+        }
+        let victims: Vec<_> = victims.into_iter()
+            .filter_map(|(from, nonce)| self.take_entry(&from, nonce))
+            .collect();
+        self.metrics.evicted += (victims.len() - replaced) as u64;
+        self.metrics.evicted_bytes += (freed - replaced_bytes) as u64;
+        self.arrivals += 1;
+        self.by_fee.insert((txn.msg.fee, self.arrivals), (from, nonce));
+        self.unindex(&from);
+        self.queues.entry(from).or_default().insert(nonce, Entry { txn, hash, arrival: self.arrivals, size });
+        self.index(&from);
+        self.len += 1;
+        self.bytes += size;
+        Ok(victims)
+    }
+
+    fn take_entry(&mut self, from: &account::Id, nonce: u32) -> Option<account::Signed<txn::Txn>> {
+        self.entry(from, nonce)?;
+        self.unindex(from);
+        let queue = self.queues.get_mut(from).expect("has the entry");
+        let entry = queue.remove(&nonce).expect("has the entry");
+        if queue.is_empty() {
+            self.queues.remove(from);
+        }
+        self.index(from);
+        self.by_fee.remove(&(entry.txn.msg.fee, entry.arrival));
+        self.len -= 1;
+        self.bytes -= entry.size;
+        Some(entry.txn)
+    }
+
+    pub fn remove(&mut self, txn: &account::Signed<txn::Txn>) -> bool {
+        if !self.contains(txn) {
+            return false;
+        }
+        self.take_entry(&sender(txn), txn.msg.nonce).is_some()
+    }
+
+    pub fn contains(&self, txn: &account::Signed<txn::Txn>) -> bool {
+        self.entry(&sender(txn), txn.msg.nonce).is_some_and(|entry| entry.hash == txn::hash(txn))
+    }
+
+    // By sender, each in nonce order.
+    pub fn iter(&self) -> impl Iterator<Item = &account::Signed<txn::Txn>> {
+        self.queues.values().flat_map(|queue| queue.values().map(|entry| &entry.txn))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self { limits: self.limits, metrics: self.metrics, ..Default::default() };
+    }
+
+    // Drop every txn `keep` says no to. It sees them by sender, each in nonce order.
+    pub fn retain(&mut self, mut keep: impl FnMut(&account::Signed<txn::Txn>) -> bool) -> usize {
+        let dropped: Vec<_> = self.iter()
+            .filter(|txn| !keep(txn))
+            .map(|txn| (sender(txn), txn.msg.nonce))
+            .collect();
+        for (from, nonce) in dropped.iter() {
+            self.take_entry(from, *nonce);
+        }
+        self.metrics.stale += dropped.len() as u64;
+        dropped.len()
+    }
+
+    // The highest fee txn that's lowest nonce for its sender, oldest first among equal fees.
+    // Parked senders are left out.
+    pub fn best(&self) -> Option<(account::Id, &account::Signed<txn::Txn>)> {
+        let (_, _, from) = self.heads.last()?;
+        let (_, entry) = self.queues[from].first_key_value()?;
+        Some((*from, &entry.txn))
+    }
+
+    pub fn pop_best(&mut self) -> Option<account::Signed<txn::Txn>> {
+        let (from, txn) = self.best()?;
+        let nonce = txn.msg.nonce;
+        self.take_entry(&from, nonce)
+    }
+
+    // Leave a sender out of `best`, say while it's waiting on a nonce gap, until `unpark`.
+    pub fn park(&mut self, from: &account::Id) {
+        self.unindex(from);
+        self.parked.insert(*from);
+    }
+
+    pub fn unpark(&mut self) {
+        for from in mem::take(&mut self.parked) {
+            self.index(&from);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(kp: &account::Keypair, nonce: u32, fee: u32) -> account::Signed<txn::Txn> {
+        kp.sign_txn(txn::Txn { payload: txn::Payload::Payment([0; 32], 1), opt_rollup: None, nonce, fee })
+    }
+
+    #[test]
+    fn eviction() {
+        let alice = account::Keypair::gen();
+        let mut pool = Pool::new(Limits { txns: 2, ..Default::default() });
+        let old = payment(&alice, 0, 1);
+        let new = payment(&alice, 1, 1);
+        assert_eq!(pool.insert(old.clone()), Ok(Vec::default()));
+        assert_eq!(pool.insert(new.clone()), Ok(Vec::default()));
+        // Cheaper than everything in there
+        assert_eq!(pool.insert(payment(&alice, 2, 0)), Err(Error::Full));
+        // Or just as cheap
+        assert_eq!(pool.insert(payment(&alice, 2, 1)), Err(Error::Full));
+        // Equal fees go oldest first
+        let rich = payment(&alice, 3, 5);
+        assert_eq!(pool.insert(rich.clone()), Ok(Vec::from([old])));
+        assert_eq!(pool.iter().cloned().collect::<Vec<_>>(), Vec::from([new.clone(), rich.clone()]));
+        assert_eq!(pool.metrics.evicted, 1);
+        assert_eq!(pool.metrics.full, 2);
+        // The bump scales with the fee
+        assert!(!outbids(109, 100));
+        assert!(outbids(110, 100));
+        // Byte cap
+        let mut pool = Pool::new(Limits { bytes: size(&new) + size(&rich) - 1, ..Default::default() });
+        assert_eq!(pool.insert(new.clone()), Ok(Vec::default()));
+        assert_eq!(pool.insert(rich.clone()), Ok(Vec::from([new.clone()])));
+        assert_eq!(pool.bytes(), size(&rich));
+        assert_eq!(pool.metrics.evicted_bytes, size(&new) as u64);
+    }
+
+    #[test]
+    fn duplicates() {
+        let alice = account::Keypair::gen();
+        let mut pool = Pool::default();
+        let txn = payment(&alice, 0, 1);
+        assert_eq!(pool.insert(txn.clone()), Ok(Vec::default()));
+        // Same txn under a different sig is still the one we have
+        let mut twin = txn.clone();
+        twin.sig = payment(&alice, 1, 1).sig;
+        assert_eq!(txn::hash(&twin), txn::hash(&txn));
+        assert!(pool.contains(&twin));
+        assert_eq!(pool.insert(twin), Ok(Vec::default()));
+        assert_eq!(pool.len(), 1);
+        // Different signer, different txn
+        let mut other = txn.clone();
+        other.from = account::Keypair::gen().sign_txn(txn.msg.clone()).from;
+        assert_ne!(txn::hash(&other), txn::hash(&txn));
+    }
+
+    #[test]
+    fn quota() {
+        let alice = account::Keypair::gen();
+        let bob = account::Keypair::gen();
+        let mut pool = Pool::new(Limits { per_sender: 1, ..Default::default() });
+        let first = payment(&alice, 0, 0);
+        assert_eq!(pool.insert(first.clone()), Ok(Vec::default()));
+        assert_eq!(pool.insert(payment(&alice, 1, 10)), Err(Error::OverQuota));
+        assert_eq!(pool.insert(payment(&bob, 0, 0)), Ok(Vec::default()));
+        assert_eq!(pool.metrics.over_quota, 1);
+        // Room frees up once the first is gone
+        assert!(pool.remove(&first));
+        assert_eq!(pool.insert(payment(&alice, 1, 10)), Ok(Vec::default()));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn queues() {
+        let alice = account::Keypair::gen();
+        let bob = account::Keypair::gen();
+        let alice_id = sender(&payment(&alice, 0, 0));
+        let mut pool = Pool::default();
+        let txns: Vec<_> = [0, 1, 2, 4].iter().map(|nonce| payment(&alice, *nonce, 0)).collect();
+        for txn in txns.iter().rev() {
+            assert_eq!(pool.insert(txn.clone()), Ok(Vec::default()));
+        }
+        assert_eq!(pool.insert(payment(&bob, 7, 0)), Ok(Vec::default()));
+        // Same nonce needs a higher fee
+        assert_eq!(pool.insert(alice.send_acc([1; 32], 1, 2, None)), Err(Error::Underpriced));
+        let bumped = payment(&alice, 2, 3);
+        assert_eq!(pool.insert(bumped.clone()), Ok(Vec::from([txns[2].clone()])));
+        let resent = alice.sign_txn(txn::Txn { payload: txn::Payload::Payment([1; 32], 1), opt_rollup: None, nonce: 2, fee: 3 });
+        assert_eq!(pool.insert(resent), Err(Error::Underpriced));
+        assert_eq!(pool.metrics.evicted, 0);
+        // Equal fees go oldest first, and a sender's txns come out in nonce order
+        assert_eq!(pool.best(), Some((alice_id, &txns[0])));
+        assert_eq!(pool.pop_best(), Some(txns[0].clone()));
+        assert_eq!(pool.pop_best(), Some(txns[1].clone()));
+        assert_eq!(pool.best(), Some((alice_id, &bumped)));
+        // A higher fee goes first however late it came
+        let carol = account::Keypair::gen();
+        let rich = payment(&carol, 0, 5);
+        assert_eq!(pool.insert(rich.clone()), Ok(Vec::default()));
+        assert_eq!(pool.pop_best(), Some(rich));
+        assert_eq!(pool.pop_best(), Some(bumped));
+        // Alice's 4 waits on 3, so she sits out
+        assert_eq!(pool.best(), Some((alice_id, &txns[3])));
+        pool.park(&alice_id);
+        assert_eq!(pool.best().map(|(_, txn)| txn.msg.nonce), Some(7));
+        pool.park(&sender(&payment(&bob, 0, 0)));
+        assert_eq!(pool.best(), None);
+        assert_eq!(pool.len(), 2);
+        pool.unpark();
+        assert_eq!(pool.best(), Some((alice_id, &txns[3])));
+    }
+}
@@ -6,10 +6,16 @@ use crate::{account, merkle, state, txn, senator};
 
 pub type Slot = [u8; 4];
 
+// Validators with fewer recent misses than this keep full weight.
+pub const MISSED_THRESHOLD: u32 = 2;
+// Weight bottoms out at 1 / (1 + MAX_MISSED) so a flaky validator can still lead and recover.
+pub const MAX_MISSED: u32 = 8;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SlotData {
     pub round: u32,
-    pub owner: Id
+    pub owner: Id,
+    pub nonce: u32 // of the txn that claimed it, see `claimed`
 }
 
 pub type Id = [u8; 32];
@@ -19,7 +25,11 @@ pub struct Data {
     // Can't unstake with anything in here!
     pub opposed: merkle::Map<()>,
     pub slots: u32,
-    pub pk: account::PublicKey
+    pub pk: account::PublicKey,
+    // Bumped for every proposal this validator let lapse, halved on every block it produces.
+    pub missed: u32,
+    // BLS key for attesting blocks. Not on any committee until registered.
+    pub bls: Option<Vec<u8>>
 }
 
 fn idx_from_seed(seed: &[u8]) -> u32 {
@@ -32,22 +42,87 @@ fn idx_from_seed(seed: &[u8]) -> u32 {
         ).floor() as u32
 }
 
+// Performance weighting is off until a strict majority of senators vote it on.
+// Missed slots are recorded either way.
+pub fn weighting(senators: &merkle::Map<senator::Data>) -> bool {
+    let (on, all) = senators.iter().fold((0, 0), |(on, all), senator| (on + senator.weighting as usize, all + 1));
+    on > all / 2
+}
+
+// Deterministic coin flip on the seed: does a slot whose owner has missed this much get passed over?
+fn passed_over(seed: &[u8], missed: u32) -> bool {
+    if missed < MISSED_THRESHOLD {
+        return false;
+    }
+    let roll = u32::from_be_bytes(
+        Sha256::digest([seed, b"weight"].concat())[..4]
+        .try_into()
+        .expect("sha256 output is less than 4 bytes")
+    );
+    roll % (1 + missed.min(MAX_MISSED)) != 0
+}
+
 pub fn leader<'a>(
     seed: &[u8], 
     slots: &'a merkle::Map<SlotData>,
     validators: &'a merkle::Map<Data>, 
-    mut proposal_no: u32
+    mut proposal_no: u32,
+    weighted: bool
 ) -> Result<&'a account::PublicKey, txn::Error> {
     let mut seed = Vec::from(seed);
     loop {
         let idx = idx_from_seed(&seed);
         let from_account = slots.get(&idx.to_be_bytes()).map_err(|_| txn::Error::NoPreimage)?;
-        if let Some(ref k) = from_account {
-            proposal_no -= 1;
-            if proposal_no == 0 {
-                return Ok(&validators.get(&k.owner).unwrap().unwrap().pk);
+        if let Some(k) = from_account {
+            let val = validators.get(&k.owner).unwrap().unwrap();
+            if !(weighted && passed_over(&seed, val.missed)) {
+                proposal_no -= 1;
+                if proposal_no == 0 {
+                    return Ok(&val.pk);
+                }
             }
         }
         seed = Sha256::digest(&seed).to_vec();
     }
+}
+
+// More than two thirds, what both attestations and finality need.
+pub fn supermajority(weight: u32, total: u32) -> bool {
+    weight as u64 * 3 > total as u64 * 2
+}
+
+pub fn first_free(slots: &merkle::Map<SlotData>, range: std::ops::Range<u32>) -> Option<u32> {
+    let end = range.end.min(state::VALIDATOR_SLOTS);
+    (range.start..end).find(|i| matches!(slots.get(&i.to_be_bytes()), Ok(None)))
+}
+
+// Slot a validator's stake txn with this nonce claimed, if it's still theirs. How a StakeIn
+// sender finds out which slot the state picked once the txn is in.
+pub fn claimed(slots: &merkle::Map<SlotData>, owner: &Id, nonce: u32) -> Option<u32> {
+    (0..state::VALIDATOR_SLOTS)
+        .find(|i| matches!(slots.get(&i.to_be_bytes()), Ok(Some(slot)) if &slot.owner == owner && slot.nonce == nonce))
+}
+
+// Account shards a validator checks when it isn't verifying everything: slot index mod shard count.
+pub fn assigned_shards(slots: &merkle::Map<SlotData>, id: &Id, num_shards: usize) -> BTreeSet<usize> {
+    (0..state::VALIDATOR_SLOTS)
+        .filter(|i| matches!(slots.get(&i.to_be_bytes()), Ok(Some(slot)) if &slot.owner == id))
+        .map(|i| i as usize % num_shards)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighting() {
+        let seeds: Vec<[u8; 32]> = (0u32..900).map(|i| Sha256::digest(i.to_be_bytes()).into()).collect();
+        assert!(seeds.iter().all(|seed| !passed_over(seed, MISSED_THRESHOLD - 1)));
+        let kept = |missed| seeds.iter().filter(|seed| !passed_over(*seed, missed)).count();
+        // Roughly 1 / (1 + missed) of slots survive, capped at MAX_MISSED.
+        assert!((250..350).contains(&kept(MISSED_THRESHOLD)));
+        assert!((60..140).contains(&kept(MAX_MISSED)));
+        assert!((60..140).contains(&kept(10 * MAX_MISSED)));
+    }
 }
\ No newline at end of file